@@ -0,0 +1,363 @@
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use common::login_module::LoginRespData;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+// 文件格式: MAGIC(4) + VERSION(1) + SALT(16) + NONCE(12) + 密文(包含16字节的tag)
+const FILE_MAGIC: &[u8; 4] = b"RCAI";
+const FILE_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+const HEADER_LEN: usize = FILE_MAGIC.len() + 1 + SALT_LEN + NONCE_LEN;
+
+// 用口令派生密钥时的迭代次数
+const PASSPHRASE_ITERATIONS: u32 = 100_000;
+
+// 未配置密钥文件时使用的路径，在当前用户的配置目录下.
+// 密钥文件不能与密文放在同一个目录，否则拿到目录的人可以直接解密
+const DEFAULT_KEY_FILE_PATH: &str = "r-chat/account_key";
+
+// 派生加密密钥的来源
+enum KeySource {
+    // 本地口令
+    Passphrase(String),
+    // 随机生成的密钥文件，不存在时自动创建
+    KeyFile(PathBuf),
+}
+
+/**
+ *  加密存储登录后的账户信息(包含token)，防止拿到磁盘文件的人直接使用token.
+ *  每个文件使用AES-256-GCM加密，文件被篡改或损坏时解密失败，读取时会被拒绝
+ **/
+pub struct CredentialStore {
+    save_path: String,
+    key_source: KeySource,
+}
+
+impl CredentialStore {
+    // CLIENT_ACCOUNT_PASSPHRASE 有值时使用口令，否则使用 CLIENT_ACCOUNT_KEY_FILE 指定的密钥文件，
+    // 未配置时使用用户配置目录下的密钥文件
+    pub fn init_from_env(save_path: String) -> Self {
+        dotenvy::dotenv().ok();
+
+        let key_source = match env::var("CLIENT_ACCOUNT_PASSPHRASE") {
+            Ok(t) if !t.is_empty() => KeySource::Passphrase(t),
+            _ => {
+                let key_file = match env::var("CLIENT_ACCOUNT_KEY_FILE") {
+                    Ok(t) if !t.is_empty() => PathBuf::from(t),
+                    _ => get_config_dir().join(DEFAULT_KEY_FILE_PATH),
+                };
+                KeySource::KeyFile(key_file)
+            }
+        };
+
+        CredentialStore {
+            save_path,
+            key_source,
+        }
+    }
+
+    // 加密保存账户信息
+    pub fn save(&self, data: &LoginRespData) -> Result<(), String> {
+        create_private_dir(&self.save_path)?;
+
+        let plain = bincode::serialize(data).map_err(|e| e.to_string())?;
+
+        let salt: [u8; SALT_LEN] = rand::thread_rng().gen();
+        let nonce: [u8; NONCE_LEN] = rand::thread_rng().gen();
+        let cipher = self.create_cipher(&salt)?;
+
+        let encrypted = cipher
+            .encrypt(Nonce::from_slice(&nonce), plain.as_slice())
+            .map_err(|_| "encrypt account info fail !".to_string())?;
+
+        let mut bytes = Vec::with_capacity(HEADER_LEN + encrypted.len());
+        bytes.extend_from_slice(FILE_MAGIC);
+        bytes.push(FILE_VERSION);
+        bytes.extend_from_slice(&salt);
+        bytes.extend_from_slice(&nonce);
+        bytes.extend_from_slice(&encrypted);
+
+        write_private_file(&self.get_file_path(&data.account)?, &bytes)
+    }
+
+    // 读取并解密账户信息. 文件不存在返回None，被篡改或损坏返回Err
+    pub fn load(&self, account: &String) -> Result<Option<LoginRespData>, String> {
+        let path = self.get_file_path(account)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let bytes = fs::read(&path).map_err(|e| e.to_string())?;
+
+        if bytes.len() <= HEADER_LEN
+            || &bytes[..FILE_MAGIC.len()] != FILE_MAGIC
+            || bytes[FILE_MAGIC.len()] != FILE_VERSION
+        {
+            return Err(format!("account file {:?} is corrupted !", path));
+        }
+
+        let salt_start = FILE_MAGIC.len() + 1;
+        let nonce_start = salt_start + SALT_LEN;
+        let salt = &bytes[salt_start..nonce_start];
+        let nonce = &bytes[nonce_start..HEADER_LEN];
+
+        let cipher = self.create_cipher(salt)?;
+
+        let plain = cipher
+            .decrypt(Nonce::from_slice(nonce), &bytes[HEADER_LEN..])
+            .map_err(|_| format!("account file {:?} is tampered or key is wrong !", path))?;
+
+        let data: LoginRespData = bincode::deserialize(&plain).map_err(|e| e.to_string())?;
+
+        // 文件名与内容中的账户不一致，说明文件被替换过
+        if data.account != *account {
+            return Err(format!("account file {:?} does not match account !", path));
+        }
+
+        Ok(Some(data))
+    }
+
+    // 账户名作为文件名，不能包含路径分隔符等字符，避免写到存储目录之外
+    fn get_file_path(&self, account: &String) -> Result<PathBuf, String> {
+        let valid = !account.is_empty()
+            && account
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(format!("invalid account name: {account}"));
+        }
+        Ok(Path::new(&self.save_path).join(account))
+    }
+
+    fn create_cipher(&self, salt: &[u8]) -> Result<Aes256Gcm, String> {
+        let key = self.derive_key(salt)?;
+        Aes256Gcm::new_from_slice(&key).map_err(|e| e.to_string())
+    }
+
+    fn derive_key(&self, salt: &[u8]) -> Result<[u8; KEY_LEN], String> {
+        let mut key = [0u8; KEY_LEN];
+
+        match &self.key_source {
+            KeySource::Passphrase(t) => {
+                pbkdf2::pbkdf2_hmac::<Sha256>(t.as_bytes(), salt, PASSPHRASE_ITERATIONS, &mut key);
+            }
+            KeySource::KeyFile(path) => {
+                if is_inside(path, Path::new(&self.save_path)) {
+                    return Err(format!(
+                        "key file {:?} can not be in account save path, please move it !",
+                        path
+                    ));
+                }
+                let secret = load_or_create_key_file(path)?;
+                let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&secret)
+                    .expect("hmac can take key of any size");
+                mac.update(salt);
+                key.copy_from_slice(&mac.finalize().into_bytes());
+            }
+        }
+
+        Ok(key)
+    }
+}
+
+// 当前用户的配置目录: $XDG_CONFIG_HOME、$HOME/.config 或者 windows的 %APPDATA%
+fn get_config_dir() -> PathBuf {
+    if let Some(t) = env::var_os("XDG_CONFIG_HOME").filter(|t| !t.is_empty()) {
+        return PathBuf::from(t);
+    }
+    if let Some(t) = env::var_os("APPDATA").filter(|t| !t.is_empty()) {
+        return PathBuf::from(t);
+    }
+    match env::var_os("HOME").filter(|t| !t.is_empty()) {
+        Some(t) => PathBuf::from(t).join(".config"),
+        None => PathBuf::from(".config"),
+    }
+}
+
+// 按路径的组成部分判断path是否在dir目录中，不要求路径已经存在
+fn is_inside(path: &Path, dir: &Path) -> bool {
+    normalize(path).starts_with(normalize(dir))
+}
+
+fn normalize(path: &Path) -> PathBuf {
+    let path = match path.is_absolute() {
+        true => path.to_path_buf(),
+        false => env::current_dir().unwrap_or_default().join(path),
+    };
+
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                result.pop();
+            }
+            t => result.push(t),
+        }
+    }
+    result
+}
+
+fn load_or_create_key_file(path: &PathBuf) -> Result<Vec<u8>, String> {
+    if path.exists() {
+        let mut secret = vec![];
+        File::open(path)
+            .and_then(|mut t| t.read_to_end(&mut secret))
+            .map_err(|e| e.to_string())?;
+
+        if secret.len() < KEY_LEN {
+            return Err(format!("key file {:?} is corrupted !", path));
+        }
+        return Ok(secret);
+    }
+
+    if let Some(parent) = path.parent() {
+        create_private_dir(&parent.to_string_lossy().to_string())?;
+    }
+
+    let secret: [u8; KEY_LEN] = rand::thread_rng().gen();
+    write_private_file(path, &secret)?;
+    Ok(secret.to_vec())
+}
+
+// 目录只允许当前用户访问
+fn create_private_dir(path: &String) -> Result<(), String> {
+    fs::create_dir_all(path).map_err(|e| e.to_string())?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o700)).map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+// 文件只允许当前用户读写
+fn write_private_file(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path).map_err(|e| e.to_string())?;
+
+    // 文件已经存在时mode不会生效，需要单独设置
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))
+            .map_err(|e| e.to_string())?;
+    }
+
+    file.write_all(bytes).map_err(|e| e.to_string())?;
+    file.sync_all().map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_store(name: &str, key_source: KeySource) -> CredentialStore {
+        let dir = env::temp_dir().join(format!("credential_store_{}_{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        CredentialStore {
+            save_path: dir.to_string_lossy().to_string(),
+            key_source,
+        }
+    }
+
+    fn create_data(account: &str) -> LoginRespData {
+        LoginRespData {
+            user_id: 1,
+            account: account.to_string(),
+            token: "token".to_string(),
+        }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let store = create_store("round_trip", KeySource::Passphrase("pass".to_string()));
+        let account = "test".to_string();
+        assert!(store.load(&account).unwrap().is_none());
+
+        store.save(&create_data("test")).unwrap();
+        let data = store.load(&account).unwrap().unwrap();
+        assert_eq!(data.user_id, 1);
+        assert_eq!(data.token, "token");
+
+        // 口令不同无法解密
+        let other = CredentialStore {
+            save_path: store.save_path.clone(),
+            key_source: KeySource::Passphrase("other".to_string()),
+        };
+        assert!(other.load(&account).is_err());
+
+        fs::remove_dir_all(&store.save_path).ok();
+    }
+
+    #[test]
+    fn reject_tampered_or_truncated_file() {
+        let key_dir = env::temp_dir().join(format!("credential_key_{}", std::process::id()));
+        let store = create_store("tamper", KeySource::KeyFile(key_dir.join("key")));
+        let account = "test".to_string();
+        store.save(&create_data("test")).unwrap();
+        assert!(store.load(&account).unwrap().is_some());
+
+        let path = store.get_file_path(&account).unwrap();
+        let bytes = fs::read(&path).unwrap();
+
+        let mut tampered = bytes.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        fs::write(&path, &tampered).unwrap();
+        assert!(store.load(&account).is_err());
+
+        fs::write(&path, &bytes[..HEADER_LEN]).unwrap();
+        assert!(store.load(&account).is_err());
+
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(store.load(&account).is_err());
+
+        // 其它账户的文件被复制过来
+        fs::write(&path, &bytes).unwrap();
+        fs::copy(&path, store.get_file_path(&"other".to_string()).unwrap()).unwrap();
+        assert!(store.load(&"other".to_string()).is_err());
+
+        fs::remove_dir_all(&store.save_path).ok();
+        fs::remove_dir_all(key_dir).ok();
+    }
+
+    #[test]
+    fn reject_invalid_account_name() {
+        let store = create_store("account", KeySource::Passphrase("pass".to_string()));
+        for account in ["", "../test", "a/b", "a\\b", ".."] {
+            assert!(store.get_file_path(&account.to_string()).is_err());
+        }
+        assert!(store.save(&create_data("../test")).is_err());
+    }
+
+    #[test]
+    fn reject_key_file_in_save_path() {
+        let dir = env::temp_dir().join(format!("credential_store_key_{}", std::process::id()));
+        let store = CredentialStore {
+            save_path: dir.to_string_lossy().to_string(),
+            key_source: KeySource::KeyFile(dir.join("./sub/../.account_key")),
+        };
+        assert!(store.save(&create_data("test")).is_err());
+
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -1,16 +1,59 @@
-use common::base::{TcpClientSide, TcpServerSide};
-use common::chat_protocol::ChatCommand;
+use crate::credential_store::CredentialStore;
+pub use crate::profile::{ClientProfile, ProfileManager};
+use common::account_module::{
+    check_pwd, AccountDataEnum, AccountRespData, AccountTypeEnum, BizAccountData, ChangePwdReqData,
+    ClientAccountModule, DefaultAccountHandler, DefaultClientAccountModule, RegisterReqData,
+};
+use common::base::{
+    connect, now_millis, read_protocol, send_msg, ConnectionRegistry, TcpClientSide, TcpServerSide,
+    MAX_RESP_DATA_LEN,
+};
+use common::chat_cache::ChatCache;
+use common::chat_content::RichContentEnum;
+use common::chat_module::{
+    create_snippet, ChatAckData, ChatContent, ChatData, ClientChatModule, ConversationMetadata,
+    DefaultChatHandler, DefaultClientChatModule, EditHistoryRespData, EventData, HistoryRespData,
+    MessageState, ReceiptData, ScheduledData, SearchRespData, SyncRespData, SyncSinceData,
+    ThreadRespData, EVENT_KIND_STOP_TYPING, EVENT_KIND_TYPING,
+};
+use common::chat_protocol::{ChatCommand, Protocol};
 use common::config::TcpSocketConfig;
-use common::login_module::{BizResult, ClientLoginModule, DefaultLoginHandler, LoginRespData};
+use common::file_module::{
+    ClientFileModule, DefaultFileHandler, DownloadUrlData, FileDataEnum, UploadRespData,
+};
+use common::group_module::{
+    ClientGroupModule, DefaultGroupHandler, GroupEventData, GroupRespData, GroupTypeEnum,
+};
+use common::login_module::{
+    ApiKeyReqData, BizLoginData, BizResult, ClientLoginModule, DefaultLoginHandler, LoginDataEnum,
+    LoginRespData, LoginTypeEnum, ScramChallengeData, ScramProofReqData, ScramStartReqData,
+    TotpChallengeData, TotpReqData,
+};
 use common::protocol_factory::HandleProtocolFactory;
+use common::scram;
 use env_logger::Env;
-use log::warn;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::env;
 use std::fmt::Error;
-use std::fs::File;
-use std::net::{SocketAddr, SocketAddrV4};
-use std::str::FromStr;
-use std::{env, fs};
-use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+mod credential_store;
+mod profile;
+
+// 本地聊天记录在账户信息目录下的子目录
+const CHAT_CACHE_DIR_NAME: &str = "chat";
+
+// 删除本地缓存中过期的阅后即焚消息的间隔
+const CHAT_CACHE_PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+// 已连接的profile发送聊天消息、账户请求使用的module
+type ClientModules = (DefaultClientChatModule, DefaultClientAccountModule);
 
 pub fn start_client() {
     // get env vars   读取.env文件中的变量，相当于读取配置文件
@@ -22,64 +65,840 @@ pub fn start_client() {
     // start trace info collect.  开启堆栈信息收集
     // tracing_subscriber::fmt::init();
 
-    start_client_socket();
+    let mut runtime = ClientRuntime::init_from_env().expect("load client profiles fail!");
+
+    // CLIENT_START_PROFILES 配置了多个profile时同时启动，否则只启动当前profile
+    let names: Vec<String> = match env::var("CLIENT_START_PROFILES") {
+        Ok(t) if !t.trim().is_empty() => t.split(',').map(|t| t.trim().to_string()).collect(),
+        _ => vec![runtime
+            .current_profile()
+            .expect("no client profile is selected!")
+            .name
+            .clone()],
+    };
+
+    for name in names {
+        runtime
+            .start_profile(&name)
+            .expect("start client profile fail!");
+    }
+
+    runtime.join_all();
 }
 
-fn start_client_socket() {
-    let factory = create_factory();
+// module_sender: 连接成功后把发送聊天消息、账户请求的module交给ClientRuntime
+fn start_client_socket(profile: ClientProfile, module_sender: Sender<ClientModules>) {
+    let server_addr = profile
+        .get_server_socket_addr()
+        .expect("server address is invalid!");
 
-    let server_addr = env::var("SERVER_ADDRESS").expect("SERVER_ADDRESS is not set in .env file");
+    // 配置了api key的profile(bot、服务账户)使用api key登录，不需要密码
+    let api_key = get_profile_api_key(&profile);
+    let pwd = match api_key {
+        Some(_) => String::new(),
+        None => get_profile_pwd(&profile),
+    };
+    // 本地聊天记录按账户保存在账户信息目录下
+    let chat_cache = ChatCache::new(
+        Path::new(&profile.account_save_path)
+            .join(CHAT_CACHE_DIR_NAME)
+            .join(&profile.account),
+    );
+    // 登录成功后通过chat module同步缺失的消息，registry需要在连接之前创建
+    let registry = ConnectionRegistry::default();
+    let chat_module =
+        DefaultClientChatModule::new(registry.clone(), server_addr, chat_cache.clone());
+    let account_module = DefaultClientAccountModule::new(registry.clone(), server_addr);
 
-    let server_socket = SocketAddrV4::from_str(server_addr.as_str()).unwrap();
+    let mut client_login = DefaultClientLoginModule::init_from_profile(profile, pwd);
+    client_login.set_chat_module(chat_module.clone());
 
-    let mut client = TcpClientSide::new(SocketAddr::V4(server_socket), factory);
+    // 登录请求需要在login module交给factory之前生成，login module中会保存scram登录的状态
+    let login_req = match api_key {
+        Some(t) => client_login.create_api_key_req(t),
+        None => client_login.create_scram_start_req(),
+    };
+
+    start_cache_purge(chat_cache.clone());
+
+    let factory = create_factory(
+        client_login,
+        chat_cache,
+        chat_module.clone(),
+        account_module.clone(),
+    );
+
+    let mut client = TcpClientSide::new_with_registry(server_addr, factory, registry);
+
+    // 接收方已经退出时不影响连接
+    module_sender.send((chat_module, account_module)).ok();
+
+    client
+        .send_to_server(&login_req)
+        .expect("send login request fail!");
 
     client.start();
 }
 
-fn create_factory() -> HandleProtocolFactory {
+// 定时删除本地缓存中过期的阅后即焚消息，离线时也会删除
+fn start_cache_purge(chat_cache: ChatCache) {
+    thread::spawn(move || loop {
+        thread::sleep(CHAT_CACHE_PURGE_INTERVAL);
+
+        match chat_cache.purge_expired() {
+            Ok(0) => {}
+            Ok(count) => info!("purge {} expired msgs from cache", count),
+            Err(e) => warn!("purge expired msgs from cache fail: {}", e),
+        }
+    });
+}
+
+// profile的登录密码: 优先读取 PASSWORD_<profile名称大写>，没有时读取 PASSWORD
+fn get_profile_pwd(profile: &ClientProfile) -> String {
+    let key = format!("PASSWORD_{}", profile.name.to_uppercase());
+    env::var(key)
+        .or(env::var("PASSWORD"))
+        .expect("PASSWORD is not set in .env file")
+}
+
+// profile的api key: 优先读取 API_KEY_<profile名称大写>，没有时读取 API_KEY
+fn get_profile_api_key(profile: &ClientProfile) -> Option<String> {
+    let key = format!("API_KEY_{}", profile.name.to_uppercase());
+    env::var(key)
+        .or(env::var("API_KEY"))
+        .ok()
+        .filter(|t| !t.is_empty())
+}
+
+/**
+ *  管理client的多个profile. 每个启动的profile在单独的线程中连接各自的server，
+ *  切换profile只改变当前profile，已经启动的profile不受影响
+ **/
+pub struct ClientRuntime {
+    profile_manager: ProfileManager,
+    running: HashMap<String, JoinHandle<()>>,
+    // 已连接的profile发送聊天消息、账户请求使用的module
+    modules: HashMap<String, ClientModules>,
+}
+
+impl ClientRuntime {
+    pub fn init_from_env() -> Result<Self, String> {
+        Ok(ClientRuntime {
+            profile_manager: ProfileManager::init_from_env()?,
+            running: Default::default(),
+            modules: Default::default(),
+        })
+    }
+
+    pub fn list_profiles(&self) -> &Vec<ClientProfile> {
+        self.profile_manager.list()
+    }
+
+    pub fn current_profile(&self) -> Option<&ClientProfile> {
+        self.profile_manager.get_current()
+    }
+
+    pub fn save_profile(&mut self, profile: ClientProfile) -> Result<(), String> {
+        self.profile_manager.save_profile(profile)
+    }
+
+    // 正在运行的profile不能删除
+    pub fn remove_profile(&mut self, name: &str) -> Result<(), String> {
+        if self.is_running(name) {
+            return Err(format!("profile is running: {name}"));
+        }
+        self.profile_manager.remove_profile(name)
+    }
+
+    pub fn is_running(&self, name: &str) -> bool {
+        self.running
+            .get(name)
+            .map(|t| !t.is_finished())
+            .unwrap_or(false)
+    }
+
+    // 在新线程中启动profile，已经在运行时不做处理
+    pub fn start_profile(&mut self, name: &str) -> Result<(), String> {
+        if self.is_running(name) {
+            return Ok(());
+        }
+
+        let profile = self
+            .profile_manager
+            .get(name)
+            .cloned()
+            .ok_or(format!("profile not exist: {name}"))?;
+
+        info!("start client profile:{}", name);
+
+        let (module_sender, module_receiver) = mpsc::channel();
+
+        let handle = thread::Builder::new()
+            .name(format!("client-{name}"))
+            .spawn(move || start_client_socket(profile, module_sender))
+            .map_err(|e| e.to_string())?;
+
+        // 等待连接到server. 连接失败时线程退出，sender被drop
+        let modules = module_receiver
+            .recv()
+            .map_err(|_| format!("profile {name} connect to server fail !"))?;
+
+        self.modules.insert(name.to_string(), modules);
+        self.running.insert(name.to_string(), handle);
+        Ok(())
+    }
+
+    // 获取运行中的profile发送聊天消息的module
+    pub fn chat_module(&self, name: &str) -> Option<&DefaultClientChatModule> {
+        match self.is_running(name) {
+            true => self.modules.get(name).map(|t| &t.0),
+            false => None,
+        }
+    }
+
+    // 获取运行中的profile发送修改密码、修改资料等请求的module，响应在账户的handler中处理
+    pub fn account_module(&self, name: &str) -> Option<&DefaultClientAccountModule> {
+        match self.is_running(name) {
+            true => self.modules.get(name).map(|t| &t.1),
+            false => None,
+        }
+    }
+
+    // 切换当前profile，没有运行时同时启动
+    pub fn switch_profile(&mut self, name: &str) -> Result<(), String> {
+        self.profile_manager.switch(name)?;
+        self.start_profile(name)
+    }
+
+    // 等待所有profile的线程结束
+    pub fn join_all(self) {
+        for (name, handle) in self.running {
+            if handle.join().is_err() {
+                warn!("client profile:{} stopped with panic", name);
+            }
+        }
+    }
+}
+
+fn create_factory(
+    client_login: DefaultClientLoginModule,
+    chat_cache: ChatCache,
+    chat_module: DefaultClientChatModule,
+    account_module: DefaultClientAccountModule,
+) -> HandleProtocolFactory {
     // login handler
-    let login_handler = create_default_client_login_handler();
-    // todo: chat handler
+    let login_handler = Box::new(DefaultLoginHandler::new(
+        false,
+        None,
+        Some(Box::new(client_login)),
+    ));
+    // account handler
+    let account_handler = Box::new(DefaultAccountHandler::new(
+        None,
+        Some(Box::new(DefaultClientAccountReceiver { account_module })),
+    ));
+    // chat handler
+    let chat_handler = Box::new(DefaultChatHandler::new(
+        None,
+        Some(Box::new(DefaultClientChatReceiver::new(chat_cache))),
+    ));
+    // group handler
+    let group_handler = Box::new(DefaultGroupHandler::new(
+        None,
+        Some(Box::new(DefaultClientGroupReceiver {})),
+    ));
+    // file handler
+    let file_handler = Box::new(DefaultFileHandler::new(
+        None,
+        Some(Box::new(DefaultClientFileReceiver { chat_module })),
+    ));
     // todo: p2p handler
 
     let mut factory = HandleProtocolFactory::new();
     factory.registry_handler(ChatCommand::Login, login_handler);
+    factory.registry_handler(ChatCommand::Account, account_handler);
+    factory.registry_handler(ChatCommand::Chat, chat_handler);
+    factory.registry_handler(ChatCommand::Group, group_handler);
+    factory.registry_handler(ChatCommand::File, file_handler);
     factory
 }
 
-fn create_default_client_login_handler() -> Box<DefaultLoginHandler> {
-    let client_login = DefaultClientLoginModule::init_from_env();
-    Box::new(DefaultLoginHandler::new(
-        false,
-        None,
-        Some(Box::new(client_login)),
-    ))
+// 当前profile的server地址
+fn get_server_address() -> Result<SocketAddr, String> {
+    let manager = ProfileManager::init_from_env()?;
+    manager
+        .get_current()
+        .ok_or("no client profile is selected !".to_string())?
+        .get_server_socket_addr()
+}
+
+// 注册账户. 只提交根据密码计算的scram校验数据，密码不发送给server
+pub fn register(account: String, pwd: String) -> Result<AccountRespData, String> {
+    check_pwd(&pwd)?;
+    send_account_req(BizAccountData {
+        account_type: AccountTypeEnum::Register,
+        data: AccountDataEnum::RegisterReq(RegisterReqData {
+            account,
+            verifier: scram::ScramVerifier::create(&pwd),
+        }),
+    })
+}
+
+// 新建一个未登录的连接来发送账户请求，并同步等待响应.
+// 已登录的连接上的请求使用DefaultClientAccountModule发送
+fn send_account_req(req: BizAccountData) -> Result<AccountRespData, String> {
+    let mut stream = connect(get_server_address()?).map_err(|e| e.to_string())?;
+    send_msg(&mut stream, &req.to_protocol_bytes()).map_err(|e| e.to_string())?;
+
+    let pkg = read_protocol(&mut stream, MAX_RESP_DATA_LEN).map_err(|e| e.to_string())?;
+    parse_account_resp(&pkg)
+}
+
+fn parse_account_resp(pkg: &Protocol) -> Result<AccountRespData, String> {
+    if pkg.data_type.as_deref() != Some(&ChatCommand::Account.to_data_type()[..]) {
+        return Err("unexpected resp command !".to_string());
+    }
+    let data = pkg
+        .data
+        .as_ref()
+        .ok_or("resp data is empty !".to_string())?;
+    let resp: BizAccountData = bincode::deserialize(data).map_err(|e| e.to_string())?;
+
+    match resp.data {
+        AccountDataEnum::RespData(t) if t.is_success => {
+            t.data.ok_or("account resp data is empty !".to_string())
+        }
+        AccountDataEnum::RespData(t) => Err(t.msg.unwrap_or_default()),
+        _ => Err("unexpected account resp !".to_string()),
+    }
+}
+
+// 收到的聊天消息打印出来并保存到本地缓存，并记录已发送消息的状态
+pub struct DefaultClientChatReceiver {
+    cache: ChatCache,
+    // 已发送消息的状态，key为server分配的消息id
+    sent_states: HashMap<String, MessageState>,
+    // 正在输入的账户，key为 (会话, 账户)，value为过期时间
+    typing: HashMap<(String, String), i64>,
+}
+
+impl DefaultClientChatReceiver {
+    pub fn new(cache: ChatCache) -> Self {
+        DefaultClientChatReceiver {
+            cache,
+            sent_states: HashMap::new(),
+            typing: HashMap::new(),
+        }
+    }
+}
+
+impl ClientChatModule for DefaultClientChatReceiver {
+    fn handle_chat_msg(&mut self, data: ChatData) {
+        if let Err(e) = self.cache.save(vec![data.clone()]) {
+            warn!("save chat msg {} to cache fail: {}", data.msg_id, e);
+        }
+
+        if let Some(t) = &data.reply_to {
+            info!(
+                "[{}] {} reply to {}: {}",
+                data.time, data.from_account, t.from_account, t.snippet
+            );
+        }
+        if let Some(t) = &data.forward_from {
+            info!(
+                "[{}] {} forwarded from {}:",
+                data.time, data.from_account, t.from_account
+            );
+        }
+        for content in data.contents {
+            match content {
+                ChatContent::Text(t) => info!("[{}] {}: {}", data.time, data.from_account, t.text),
+                ChatContent::File(t) => info!(
+                    "[{}] {} send file: {}",
+                    data.time, data.from_account, t.file_name
+                ),
+                // 不认识的类型显示fallback
+                ChatContent::Rich(t) => match t.decode() {
+                    Some(RichContentEnum::Markdown(m)) => {
+                        info!("[{}] {}: {}", data.time, data.from_account, m.text)
+                    }
+                    _ => info!("[{}] {}: {}", data.time, data.from_account, t.fallback),
+                },
+            }
+        }
+    }
+
+    fn handle_chat_ack(&mut self, resp: BizResult<ChatAckData>) {
+        if resp.is_success {
+            let ack = resp.data.unwrap();
+            match ack.offline {
+                true => info!(
+                    "{} is offline, msg will be delivered after login",
+                    ack.to_account
+                ),
+                false => info!("send msg to {} success", ack.to_account),
+            }
+            if let Err(e) = self.cache.confirm_pending(&ack) {
+                warn!("save sent msg {} to cache fail: {}", ack.msg_id, e);
+            }
+            self.sent_states.insert(ack.msg_id, MessageState::Sent);
+        } else {
+            warn!("send msg fail,原因:{}", resp.msg.unwrap());
+        }
+    }
+
+    fn handle_receipt(&mut self, data: ReceiptData) {
+        for msg_id in data.msg_ids {
+            // 回执可能乱序到达，状态只前进不后退
+            let state = self
+                .sent_states
+                .entry(msg_id.clone())
+                .or_insert(MessageState::Sent);
+            if data.state > *state {
+                *state = data.state;
+                info!(
+                    "[{}] msg {} to {} is {:?}",
+                    data.time, msg_id, data.from_account, data.state
+                );
+            }
+        }
+    }
+
+    fn handle_history_resp(&mut self, resp: BizResult<HistoryRespData>) {
+        if !resp.is_success {
+            warn!("find chat history fail,原因:{}", resp.msg.unwrap());
+            return;
+        }
+
+        let resp = resp.data.unwrap();
+        info!(
+            "receive {} history msgs with {}, has more: {}",
+            resp.messages.len(),
+            resp.peer_account,
+            resp.has_more
+        );
+        if let Err(e) = self.cache.save(resp.messages) {
+            warn!("save chat history to cache fail: {}", e);
+        }
+    }
+
+    fn handle_sync_resp(&mut self, resp: BizResult<SyncRespData>) -> Option<SyncSinceData> {
+        if !resp.is_success {
+            warn!("sync chat msgs fail,原因:{}", resp.msg.unwrap());
+            return None;
+        }
+
+        let resp = resp.data.unwrap();
+        let last_seq = resp.messages.last().map(|t| t.seq);
+        if !resp.messages.is_empty() {
+            info!("sync {} msgs of {}", resp.messages.len(), resp.conversation);
+        }
+        if let Err(e) = self.cache.save(resp.messages) {
+            warn!("save synced msgs to cache fail: {}", e);
+            return None;
+        }
+
+        match (resp.has_more, last_seq) {
+            (true, Some(seq)) => Some(SyncSinceData {
+                conversation: resp.conversation,
+                seq,
+            }),
+            _ => None,
+        }
+    }
+
+    fn handle_message_updated(&mut self, data: ChatData) {
+        match &data.recall_by {
+            Some(t) => info!("msg {} is recalled by {}", data.msg_id, t),
+            None => info!(
+                "msg {} is updated, reactions: {}",
+                data.msg_id,
+                data.reactions
+                    .iter()
+                    .map(|t| format!("{} {}", t.emoji, t.count()))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+        }
+        if let Err(e) = self.cache.save(vec![data]) {
+            warn!("save updated msg to cache fail: {}", e);
+        }
+    }
+
+    fn handle_update_resp(&mut self, resp: BizResult<ChatData>) {
+        if !resp.is_success {
+            warn!("edit or recall msg fail,原因:{}", resp.msg.unwrap());
+            return;
+        }
+
+        let data = resp.data.unwrap();
+        info!("update msg {} success", data.msg_id);
+        if let Err(e) = self.cache.save(vec![data]) {
+            warn!("save updated msg to cache fail: {}", e);
+        }
+    }
+
+    fn handle_disappear_timer_resp(&mut self, resp: BizResult<ChatData>) {
+        if !resp.is_success {
+            warn!("set disappear timer fail,原因:{}", resp.msg.unwrap());
+            return;
+        }
+
+        let data = resp.data.unwrap();
+        info!("set disappear timer of {} success", data.conversation_id());
+        if let Err(e) = self.cache.save(vec![data]) {
+            warn!("save disappear timer msg to cache fail: {}", e);
+        }
+    }
+
+    fn handle_schedule_resp(&mut self, resp: BizResult<ScheduledData>) {
+        if !resp.is_success {
+            warn!("schedule msg fail,原因:{}", resp.msg.unwrap());
+            return;
+        }
+
+        let data = resp.data.unwrap();
+        info!(
+            "scheduled msg {} will be sent at {}",
+            data.schedule_id, data.send_time
+        );
+    }
+
+    fn handle_schedule_list_resp(&mut self, resp: BizResult<Vec<ScheduledData>>) {
+        if !resp.is_success {
+            warn!("find scheduled msgs fail,原因:{}", resp.msg.unwrap());
+            return;
+        }
+
+        for t in resp.data.unwrap() {
+            info!(
+                "[{}] scheduled msg {} to {}: {}",
+                t.send_time,
+                t.schedule_id,
+                t.data.conversation_id(),
+                create_snippet(&t.data.contents)
+            );
+        }
+    }
+
+    fn handle_metadata_resp(&mut self, resp: BizResult<ConversationMetadata>) {
+        if !resp.is_success {
+            warn!("find conversation metadata fail,原因:{}", resp.msg.unwrap());
+            return;
+        }
+
+        let t = resp.data.unwrap();
+        info!(
+            "conversation {} title:{:?} topic:{:?} avatar:{:?} pinned:{:?} disappear:{:?}",
+            t.conversation, t.title, t.topic, t.avatar, t.pinned_msg_ids, t.disappear_seconds
+        );
+    }
+
+    fn handle_update_metadata_resp(&mut self, resp: BizResult<ChatData>) {
+        if !resp.is_success {
+            warn!(
+                "update conversation metadata fail,原因:{}",
+                resp.msg.unwrap()
+            );
+            return;
+        }
+
+        let data = resp.data.unwrap();
+        info!("update metadata of {} success", data.conversation_id());
+        if let Err(e) = self.cache.save(vec![data]) {
+            warn!("save metadata msg to cache fail: {}", e);
+        }
+    }
+
+    fn handle_forward_resp(&mut self, resp: BizResult<Vec<ChatData>>) {
+        if !resp.is_success {
+            warn!("forward msgs fail,原因:{}", resp.msg.unwrap());
+            return;
+        }
+
+        let list = resp.data.unwrap();
+        info!("forward {} msgs success", list.len());
+        if let Err(e) = self.cache.save(list) {
+            warn!("save forwarded msgs to cache fail: {}", e);
+        }
+    }
+
+    fn handle_search_resp(&mut self, resp: BizResult<SearchRespData>) {
+        if !resp.is_success {
+            warn!("search msgs fail,原因:{}", resp.msg.unwrap());
+            return;
+        }
+
+        let resp = resp.data.unwrap();
+        info!(
+            "found {} msgs from offset {}, has more: {}",
+            resp.messages.len(),
+            resp.offset,
+            resp.has_more
+        );
+        for data in resp.messages {
+            info!(
+                "[{}] {} in {}: {}",
+                data.time,
+                data.from_account,
+                data.conversation_id(),
+                create_snippet(&data.contents)
+            );
+        }
+    }
+
+    fn handle_edit_history_resp(&mut self, resp: BizResult<EditHistoryRespData>) {
+        if !resp.is_success {
+            warn!("find edit history fail,原因:{}", resp.msg.unwrap());
+            return;
+        }
+
+        let resp = resp.data.unwrap();
+        for version in resp.versions {
+            info!(
+                "[{}] msg {} {} by {}: {:?}",
+                version.time,
+                resp.msg_id,
+                if version.recalled {
+                    "recalled"
+                } else {
+                    "edited"
+                },
+                version.editor,
+                version.contents
+            );
+        }
+    }
+
+    fn handle_event(&mut self, event: EventData) {
+        let key = (event.conversation.clone(), event.from_account.clone());
+        match event.kind.as_str() {
+            EVENT_KIND_TYPING => {
+                // 重复的正在输入只延长过期时间
+                let expire_time = event.time + event.ttl_millis;
+                if self.typing.insert(key, expire_time).is_none() {
+                    info!("{} is typing in {}", event.from_account, event.conversation);
+                }
+            }
+            EVENT_KIND_STOP_TYPING => {
+                self.typing.remove(&key);
+            }
+            t => info!("receive event {} from {}", t, event.from_account),
+        }
+
+        let now = now_millis();
+        self.typing.retain(|_, expire_time| *expire_time >= now);
+    }
+
+    fn handle_thread_resp(&mut self, resp: BizResult<ThreadRespData>) {
+        if !resp.is_success {
+            warn!("find reply thread fail,原因:{}", resp.msg.unwrap());
+            return;
+        }
+
+        let resp = resp.data.unwrap();
+        info!(
+            "receive {} replies of thread {}, has more: {}",
+            resp.replies.len(),
+            resp.root.msg_id,
+            resp.has_more
+        );
+
+        let mut messages = resp.replies;
+        messages.push(resp.root);
+        if let Err(e) = self.cache.save(messages) {
+            warn!("save reply thread to cache fail: {}", e);
+        }
+    }
+}
+
+// 账户请求的响应暂时只打印出来
+pub struct DefaultClientAccountReceiver {
+    account_module: DefaultClientAccountModule,
+}
+
+impl ClientAccountModule for DefaultClientAccountReceiver {
+    fn handle_account_resp(
+        &mut self,
+        account_type: AccountTypeEnum,
+        resp: BizResult<AccountRespData>,
+    ) {
+        if resp.is_success {
+            info!("{:?} success: {:?}", account_type, resp.data.unwrap());
+        } else {
+            warn!("{:?} fail,原因:{}", account_type, resp.msg.unwrap());
+        }
+    }
+
+    fn handle_change_pwd_challenge(
+        &mut self,
+        challenge: BizResult<ScramChallengeData>,
+    ) -> Option<ChangePwdReqData> {
+        self.account_module
+            .create_change_pwd_req(challenge)
+            .map_err(|e| warn!("ChangePwd fail,原因:{}", e))
+            .ok()
+    }
+}
+
+// 群组的响应和变化暂时只打印出来
+pub struct DefaultClientGroupReceiver {}
+
+impl ClientGroupModule for DefaultClientGroupReceiver {
+    fn handle_group_resp(&mut self, group_type: GroupTypeEnum, resp: BizResult<GroupRespData>) {
+        if resp.is_success {
+            info!("group {:?} success: {:?}", group_type, resp.data.unwrap());
+        } else {
+            warn!("group {:?} fail,原因:{}", group_type, resp.msg.unwrap());
+        }
+    }
+
+    fn handle_group_event(&mut self, group_type: GroupTypeEnum, event: GroupEventData) {
+        info!(
+            "[{}] group {} {:?} by {}: {:?}",
+            event.time, event.group.group_id, group_type, event.operator, event.accounts
+        );
+    }
+}
+
+// 上传完成之后发送附件所在的消息
+pub struct DefaultClientFileReceiver {
+    chat_module: DefaultClientChatModule,
+}
+
+impl ClientFileModule for DefaultClientFileReceiver {
+    fn handle_upload_resp(&mut self, resp: UploadRespData) -> Option<FileDataEnum> {
+        let (req, completed) = self.chat_module.file_module().next_upload_req(resp);
+
+        if let Some((task, url)) = completed {
+            info!("upload {} success: {}", task.file_path, url);
+            if let Some(message) = task.message {
+                if self.chat_module.send_uploaded(message, url).is_err() {
+                    warn!("send msg with file {} fail", task.file_name);
+                }
+            }
+        }
+        req
+    }
+
+    fn handle_download_url_resp(&mut self, resp: BizResult<DownloadUrlData>) {
+        if resp.is_success {
+            let data = resp.data.unwrap();
+            info!(
+                "download {} from {}, expire at {}",
+                data.url, data.download_url, data.expire_time
+            );
+        } else {
+            warn!("get download url fail,原因:{}", resp.msg.unwrap());
+        }
+    }
 }
 
 pub struct DefaultClientLoginModule {
-    // 账户信息存储路径
-    save_path: String,
+    // 账户信息的加密存储
+    credential_store: CredentialStore,
     // 缓存的账户信息
     cache_account_info: Option<LoginRespData>,
+    // 登录使用的账户和密码
+    account: String,
+    pwd: String,
+    // 进行中的scram登录的状态
+    scram_state: Option<ScramClientState>,
+    // 登录成功后用于同步缺失的消息
+    chat_module: Option<DefaultClientChatModule>,
+}
+
+struct ScramClientState {
+    client_nonce: String,
+    // 期望的server签名，收到challenge之后计算得到
+    server_signature: Option<String>,
 }
 
 impl DefaultClientLoginModule {
     pub fn init_from_env() -> Self {
         dotenvy::dotenv().ok();
-        let save_path = env::var("CLIENT_ACCOUNT_SAVE_PATH")
-            .expect("CLIENT_ACCOUNT_SAVE_PATH is not set in .env file");
+        let profile = ClientProfile::init_from_env();
+        let pwd = env::var("PASSWORD").expect("PASSWORD is not set in .env file");
+        Self::init_from_profile(profile, pwd)
+    }
+
+    pub fn init_from_profile(profile: ClientProfile, pwd: String) -> Self {
+        let account = profile.account;
+
+        let credential_store = CredentialStore::init_from_env(profile.account_save_path);
+
+        // 读取上次登录保存的账户信息，文件被篡改或损坏时丢弃
+        let cache_account_info = match credential_store.load(&account) {
+            Ok(t) => t,
+            Err(e) => {
+                warn!("读取账户信息失败,原因:{}", e);
+                None
+            }
+        };
+
         DefaultClientLoginModule {
-            save_path,
-            cache_account_info: None,
+            credential_store,
+            cache_account_info,
+            account,
+            pwd,
+            scram_state: None,
+            chat_module: None,
         }
     }
 
+    pub fn set_chat_module(&mut self, chat_module: DefaultClientChatModule) {
+        self.chat_module = Some(chat_module);
+    }
+
+    // 生成scram登录的第一个请求，返回可以直接写入stream的字节
+    pub fn create_scram_start_req(&mut self) -> Vec<u8> {
+        let client_nonce = scram::create_nonce();
+
+        self.scram_state = Some(ScramClientState {
+            client_nonce: client_nonce.clone(),
+            server_signature: None,
+        });
+
+        let req = BizLoginData {
+            login_type: LoginTypeEnum::Req,
+            data: LoginDataEnum::ScramStartReq(ScramStartReqData {
+                account: self.account.clone(),
+                client_nonce,
+            }),
+        };
+
+        Protocol::create_by_data(ChatCommand::Login, bincode::serialize(&req).unwrap()).to_vec()
+    }
+
+    // api key登录不需要scram的多步交互，server直接返回登录结果
+    pub fn create_api_key_req(&mut self, api_key: String) -> Vec<u8> {
+        self.scram_state = None;
+
+        let req = BizLoginData {
+            login_type: LoginTypeEnum::Req,
+            data: LoginDataEnum::ApiKeyReq(ApiKeyReqData {
+                account: self.account.clone(),
+                api_key,
+            }),
+        };
+
+        Protocol::create_by_data(ChatCommand::Login, bincode::serialize(&req).unwrap()).to_vec()
+    }
+
     fn handle_login_resp(&mut self, resp: LoginRespData) {
-        // 存储账户信息到文件
-        let cache_data = save_account_info(&self.save_path, resp);
+        // 加密存储账户信息到文件
+        if let Err(e) = self.credential_store.save(&resp) {
+            warn!("存储账户信息失败,原因:{}", e);
+        }
         //  存储账户信息到缓存
-        self.cache_account_info = Some(cache_data);
+        self.cache_account_info = Some(resp);
+
+        // 断线重连后同步离线期间其它设备收发的消息
+        if let Some(chat_module) = &self.chat_module {
+            if chat_module.sync_all().is_err() {
+                warn!("同步聊天记录失败");
+            }
+        }
     }
 
     fn get_login_cache_info(&self) -> Option<LoginRespData> {
@@ -110,16 +929,114 @@ impl ClientLoginModule for DefaultClientLoginModule {
             warn!("登录失败,原因:{}", resp.msg.unwrap());
         }
     }
+
+    fn handle_scram_challenge(
+        &mut self,
+        challenge: ScramChallengeData,
+    ) -> Option<ScramProofReqData> {
+        let state = match self.scram_state.as_mut() {
+            None => {
+                warn!("收到了scram challenge，但是没有进行中的登录");
+                return None;
+            }
+            Some(t) => t,
+        };
+
+        // server返回的nonce必须以client生成的nonce开头
+        if !challenge.nonce.starts_with(&state.client_nonce) {
+            warn!("scram challenge中的nonce不匹配，放弃登录");
+            return None;
+        }
+
+        let auth_message = scram::auth_message(
+            &self.account,
+            &challenge.nonce,
+            &challenge.salt,
+            challenge.iterations,
+        );
+
+        let proof = scram::create_client_proof(
+            &self.pwd,
+            &challenge.salt,
+            challenge.iterations,
+            &auth_message,
+        );
+
+        state.server_signature = Some(proof.server_signature);
+
+        Some(ScramProofReqData {
+            account: self.account.clone(),
+            nonce: challenge.nonce,
+            proof: proof.proof,
+        })
+    }
+
+    fn verify_server_signature(&mut self, server_signature: &str) -> bool {
+        match self.scram_state.take().and_then(|t| t.server_signature) {
+            None => false,
+            Some(t) => scram::constant_time_eq(t.as_bytes(), server_signature.as_bytes()),
+        }
+    }
+
+    fn handle_totp_challenge(&mut self, challenge: TotpChallengeData) -> Option<TotpReqData> {
+        // 从命令行读取验证码
+        println!(
+            "账户[{}]已开启两步验证，请输入认证器上的验证码或者一个恢复码:",
+            challenge.account
+        );
+
+        let mut code = String::new();
+        if let Err(e) = std::io::stdin().read_line(&mut code) {
+            warn!("读取验证码失败:{}", e);
+            return None;
+        }
+
+        Some(TotpReqData {
+            account: challenge.account,
+            ticket: challenge.ticket,
+            code: code.trim().to_string(),
+        })
+    }
 }
 
-fn save_account_info(path: &String, data: LoginRespData) -> LoginRespData {
-    // 1. 转换data为字节
-    let byte_result = bincode::serialize(&data);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_account_resp_without_panic() {
+        let resp = BizAccountData {
+            account_type: AccountTypeEnum::Register,
+            data: AccountDataEnum::RespData(BizResult {
+                is_success: true,
+                msg: None,
+                data: Some(AccountRespData {
+                    user_id: 1,
+                    account: "alice".to_string(),
+                    nickname: None,
+                    signature: None,
+                }),
+            }),
+        };
+        let pkg =
+            Protocol::create_by_data(ChatCommand::Account, bincode::serialize(&resp).unwrap());
+        assert_eq!(parse_account_resp(&pkg).unwrap().account, "alice");
 
-    // 2. 将字节数据存储到文件中
-    fs::create_dir_all(path).expect("创建account存储目录失败!");
-    let file_name = format!("{}/{}", path, &data.account);
-    let mut file = File::create(file_name).unwrap();
-    file.write_all(byte_result.unwrap().as_slice()).expect("save_account_info fail!");
-    data
+        // 其它command的报文、无法解码的数据、缺少数据的响应都返回错误
+        let pkg = Protocol::create_by_data(ChatCommand::Chat, bincode::serialize(&resp).unwrap());
+        assert!(parse_account_resp(&pkg).is_err());
+        let pkg = Protocol::create_by_data(ChatCommand::Account, vec![9, 9]);
+        assert!(parse_account_resp(&pkg).is_err());
+        let resp = BizAccountData {
+            account_type: AccountTypeEnum::Register,
+            data: AccountDataEnum::RespData(BizResult {
+                is_success: true,
+                msg: None,
+                data: None,
+            }),
+        };
+        let pkg =
+            Protocol::create_by_data(ChatCommand::Account, bincode::serialize(&resp).unwrap());
+        assert!(parse_account_resp(&pkg).is_err());
+    }
 }
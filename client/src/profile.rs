@@ -0,0 +1,205 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
+
+// 没有配置文件时，根据.env生成的profile的名称
+pub const DEFAULT_PROFILE_NAME: &str = "default";
+
+// 一个client配置: 连接哪个server、使用哪个账户、账户信息(token)保存在哪里
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientProfile {
+    pub name: String,
+    // 符合 ip:port 格式
+    pub server_address: String,
+    pub account: String,
+    // 账户信息的加密存储目录
+    pub account_save_path: String,
+}
+
+impl ClientProfile {
+    // 根据.env中的配置生成profile，兼容只有单个账户的旧配置
+    pub fn init_from_env() -> Self {
+        dotenvy::dotenv().ok();
+
+        let server_address =
+            env::var("SERVER_ADDRESS").expect("SERVER_ADDRESS is not set in .env file");
+        let account = env::var("ACCOUNT").expect("ACCOUNT is not set in .env file");
+        let account_save_path = env::var("CLIENT_ACCOUNT_SAVE_PATH")
+            .expect("CLIENT_ACCOUNT_SAVE_PATH is not set in .env file");
+
+        ClientProfile {
+            name: DEFAULT_PROFILE_NAME.to_string(),
+            server_address,
+            account,
+            account_save_path,
+        }
+    }
+
+    pub fn get_server_socket_addr(&self) -> Result<SocketAddr, String> {
+        self.server_address
+            .to_socket_addrs()
+            .map_err(|e| e.to_string())?
+            .next()
+            .ok_or(format!("invalid server address: {}", self.server_address))
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ProfileFileData {
+    // 当前使用的profile名称
+    current: Option<String>,
+    profiles: Vec<ClientProfile>,
+}
+
+/**
+ *  管理多个client profile，保存在 CLIENT_PROFILE_FILE 指定的json文件中
+ **/
+pub struct ProfileManager {
+    file_path: String,
+    data: ProfileFileData,
+}
+
+impl ProfileManager {
+    // 加载profile文件. 文件不存在时用.env的配置生成default profile
+    pub fn init_from_env() -> Result<Self, String> {
+        dotenvy::dotenv().ok();
+
+        let file_path =
+            env::var("CLIENT_PROFILE_FILE").expect("CLIENT_PROFILE_FILE is not set in .env file");
+
+        let data = if Path::new(&file_path).exists() {
+            let content = fs::read_to_string(&file_path).map_err(|e| e.to_string())?;
+            serde_json::from_str(&content).map_err(|e| e.to_string())?
+        } else {
+            let default = ClientProfile::init_from_env();
+            ProfileFileData {
+                current: Some(default.name.clone()),
+                profiles: vec![default],
+            }
+        };
+
+        Ok(ProfileManager { file_path, data })
+    }
+
+    pub fn list(&self) -> &Vec<ClientProfile> {
+        &self.data.profiles
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ClientProfile> {
+        self.data.profiles.iter().find(|t| t.name == name)
+    }
+
+    pub fn get_current(&self) -> Option<&ClientProfile> {
+        self.data.current.as_ref().and_then(|t| self.get(t))
+    }
+
+    // 新增或者覆盖同名的profile
+    pub fn save_profile(&mut self, profile: ClientProfile) -> Result<(), String> {
+        if profile.name.is_empty() {
+            return Err("profile name can not be empty !".to_string());
+        }
+        profile.get_server_socket_addr()?;
+
+        self.data.profiles.retain(|t| t.name != profile.name);
+        if self.data.current.is_none() {
+            self.data.current = Some(profile.name.clone());
+        }
+        self.data.profiles.push(profile);
+        self.flush()
+    }
+
+    pub fn remove_profile(&mut self, name: &str) -> Result<(), String> {
+        if self.get(name).is_none() {
+            return Err(format!("profile not exist: {name}"));
+        }
+
+        self.data.profiles.retain(|t| t.name != name);
+        if self.data.current.as_deref() == Some(name) {
+            self.data.current = self.data.profiles.first().map(|t| t.name.clone());
+        }
+        self.flush()
+    }
+
+    // 切换当前使用的profile
+    pub fn switch(&mut self, name: &str) -> Result<ClientProfile, String> {
+        let profile = self
+            .get(name)
+            .cloned()
+            .ok_or(format!("profile not exist: {name}"))?;
+
+        self.data.current = Some(profile.name.clone());
+        self.flush()?;
+        Ok(profile)
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        if let Some(parent) = Path::new(&self.file_path).parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+
+        let content = serde_json::to_string_pretty(&self.data).map_err(|e| e.to_string())?;
+        fs::write(&self.file_path, content).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_profile(name: &str, account: &str) -> ClientProfile {
+        ClientProfile {
+            name: name.to_string(),
+            server_address: "127.0.0.1:9000".to_string(),
+            account: account.to_string(),
+            account_save_path: "./account".to_string(),
+        }
+    }
+
+    #[test]
+    fn save_switch_and_remove() {
+        let dir = env::temp_dir().join(format!("client_profile_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let file_path = dir.join("profiles.json").to_string_lossy().to_string();
+        let mut manager = ProfileManager {
+            file_path: file_path.clone(),
+            data: Default::default(),
+        };
+
+        // 第一个profile成为当前profile
+        manager
+            .save_profile(create_profile("work", "alice"))
+            .unwrap();
+        manager.save_profile(create_profile("home", "bob")).unwrap();
+        assert_eq!(manager.get_current().unwrap().name, "work");
+
+        // 同名覆盖
+        manager
+            .save_profile(create_profile("home", "carol"))
+            .unwrap();
+        assert_eq!(manager.list().len(), 2);
+        assert_eq!(manager.get("home").unwrap().account, "carol");
+
+        assert_eq!(manager.switch("home").unwrap().account, "carol");
+        assert!(manager.switch("none").is_err());
+
+        let mut invalid = create_profile("bad", "bob");
+        invalid.server_address = "not address".to_string();
+        assert!(manager.save_profile(invalid).is_err());
+        assert!(manager.save_profile(create_profile("", "bob")).is_err());
+
+        // 删除当前profile之后切换到剩下的第一个
+        manager.remove_profile("home").unwrap();
+        assert_eq!(manager.get_current().unwrap().name, "work");
+        assert!(manager.remove_profile("home").is_err());
+
+        // 修改已经保存到文件
+        let content = fs::read_to_string(&file_path).unwrap();
+        let data: ProfileFileData = serde_json::from_str(&content).unwrap();
+        assert_eq!(data.current.as_deref(), Some("work"));
+        assert_eq!(data.profiles.len(), 1);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,525 @@
+use crate::base::ConnectionRegistry;
+use crate::chat_protocol::{ChatCommand, Protocol};
+use crate::login_module::{BizResult, ScramChallengeData, ScramStartReqData};
+use crate::protocol_factory::HandlerProtocolData;
+use crate::scram;
+use crate::scram::ScramVerifier;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+// 密码长度限制. 密码不发送给server，由client在计算校验数据之前检查
+const PWD_MIN_LEN: usize = 6;
+const PWD_MAX_LEN: usize = 64;
+
+pub struct DefaultAccountHandler {
+    server: Option<Box<dyn ServerAccountModule + Send>>,
+
+    client: Option<Box<dyn ClientAccountModule + Send>>,
+}
+
+impl DefaultAccountHandler {
+    pub fn new(
+        server: Option<Box<dyn ServerAccountModule + Send>>,
+        client: Option<Box<dyn ClientAccountModule + Send>>,
+    ) -> Self {
+        DefaultAccountHandler { server, client }
+    }
+
+    fn server_module(&mut self) -> &mut Box<dyn ServerAccountModule + Send> {
+        if self.server.is_none() {
+            panic!("ServerAccountModule is None!");
+        }
+        self.server.as_mut().unwrap()
+    }
+
+    // 无法解析的数据: server端回复失败的结果.
+    // 回复中需要带上请求的类型，client才知道是哪个请求失败，类型也无法解析时不回复
+    fn reject(&self, address: SocketAddr, data: &[u8], msg: String) -> Option<Vec<u8>> {
+        warn!("reject account data from {}: {}", address, msg);
+        // client端忽略
+        self.server.as_ref()?;
+
+        let account_type: AccountTypeEnum = bincode::deserialize(data).ok()?;
+        let resp_data = BizAccountData {
+            account_type,
+            data: AccountDataEnum::RespData(BizResult {
+                is_success: false,
+                msg: Some(msg),
+                data: None,
+            }),
+        };
+        Some(bincode::serialize(&resp_data).unwrap())
+    }
+}
+
+impl HandlerProtocolData for DefaultAccountHandler {
+    fn handle(&mut self, address: SocketAddr, data: &Vec<u8>) -> Option<Vec<u8>> {
+        let biz: BizAccountData = match bincode::deserialize(data) {
+            Ok(t) => t,
+            Err(e) => return self.reject(address, data, format!("invalid account data: {e}")),
+        };
+
+        let account_type = biz.account_type;
+
+        // server端处理请求
+        let resp = match biz.data {
+            AccountDataEnum::RegisterReq(req) => self.server_module().handle_register(req),
+
+            AccountDataEnum::ChangePwdStartReq(req) => {
+                let result = match self.server_module().handle_change_pwd_start(req, address) {
+                    Ok(t) => BizResult {
+                        is_success: true,
+                        msg: None,
+                        data: Some(t),
+                    },
+                    Err(e) => {
+                        warn!("account request {:?} fail: {}", account_type, e);
+                        BizResult {
+                            is_success: false,
+                            msg: Some(e),
+                            data: None,
+                        }
+                    }
+                };
+                let resp_data = BizAccountData {
+                    account_type,
+                    data: AccountDataEnum::ChangePwdChallenge(result),
+                };
+                return Some(bincode::serialize(&resp_data).unwrap());
+            }
+
+            AccountDataEnum::ChangePwdReq(req) => {
+                self.server_module().handle_change_pwd(req, address)
+            }
+
+            AccountDataEnum::UpdateProfileReq(req) => {
+                self.server_module().handle_update_profile(req, address)
+            }
+
+            // client端处理响应
+            AccountDataEnum::RespData(resp) => {
+                if self.client.is_none() {
+                    panic!("ClientAccountModule is None!");
+                }
+                self.client
+                    .as_mut()
+                    .unwrap()
+                    .handle_account_resp(account_type, resp);
+                return None;
+            }
+
+            // client端使用旧密码计算proof，继续发送修改密码的第二步
+            AccountDataEnum::ChangePwdChallenge(challenge) => {
+                let client = match self.client.as_mut() {
+                    Some(t) => t,
+                    None => {
+                        warn!("unexpected change password challenge !");
+                        return None;
+                    }
+                };
+                let req = client.handle_change_pwd_challenge(challenge)?;
+                let req_data = BizAccountData {
+                    account_type,
+                    data: AccountDataEnum::ChangePwdReq(req),
+                };
+                return Some(bincode::serialize(&req_data).unwrap());
+            }
+        };
+
+        let biz_result = match resp {
+            Ok(t) => BizResult {
+                is_success: true,
+                msg: None,
+                data: Some(t),
+            },
+            Err(e) => {
+                warn!("account request {:?} fail: {}", account_type, e);
+                BizResult {
+                    is_success: false,
+                    msg: Some(e),
+                    data: None,
+                }
+            }
+        };
+
+        let resp_data = BizAccountData {
+            account_type,
+            data: AccountDataEnum::RespData(biz_result),
+        };
+
+        Some(bincode::serialize(&resp_data).unwrap())
+    }
+}
+
+/**
+*  server端处理账户注册、修改密码、修改资料的模块trait
+**/
+pub trait ServerAccountModule {
+    fn handle_register(&mut self, req: RegisterReqData) -> Result<AccountRespData, String>;
+
+    // 修改密码的第一步，返回校验旧密码的scram参数. 需要当前连接已经登录
+    fn handle_change_pwd_start(
+        &mut self,
+        req: ScramStartReqData,
+        address: SocketAddr,
+    ) -> Result<ScramChallengeData, String>;
+
+    // 修改密码的第二步，使用scram proof校验旧密码，保存client计算的新密码的校验数据
+    fn handle_change_pwd(
+        &mut self,
+        req: ChangePwdReqData,
+        address: SocketAddr,
+    ) -> Result<AccountRespData, String>;
+
+    // 修改资料需要当前连接已经登录
+    fn handle_update_profile(
+        &mut self,
+        req: UpdateProfileReqData,
+        address: SocketAddr,
+    ) -> Result<AccountRespData, String>;
+}
+
+/**
+ *  client端处理账户相关响应的模块trait
+ **/
+pub trait ClientAccountModule {
+    fn handle_account_resp(
+        &mut self,
+        account_type: AccountTypeEnum,
+        resp: BizResult<AccountRespData>,
+    );
+
+    // 修改密码时收到校验旧密码的scram参数，返回需要继续发送的请求
+    fn handle_change_pwd_challenge(
+        &mut self,
+        challenge: BizResult<ScramChallengeData>,
+    ) -> Option<ChangePwdReqData>;
+}
+
+// 等待server返回challenge的修改密码请求. 密码只保存在client内存中，不发送给server
+struct ChangePwdState {
+    account: String,
+    client_nonce: String,
+    old_pwd: String,
+    new_pwd: String,
+    totp_code: Option<String>,
+}
+
+/**
+ *  client端在已登录的连接上发送账户请求，响应在ClientAccountModule中处理
+ **/
+#[derive(Clone)]
+pub struct DefaultClientAccountModule {
+    registry: ConnectionRegistry,
+    server_addr: SocketAddr,
+    // 发送请求和处理challenge的module共用
+    change_pwd: Arc<Mutex<Option<ChangePwdState>>>,
+}
+
+impl DefaultClientAccountModule {
+    pub fn new(registry: ConnectionRegistry, server_addr: SocketAddr) -> Self {
+        DefaultClientAccountModule {
+            registry,
+            server_addr,
+            change_pwd: Default::default(),
+        }
+    }
+
+    // 修改密码. 旧密码通过scram交互校验，新密码只提交校验数据.
+    // 开启了两步验证的账户需要传入验证码或者恢复码
+    pub fn change_pwd(
+        &self,
+        account: String,
+        old_pwd: String,
+        new_pwd: String,
+        totp_code: Option<String>,
+    ) -> Result<(), String> {
+        check_pwd(&new_pwd)?;
+
+        let client_nonce = scram::create_nonce();
+        let req = AccountDataEnum::ChangePwdStartReq(ScramStartReqData {
+            account: account.clone(),
+            client_nonce: client_nonce.clone(),
+        });
+        // 重复修改时只保留最后一次的请求
+        *self.change_pwd.lock().unwrap() = Some(ChangePwdState {
+            account,
+            client_nonce,
+            old_pwd,
+            new_pwd,
+            totp_code,
+        });
+
+        let result = self.send_req(AccountTypeEnum::ChangePwd, req);
+        if result.is_err() {
+            self.change_pwd.lock().unwrap().take();
+        }
+        result
+    }
+
+    // 修改资料，为None的字段不修改
+    pub fn update_profile(
+        &self,
+        nickname: Option<String>,
+        signature: Option<String>,
+    ) -> Result<(), String> {
+        let req = AccountDataEnum::UpdateProfileReq(UpdateProfileReqData {
+            nickname,
+            signature,
+        });
+        self.send_req(AccountTypeEnum::UpdateProfile, req)
+    }
+
+    // 使用旧密码计算proof，生成修改密码的第二步请求
+    pub fn create_change_pwd_req(
+        &self,
+        challenge: BizResult<ScramChallengeData>,
+    ) -> Result<ChangePwdReqData, String> {
+        let state = self
+            .change_pwd
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or("no change password request !".to_string())?;
+
+        if !challenge.is_success {
+            return Err(challenge.msg.unwrap_or_default());
+        }
+        let challenge = challenge
+            .data
+            .ok_or("change password challenge is empty !".to_string())?;
+
+        // server返回的nonce必须以client生成的nonce开头
+        if !challenge.nonce.starts_with(&state.client_nonce) {
+            return Err("nonce in challenge does not match !".to_string());
+        }
+
+        let auth_message = scram::auth_message(
+            &state.account,
+            &challenge.nonce,
+            &challenge.salt,
+            challenge.iterations,
+        );
+        let proof = scram::create_client_proof(
+            &state.old_pwd,
+            &challenge.salt,
+            challenge.iterations,
+            &auth_message,
+        );
+
+        Ok(ChangePwdReqData {
+            account: state.account,
+            nonce: challenge.nonce,
+            proof: proof.proof,
+            verifier: ScramVerifier::create(&state.new_pwd),
+            totp_code: state.totp_code,
+        })
+    }
+
+    fn send_req(&self, account_type: AccountTypeEnum, data: AccountDataEnum) -> Result<(), String> {
+        let biz = BizAccountData { account_type, data };
+        self.registry
+            .send_to(&self.server_addr, &biz.to_protocol_bytes())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BizAccountData {
+    pub account_type: AccountTypeEnum,
+    pub data: AccountDataEnum,
+}
+
+impl BizAccountData {
+    // 打包成可以直接写入stream的字节
+    pub fn to_protocol_bytes(&self) -> Vec<u8> {
+        let data = bincode::serialize(self).unwrap();
+        Protocol::create_by_data(ChatCommand::Account, data).to_vec()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub enum AccountTypeEnum {
+    Register,
+    ChangePwd,
+    UpdateProfile,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AccountDataEnum {
+    RegisterReq(RegisterReqData),
+    ChangePwdReq(ChangePwdReqData),
+    UpdateProfileReq(UpdateProfileReqData),
+    RespData(BizResult<AccountRespData>),
+    // 修改密码: client获取校验旧密码的scram参数
+    ChangePwdStartReq(ScramStartReqData),
+    // 修改密码: server返回salt等参数
+    ChangePwdChallenge(BizResult<ScramChallengeData>),
+}
+
+// 密码不在网络上传输，client根据密码计算scram校验数据之后提交
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RegisterReqData {
+    pub account: String,
+    pub verifier: ScramVerifier,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangePwdReqData {
+    pub account: String,
+    // ChangePwdChallenge中的nonce，以及使用旧密码计算的proof
+    pub nonce: String,
+    pub proof: String,
+    // 新密码的校验数据
+    pub verifier: ScramVerifier,
+    // 开启了两步验证的账户需要提供验证码或者恢复码
+    pub totp_code: Option<String>,
+}
+
+// 为None的字段表示不修改
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateProfileReqData {
+    pub nickname: Option<String>,
+    pub signature: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AccountRespData {
+    pub user_id: i32,
+    pub account: String,
+    pub nickname: Option<String>,
+    pub signature: Option<String>,
+}
+
+// 校验密码: 长度限制
+pub fn check_pwd(pwd: &str) -> Result<(), String> {
+    let len = pwd.chars().count();
+    if !(PWD_MIN_LEN..=PWD_MAX_LEN).contains(&len) {
+        return Err(format!(
+            "password length must between {PWD_MIN_LEN} and {PWD_MAX_LEN}"
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::{connect, read_protocol, MAX_REQ_DATA_LEN};
+    use std::net::TcpListener;
+
+    #[test]
+    fn change_pwd_by_challenge() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let registry = ConnectionRegistry::default();
+        registry.register(server_addr, connect(server_addr).unwrap());
+        let (mut server_stream, _) = listener.accept().unwrap();
+
+        let module = DefaultClientAccountModule::new(registry, server_addr);
+        assert!(module
+            .change_pwd(
+                "alice".to_string(),
+                "old-pwd".to_string(),
+                "new".to_string(),
+                None
+            )
+            .is_err());
+        module
+            .change_pwd(
+                "alice".to_string(),
+                "old-pwd".to_string(),
+                "new-pwd".to_string(),
+                None,
+            )
+            .unwrap();
+
+        let pkg = read_protocol(&mut server_stream, MAX_REQ_DATA_LEN).unwrap();
+        let biz: BizAccountData = bincode::deserialize(pkg.data.as_ref().unwrap()).unwrap();
+        let client_nonce = match biz.data {
+            AccountDataEnum::ChangePwdStartReq(t) => t.client_nonce,
+            _ => panic!("expect change password start request"),
+        };
+
+        let old = ScramVerifier::create("old-pwd");
+        let challenge = |nonce: String| BizResult {
+            is_success: true,
+            msg: None,
+            data: Some(ScramChallengeData {
+                salt: old.salt.clone(),
+                iterations: old.iterations,
+                nonce,
+            }),
+        };
+        let nonce = format!("{}{}", client_nonce, scram::create_nonce());
+        let req = module
+            .create_change_pwd_req(challenge(nonce.clone()))
+            .unwrap();
+        let auth_message = scram::auth_message("alice", &nonce, &old.salt, old.iterations);
+        assert!(old.verify_proof(&auth_message, &req.proof).is_some());
+        assert!(req.verifier.verify_pwd("new-pwd"));
+
+        // 每次请求只能使用一次challenge
+        assert!(module.create_change_pwd_req(challenge(nonce)).is_err());
+    }
+
+    struct ClosedAccountModule;
+
+    impl ServerAccountModule for ClosedAccountModule {
+        fn handle_register(&mut self, _req: RegisterReqData) -> Result<AccountRespData, String> {
+            Err("closed".to_string())
+        }
+
+        fn handle_change_pwd_start(
+            &mut self,
+            _req: ScramStartReqData,
+            _address: SocketAddr,
+        ) -> Result<ScramChallengeData, String> {
+            Err("closed".to_string())
+        }
+
+        fn handle_change_pwd(
+            &mut self,
+            _req: ChangePwdReqData,
+            _address: SocketAddr,
+        ) -> Result<AccountRespData, String> {
+            Err("closed".to_string())
+        }
+
+        fn handle_update_profile(
+            &mut self,
+            _req: UpdateProfileReqData,
+            _address: SocketAddr,
+        ) -> Result<AccountRespData, String> {
+            Err("closed".to_string())
+        }
+    }
+
+    #[test]
+    fn reject_invalid_account_data() {
+        let address: SocketAddr = "127.0.0.1:5001".parse().unwrap();
+        let mut handler = DefaultAccountHandler::new(Some(Box::new(ClosedAccountModule)), None);
+
+        // 只有请求类型，没有数据. 回复失败的结果，并带上请求的类型
+        let data = bincode::serialize(&AccountTypeEnum::ChangePwd).unwrap();
+        let resp = handler.handle(address, &data).unwrap();
+        let biz: BizAccountData = bincode::deserialize(&resp).unwrap();
+        assert!(matches!(biz.account_type, AccountTypeEnum::ChangePwd));
+        match biz.data {
+            AccountDataEnum::RespData(t) => {
+                assert!(!t.is_success);
+                assert!(t.msg.unwrap().starts_with("invalid account data"));
+            }
+            _ => panic!("expect account resp"),
+        }
+
+        // 类型也无法解析时不回复
+        assert!(handler.handle(address, &vec![u8::MAX; 3]).is_none());
+
+        // client端忽略无法解析的数据
+        let mut client = DefaultAccountHandler::new(None, None);
+        assert!(client.handle(address, &data).is_none());
+    }
+}
@@ -1,11 +1,28 @@
 use crate::chat_module::{ChatContent, ChatData, ChatFileContent, ChatTextContent};
 use crate::chat_protocol::{ChatCommand, Protocol};
+use crate::file_module::FILE_CHUNK_SIZE;
 use crate::protocol_factory::HandleProtocolFactory;
-use log::info;
+use log::{error, info};
 use std::collections::HashMap;
 use std::error::Error;
-use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+// server接收的报文数据区长度上限. 最大的请求是文件分片，再加上其它字段的长度
+pub const MAX_REQ_DATA_LEN: usize = FILE_CHUNK_SIZE * 4 + 64 * 1024;
+
+// client接收的报文数据区长度上限，历史消息等响应中可能包含多个缩略图
+pub const MAX_RESP_DATA_LEN: usize = 64 * 1024 * 1024;
+
+// 向连接写入的超时时间，对端长时间不读取时放弃该连接，避免推送数据的线程一直阻塞
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// 每个连接使用单独的handler，连接建立时调用创建
+pub type FactoryCreator = Arc<dyn Fn() -> HandleProtocolFactory + Send + Sync>;
 
 #[derive(PartialEq, Clone, Copy, Debug)]
 pub enum TcpSideState {
@@ -14,17 +31,79 @@ pub enum TcpSideState {
     STOPPED,
 }
 
+/**
+ *  所有连接的写端，key为对端地址. 读取在各自的线程中进行，
+ *  写入通过这里完成，这样handler可以主动向其它连接推送数据(例如转发聊天消息)
+ **/
+#[derive(Clone, Default)]
+pub struct ConnectionRegistry {
+    conns: Arc<RwLock<HashMap<SocketAddr, Arc<Mutex<TcpStream>>>>>,
+}
+
+impl ConnectionRegistry {
+    pub fn register(&self, address: SocketAddr, stream: TcpStream) {
+        if let Err(e) = stream.set_write_timeout(Some(WRITE_TIMEOUT)) {
+            error!("set write timeout of {} fail: {}", address, e);
+        }
+
+        self.conns
+            .write()
+            .unwrap()
+            .insert(address, Arc::new(Mutex::new(stream)));
+    }
+
+    pub fn remove(&self, address: &SocketAddr) {
+        self.conns.write().unwrap().remove(address);
+    }
+
+    pub fn contains(&self, address: &SocketAddr) -> bool {
+        self.conns.read().unwrap().contains_key(address)
+    }
+
+    // 向指定连接发送一个完整的协议报文. 同一个连接的写入是串行的，报文不会交错
+    pub fn send_to(&self, address: &SocketAddr, data: &Vec<u8>) -> Result<(), String> {
+        let stream = self
+            .conns
+            .read()
+            .unwrap()
+            .get(address)
+            .cloned()
+            .ok_or(format!("connection {address} is closed !"))?;
+
+        let mut stream = stream.lock().unwrap_or_else(|e| e.into_inner());
+        send_msg(&mut stream, data).map_err(|e| {
+            // 超时的时候报文可能只写入了一部分，连接已经不可用，关闭之后读取线程会清理
+            let _ = stream.shutdown(Shutdown::Both);
+            e.to_string()
+        })
+    }
+}
+
 pub struct TcpClientSide {
     local_addr: SocketAddr,
-    server_side: Option<TcpServerSide>,
+    server_addr: SocketAddr,
+    // 与server端的连接，用于读取
+    server_stream: TcpStream,
+    factory: HandleProtocolFactory,
+    registry: ConnectionRegistry,
+    state: TcpSideState,
 }
 
 impl TcpClientSide {
     pub fn get_state(&self) -> &TcpSideState {
-        &self.server_side.as_ref().unwrap().state
+        &self.state
     }
 
     pub fn new(server_side_address: SocketAddr, factory: HandleProtocolFactory) -> Self {
+        Self::new_with_registry(server_side_address, factory, ConnectionRegistry::default())
+    }
+
+    // 使用外部的registry，连接之前就可以把registry交给需要发送数据的module
+    pub fn new_with_registry(
+        server_side_address: SocketAddr,
+        factory: HandleProtocolFactory,
+        registry: ConnectionRegistry,
+    ) -> Self {
         // 连接server端，得到stream
         let server_stream = TcpStream::connect(server_side_address).expect("连接server端失败!");
 
@@ -33,65 +112,104 @@ impl TcpClientSide {
 
         info!("client使用端口地址:{}", local_addr.to_string());
 
-        let mut client = TcpClientSide {
-            local_addr,
-            server_side: None,
-        };
+        registry.register(
+            server_side_address,
+            server_stream
+                .try_clone()
+                .expect("clone server stream fail!"),
+        );
 
-        // 用local_addr创建tcpServerSide
-        let mut server = TcpServerSide::new(local_addr.to_string(), factory);
+        TcpClientSide {
+            local_addr,
+            server_addr: server_side_address,
+            server_stream,
+            factory,
+            registry,
+            state: TcpSideState::INIT,
+        }
+    }
 
-        let cache_data = ProtocolCacheData {
-            stream: server_stream,
-            data: None,
-        };
+    pub fn get_local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
 
-        server.add_stream(server_side_address, cache_data);
+    pub fn get_server_addr(&self) -> SocketAddr {
+        self.server_addr
+    }
 
-        client.server_side = Some(server);
+    // 其它线程可以通过registry向server发送数据
+    pub fn get_registry(&self) -> ConnectionRegistry {
+        self.registry.clone()
+    }
 
-        client
+    // 通过与server端的连接发送数据
+    pub fn send_to_server(&mut self, data: &Vec<u8>) -> Result<(), Box<dyn Error>> {
+        self.registry
+            .send_to(&self.server_addr, data)
+            .map_err(|e| e.into())
     }
 
-    // invoke this function , current thread will be loop to execute handle accept request.
+    // invoke this function , current thread will be loop to read and handle data from server.
     pub fn start(&mut self) {
-        self.server_side.as_mut().unwrap().start();
+        self.state = TcpSideState::RUNNING;
+
+        while self.state == TcpSideState::RUNNING {
+            let pkg = match read_protocol(&mut self.server_stream, MAX_RESP_DATA_LEN) {
+                Ok(t) => t,
+                Err(e) => {
+                    info!("connection to server {} closed: {}", self.server_addr, e);
+                    break;
+                }
+            };
+
+            let resp = match handle_pkg(&pkg, self.server_addr, &mut self.factory) {
+                Ok(t) => t,
+                Err(e) => {
+                    error!("handle pkg from server fail: {}", e);
+                    break;
+                }
+            };
+
+            if let Some(t) = resp {
+                if let Err(e) = self.registry.send_to(&self.server_addr, &t) {
+                    error!("send resp to server fail: {}", e);
+                    break;
+                }
+            }
+        }
+
+        self.registry.remove(&self.server_addr);
+        self.factory.on_disconnect(self.server_addr);
+        self.state = TcpSideState::STOPPED;
     }
 }
 
 pub struct TcpServerSide {
     // addr必须是 "ip:port"的格式
     addr: String,
-    factory: HandleProtocolFactory,
+    // 每个连接在单独的线程中读取，并使用自己的handler，处理请求时不需要全局的锁.
+    // 连接之间共享的状态由handler内部各自加锁
+    create_factory: FactoryCreator,
     state: TcpSideState,
-    all_conn_cache: HashMap<SocketAddr, ProtocolCacheData>,
+    registry: ConnectionRegistry,
 }
 
 impl TcpServerSide {
-    /***
-     ***    insert new value to all_conn_cache , but if already had key , then return false,else return ture.
-     ***
-     ***/
-    pub fn add_stream(&mut self, addr: SocketAddr, stream_cache: ProtocolCacheData) -> bool {
-        match self.all_conn_cache.contains_key(&addr) {
-            // note: if already had key , can not insert new value
-            true => {
-                return false;
-            }
-
-            false => {
-                self.all_conn_cache.insert(addr, stream_cache);
-                return true;
-            }
-        }
+    pub fn new(addr: String, create_factory: FactoryCreator) -> Self {
+        Self::new_with_registry(addr, create_factory, Default::default())
     }
 
-    pub fn new(addr: String, factory: HandleProtocolFactory) -> Self {
+    // handler需要主动向连接推送数据时，先创建registry再传入
+    pub fn new_with_registry(
+        addr: String,
+        create_factory: FactoryCreator,
+        registry: ConnectionRegistry,
+    ) -> Self {
         TcpServerSide {
             addr,
-            factory,
+            create_factory,
             state: TcpSideState::INIT,
-            all_conn_cache: Default::default(),
+            registry,
         }
     }
 
@@ -99,6 +217,10 @@ impl TcpServerSide {
         &self.state
     }
 
+    pub fn get_registry(&self) -> ConnectionRegistry {
+        self.registry.clone()
+    }
+
     pub fn start(&mut self) {
         self.state = TcpSideState::RUNNING;
         self.start_server_accept();
@@ -114,112 +236,94 @@ impl TcpServerSide {
         println!("##########  TcpServer started! ###########");
 
         while self.state == TcpSideState::RUNNING {
-            let (stream, address) = listener.accept().unwrap();
-            parse_tcp_stream(stream, address, &mut self.all_conn_cache, &mut self.factory);
+            let (stream, address) = match listener.accept() {
+                Ok(t) => t,
+                Err(e) => {
+                    error!("accept connection fail: {}", e);
+                    continue;
+                }
+            };
+
+            let write_stream = match stream.try_clone() {
+                Ok(t) => t,
+                Err(e) => {
+                    error!("clone stream of {} fail: {}", address, e);
+                    continue;
+                }
+            };
+            self.registry.register(address, write_stream);
+
+            let create_factory = Arc::clone(&self.create_factory);
+            let registry = self.registry.clone();
+            thread::spawn(move || handle_connection(stream, address, create_factory(), registry));
         }
 
         println!("##########  TcpServer stopped! ###########");
     }
 }
 
-pub struct ProtocolCacheData {
-    stream: TcpStream,
-
-    data: Option<Protocol>,
-}
-
-fn parse_tcp_stream(
-    stream: TcpStream,
+// 循环读取一个连接上的报文，直到连接断开
+fn handle_connection(
+    mut stream: TcpStream,
     address: SocketAddr,
-    all_cache: &mut HashMap<SocketAddr, ProtocolCacheData>,
-    factory: &mut HandleProtocolFactory,
+    mut factory: HandleProtocolFactory,
+    registry: ConnectionRegistry,
 ) {
-    let mut pca = match all_cache.remove(&address) {
-        Some(mut t) => {
-            match t.data {
-                None => t.data = Some(Protocol::create_new()),
-                Some(_) => {}
-            }
-            t
-        }
-
-        None => ProtocolCacheData {
-            stream,
-            data: Some(Protocol::create_new()),
-        },
-    };
-
-    let mut buf = [0; 128];
-
-    let mut remain = pca.stream.read(&mut buf).unwrap();
-
-    let total_len = remain.clone();
-
-    let mut index = 0;
-
-    let buffer = buf.to_vec();
-
-    while remain > 0 {
-        let len = fill(
-            pca.data.as_mut().unwrap(),
-            &buffer,
-            index.clone(),
-            total_len.clone(),
-        );
-
-        remain -= len;
-
-        index += len.clone();
-
-        if pca.data.as_ref().unwrap().completion() {
-            let resp = handle_pkg(pca.data.as_ref().unwrap(), address.clone(), factory);
-
-            if resp.is_some() {
-                pca.stream
-                    .write_all(&resp.unwrap())
-                    .expect("stream send resp occurs fail !");
+    loop {
+        let pkg = match read_protocol(&mut stream, MAX_REQ_DATA_LEN) {
+            Ok(t) => t,
+            Err(e) => {
+                info!("connection {} closed: {}", address, e);
+                break;
             }
+        };
 
-            if remain > 0 {
-                pca.data = Some(Protocol::create_new());
+        // handler的panic只关闭当前连接，不影响其它连接
+        let resp =
+            match panic::catch_unwind(AssertUnwindSafe(|| handle_pkg(&pkg, address, &mut factory)))
+            {
+                Ok(Ok(t)) => t,
+                Ok(Err(e)) => {
+                    error!("handle pkg from {} fail: {}", address, e);
+                    break;
+                }
+                Err(_) => {
+                    error!("handle pkg from {} panicked, close the connection", address);
+                    break;
+                }
+            };
+
+        if let Some(t) = resp {
+            if let Err(e) = registry.send_to(&address, &t) {
+                error!("send resp to {} fail: {}", address, e);
+                break;
             }
         }
     }
 
-    if !pca.data.as_ref().unwrap().completion() {
-        all_cache.insert(address, pca);
+    registry.remove(&address);
+    let _ = stream.shutdown(Shutdown::Both);
+    if panic::catch_unwind(AssertUnwindSafe(|| factory.on_disconnect(address))).is_err() {
+        error!("clean up connection {} panicked", address);
     }
 }
 
-fn fill(pkg: &mut Protocol, all_bytes: &Vec<u8>, mut index: usize, total_len: usize) -> usize {
-    while index < total_len && !pkg.completion() {
-        for field_name in Protocol::get_all_filed_name() {
-            // 如果字段没有填充完成，则进行填充
-            if !pkg.check_field_fill(&field_name) {
-                let len = pkg.get_diff_size(&field_name);
-
-                let bytes: Vec<u8> = all_bytes[index..index.clone() + len].to_vec();
-
-                pkg.fill_field(&field_name, bytes);
-
-                index += len.clone();
-            }
-        }
-    }
-
-    return index;
-}
-
 fn handle_pkg(
     pkg: &Protocol,
     address: SocketAddr,
     factory: &mut HandleProtocolFactory,
-) -> Option<Vec<u8>> {
+) -> Result<Option<Vec<u8>>, String> {
     // convert bytes to struct by type
-    let data_type = pkg.data_type.as_ref().unwrap()[0].clone();
-    let command = ChatCommand::to_self(data_type);
-    let handler = factory.get_handler(&command);
-    handler.handle(address, pkg.data.as_ref().unwrap())
+    let data_type = pkg.data_type.as_ref().unwrap()[0];
+    let command =
+        ChatCommand::to_self(data_type).ok_or(format!("unknown data type: {data_type}"))?;
+    let handler = factory
+        .get_handler(&command)
+        .ok_or(format!("not supported command: {command:?}"))?;
+    let resp = handler.handle(address, pkg.data.as_ref().unwrap());
+
+    // 响应数据同样按协议格式打包，对端才能解析
+    Ok(resp.map(|data| Protocol::create_by_data(command, data).to_vec()))
 }
 
 // 连接到指定地址
@@ -233,6 +337,33 @@ pub fn send_msg(stream: &mut TcpStream, data: &Vec<u8>) -> Result<(), Box<dyn Er
     Ok(())
 }
 
+// 阻塞读取一个完整的协议报文. 数据区超过max_data_len时返回错误，调用方需要关闭连接
+pub fn read_protocol(stream: &mut TcpStream, max_data_len: usize) -> std::io::Result<Protocol> {
+    let mut pkg = Protocol::create_new();
+
+    for field_name in Protocol::get_all_filed_name() {
+        // 数据区长度来自对端，需要先检查再分配内存
+        let len = pkg.get_field_usize(&field_name);
+        if len > max_data_len {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("data length {len} exceeds {max_data_len} !"),
+            ));
+        }
+
+        let mut bytes = vec![0; len];
+        stream.read_exact(&mut bytes)?;
+        pkg.fill_field(&field_name, bytes);
+    }
+
+    Ok(pkg)
+}
+
+// 当前的utc时间，毫秒时间戳
+pub fn now_millis() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
 pub fn create_init_factory() -> HandleProtocolFactory {
     let factory = HandleProtocolFactory::new();
     factory
@@ -252,11 +383,132 @@ pub fn get_chat_vec() -> Vec<u8> {
 
     let v = vec![text, f];
     let c = ChatData {
+        msg_id: "1".to_string(),
         from_account: "1".to_string(),
         to_account: "2".to_string(),
+        group_id: None,
         contents: v,
+        seq: 0,
         time: 0,
+        edit_time: None,
+        recall_by: None,
+        reply_to: None,
+        reactions: vec![],
+        expire_time: None,
+        forward_from: None,
     };
 
     bincode::serialize(&c).unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol_factory::HandlerProtocolData;
+    use std::thread::JoinHandle;
+
+    // 回显数据，数据为空时panic
+    struct PanicHandler;
+
+    impl HandlerProtocolData for PanicHandler {
+        fn handle(&mut self, _address: SocketAddr, data: &Vec<u8>) -> Option<Vec<u8>> {
+            if data.is_empty() {
+                panic!("empty data");
+            }
+            Some(data.clone())
+        }
+    }
+
+    fn accept_connection(
+        listener: &TcpListener,
+        registry: &ConnectionRegistry,
+    ) -> (TcpStream, SocketAddr, JoinHandle<()>) {
+        let client = connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, address) = listener.accept().unwrap();
+        registry.register(address, stream.try_clone().unwrap());
+
+        let mut factory = HandleProtocolFactory::new();
+        factory.registry_handler(ChatCommand::Chat, Box::new(PanicHandler));
+        let registry = registry.clone();
+        let handle = thread::spawn(move || handle_connection(stream, address, factory, registry));
+        (client, address, handle)
+    }
+
+    fn send_chat(stream: &mut TcpStream, data: Vec<u8>) {
+        let bytes = Protocol::create_by_data(ChatCommand::Chat, data).to_vec();
+        send_msg(stream, &bytes).unwrap();
+    }
+
+    #[test]
+    fn registry_sends_to_registered_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, address) = listener.accept().unwrap();
+
+        let registry = ConnectionRegistry::default();
+        let read_stream = stream.try_clone().unwrap();
+        registry.register(address, stream);
+        assert!(registry.contains(&address));
+        assert_eq!(read_stream.write_timeout().unwrap(), Some(WRITE_TIMEOUT));
+
+        let bytes = Protocol::create_by_data(ChatCommand::Chat, vec![1, 2, 3]).to_vec();
+        registry.send_to(&address, &bytes).unwrap();
+        let pkg = read_protocol(&mut client, MAX_RESP_DATA_LEN).unwrap();
+        assert!(pkg.completion());
+        assert_eq!(pkg.data, Some(vec![1, 2, 3]));
+
+        // 连接移除后不能再发送
+        registry.remove(&address);
+        assert!(!registry.contains(&address));
+        assert!(registry.send_to(&address, &bytes).is_err());
+    }
+
+    #[test]
+    fn reject_data_over_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+
+        // 只发送声明长度的头部，不需要发送数据就会被拒绝
+        let mut head = Protocol::create_by_data(ChatCommand::Chat, vec![]);
+        head.data_len = Some((u32::MAX).to_be_bytes().to_vec());
+        head.data = Some(vec![]);
+        send_msg(&mut client, &head.to_vec()).unwrap();
+        let e = read_protocol(&mut stream, MAX_REQ_DATA_LEN).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+
+        let bytes = Protocol::create_by_data(ChatCommand::Chat, vec![0; 100]).to_vec();
+        send_msg(&mut client, &bytes).unwrap();
+        assert!(read_protocol(&mut stream, 100).is_ok());
+    }
+
+    #[test]
+    fn close_only_the_failed_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let registry = ConnectionRegistry::default();
+        let (mut a, a_addr, a_handle) = accept_connection(&listener, &registry);
+        let (mut b, b_addr, b_handle) = accept_connection(&listener, &registry);
+
+        send_chat(&mut a, vec![1, 2, 3]);
+        let pkg = read_protocol(&mut a, MAX_RESP_DATA_LEN).unwrap();
+        assert_eq!(pkg.data, Some(vec![1, 2, 3]));
+
+        // handler panic之后只关闭当前连接
+        send_chat(&mut a, vec![]);
+        a_handle.join().unwrap();
+        assert!(read_protocol(&mut a, MAX_RESP_DATA_LEN).is_err());
+        assert!(!registry.contains(&a_addr));
+
+        send_chat(&mut b, vec![4]);
+        let pkg = read_protocol(&mut b, MAX_RESP_DATA_LEN).unwrap();
+        assert_eq!(pkg.data, Some(vec![4]));
+
+        // 不存在的command同样关闭连接
+        let mut pkg = Protocol::create_by_data(ChatCommand::Chat, vec![5]);
+        pkg.data_type = Some(vec![u8::MAX]);
+        send_msg(&mut b, &pkg.to_vec()).unwrap();
+        b_handle.join().unwrap();
+        assert!(read_protocol(&mut b, MAX_RESP_DATA_LEN).is_err());
+        assert!(!registry.contains(&b_addr));
+    }
+}
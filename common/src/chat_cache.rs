@@ -0,0 +1,268 @@
+use crate::base::now_millis;
+use crate::chat_module::{ChatAckData, ChatData};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+// 缓存文件的扩展名，文件名为hex编码的会话id
+const CACHE_FILE_EXTENSION: &str = "bin";
+
+/**
+ *  client端本地的聊天记录缓存，每个会话保存为一个文件，重启后仍然可以查看.
+ *  发送和接收在不同的线程，读写文件时共用一把锁
+ **/
+#[derive(Clone)]
+pub struct ChatCache {
+    dir: PathBuf,
+    // 已发送但server还没有确认的消息，key为client填写的临时消息id
+    pending: Arc<Mutex<HashMap<String, ChatData>>>,
+}
+
+impl ChatCache {
+    pub fn new(dir: PathBuf) -> Self {
+        ChatCache {
+            dir,
+            pending: Default::default(),
+        }
+    }
+
+    // 按序号顺序返回会话的所有缓存消息，不包括已经过期的阅后即焚消息
+    pub fn load(&self, conversation: &str) -> Result<Vec<ChatData>, String> {
+        let _lock = self.pending.lock().unwrap();
+        let now = now_millis();
+        let mut list = self.read_file(conversation)?;
+        list.retain(|t| !t.is_expired(now));
+        Ok(list)
+    }
+
+    // 会话中已缓存的最大序号，用于重新连接后同步缺失的消息
+    pub fn last_seq(&self, conversation: &str) -> Result<i64, String> {
+        let list = self.load(conversation)?;
+        Ok(list.last().map(|t| t.seq).unwrap_or_default())
+    }
+
+    // 已缓存的所有会话
+    pub fn list_conversations(&self) -> Result<Vec<String>, String> {
+        let _lock = self.pending.lock().unwrap();
+        if !self.dir.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut list = vec![];
+        for entry in fs::read_dir(&self.dir).map_err(|e| e.to_string())? {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            if path.extension().and_then(|t| t.to_str()) != Some(CACHE_FILE_EXTENSION) {
+                continue;
+            }
+
+            let conversation = path
+                .file_stem()
+                .and_then(|t| t.to_str())
+                .and_then(|t| hex::decode(t).ok())
+                .and_then(|t| String::from_utf8(t).ok());
+            if let Some(t) = conversation {
+                list.push(t);
+            }
+        }
+        Ok(list)
+    }
+
+    // 删除所有会话中已经过期的阅后即焚消息，返回删除的数量
+    pub fn purge_expired(&self) -> Result<usize, String> {
+        let conversations = self.list_conversations()?;
+
+        let _lock = self.pending.lock().unwrap();
+        let now = now_millis();
+        let mut count = 0;
+        for conversation in conversations {
+            let mut list = self.read_file(&conversation)?;
+            let len = list.len();
+            list.retain(|t| !t.is_expired(now));
+            if list.len() != len {
+                count += len - list.len();
+                self.write_file(&conversation, &list)?;
+            }
+        }
+        Ok(count)
+    }
+
+    // 保存消息，已经缓存过的消息(相同的消息id)会被覆盖
+    pub fn save(&self, list: Vec<ChatData>) -> Result<(), String> {
+        let _lock = self.pending.lock().unwrap();
+        self.merge(list)
+    }
+
+    // 发送消息时先记录下来，收到server的确认后再保存
+    pub fn add_pending(&self, data: ChatData) {
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(data.msg_id.clone(), data);
+    }
+
+    // 使用server分配的消息id和时间保存已发送的消息
+    pub fn confirm_pending(&self, ack: &ChatAckData) -> Result<(), String> {
+        let mut pending = self.pending.lock().unwrap();
+
+        let mut data = match pending.remove(&ack.client_msg_id) {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        data.msg_id = ack.msg_id.clone();
+        data.seq = ack.seq;
+        data.time = ack.time;
+
+        self.merge(vec![data])
+    }
+
+    // 调用方需要持有锁
+    fn merge(&self, list: Vec<ChatData>) -> Result<(), String> {
+        let now = now_millis();
+        let mut group: HashMap<String, Vec<ChatData>> = HashMap::new();
+        for data in list {
+            let conversation = data.conversation_id();
+            group.entry(conversation).or_default().push(data);
+        }
+
+        for (conversation, list) in group {
+            let mut cached = self.read_file(&conversation)?;
+            // 同一批中也可能有重复的消息，以后面的为准
+            for data in list {
+                cached.retain(|t| t.msg_id != data.msg_id);
+                cached.push(data);
+            }
+            // 写入时顺便删除已经过期的消息
+            cached.retain(|t| !t.is_expired(now));
+            cached.sort_by_key(|t| t.seq);
+
+            self.write_file(&conversation, &cached)?;
+        }
+        Ok(())
+    }
+
+    fn read_file(&self, conversation: &str) -> Result<Vec<ChatData>, String> {
+        let path = self.get_file_path(conversation);
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+
+        let bytes = fs::read(&path).map_err(|e| e.to_string())?;
+        bincode::deserialize(&bytes).map_err(|e| e.to_string())
+    }
+
+    // 先写临时文件再重命名，写入过程中退出不会损坏已有的缓存
+    fn write_file(&self, conversation: &str, list: &Vec<ChatData>) -> Result<(), String> {
+        fs::create_dir_all(&self.dir).map_err(|e| e.to_string())?;
+
+        let bytes = bincode::serialize(list).map_err(|e| e.to_string())?;
+        let path = self.get_file_path(conversation);
+        let tmp_path = path.with_extension("tmp");
+
+        fs::write(&tmp_path, bytes).map_err(|e| e.to_string())?;
+        fs::rename(&tmp_path, &path).map_err(|e| e.to_string())
+    }
+
+    // 账户名可能包含不能用于文件名的字符，使用hex编码
+    fn get_file_path(&self, conversation: &str) -> PathBuf {
+        self.dir.join(format!(
+            "{}.{}",
+            hex::encode(conversation.as_bytes()),
+            CACHE_FILE_EXTENSION
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_module::{create_msg_id, get_conversation_id, ChatContent, ChatTextContent};
+
+    fn create_chat_data(from: &str, to: &str, seq: i64) -> ChatData {
+        ChatData {
+            msg_id: create_msg_id(),
+            from_account: from.to_string(),
+            to_account: to.to_string(),
+            group_id: None,
+            seq,
+            time: 0,
+            edit_time: None,
+            recall_by: None,
+            reply_to: None,
+            reactions: vec![],
+            expire_time: None,
+            forward_from: None,
+            contents: vec![ChatContent::Text(ChatTextContent {
+                text: format!("msg {}", seq),
+            })],
+        }
+    }
+
+    #[test]
+    fn cache_survives_reload_and_confirms_pending() {
+        let dir = std::env::temp_dir().join(format!("chat_cache_{}", create_msg_id()));
+        let cache = ChatCache::new(dir.clone());
+
+        let received = create_chat_data("bob", "alice", 2);
+        cache
+            .save(vec![received.clone(), received.clone()])
+            .unwrap();
+
+        let sent = create_chat_data("alice", "bob", 0);
+        cache.add_pending(sent.clone());
+        let ack = ChatAckData {
+            msg_id: create_msg_id(),
+            client_msg_id: sent.msg_id.clone(),
+            to_account: "bob".to_string(),
+            seq: 1,
+            time: 0,
+            offline: false,
+        };
+        cache.confirm_pending(&ack).unwrap();
+
+        // 重新创建，模拟重启
+        let reloaded = ChatCache::new(dir.clone());
+        let conversation = get_conversation_id("bob", "alice");
+        assert_eq!(
+            reloaded.list_conversations().unwrap(),
+            vec![conversation.clone()]
+        );
+        assert_eq!(reloaded.last_seq(&conversation).unwrap(), 2);
+
+        let ids: Vec<String> = reloaded
+            .load(&conversation)
+            .unwrap()
+            .into_iter()
+            .map(|t| t.msg_id)
+            .collect();
+        assert_eq!(ids, vec![ack.msg_id, received.msg_id]);
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn purge_expired_msgs() {
+        let dir = std::env::temp_dir().join(format!("chat_cache_{}", create_msg_id()));
+        let cache = ChatCache::new(dir.clone());
+
+        let mut expired = create_chat_data("bob", "alice", 1);
+        expired.expire_time = Some(now_millis() - 1);
+        let mut alive = create_chat_data("bob", "alice", 2);
+        alive.expire_time = Some(now_millis() + 60_000);
+        let conversation = expired.conversation_id();
+        cache
+            .write_file(&conversation, &vec![expired, alive.clone()])
+            .unwrap();
+
+        assert_eq!(cache.purge_expired().unwrap(), 1);
+        let ids: Vec<String> = cache
+            .load(&conversation)
+            .unwrap()
+            .into_iter()
+            .map(|t| t.msg_id)
+            .collect();
+        assert_eq!(ids, vec![alive.msg_id]);
+
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,524 @@
+use crate::chat_module::{ChatContent, ChatFileContent, ConversationMetadata, MetadataChangeEnum};
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageReader, Limits};
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+
+// 图片宽、高的上限
+const IMAGE_MAX_SIDE: u32 = 16384;
+
+// 缩略图的最大字节数，缩略图随消息一起发送
+const THUMBNAIL_MAX_BYTES: usize = 64 * 1024;
+
+// 缩略图宽、高的上限
+const THUMBNAIL_MAX_SIDE: u32 = 256;
+
+// 生成缩略图时的jpeg质量
+const THUMBNAIL_QUALITY: u8 = 75;
+
+// 语音的最长时长
+const AUDIO_MAX_DURATION_MILLIS: u32 = 10 * 60 * 1000;
+
+// 支持的语音编码
+const AUDIO_CODECS: [&str; 4] = ["opus", "aac", "mp3", "amr"];
+
+// 位置名称的最大长度
+const LOCATION_LABEL_MAX_LEN: usize = 128;
+
+// markdown文本的最大长度
+const MARKDOWN_MAX_LEN: usize = 10000;
+
+/**
+ *  图片、语音、位置、markdown等富文本内容. 消息中以ChatContent::Rich发送，
+ *  data为序列化之后的RichContentEnum，不认识其类型的旧版本client显示fallback.
+ *  新增类型只能追加在末尾
+ **/
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum RichContentEnum {
+    Image(ChatImageContent),
+    Audio(ChatAudioContent),
+    Location(ChatLocationContent),
+    Markdown(ChatMarkdownContent),
+    // 以下为server生成的系统消息，client不能发送
+    DisappearTimer(ChatDisappearTimerContent),
+    MetadataChanged(ChatMetadataContent),
+}
+
+// 消息内容的类型，用于搜索时过滤
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum ChatContentTypeEnum {
+    Text,
+    File,
+    Image,
+    Audio,
+    Location,
+    Markdown,
+}
+
+impl ChatContentTypeEnum {
+    // 系统消息和不认识的富文本返回None
+    pub fn of(content: &ChatContent) -> Option<Self> {
+        match content {
+            ChatContent::Text(_) => Some(ChatContentTypeEnum::Text),
+            ChatContent::File(_) => Some(ChatContentTypeEnum::File),
+            ChatContent::Rich(t) => match t.decode()? {
+                RichContentEnum::Image(_) => Some(ChatContentTypeEnum::Image),
+                RichContentEnum::Audio(_) => Some(ChatContentTypeEnum::Audio),
+                RichContentEnum::Location(_) => Some(ChatContentTypeEnum::Location),
+                RichContentEnum::Markdown(_) => Some(ChatContentTypeEnum::Markdown),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChatContentTypeEnum::Text => "text",
+            ChatContentTypeEnum::File => "file",
+            ChatContentTypeEnum::Image => "image",
+            ChatContentTypeEnum::Audio => "audio",
+            ChatContentTypeEnum::Location => "location",
+            ChatContentTypeEnum::Markdown => "markdown",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChatImageContent {
+    pub file: ChatFileContent,
+    pub width: u32,
+    pub height: u32,
+    // 发送方生成的缩略图，接收方不需要下载原图就可以预览
+    pub thumbnail: Vec<u8>,
+}
+
+impl ChatImageContent {
+    // 发送图片时根据原图生成缩略图，并读取原图的宽、高. image_bytes为原图文件的内容
+    pub fn create(file: ChatFileContent, image_bytes: &[u8]) -> Result<Self, String> {
+        let image = decode_image(image_bytes, IMAGE_MAX_SIDE)?;
+        let thumbnail = create_thumbnail(&image)?;
+
+        Ok(ChatImageContent {
+            file,
+            width: image.width(),
+            height: image.height(),
+            thumbnail,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChatAudioContent {
+    pub file: ChatFileContent,
+    pub duration_millis: u32,
+    // 编码格式，例如opus
+    pub codec: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChatLocationContent {
+    pub latitude: f64,
+    pub longitude: f64,
+    // 位置的名称，例如地址
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChatMarkdownContent {
+    pub text: String,
+}
+
+// 会话的阅后即焚计时器被修改
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChatDisappearTimerContent {
+    pub operator: String,
+    // None为关闭
+    pub expire_seconds: Option<i64>,
+}
+
+// 会话的元数据被修改，metadata为修改之后的完整元数据
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChatMetadataContent {
+    pub operator: String,
+    pub change: MetadataChangeEnum,
+    pub metadata: ConversationMetadata,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChatRichContent {
+    // 不认识data类型的client显示的文本
+    pub fallback: String,
+    // 序列化之后的RichContentEnum
+    pub data: Vec<u8>,
+}
+
+impl ChatRichContent {
+    pub fn new(content: &RichContentEnum) -> Self {
+        ChatRichContent {
+            fallback: content.fallback_text(),
+            data: bincode::serialize(content).unwrap(),
+        }
+    }
+
+    // 不认识的类型返回None，此时显示fallback
+    pub fn decode(&self) -> Option<RichContentEnum> {
+        bincode::deserialize(&self.data).ok()
+    }
+}
+
+impl RichContentEnum {
+    // 发送之前client和server都会校验
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            RichContentEnum::Image(t) => {
+                check_file(&t.file)?;
+                if t.width == 0 || t.height == 0 || t.width.max(t.height) > IMAGE_MAX_SIDE {
+                    return Err(format!(
+                        "image width and height must be in 1..={IMAGE_MAX_SIDE} !"
+                    ));
+                }
+                if t.thumbnail.is_empty() || t.thumbnail.len() > THUMBNAIL_MAX_BYTES {
+                    return Err(format!(
+                        "image thumbnail size must be in 1..={THUMBNAIL_MAX_BYTES} bytes !"
+                    ));
+                }
+                // 缩略图必须是可以解码的图片，避免接收方预览时才发现数据不可用
+                decode_image(&t.thumbnail, THUMBNAIL_MAX_SIDE)
+                    .map_err(|e| format!("invalid image thumbnail: {e}"))?;
+            }
+            RichContentEnum::Audio(t) => {
+                check_file(&t.file)?;
+                if t.duration_millis == 0 || t.duration_millis > AUDIO_MAX_DURATION_MILLIS {
+                    return Err(format!(
+                        "audio duration must be in 1..={AUDIO_MAX_DURATION_MILLIS} millis !"
+                    ));
+                }
+                if !AUDIO_CODECS.contains(&t.codec.as_str()) {
+                    return Err(format!("unsupported audio codec: {}", t.codec));
+                }
+            }
+            RichContentEnum::Location(t) => {
+                if !(-90.0..=90.0).contains(&t.latitude) || !(-180.0..=180.0).contains(&t.longitude)
+                {
+                    return Err("invalid location !".to_string());
+                }
+                if let Some(label) = &t.label {
+                    if label.chars().count() > LOCATION_LABEL_MAX_LEN {
+                        return Err(format!(
+                            "location label can not exceed {LOCATION_LABEL_MAX_LEN} !"
+                        ));
+                    }
+                }
+            }
+            RichContentEnum::Markdown(t) => {
+                if t.text.trim().is_empty() || t.text.chars().count() > MARKDOWN_MAX_LEN {
+                    return Err(format!(
+                        "markdown length must be in 1..={MARKDOWN_MAX_LEN} !"
+                    ));
+                }
+            }
+            RichContentEnum::DisappearTimer(_) | RichContentEnum::MetadataChanged(_) => {
+                return Err("system content can not be sent !".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    // 旧版本client显示的文本
+    pub fn fallback_text(&self) -> String {
+        match self {
+            RichContentEnum::Image(t) => format!("[image] {}", t.file.file_name),
+            RichContentEnum::Audio(t) => format!("[audio] {}s", t.duration_millis / 1000),
+            RichContentEnum::Location(t) => match &t.label {
+                Some(label) => format!("[location] {} ({}, {})", label, t.latitude, t.longitude),
+                None => format!("[location] ({}, {})", t.latitude, t.longitude),
+            },
+            // markdown原文本身就是可读的
+            RichContentEnum::Markdown(t) => t.text.clone(),
+            RichContentEnum::DisappearTimer(t) => match t.expire_seconds {
+                Some(seconds) => format!(
+                    "[system] {} set disappearing messages to {}",
+                    t.operator,
+                    format_duration(seconds)
+                ),
+                None => format!("[system] {} turned off disappearing messages", t.operator),
+            },
+            RichContentEnum::MetadataChanged(t) => {
+                let action = match &t.change {
+                    MetadataChangeEnum::Title(Some(title)) => {
+                        format!("changed the title to {}", title)
+                    }
+                    MetadataChangeEnum::Title(None) => "removed the title".to_string(),
+                    MetadataChangeEnum::Topic(Some(topic)) => {
+                        format!("changed the topic to {}", topic)
+                    }
+                    MetadataChangeEnum::Topic(None) => "removed the topic".to_string(),
+                    MetadataChangeEnum::Avatar(Some(_)) => "changed the avatar".to_string(),
+                    MetadataChangeEnum::Avatar(None) => "removed the avatar".to_string(),
+                    MetadataChangeEnum::Pin(_) => "pinned a message".to_string(),
+                    MetadataChangeEnum::Unpin(_) => "unpinned a message".to_string(),
+                };
+                format!("[system] {} {}", t.operator, action)
+            }
+        }
+    }
+}
+
+// 例如 90 -> 90s, 3600 -> 1h, 604800 -> 7d
+fn format_duration(seconds: i64) -> String {
+    match seconds {
+        t if t % 86400 == 0 => format!("{}d", t / 86400),
+        t if t % 3600 == 0 => format!("{}h", t / 3600),
+        t if t % 60 == 0 => format!("{}m", t / 60),
+        t => format!("{}s", t),
+    }
+}
+
+// server端校验client发送的消息内容，并重新生成富文本的fallback，不使用client填写的
+pub fn check_contents(contents: &mut [ChatContent]) -> Result<(), String> {
+    if contents.is_empty() {
+        return Err("chat contents can not be empty !".to_string());
+    }
+
+    for content in contents.iter_mut() {
+        match content {
+            ChatContent::Rich(t) => {
+                let rich = t.decode().ok_or("unsupported chat content !".to_string())?;
+                rich.validate()?;
+                *t = ChatRichContent::new(&rich);
+            }
+            ChatContent::File(t) => check_file(t)?,
+            ChatContent::Text(_) => {}
+        }
+    }
+    Ok(())
+}
+
+// 消息内容中所有附件的url，包括图片、语音的文件和会话头像
+pub fn find_file_urls(contents: &Vec<ChatContent>) -> Vec<String> {
+    let mut urls = vec![];
+    for content in contents {
+        let url = match content {
+            ChatContent::File(t) => t.url.clone(),
+            ChatContent::Rich(t) => match t.decode() {
+                Some(RichContentEnum::Image(t)) => t.file.url,
+                Some(RichContentEnum::Audio(t)) => t.file.url,
+                // 修改之后的头像，会话的参与者都可以下载
+                Some(RichContentEnum::MetadataChanged(ChatMetadataContent {
+                    change: MetadataChangeEnum::Avatar(url),
+                    ..
+                })) => url,
+                _ => None,
+            },
+            ChatContent::Text(_) => None,
+        };
+        if let Some(url) = url {
+            urls.push(url);
+        }
+    }
+    urls
+}
+
+// 解码图片，宽、高超过max_side时不解码像素数据
+fn decode_image(bytes: &[u8], max_side: u32) -> Result<DynamicImage, String> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(max_side);
+    limits.max_image_height = Some(max_side);
+
+    let mut reader = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|e| e.to_string())?;
+    reader.limits(limits);
+    reader.decode().map_err(|e| e.to_string())
+}
+
+// 等比缩小到THUMBNAIL_MAX_SIDE以内，编码为jpeg
+fn create_thumbnail(image: &DynamicImage) -> Result<Vec<u8>, String> {
+    let thumbnail = image
+        .thumbnail(THUMBNAIL_MAX_SIDE, THUMBNAIL_MAX_SIDE)
+        .to_rgb8();
+
+    let mut bytes = vec![];
+    JpegEncoder::new_with_quality(&mut bytes, THUMBNAIL_QUALITY)
+        .encode_image(&thumbnail)
+        .map_err(|e| e.to_string())?;
+
+    if bytes.len() > THUMBNAIL_MAX_BYTES {
+        return Err("image thumbnail is too large !".to_string());
+    }
+    Ok(bytes)
+}
+
+// 文件需要先分片上传，消息中只能引用上传之后的url，不接收内嵌的文件数据
+fn check_file(file: &ChatFileContent) -> Result<(), String> {
+    if file.file_name.trim().is_empty() {
+        return Err("file name can not be empty !".to_string());
+    }
+    if file.data.is_some() {
+        return Err(
+            "inline file data is not supported, please upload the file first !".to_string(),
+        );
+    }
+    if file.url.is_none() {
+        return Err("file url is required !".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_module::ChatTextContent;
+    use image::{ImageFormat, Rgb, RgbImage};
+
+    fn create_png(width: u32, height: u32) -> Vec<u8> {
+        let image = RgbImage::from_fn(width, height, |x, y| Rgb([x as u8, y as u8, 128]));
+        let mut bytes = Cursor::new(vec![]);
+        image.write_to(&mut bytes, ImageFormat::Png).unwrap();
+        bytes.into_inner()
+    }
+
+    fn create_file(name: &str) -> ChatFileContent {
+        ChatFileContent {
+            file_name: name.to_string(),
+            url: Some("url".to_string()),
+            data: None,
+        }
+    }
+
+    #[test]
+    fn create_image_with_thumbnail() {
+        let image = ChatImageContent::create(create_file("a.png"), &create_png(800, 400)).unwrap();
+        assert_eq!((image.width, image.height), (800, 400));
+
+        let thumbnail = image::load_from_memory(&image.thumbnail).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (256, 128));
+        assert!(RichContentEnum::Image(image).validate().is_ok());
+
+        assert!(ChatImageContent::create(create_file("a.png"), b"not image").is_err());
+    }
+
+    #[test]
+    fn validate_contents() {
+        let mut image =
+            ChatImageContent::create(create_file("a.png"), &create_png(64, 64)).unwrap();
+        image.thumbnail = b"not image".to_vec();
+        assert!(RichContentEnum::Image(image.clone()).validate().is_err());
+        // 缩略图尺寸超过上限
+        image.thumbnail = create_png(512, 16);
+        assert!(RichContentEnum::Image(image.clone()).validate().is_err());
+        image.thumbnail = create_png(16, 16);
+        image.file = create_file(" ");
+        assert!(RichContentEnum::Image(image).validate().is_err());
+
+        let audio = |duration_millis: u32, codec: &str| {
+            RichContentEnum::Audio(ChatAudioContent {
+                file: create_file("a.opus"),
+                duration_millis,
+                codec: codec.to_string(),
+            })
+        };
+        assert!(audio(1000, "opus").validate().is_ok());
+        assert!(audio(0, "opus").validate().is_err());
+        assert!(audio(1000, "wav").validate().is_err());
+
+        let location = |latitude: f64, longitude: f64| {
+            RichContentEnum::Location(ChatLocationContent {
+                latitude,
+                longitude,
+                label: None,
+            })
+        };
+        assert!(location(31.2, 121.5).validate().is_ok());
+        assert!(location(91.0, 0.0).validate().is_err());
+        assert!(location(0.0, -181.0).validate().is_err());
+
+        let markdown = |text: &str| {
+            RichContentEnum::Markdown(ChatMarkdownContent {
+                text: text.to_string(),
+            })
+        };
+        assert!(markdown("**hi**").validate().is_ok());
+        assert!(markdown("  ").validate().is_err());
+
+        // 系统消息不能由client发送
+        let timer = RichContentEnum::DisappearTimer(ChatDisappearTimerContent {
+            operator: "alice".to_string(),
+            expire_seconds: Some(60),
+        });
+        assert!(timer.validate().is_err());
+    }
+
+    #[test]
+    fn fallback_text() {
+        let location = RichContentEnum::Location(ChatLocationContent {
+            latitude: 1.5,
+            longitude: 2.5,
+            label: Some("home".to_string()),
+        });
+        assert_eq!(location.fallback_text(), "[location] home (1.5, 2.5)");
+
+        let audio = RichContentEnum::Audio(ChatAudioContent {
+            file: create_file("a.opus"),
+            duration_millis: 90_500,
+            codec: "opus".to_string(),
+        });
+        assert_eq!(audio.fallback_text(), "[audio] 90s");
+
+        let timer = RichContentEnum::DisappearTimer(ChatDisappearTimerContent {
+            operator: "alice".to_string(),
+            expire_seconds: Some(3600),
+        });
+        assert_eq!(
+            timer.fallback_text(),
+            "[system] alice set disappearing messages to 1h"
+        );
+    }
+
+    #[test]
+    fn check_contents_regenerates_fallback() {
+        let markdown = RichContentEnum::Markdown(ChatMarkdownContent {
+            text: "# title".to_string(),
+        });
+        let mut rich = ChatRichContent::new(&markdown);
+        rich.fallback = "forged".to_string();
+
+        let mut contents = vec![
+            ChatContent::Text(ChatTextContent {
+                text: "hi".to_string(),
+            }),
+            ChatContent::Rich(rich),
+        ];
+        check_contents(&mut contents).unwrap();
+        match &contents[1] {
+            ChatContent::Rich(t) => assert_eq!(t.fallback, "# title"),
+            _ => panic!("rich content expected"),
+        }
+
+        assert!(check_contents(&mut []).is_err());
+
+        // 文件只能引用上传之后的url
+        let mut file = create_file("a.txt");
+        assert!(check_contents(&mut [ChatContent::File(file.clone())]).is_ok());
+        file.data = Some(vec![1]);
+        assert!(check_contents(&mut [ChatContent::File(file.clone())]).is_err());
+        file.url = None;
+        file.data = None;
+        assert!(check_contents(&mut [ChatContent::File(file)]).is_err());
+    }
+
+    #[test]
+    fn unknown_data_falls_back() {
+        // 新版本client发送的类型，当前版本无法解码
+        let rich = ChatRichContent {
+            fallback: "[sticker] smile".to_string(),
+            data: vec![200, 0, 0, 0, 1, 2, 3],
+        };
+        assert!(rich.decode().is_none());
+        let content = ChatContent::Rich(rich);
+        assert_eq!(ChatContentTypeEnum::of(&content), None);
+
+        // server不接受不认识的类型
+        assert!(check_contents(&mut [content]).is_err());
+    }
+}
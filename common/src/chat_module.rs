@@ -1,9 +1,35 @@
+use crate::base::{now_millis, ConnectionRegistry};
+use crate::chat_cache::ChatCache;
+use crate::chat_content::{
+    ChatContentTypeEnum, ChatImageContent, ChatRichContent, RichContentEnum,
+};
+use crate::chat_protocol::{ChatCommand, Protocol};
+use crate::file_module::{DefaultClientFileModule, UploadTasks};
+use crate::group_module::DefaultClientGroupModule;
+use crate::login_module::BizResult;
+use crate::protocol_factory::HandlerProtocolData;
+use log::{error, warn};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
 use std::fmt::Error;
 use std::net::SocketAddr;
-use std::rc::Weak;
-use std::sync::RwLock;
+
+// 群组会话id的前缀
+const GROUP_CONVERSATION_PREFIX: &str = "group#";
+
+// 回复时引用的消息摘要的最大长度
+const QUOTE_SNIPPET_MAX_LEN: usize = 100;
+
+// 正在输入、停止输入的临时事件类型
+pub const EVENT_KIND_TYPING: &str = "typing";
+pub const EVENT_KIND_STOP_TYPING: &str = "stop_typing";
+
+// 正在输入事件的有效时间，client需要在过期之前重新发送
+pub const TYPING_EVENT_TTL_MILLIS: i64 = 5000;
+
+// 阅后即焚计时器的范围，秒
+pub const DISAPPEAR_TIMER_MIN_SECONDS: i64 = 60;
+pub const DISAPPEAR_TIMER_MAX_SECONDS: i64 = 30 * 24 * 3600;
 
 //聊天模块
 pub trait ChatModule {
@@ -23,33 +49,1449 @@ pub trait ChatModule {
     fn find_chat_history(&self, account_a: String, account_b: String) -> Option<Vec<ChatData>>;
 }
 
-struct DefaultClientChatModule {
-    // share: Weak<ModuleEngine>,
-    // 读写锁，存储对方账户与socket的映射关系
-    connected_accounts: RwLock<HashMap<String, SocketAddr>>,
+pub struct DefaultChatHandler {
+    server: Option<Box<dyn ServerChatModule + Send>>,
+
+    client: Option<Box<dyn ClientChatModule + Send>>,
+}
+
+impl DefaultChatHandler {
+    pub fn new(
+        server: Option<Box<dyn ServerChatModule + Send>>,
+        client: Option<Box<dyn ClientChatModule + Send>>,
+    ) -> Self {
+        DefaultChatHandler { server, client }
+    }
+
+    fn server_module(&mut self) -> &mut Box<dyn ServerChatModule + Send> {
+        if self.server.is_none() {
+            panic!("ServerChatModule is None!");
+        }
+        self.server.as_mut().unwrap()
+    }
+
+    fn client_module(&mut self) -> &mut Box<dyn ClientChatModule + Send> {
+        if self.client.is_none() {
+            panic!("ClientChatModule is None!");
+        }
+        self.client.as_mut().unwrap()
+    }
+
+    // 无法解析的数据: server端回复失败的确认，client端忽略
+    fn reject(&self, address: SocketAddr, msg: String) -> Option<Vec<u8>> {
+        if self.server.is_none() {
+            warn!("ignore chat data from {}: {}", address, msg);
+            return None;
+        }
+
+        let resp_data = BizChatData {
+            chat_type: ChatTypeEnum::Resp,
+            data: ChatDataEnum::Ack(to_biz_result(Err(msg), address)),
+        };
+        Some(bincode::serialize(&resp_data).unwrap())
+    }
+
+    // client端处理server推送的数据，返回需要回复给server的数据
+    fn handle_push(&mut self, data: ChatDataEnum) -> Option<ChatDataEnum> {
+        match data {
+            // 收到消息后自动回复送达回执
+            ChatDataEnum::Msg(msg) => {
+                let receipt = ReceiptData {
+                    from_account: msg.to_account.clone(),
+                    to_account: msg.from_account.clone(),
+                    msg_ids: vec![msg.msg_id.clone()],
+                    state: MessageState::Delivered,
+                    time: 0,
+                };
+                self.client_module().handle_chat_msg(msg);
+                Some(ChatDataEnum::Receipt(receipt))
+            }
+
+            ChatDataEnum::Receipt(receipt) => {
+                self.client_module().handle_receipt(receipt);
+                None
+            }
+
+            ChatDataEnum::Updated(msg) => {
+                self.client_module().handle_message_updated(msg);
+                None
+            }
+
+            // 过期的临时事件直接丢弃
+            ChatDataEnum::Event(event) => {
+                if event.time + event.ttl_millis >= now_millis() {
+                    self.client_module().handle_event(event);
+                }
+                None
+            }
+
+            // 离线消息处理完之后回复确认，server收到确认才会删除，并代为回复送达回执
+            ChatDataEnum::OfflineMsg(msg) => {
+                match *msg.data {
+                    ChatDataEnum::Msg(t) => self.client_module().handle_chat_msg(t),
+                    ChatDataEnum::Receipt(t) => self.client_module().handle_receipt(t),
+                    ChatDataEnum::Updated(t) => self.client_module().handle_message_updated(t),
+                    t => warn!("unsupported offline chat data: {:?}", t),
+                }
+                Some(ChatDataEnum::OfflineAck(OfflineAckData {
+                    ids: vec![msg.offline_id],
+                }))
+            }
+
+            t => {
+                warn!("unsupported push chat data: {:?}", t);
+                None
+            }
+        }
+    }
+}
+
+impl HandlerProtocolData for DefaultChatHandler {
+    fn handle(&mut self, address: SocketAddr, data: &Vec<u8>) -> Option<Vec<u8>> {
+        let biz: BizChatData = match bincode::deserialize(data) {
+            Ok(t) => t,
+            Err(e) => return self.reject(address, format!("invalid chat data: {e}")),
+        };
+
+        match (biz.chat_type, biz.data) {
+            // server端转发client发送的消息，并回复发送方
+            (ChatTypeEnum::Req, ChatDataEnum::Msg(msg)) => {
+                let resp = self.server_module().handle_chat_msg(msg, address);
+                let resp_data = BizChatData {
+                    chat_type: ChatTypeEnum::Resp,
+                    data: ChatDataEnum::Ack(to_biz_result(resp, address)),
+                };
+                return Some(bincode::serialize(&resp_data).unwrap());
+            }
+
+            // server端查询历史消息
+            (ChatTypeEnum::Req, ChatDataEnum::HistoryReq(req)) => {
+                let resp = self.server_module().handle_history_req(req, address);
+                let resp_data = BizChatData {
+                    chat_type: ChatTypeEnum::Resp,
+                    data: ChatDataEnum::HistoryResp(to_biz_result(resp, address)),
+                };
+                return Some(bincode::serialize(&resp_data).unwrap());
+            }
+
+            // server端返回会话中缺失的消息
+            (ChatTypeEnum::Req, ChatDataEnum::SyncSince(req)) => {
+                let resp = self.server_module().handle_sync_since(req, address);
+                let resp_data = BizChatData {
+                    chat_type: ChatTypeEnum::Resp,
+                    data: ChatDataEnum::SyncResp(to_biz_result(resp, address)),
+                };
+                return Some(bincode::serialize(&resp_data).unwrap());
+            }
+
+            // server端修改、撤回消息，并推送给会话的参与者
+            (ChatTypeEnum::Req, ChatDataEnum::EditMessage(req)) => {
+                let resp = self.server_module().handle_edit_message(req, address);
+                let resp_data = BizChatData {
+                    chat_type: ChatTypeEnum::Resp,
+                    data: ChatDataEnum::UpdateResp(to_biz_result(resp, address)),
+                };
+                return Some(bincode::serialize(&resp_data).unwrap());
+            }
+
+            (ChatTypeEnum::Req, ChatDataEnum::RecallMessage(req)) => {
+                let resp = self.server_module().handle_recall_message(req, address);
+                let resp_data = BizChatData {
+                    chat_type: ChatTypeEnum::Resp,
+                    data: ChatDataEnum::UpdateResp(to_biz_result(resp, address)),
+                };
+                return Some(bincode::serialize(&resp_data).unwrap());
+            }
+
+            // server端添加、删除表情回应，并推送给会话的参与者
+            (ChatTypeEnum::Req, ChatDataEnum::Reaction(req)) => {
+                let resp = self.server_module().handle_reaction(req, address);
+                let resp_data = BizChatData {
+                    chat_type: ChatTypeEnum::Resp,
+                    data: ChatDataEnum::UpdateResp(to_biz_result(resp, address)),
+                };
+                return Some(bincode::serialize(&resp_data).unwrap());
+            }
+
+            // server端查询回复话题
+            (ChatTypeEnum::Req, ChatDataEnum::ThreadReq(req)) => {
+                let resp = self.server_module().handle_thread_req(req, address);
+                let resp_data = BizChatData {
+                    chat_type: ChatTypeEnum::Resp,
+                    data: ChatDataEnum::ThreadResp(to_biz_result(resp, address)),
+                };
+                return Some(bincode::serialize(&resp_data).unwrap());
+            }
+
+            (ChatTypeEnum::Req, ChatDataEnum::EditHistoryReq(req)) => {
+                let resp = self.server_module().handle_edit_history_req(req, address);
+                let resp_data = BizChatData {
+                    chat_type: ChatTypeEnum::Resp,
+                    data: ChatDataEnum::EditHistoryResp(to_biz_result(resp, address)),
+                };
+                return Some(bincode::serialize(&resp_data).unwrap());
+            }
+
+            // server端修改阅后即焚计时器，并推送系统消息给会话的参与者
+            (ChatTypeEnum::Req, ChatDataEnum::DisappearTimer(req)) => {
+                let resp = self.server_module().handle_disappear_timer(req, address);
+                let resp_data = BizChatData {
+                    chat_type: ChatTypeEnum::Resp,
+                    data: ChatDataEnum::DisappearTimerResp(to_biz_result(resp, address)),
+                };
+                return Some(bincode::serialize(&resp_data).unwrap());
+            }
+
+            // server端保存、修改、取消定时发送的消息，到达发送时间后由server发送
+            (ChatTypeEnum::Req, ChatDataEnum::Schedule(req)) => {
+                let resp = self.server_module().handle_schedule(req, address);
+                let resp_data = BizChatData {
+                    chat_type: ChatTypeEnum::Resp,
+                    data: ChatDataEnum::ScheduleResp(to_biz_result(resp, address)),
+                };
+                return Some(bincode::serialize(&resp_data).unwrap());
+            }
+
+            (ChatTypeEnum::Req, ChatDataEnum::ScheduleEdit(req)) => {
+                let resp = self.server_module().handle_schedule_edit(req, address);
+                let resp_data = BizChatData {
+                    chat_type: ChatTypeEnum::Resp,
+                    data: ChatDataEnum::ScheduleResp(to_biz_result(resp, address)),
+                };
+                return Some(bincode::serialize(&resp_data).unwrap());
+            }
+
+            (ChatTypeEnum::Req, ChatDataEnum::ScheduleCancel(req)) => {
+                let resp = self.server_module().handle_schedule_cancel(req, address);
+                let resp_data = BizChatData {
+                    chat_type: ChatTypeEnum::Resp,
+                    data: ChatDataEnum::ScheduleResp(to_biz_result(resp, address)),
+                };
+                return Some(bincode::serialize(&resp_data).unwrap());
+            }
+
+            (ChatTypeEnum::Req, ChatDataEnum::ScheduleListReq) => {
+                let resp = self.server_module().handle_schedule_list(address);
+                let resp_data = BizChatData {
+                    chat_type: ChatTypeEnum::Resp,
+                    data: ChatDataEnum::ScheduleListResp(to_biz_result(resp, address)),
+                };
+                return Some(bincode::serialize(&resp_data).unwrap());
+            }
+
+            // server端查询、修改会话的元数据，修改之后推送系统消息给会话的参与者
+            (ChatTypeEnum::Req, ChatDataEnum::MetadataReq(req)) => {
+                let resp = self.server_module().handle_metadata_req(req, address);
+                let resp_data = BizChatData {
+                    chat_type: ChatTypeEnum::Resp,
+                    data: ChatDataEnum::MetadataResp(to_biz_result(resp, address)),
+                };
+                return Some(bincode::serialize(&resp_data).unwrap());
+            }
+
+            (ChatTypeEnum::Req, ChatDataEnum::UpdateMetadata(req)) => {
+                let resp = self.server_module().handle_update_metadata(req, address);
+                let resp_data = BizChatData {
+                    chat_type: ChatTypeEnum::Resp,
+                    data: ChatDataEnum::UpdateMetadataResp(to_biz_result(resp, address)),
+                };
+                return Some(bincode::serialize(&resp_data).unwrap());
+            }
+
+            // server端把消息复制到目标会话，附件不需要重新上传
+            (ChatTypeEnum::Req, ChatDataEnum::Forward(req)) => {
+                let resp = self.server_module().handle_forward(req, address);
+                let resp_data = BizChatData {
+                    chat_type: ChatTypeEnum::Resp,
+                    data: ChatDataEnum::ForwardResp(to_biz_result(resp, address)),
+                };
+                return Some(bincode::serialize(&resp_data).unwrap());
+            }
+
+            (ChatTypeEnum::Req, ChatDataEnum::SearchReq(req)) => {
+                let resp = self.server_module().handle_search(req, address);
+                let resp_data = BizChatData {
+                    chat_type: ChatTypeEnum::Resp,
+                    data: ChatDataEnum::SearchResp(to_biz_result(resp, address)),
+                };
+                return Some(bincode::serialize(&resp_data).unwrap());
+            }
+
+            // server端转发临时事件，不回复发送方
+            (ChatTypeEnum::Req, ChatDataEnum::Event(req)) => {
+                if let Err(e) = self.server_module().handle_event(req, address) {
+                    warn!("event from {} fail: {}", address, e);
+                }
+            }
+
+            // server端收到client对离线消息的确认
+            (ChatTypeEnum::Req, ChatDataEnum::OfflineAck(req)) => {
+                if let Err(e) = self.server_module().handle_offline_ack(req, address) {
+                    warn!("offline ack from {} fail: {}", address, e);
+                }
+            }
+
+            // server端转发接收方的送达、已读回执
+            (ChatTypeEnum::Req, ChatDataEnum::Receipt(req)) => {
+                if let Err(e) = self.server_module().handle_receipt(req, address) {
+                    warn!("receipt from {} fail: {}", address, e);
+                }
+            }
+
+            // client端收到server推送的数据
+            (ChatTypeEnum::Push, data) => {
+                return self.handle_push(data).map(|t| {
+                    let req = BizChatData {
+                        chat_type: ChatTypeEnum::Req,
+                        data: t,
+                    };
+                    bincode::serialize(&req).unwrap()
+                });
+            }
+
+            // client端收到server对已发送消息的回复
+            (ChatTypeEnum::Resp, ChatDataEnum::Ack(resp)) => {
+                self.client_module().handle_chat_ack(resp);
+            }
+
+            // client端收到查询的历史消息
+            (ChatTypeEnum::Resp, ChatDataEnum::HistoryResp(resp)) => {
+                self.client_module().handle_history_resp(resp);
+            }
+
+            // client端收到修改、撤回的结果
+            (ChatTypeEnum::Resp, ChatDataEnum::UpdateResp(resp)) => {
+                self.client_module().handle_update_resp(resp);
+            }
+
+            (ChatTypeEnum::Resp, ChatDataEnum::EditHistoryResp(resp)) => {
+                self.client_module().handle_edit_history_resp(resp);
+            }
+
+            (ChatTypeEnum::Resp, ChatDataEnum::DisappearTimerResp(resp)) => {
+                self.client_module().handle_disappear_timer_resp(resp);
+            }
+
+            // client端收到定时消息的处理结果
+            (ChatTypeEnum::Resp, ChatDataEnum::ScheduleResp(resp)) => {
+                self.client_module().handle_schedule_resp(resp);
+            }
+
+            (ChatTypeEnum::Resp, ChatDataEnum::ScheduleListResp(resp)) => {
+                self.client_module().handle_schedule_list_resp(resp);
+            }
+
+            // client端收到会话元数据的查询、修改结果
+            (ChatTypeEnum::Resp, ChatDataEnum::MetadataResp(resp)) => {
+                self.client_module().handle_metadata_resp(resp);
+            }
+
+            (ChatTypeEnum::Resp, ChatDataEnum::UpdateMetadataResp(resp)) => {
+                self.client_module().handle_update_metadata_resp(resp);
+            }
+
+            (ChatTypeEnum::Resp, ChatDataEnum::ForwardResp(resp)) => {
+                self.client_module().handle_forward_resp(resp);
+            }
+
+            (ChatTypeEnum::Resp, ChatDataEnum::SearchResp(resp)) => {
+                self.client_module().handle_search_resp(resp);
+            }
+
+            // client端收到查询的回复话题
+            (ChatTypeEnum::Resp, ChatDataEnum::ThreadResp(resp)) => {
+                self.client_module().handle_thread_resp(resp);
+            }
+
+            // client端收到同步的消息，还有更多消息时继续同步
+            (ChatTypeEnum::Resp, ChatDataEnum::SyncResp(resp)) => {
+                return self.client_module().handle_sync_resp(resp).map(|t| {
+                    let req = BizChatData {
+                        chat_type: ChatTypeEnum::Req,
+                        data: ChatDataEnum::SyncSince(t),
+                    };
+                    bincode::serialize(&req).unwrap()
+                });
+            }
+
+            (chat_type, _) => {
+                warn!("unsupported chat data, chat_type:{:?}", chat_type);
+            }
+        }
+
+        None
+    }
+}
+
+fn to_biz_result<T>(resp: Result<T, String>, address: SocketAddr) -> BizResult<T> {
+    match resp {
+        Ok(t) => BizResult {
+            is_success: true,
+            msg: None,
+            data: Some(t),
+        },
+        Err(e) => {
+            warn!("chat req from {} fail: {}", address, e);
+            BizResult {
+                is_success: false,
+                msg: Some(e),
+                data: None,
+            }
+        }
+    }
+}
+
+/**
+*  server端处理聊天消息的模块trait
+**/
+pub trait ServerChatModule {
+    // 把消息转发给在线的接收方，返回给发送方的回复
+    fn handle_chat_msg(
+        &mut self,
+        data: ChatData,
+        address: SocketAddr,
+    ) -> Result<ChatAckData, String>;
+
+    // client确认已经收到离线消息
+    fn handle_offline_ack(
+        &mut self,
+        req: OfflineAckData,
+        address: SocketAddr,
+    ) -> Result<(), String>;
+
+    // 把接收方的送达、已读回执转发给发送方的所有设备
+    fn handle_receipt(&mut self, req: ReceiptData, address: SocketAddr) -> Result<(), String>;
+
+    // 分页查询当前账户与指定账户之间的历史消息
+    fn handle_history_req(
+        &mut self,
+        req: HistoryReqData,
+        address: SocketAddr,
+    ) -> Result<HistoryRespData, String>;
+
+    // 返回会话中序号大于指定序号的消息
+    fn handle_sync_since(
+        &mut self,
+        req: SyncSinceData,
+        address: SocketAddr,
+    ) -> Result<SyncRespData, String>;
+
+    // 发送方在修改时限内可以修改自己的消息
+    fn handle_edit_message(
+        &mut self,
+        req: EditMessageData,
+        address: SocketAddr,
+    ) -> Result<ChatData, String>;
+
+    // 发送方在撤回时限内可以撤回自己的消息，管理员可以撤回任何消息
+    fn handle_recall_message(
+        &mut self,
+        req: RecallMessageData,
+        address: SocketAddr,
+    ) -> Result<ChatData, String>;
+
+    // 只有管理员可以查看消息的修改历史
+    fn handle_edit_history_req(
+        &mut self,
+        req: EditHistoryReqData,
+        address: SocketAddr,
+    ) -> Result<EditHistoryRespData, String>;
+
+    // 会话的参与者可以对消息添加、删除表情回应，返回回应之后的消息
+    fn handle_reaction(
+        &mut self,
+        req: ReactionReqData,
+        address: SocketAddr,
+    ) -> Result<ChatData, String>;
+
+    // 分页查询消息所在的回复话题
+    fn handle_thread_req(
+        &mut self,
+        req: ThreadReqData,
+        address: SocketAddr,
+    ) -> Result<ThreadRespData, String>;
+
+    // 把临时事件转发给会话参与者在线的设备，不保存，超过频率限制的事件被丢弃
+    fn handle_event(&mut self, req: EventData, address: SocketAddr) -> Result<(), String>;
+
+    // 修改会话的阅后即焚计时器，返回计时器修改的系统消息. 之后发送的消息才会过期
+    fn handle_disappear_timer(
+        &mut self,
+        req: DisappearTimerReqData,
+        address: SocketAddr,
+    ) -> Result<ChatData, String>;
+
+    // 保存定时发送的消息，检查的规则与直接发送相同
+    fn handle_schedule(
+        &mut self,
+        req: ScheduleReqData,
+        address: SocketAddr,
+    ) -> Result<ScheduledData, String>;
+
+    // 修改未发送的定时消息的内容或发送时间
+    fn handle_schedule_edit(
+        &mut self,
+        req: ScheduleEditData,
+        address: SocketAddr,
+    ) -> Result<ScheduledData, String>;
+
+    // 取消未发送的定时消息，返回被取消的消息
+    fn handle_schedule_cancel(
+        &mut self,
+        req: ScheduleCancelData,
+        address: SocketAddr,
+    ) -> Result<ScheduledData, String>;
+
+    // 按发送时间返回当前账户未发送的定时消息
+    fn handle_schedule_list(&mut self, address: SocketAddr) -> Result<Vec<ScheduledData>, String>;
+
+    // 一次返回会话的所有元数据，只有会话的参与者可以查询
+    fn handle_metadata_req(
+        &mut self,
+        req: MetadataReqData,
+        address: SocketAddr,
+    ) -> Result<ConversationMetadata, String>;
+
+    // 修改会话的元数据，返回修改的系统消息. 群组中只有群主、管理员可以修改
+    fn handle_update_metadata(
+        &mut self,
+        req: UpdateMetadataData,
+        address: SocketAddr,
+    ) -> Result<ChatData, String>;
+
+    // 把源会话中的消息转发到目标会话，调用方必须能访问源会话并且能在目标会话中发送消息
+    fn handle_forward(
+        &mut self,
+        req: ForwardReqData,
+        address: SocketAddr,
+    ) -> Result<Vec<ChatData>, String>;
+
+    // 全文搜索当前账户参与的会话中的消息，不包括撤回和阅后即焚的消息
+    fn handle_search(
+        &mut self,
+        req: SearchReqData,
+        address: SocketAddr,
+    ) -> Result<SearchRespData, String>;
+}
+
+/**
+ *  client端处理server推送的聊天数据的模块trait
+ **/
+pub trait ClientChatModule {
+    // 收到其它账户发送的消息
+    fn handle_chat_msg(&mut self, data: ChatData);
+
+    // 已发送消息的处理结果
+    fn handle_chat_ack(&mut self, resp: BizResult<ChatAckData>);
+
+    // 已发送消息的送达、已读回执
+    fn handle_receipt(&mut self, data: ReceiptData);
+
+    // 查询历史消息的结果
+    fn handle_history_resp(&mut self, resp: BizResult<HistoryRespData>);
+
+    // 同步到的消息，返回继续同步的请求
+    fn handle_sync_resp(&mut self, resp: BizResult<SyncRespData>) -> Option<SyncSinceData>;
+
+    // 会话中的消息被修改、撤回
+    fn handle_message_updated(&mut self, data: ChatData);
+
+    // 修改、撤回消息的结果
+    fn handle_update_resp(&mut self, resp: BizResult<ChatData>);
+
+    // 查看消息修改历史的结果
+    fn handle_edit_history_resp(&mut self, resp: BizResult<EditHistoryRespData>);
+
+    // 查询回复话题的结果
+    fn handle_thread_resp(&mut self, resp: BizResult<ThreadRespData>);
+
+    // 会话中其它参与者发送的临时事件，已过期的事件不会调用
+    fn handle_event(&mut self, event: EventData);
+
+    // 修改阅后即焚计时器的结果
+    fn handle_disappear_timer_resp(&mut self, resp: BizResult<ChatData>);
+
+    // 创建、修改、取消定时消息的结果
+    fn handle_schedule_resp(&mut self, resp: BizResult<ScheduledData>);
+
+    // 查询未发送的定时消息的结果
+    fn handle_schedule_list_resp(&mut self, resp: BizResult<Vec<ScheduledData>>);
+
+    // 查询会话元数据的结果
+    fn handle_metadata_resp(&mut self, resp: BizResult<ConversationMetadata>);
+
+    // 修改会话元数据的结果，其它参与者以系统消息收到修改
+    fn handle_update_metadata_resp(&mut self, resp: BizResult<ChatData>);
+
+    // 转发消息的结果，为转发之后的消息
+    fn handle_forward_resp(&mut self, resp: BizResult<Vec<ChatData>>);
+
+    // 搜索消息的结果
+    fn handle_search_resp(&mut self, resp: BizResult<SearchRespData>);
+}
+
+/**
+ *  client端发送聊天消息. 消息通过与server的连接发送，由server转发给接收方
+ **/
+#[derive(Clone)]
+pub struct DefaultClientChatModule {
+    registry: ConnectionRegistry,
+    server_addr: SocketAddr,
+    // 本地的聊天记录
+    cache: ChatCache,
+    // 进行中的文件上传
+    uploads: UploadTasks,
+}
+
+impl DefaultClientChatModule {
+    pub fn new(registry: ConnectionRegistry, server_addr: SocketAddr, cache: ChatCache) -> Self {
+        DefaultClientChatModule {
+            registry,
+            server_addr,
+            cache,
+            uploads: Default::default(),
+        }
+    }
+
+    // 向server查询与指定账户之间的历史消息，结果在ClientChatModule::handle_history_resp中处理
+    pub fn request_history(
+        &self,
+        peer_account: String,
+        cursor: HistoryCursor,
+        limit: u32,
+    ) -> Result<(), Error> {
+        self.send_req(ChatDataEnum::HistoryReq(HistoryReqData {
+            peer_account,
+            group_id: None,
+            cursor,
+            limit,
+        }))
+    }
+
+    pub fn request_group_history(
+        &self,
+        group_id: i32,
+        cursor: HistoryCursor,
+        limit: u32,
+    ) -> Result<(), Error> {
+        self.send_req(ChatDataEnum::HistoryReq(HistoryReqData {
+            peer_account: String::new(),
+            group_id: Some(group_id),
+            cursor,
+            limit,
+        }))
+    }
+
+    // 修改自己发送的文本消息
+    pub fn edit_msg(&self, msg_id: String, msg: String) -> Result<(), Error> {
+        self.send_req(ChatDataEnum::EditMessage(EditMessageData {
+            msg_id,
+            contents: vec![ChatContent::Text(ChatTextContent { text: msg })],
+        }))
+    }
+
+    pub fn recall_msg(&self, msg_id: String) -> Result<(), Error> {
+        self.send_req(ChatDataEnum::RecallMessage(RecallMessageData { msg_id }))
+    }
+
+    // 回复指定的消息，回复发送到被回复消息所在的会话
+    pub fn reply_msg(
+        &self,
+        from_account: String,
+        reply_to: &ChatData,
+        msg: String,
+    ) -> Result<(), Error> {
+        let to_account = match reply_to.group_id {
+            Some(_) => String::new(),
+            None if reply_to.from_account == from_account => reply_to.to_account.clone(),
+            None => reply_to.from_account.clone(),
+        };
+
+        let data = ChatData {
+            msg_id: create_msg_id(),
+            from_account,
+            to_account,
+            group_id: reply_to.group_id,
+            contents: vec![ChatContent::Text(ChatTextContent { text: msg })],
+            seq: 0,
+            time: now_millis(),
+            edit_time: None,
+            recall_by: None,
+            // 摘要和话题由server填写
+            reply_to: Some(ReplyData {
+                msg_id: reply_to.msg_id.clone(),
+                from_account: String::new(),
+                snippet: String::new(),
+                thread_id: String::new(),
+            }),
+            reactions: vec![],
+            expire_time: None,
+            forward_from: None,
+        };
+        self.send_chat_data(data)
+    }
+
+    // 发送图片、语音、位置、markdown，发送之前先校验. 群组消息的to_account为空
+    pub fn send_rich_content(
+        &self,
+        from_account: String,
+        to_account: String,
+        group_id: Option<i32>,
+        content: RichContentEnum,
+    ) -> Result<(), Error> {
+        content.validate().map_err(|e| {
+            error!("invalid rich content: {}", e);
+            Error
+        })?;
+
+        let data = ChatData {
+            msg_id: create_msg_id(),
+            from_account,
+            to_account,
+            group_id,
+            contents: vec![ChatContent::Rich(ChatRichContent::new(&content))],
+            seq: 0,
+            time: now_millis(),
+            edit_time: None,
+            recall_by: None,
+            reply_to: None,
+            reactions: vec![],
+            expire_time: None,
+            forward_from: None,
+        };
+        self.send_chat_data(data)
+    }
+
+    // 发送图片，根据原图内容生成缩略图. file为已上传的文件，image_bytes为原图文件的内容
+    pub fn send_image(
+        &self,
+        from_account: String,
+        to_account: String,
+        group_id: Option<i32>,
+        file: ChatFileContent,
+        image_bytes: &[u8],
+    ) -> Result<(), Error> {
+        let image = ChatImageContent::create(file, image_bytes).map_err(|e| {
+            error!("create image content fail: {}", e);
+            Error
+        })?;
+        self.send_rich_content(
+            from_account,
+            to_account,
+            group_id,
+            RichContentEnum::Image(image),
+        )
+    }
+
+    // 添加或删除自己对消息的表情回应
+    pub fn react(&self, msg_id: String, emoji: String, add: bool) -> Result<(), Error> {
+        self.send_req(ChatDataEnum::Reaction(ReactionReqData {
+            msg_id,
+            emoji,
+            add,
+        }))
+    }
+
+    // 查询消息所在的回复话题，after_seq为本地已有的最大序号，结果在handle_thread_resp中处理
+    pub fn request_thread(&self, msg_id: String, after_seq: i64, limit: u32) -> Result<(), Error> {
+        self.send_req(ChatDataEnum::ThreadReq(ThreadReqData {
+            msg_id,
+            after_seq,
+            limit,
+        }))
+    }
+
+    // 发送临时事件到会话，server只转发给在线的设备
+    pub fn send_event(
+        &self,
+        conversation: String,
+        kind: String,
+        payload: Vec<u8>,
+        ttl_millis: i64,
+    ) -> Result<(), Error> {
+        self.send_req(ChatDataEnum::Event(EventData {
+            conversation,
+            from_account: String::new(),
+            kind,
+            payload,
+            ttl_millis,
+            time: 0,
+        }))
+    }
+
+    // 正在输入时需要在TYPING_EVENT_TTL_MILLIS之内重复发送，停止输入时发送一次
+    pub fn send_typing(&self, conversation: String, typing: bool) -> Result<(), Error> {
+        let kind = match typing {
+            true => EVENT_KIND_TYPING,
+            false => EVENT_KIND_STOP_TYPING,
+        };
+        self.send_event(
+            conversation,
+            kind.to_string(),
+            vec![],
+            TYPING_EVENT_TTL_MILLIS,
+        )
+    }
+
+    // 修改会话的阅后即焚计时器，expire_seconds为None时关闭. 群组中只有群主、管理员可以修改
+    pub fn set_disappear_timer(
+        &self,
+        conversation: String,
+        expire_seconds: Option<i64>,
+    ) -> Result<(), Error> {
+        self.send_req(ChatDataEnum::DisappearTimer(DisappearTimerReqData {
+            conversation,
+            expire_seconds,
+        }))
+    }
+
+    // 在send_time发送文本消息，群组消息的to_account为空. 发送之前可以修改、取消
+    pub fn schedule_msg(
+        &self,
+        from_account: String,
+        to_account: String,
+        group_id: Option<i32>,
+        msg: String,
+        send_time: i64,
+    ) -> Result<(), Error> {
+        let data = ChatData {
+            msg_id: create_msg_id(),
+            from_account,
+            to_account,
+            group_id,
+            contents: vec![ChatContent::Text(ChatTextContent { text: msg })],
+            seq: 0,
+            time: now_millis(),
+            edit_time: None,
+            recall_by: None,
+            reply_to: None,
+            reactions: vec![],
+            expire_time: None,
+            forward_from: None,
+        };
+        self.send_req(ChatDataEnum::Schedule(ScheduleReqData { data, send_time }))
+    }
+
+    // 查询未发送的定时消息，结果在handle_schedule_list_resp中处理
+    pub fn request_scheduled(&self) -> Result<(), Error> {
+        self.send_req(ChatDataEnum::ScheduleListReq)
+    }
+
+    // 修改未发送的定时消息，msg、send_time为None时不修改
+    pub fn edit_scheduled(
+        &self,
+        schedule_id: String,
+        msg: Option<String>,
+        send_time: Option<i64>,
+    ) -> Result<(), Error> {
+        self.send_req(ChatDataEnum::ScheduleEdit(ScheduleEditData {
+            schedule_id,
+            contents: msg.map(|t| vec![ChatContent::Text(ChatTextContent { text: t })]),
+            send_time,
+        }))
+    }
+
+    pub fn cancel_scheduled(&self, schedule_id: String) -> Result<(), Error> {
+        self.send_req(ChatDataEnum::ScheduleCancel(ScheduleCancelData {
+            schedule_id,
+        }))
+    }
+
+    // 查询会话的元数据，结果在handle_metadata_resp中处理
+    pub fn request_metadata(&self, conversation: String) -> Result<(), Error> {
+        self.send_req(ChatDataEnum::MetadataReq(MetadataReqData { conversation }))
+    }
+
+    // 修改会话的标题、话题、头像或者置顶消息，头像需要先上传
+    pub fn update_metadata(
+        &self,
+        conversation: String,
+        change: MetadataChangeEnum,
+    ) -> Result<(), Error> {
+        self.send_req(ChatDataEnum::UpdateMetadata(UpdateMetadataData {
+            conversation,
+            change,
+        }))
+    }
+
+    // 把消息转发到会话，conversations为目标会话的id
+    pub fn forward_msgs(
+        &self,
+        msg_ids: Vec<String>,
+        conversations: Vec<String>,
+    ) -> Result<(), Error> {
+        self.send_req(ChatDataEnum::Forward(ForwardReqData {
+            msg_ids,
+            conversations,
+        }))
+    }
+
+    // 搜索消息，结果在handle_search_resp中处理
+    pub fn search_msgs(&self, req: SearchReqData) -> Result<(), Error> {
+        self.send_req(ChatDataEnum::SearchReq(req))
+    }
+
+    // 管理员查看消息的修改历史
+    pub fn request_edit_history(&self, msg_id: String) -> Result<(), Error> {
+        self.send_req(ChatDataEnum::EditHistoryReq(EditHistoryReqData { msg_id }))
+    }
+
+    // 发送消息到群组
+    pub fn send_group_msg(
+        &self,
+        from_account: String,
+        group_id: i32,
+        msg: String,
+    ) -> Result<(), Error> {
+        let data = ChatData {
+            msg_id: create_msg_id(),
+            from_account,
+            to_account: String::new(),
+            group_id: Some(group_id),
+            contents: vec![ChatContent::Text(ChatTextContent { text: msg })],
+            seq: 0,
+            time: now_millis(),
+            edit_time: None,
+            recall_by: None,
+            reply_to: None,
+            reactions: vec![],
+            expire_time: None,
+            forward_from: None,
+        };
+        self.send_chat_data(data)
+    }
+
+    // 只查询本地缓存
+    pub fn find_group_history(&self, group_id: i32) -> Option<Vec<ChatData>> {
+        let conversation = get_group_conversation_id(group_id);
+        self.cache
+            .load(&conversation)
+            .map_err(|e| error!("load chat history of {} fail: {}", conversation, e))
+            .ok()
+    }
+
+    // 使用同一个连接发送群组请求
+    pub fn group_module(&self) -> DefaultClientGroupModule {
+        DefaultClientGroupModule::new(self.registry.clone(), self.server_addr)
+    }
+
+    // 使用同一个连接上传文件
+    pub fn file_module(&self) -> DefaultClientFileModule {
+        DefaultClientFileModule::new(
+            self.registry.clone(),
+            self.server_addr,
+            self.uploads.clone(),
+        )
+    }
+
+    // 附件上传完成之后，把url填写到消息的文件内容中发送
+    pub fn send_uploaded(&self, mut data: ChatData, url: String) -> Result<(), Error> {
+        for content in data.contents.iter_mut() {
+            if let ChatContent::File(t) = content {
+                if t.url.is_none() && t.data.is_none() {
+                    t.url = Some(url.clone());
+                }
+            }
+        }
+        self.send_chat_data(data)
+    }
+
+    // 回复已读回执. msg_ids为对方发送的消息
+    pub fn send_read_receipt(
+        &self,
+        from_account: String,
+        to_account: String,
+        msg_ids: Vec<String>,
+    ) -> Result<(), Error> {
+        self.send_req(ChatDataEnum::Receipt(ReceiptData {
+            from_account,
+            to_account,
+            msg_ids,
+            state: MessageState::Read,
+            time: 0,
+        }))
+    }
+
+    // 同步本地缓存的所有会话中缺失的消息，并继续未完成的上传，重新连接登录后调用
+    pub fn sync_all(&self) -> Result<(), Error> {
+        self.file_module().resume_all()?;
+
+        // 离线期间过期的消息先删除
+        if let Err(e) = self.cache.purge_expired() {
+            warn!("purge expired msgs fail: {}", e);
+        }
+
+        let conversations = self.cache.list_conversations().map_err(|e| {
+            error!("list cached conversations fail: {}", e);
+            Error
+        })?;
+
+        for conversation in conversations {
+            let seq = self.cache.last_seq(&conversation).unwrap_or_default();
+            self.send_req(ChatDataEnum::SyncSince(SyncSinceData { conversation, seq }))?;
+        }
+        Ok(())
+    }
+
+    fn send_chat_data(&self, data: ChatData) -> Result<(), Error> {
+        self.cache.add_pending(data.clone());
+        self.send_req(ChatDataEnum::Msg(data))
+    }
+
+    fn send_req(&self, data: ChatDataEnum) -> Result<(), Error> {
+        let biz = BizChatData {
+            chat_type: ChatTypeEnum::Req,
+            data,
+        };
+
+        self.registry
+            .send_to(&self.server_addr, &biz.to_protocol_bytes())
+            .map_err(|e| {
+                error!("send chat msg fail: {}", e);
+                Error
+            })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BizChatData {
+    pub chat_type: ChatTypeEnum,
+    pub data: ChatDataEnum,
+}
+
+impl BizChatData {
+    // 打包成可以直接写入stream的字节
+    pub fn to_protocol_bytes(&self) -> Vec<u8> {
+        let data = bincode::serialize(self).unwrap();
+        Protocol::create_by_data(ChatCommand::Chat, data).to_vec()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub enum ChatTypeEnum {
+    // client发送给server
+    Req,
+    // server对Req的回复
+    Resp,
+    // server主动推送给client
+    Push,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ChatDataEnum {
+    Msg(ChatData),
+    Ack(BizResult<ChatAckData>),
+    // server投递的离线数据
+    OfflineMsg(OfflineChatData),
+    // client确认收到离线数据
+    OfflineAck(OfflineAckData),
+    // 送达、已读回执
+    Receipt(ReceiptData),
+    // 分页查询历史消息
+    HistoryReq(HistoryReqData),
+    HistoryResp(BizResult<HistoryRespData>),
+    // 同步会话中序号大于指定序号的消息
+    SyncSince(SyncSinceData),
+    SyncResp(BizResult<SyncRespData>),
+    // 修改、撤回消息
+    EditMessage(EditMessageData),
+    RecallMessage(RecallMessageData),
+    // 修改、撤回的结果，为修改之后的消息
+    UpdateResp(BizResult<ChatData>),
+    // 推送给会话参与者的修改之后的消息
+    Updated(ChatData),
+    // 管理员查看消息的修改历史
+    EditHistoryReq(EditHistoryReqData),
+    EditHistoryResp(BizResult<EditHistoryRespData>),
+    // 添加、删除表情回应，结果为UpdateResp，并以Updated推送给会话参与者
+    Reaction(ReactionReqData),
+    // 查询回复话题
+    ThreadReq(ThreadReqData),
+    ThreadResp(BizResult<ThreadRespData>),
+    // 只转发不保存的临时事件，例如正在输入
+    Event(EventData),
+    // 修改会话的阅后即焚计时器，结果为计时器修改的系统消息，并以Msg推送给会话参与者
+    DisappearTimer(DisappearTimerReqData),
+    DisappearTimerResp(BizResult<ChatData>),
+    // 定时发送的消息，创建、修改、取消的结果都为ScheduleResp
+    Schedule(ScheduleReqData),
+    ScheduleEdit(ScheduleEditData),
+    ScheduleCancel(ScheduleCancelData),
+    ScheduleResp(BizResult<ScheduledData>),
+    // 查询当前账户未发送的定时消息
+    ScheduleListReq,
+    ScheduleListResp(BizResult<Vec<ScheduledData>>),
+    // 查询会话的标题、话题、头像和置顶消息
+    MetadataReq(MetadataReqData),
+    MetadataResp(BizResult<ConversationMetadata>),
+    // 修改会话的元数据，结果为修改的系统消息，并以Msg推送给会话参与者
+    UpdateMetadata(UpdateMetadataData),
+    UpdateMetadataResp(BizResult<ChatData>),
+    // 把消息转发到其它会话，结果为转发之后的消息，并以Msg推送给目标会话的参与者
+    Forward(ForwardReqData),
+    ForwardResp(BizResult<Vec<ChatData>>),
+    // 在当前账户参与的会话中搜索消息
+    SearchReq(SearchReqData),
+    SearchResp(BizResult<SearchRespData>),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SearchReqData {
+    // 搜索的关键词，多个关键词以空格分隔. 为空时只按过滤条件查询
+    pub text: String,
+    // 以下为None时不过滤
+    pub conversation: Option<String>,
+    pub from_account: Option<String>,
+    // 发送时间的范围，utc毫秒时间戳，包括start_time，不包括end_time
+    pub start_time: Option<i64>,
+    pub end_time: Option<i64>,
+    pub content_type: Option<ChatContentTypeEnum>,
+    // 跳过前面的结果，用于翻页
+    pub offset: u32,
+    // 为0时使用默认数量
+    pub limit: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SearchRespData {
+    pub offset: u32,
+    // 按发送时间倒序
+    pub messages: Vec<ChatData>,
+    pub has_more: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ForwardReqData {
+    // 按顺序转发
+    pub msg_ids: Vec<String>,
+    // 目标会话的id
+    pub conversations: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MetadataReqData {
+    pub conversation: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UpdateMetadataData {
+    pub conversation: String,
+    pub change: MetadataChangeEnum,
+}
+
+// 对会话元数据的一次修改
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum MetadataChangeEnum {
+    // None为清除
+    Title(Option<String>),
+    Topic(Option<String>),
+    // 已上传的头像文件的url
+    Avatar(Option<String>),
+    // 置顶、取消置顶会话中的消息
+    Pin(String),
+    Unpin(String),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConversationMetadata {
+    pub conversation: String,
+    pub title: Option<String>,
+    pub topic: Option<String>,
+    pub avatar: Option<String>,
+    // 按置顶的顺序
+    pub pinned_msg_ids: Vec<String>,
+    // 阅后即焚的计时器，秒
+    pub disappear_seconds: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScheduleReqData {
+    // 与直接发送的消息相同，msg_id不使用
+    pub data: ChatData,
+    // 发送时间，utc毫秒时间戳
+    pub send_time: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScheduleEditData {
+    pub schedule_id: String,
+    // None为不修改
+    pub contents: Option<Vec<ChatContent>>,
+    pub send_time: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScheduleCancelData {
+    pub schedule_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScheduledData {
+    // server分配的id，发送之后作为消息id
+    pub schedule_id: String,
+    pub data: ChatData,
+    // 发送时间，utc毫秒时间戳
+    pub send_time: i64,
+    pub create_time: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DisappearTimerReqData {
+    pub conversation: String,
+    // 消息发送之后多长时间删除，秒. None为关闭
+    pub expire_seconds: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EventData {
+    pub conversation: String,
+    // server填写
+    pub from_account: String,
+    // 事件类型，client之间约定，server不解析
+    pub kind: String,
+    pub payload: Vec<u8>,
+    // 有效时间，server会限制上限
+    pub ttl_millis: i64,
+    // server转发的时间，utc毫秒时间戳
+    pub time: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReactionReqData {
+    pub msg_id: String,
+    pub emoji: String,
+    // true为添加，false为删除
+    pub add: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ThreadReqData {
+    // 话题中的任意一条消息
+    pub msg_id: String,
+    // 只返回序号大于after_seq的回复，第一次查询填0
+    pub after_seq: i64,
+    // 最多返回的回复数量，server会限制上限
+    pub limit: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ThreadRespData {
+    // 话题的第一条消息
+    pub root: ChatData,
+    // 按序号正序的回复，包括回复的回复
+    pub replies: Vec<ChatData>,
+    // 还有更多回复时从最后一条回复的序号继续查询
+    pub has_more: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EditMessageData {
+    pub msg_id: String,
+    // 修改之后的内容
+    pub contents: Vec<ChatContent>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RecallMessageData {
+    pub msg_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EditHistoryReqData {
+    pub msg_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EditHistoryRespData {
+    pub msg_id: String,
+    // 按修改的顺序，每个元素为一次修改、撤回之前的内容
+    pub versions: Vec<MessageVersionData>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MessageVersionData {
+    // 进行修改、撤回的账户
+    pub editor: String,
+    // 是否为撤回
+    pub recalled: bool,
+    pub contents: Vec<ChatContent>,
+    // 修改、撤回的时间，utc毫秒时间戳
+    pub time: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SyncSinceData {
+    pub conversation: String,
+    // 本地已有的最大序号，没有消息时为0
+    pub seq: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SyncRespData {
+    pub conversation: String,
+    // 按序号正序
+    pub messages: Vec<ChatData>,
+    // 消息较多时分批返回，client需要从最后一条消息的序号继续同步
+    pub has_more: bool,
+}
+
+// 历史消息的分页位置，消息id为server分配的id
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum HistoryCursor {
+    // 最新的消息
+    Latest,
+    // 指定消息之前(更早)的消息
+    Before(String),
+    // 指定消息之后(更新)的消息
+    After(String),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HistoryReqData {
+    // 查询两人会话时填写
+    pub peer_account: String,
+    // 查询群组会话时填写，此时忽略peer_account
+    pub group_id: Option<i32>,
+    pub cursor: HistoryCursor,
+    // 最多返回的消息数量，server会限制上限
+    pub limit: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HistoryRespData {
+    pub peer_account: String,
+    pub group_id: Option<i32>,
+    pub cursor: HistoryCursor,
+    // 按时间正序
+    pub messages: Vec<ChatData>,
+    // 沿查询方向是否还有更多消息
+    pub has_more: bool,
+}
+
+// 消息的状态: server接收 -> 送达接收方设备 -> 接收方已读
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MessageState {
+    Sent,
+    Delivered,
+    Read,
+}
+
+// server收到消息后回复给发送方
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChatAckData {
+    // server分配的消息id
+    pub msg_id: String,
+    // 发送时client填写的临时id，用于对应发送的消息
+    pub client_msg_id: String,
+    pub to_account: String,
+    // 会话内的序号
+    pub seq: i64,
+    // 消息被server接收的时间，utc毫秒时间戳
+    pub time: i64,
+    // 接收方不在线，消息已保存，等接收方登录后投递
+    pub offline: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+pub struct OfflineChatData {
+    // 离线数据的id，确认时带上
+    pub offline_id: i32,
+    // 消息或者回执
+    pub data: Box<ChatDataEnum>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReceiptData {
+    // 回执的发送方，即消息的接收方
+    pub from_account: String,
+    // 消息的发送方
+    pub to_account: String,
+    pub msg_ids: Vec<String>,
+    // 只能是 Delivered 或 Read
+    pub state: MessageState,
+    // server填写，utc毫秒时间戳
+    pub time: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OfflineAckData {
+    pub ids: Vec<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ChatData {
+    // 全局唯一的消息id，由server分配. client发送时填写临时id
+    pub msg_id: String,
     pub from_account: String,
+    // 发送到群组时为空
     pub to_account: String,
+    // 发送到群组时填写，server转发给所有成员
+    pub group_id: Option<i32>,
     pub contents: Vec<ChatContent>,
-    // todo:暂时用u32来表示时间错
-    pub time: u32,
+    // server分配的会话内序号，从1开始递增. client发送时填0
+    pub seq: i64,
+    // server接收消息的时间，utc毫秒时间戳
+    pub time: i64,
+    // 最后一次修改的时间，没有修改过时为None
+    pub edit_time: Option<i64>,
+    // 撤回消息的账户，撤回之后contents为空，只保留消息的位置
+    pub recall_by: Option<String>,
+    // 回复的消息. client发送时只需要填写msg_id，其它字段由server填写
+    pub reply_to: Option<ReplyData>,
+    // 按表情聚合的回应，由server维护
+    pub reactions: Vec<ReactionData>,
+    // 阅后即焚的消息过期的时间，utc毫秒时间戳. 由server根据会话的计时器填写，过期之后删除
+    pub expire_time: Option<i64>,
+    // 转发的消息的来源，由server填写
+    pub forward_from: Option<ForwardData>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ForwardData {
+    // 被转发的消息，再次转发时保留最初的来源
+    pub msg_id: String,
+    pub from_account: String,
+    pub time: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReplyData {
+    pub msg_id: String,
+    // 被回复消息的发送方
+    pub from_account: String,
+    // 被回复消息在回复时的内容摘要
+    pub snippet: String,
+    // 话题第一条消息的id，回复的回复属于同一个话题
+    pub thread_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReactionData {
+    pub emoji: String,
+    // 按回应的顺序
+    pub accounts: Vec<String>,
+}
+
+impl ReactionData {
+    pub fn count(&self) -> usize {
+        self.accounts.len()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum ChatContent {
     Text(ChatTextContent),
     File(ChatFileContent),
+    // 图片、语音、位置、markdown，见chat_content
+    Rich(ChatRichContent),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ChatTextContent {
     pub text: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ChatFileContent {
     pub file_name: String,
     pub url: Option<String>,
@@ -58,11 +1500,25 @@ pub struct ChatFileContent {
 
 impl ChatModule for DefaultClientChatModule {
     fn sendMsg(&self, from_account: String, to_account: String, msg: String) -> Result<(), Error> {
-        todo!()
-        // 1. 根据 to_account检查是否有对应的socket链接
-        // 2. 如果有，则使用该链接发送消息
+        let data = ChatData {
+            msg_id: create_msg_id(),
+            from_account,
+            to_account,
+            group_id: None,
+            contents: vec![ChatContent::Text(ChatTextContent { text: msg })],
+            seq: 0,
+            time: now_millis(),
+            edit_time: None,
+            recall_by: None,
+            reply_to: None,
+            reactions: vec![],
+            expire_time: None,
+            forward_from: None,
+        };
+        self.send_chat_data(data)
     }
 
+    // 文件先分片上传，上传完成之后再发送消息
     fn sendFile(
         &self,
         from_account: String,
@@ -70,10 +1526,191 @@ impl ChatModule for DefaultClientChatModule {
         file_nmae: String,
         file_path: String,
     ) -> Result<(), Error> {
-        todo!()
+        let data = ChatData {
+            msg_id: create_msg_id(),
+            from_account,
+            to_account,
+            group_id: None,
+            contents: vec![ChatContent::File(ChatFileContent {
+                file_name: file_nmae.clone(),
+                url: None,
+                data: None,
+            })],
+            seq: 0,
+            time: now_millis(),
+            edit_time: None,
+            recall_by: None,
+            reply_to: None,
+            reactions: vec![],
+            expire_time: None,
+            forward_from: None,
+        };
+        self.file_module().upload(file_path, file_nmae, Some(data))
     }
 
+    // 只查询本地缓存，缓存中没有的消息使用request_history向server查询
     fn find_chat_history(&self, account_a: String, account_b: String) -> Option<Vec<ChatData>> {
-        todo!()
+        let conversation = get_conversation_id(&account_a, &account_b);
+        self.cache
+            .load(&conversation)
+            .map_err(|e| error!("load chat history of {} fail: {}", conversation, e))
+            .ok()
+    }
+}
+
+impl ChatData {
+    // 消息所属的会话
+    pub fn conversation_id(&self) -> String {
+        match self.group_id {
+            Some(t) => get_group_conversation_id(t),
+            None => get_conversation_id(&self.from_account, &self.to_account),
+        }
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expire_time.map(|t| t <= now).unwrap_or(false)
+    }
+}
+
+// 回复时引用的消息摘要: 文本内容和文件名，超过长度时截断
+pub fn create_snippet(contents: &[ChatContent]) -> String {
+    let text = contents
+        .iter()
+        .map(|t| match t {
+            ChatContent::Text(t) => t.text.clone(),
+            ChatContent::File(t) => format!("[file] {}", t.file_name),
+            ChatContent::Rich(t) => t.fallback.clone(),
+        })
+        .collect::<Vec<String>>()
+        .join(" ");
+
+    match text.char_indices().nth(QUOTE_SNIPPET_MAX_LEN) {
+        Some((i, _)) => format!("{}...", &text[..i]),
+        None => text,
+    }
+}
+
+// 群组的会话id. 账户名只能包含字母、数字和下划线，不会与两人会话的id冲突
+pub fn get_group_conversation_id(group_id: i32) -> String {
+    format!("{}{}", GROUP_CONVERSATION_PREFIX, group_id)
+}
+
+// 群组会话的群组id，不是群组会话时返回None
+pub fn get_group_id(conversation: &str) -> Option<i32> {
+    conversation
+        .strip_prefix(GROUP_CONVERSATION_PREFIX)
+        .and_then(|t| t.parse().ok())
+}
+
+// 两个账户之间的会话id，与参数的顺序无关
+pub fn get_conversation_id(account_a: &str, account_b: &str) -> String {
+    if account_a <= account_b {
+        format!("{}:{}", account_a, account_b)
+    } else {
+        format!("{}:{}", account_b, account_a)
+    }
+}
+
+// 生成全局唯一的消息id: 毫秒时间戳 + 随机数，按生成时间排序
+pub fn create_msg_id() -> String {
+    let random: [u8; 10] = rand::thread_rng().gen();
+    format!("{:012x}{}", now_millis(), hex::encode(random))
+}
+
+// 账户参与的两人会话中另一方的账户，不是该账户参与的会话时返回None
+pub fn get_conversation_peer(conversation: &str, account: &str) -> Option<String> {
+    let peer = conversation
+        .strip_prefix(&format!("{}:", account))
+        .or(conversation.strip_suffix(&format!(":{}", account)))?;
+
+    // 账户名中可能包含分隔符，需要按规则重新生成后比较
+    match get_conversation_id(account, peer) == conversation {
+        true => Some(peer.to_string()),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversation_ids() {
+        assert_eq!(get_conversation_id("bob", "alice"), "alice:bob");
+        assert_eq!(get_conversation_id("alice", "bob"), "alice:bob");
+        assert_eq!(
+            get_conversation_peer("alice:bob", "alice"),
+            Some("bob".to_string())
+        );
+        assert_eq!(
+            get_conversation_peer("alice:bob", "bob"),
+            Some("alice".to_string())
+        );
+        assert_eq!(get_conversation_peer("alice:bob", "carol"), None);
+        // 账户名中包含分隔符
+        assert_eq!(get_conversation_peer("a:b:c", "a:b"), Some("c".to_string()));
+
+        assert_eq!(
+            get_group_conversation_id(7),
+            format!("{}7", GROUP_CONVERSATION_PREFIX)
+        );
+        assert_eq!(get_group_id(&get_group_conversation_id(7)), Some(7));
+        assert_eq!(get_group_id("alice:bob"), None);
+    }
+
+    #[test]
+    fn expire_at_expire_time() {
+        let mut data = ChatData {
+            msg_id: create_msg_id(),
+            from_account: "alice".to_string(),
+            to_account: "bob".to_string(),
+            group_id: None,
+            contents: vec![],
+            seq: 1,
+            time: 0,
+            edit_time: None,
+            recall_by: None,
+            reply_to: None,
+            reactions: vec![],
+            expire_time: None,
+            forward_from: None,
+        };
+        assert!(!data.is_expired(i64::MAX));
+        data.expire_time = Some(100);
+        assert!(!data.is_expired(99));
+        assert!(data.is_expired(100));
+    }
+
+    #[test]
+    fn msg_ids_are_unique_and_ordered() {
+        let first = create_msg_id();
+        let second = create_msg_id();
+        assert_ne!(first, second);
+        assert_eq!(first.len(), 32);
+        assert!(first[..12] <= second[..12]);
+    }
+
+    #[test]
+    fn snippet_of_contents() {
+        let contents = [
+            ChatContent::Text(ChatTextContent {
+                text: "see".to_string(),
+            }),
+            ChatContent::File(ChatFileContent {
+                file_name: "a.txt".to_string(),
+                url: None,
+                data: None,
+            }),
+        ];
+        assert_eq!(create_snippet(&contents), "see [file] a.txt");
+
+        // 按字符截断
+        let contents = [ChatContent::Text(ChatTextContent {
+            text: "长".repeat(QUOTE_SNIPPET_MAX_LEN + 1),
+        })];
+        assert_eq!(
+            create_snippet(&contents),
+            format!("{}...", "长".repeat(QUOTE_SNIPPET_MAX_LEN))
+        );
     }
 }
@@ -10,6 +10,9 @@ use std::net::{SocketAddr, TcpStream};
 
 static MAX_DATA_LEN: u64 = u32::MAX as u64;
 
+// 当前使用的协议版本号
+pub static PROTOCOL_VERSION: u8 = 1;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Protocol {
     // -----------------    head 区   ---------------
@@ -37,11 +40,15 @@ pub enum ProtocolFieldNameEnum {
     data,
 }
 
+// note: 新的command只能追加在末尾，否则会改变已有command的data_type
 #[derive(Debug, Clone, EnumIndex, IndexEnum, Hash, Serialize, Deserialize)]
 pub enum ChatCommand {
     Login,
     Chat,
     P2p,
+    Account,
+    Group,
+    File,
 }
 
 impl PartialEq<Self> for ChatCommand {
@@ -58,8 +65,19 @@ impl ChatCommand {
         vec![v]
     }
 
-    pub fn to_self(b: u8) -> Self {
-        ChatCommand::index_enum(b as usize).unwrap()
+    // data_type来自对端，不存在的command返回None
+    pub fn to_self(b: u8) -> Option<Self> {
+        ChatCommand::index_enum(b as usize)
+    }
+
+    // 所有command，按data_type的顺序
+    pub fn all() -> Vec<ChatCommand> {
+        (0..).map_while(ChatCommand::index_enum).collect()
+    }
+
+    // command的名称，用于配置和展示，例如api key的scope
+    pub fn name(&self) -> String {
+        format!("{:?}", self)
     }
 }
 
@@ -73,6 +91,16 @@ impl Protocol {
         v
     }
 
+    // 用业务数据创建一个填充完整的Protocol
+    pub fn create_by_data(command: ChatCommand, data: Vec<u8>) -> Self {
+        Protocol {
+            version: Some(vec![PROTOCOL_VERSION]),
+            data_type: Some(command.to_data_type()),
+            data_len: Some(calculate_len_by_data(&data)),
+            data: Some(data),
+        }
+    }
+
     pub fn create_new() -> Self {
         Protocol {
             version: None,
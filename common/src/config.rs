@@ -59,3 +59,134 @@ impl ClientDefaultConfig {
         ClientDefaultConfig { account_save_path }
     }
 }
+
+// server端登录方式的配置
+#[derive(Debug, Clone)]
+pub struct ServerLoginConfig {
+    // 是否允许client提交明文密码登录(旧的登录方式). client都迁移到scram登录之后应关闭
+    pub allow_plain_pwd: bool,
+}
+
+impl ServerLoginConfig {
+    pub fn init_from_env() -> Self {
+        dotenvy::dotenv().ok();
+
+        ServerLoginConfig {
+            allow_plain_pwd: parse_env("LOGIN_ALLOW_PLAIN_PWD"),
+        }
+    }
+}
+
+// 登录防暴力破解的配置
+#[derive(Debug, Clone)]
+pub struct LoginGuardConfig {
+    // 连续失败多少次后锁定账户
+    pub max_fail_count: i32,
+    // 同一个ip连续失败多少次后锁定该ip
+    pub ip_max_fail_count: i32,
+    // 账户锁定时长，秒
+    pub lock_seconds: i64,
+    // 失败后需要等待的基础时长，每多失败一次翻倍，毫秒
+    pub base_delay_millis: i64,
+    // 等待时长的上限，毫秒
+    pub max_delay_millis: i64,
+}
+
+impl LoginGuardConfig {
+    pub fn init_from_env() -> Self {
+        dotenvy::dotenv().ok();
+
+        LoginGuardConfig {
+            max_fail_count: parse_env("LOGIN_MAX_FAIL_COUNT"),
+            ip_max_fail_count: parse_env("LOGIN_IP_MAX_FAIL_COUNT"),
+            lock_seconds: parse_env("LOGIN_LOCK_SECONDS"),
+            base_delay_millis: parse_env("LOGIN_BASE_DELAY_MILLIS"),
+            max_delay_millis: parse_env("LOGIN_MAX_DELAY_MILLIS"),
+        }
+    }
+
+    // 第fail_count次失败之后，下次尝试前需要等待的毫秒数
+    pub fn delay_millis(&self, fail_count: i32) -> i64 {
+        if fail_count <= 0 {
+            return 0;
+        }
+        let shift = (fail_count - 1).min(30) as u32;
+        self.base_delay_millis
+            .saturating_mul(1i64 << shift)
+            .min(self.max_delay_millis)
+    }
+}
+
+// 聊天消息的配置
+#[derive(Debug, Clone)]
+pub struct ChatConfig {
+    // 发送之后多长时间内可以修改，秒
+    pub edit_window_seconds: i64,
+    // 发送之后多长时间内可以撤回，秒. 管理员撤回不受限制
+    pub recall_window_seconds: i64,
+    // 删除过期的阅后即焚消息的间隔，秒
+    pub disappear_sweep_seconds: u64,
+    // 消息全文索引保存的目录
+    pub search_index_dir: String,
+    // 消息全文索引提交的间隔，毫秒. 提交之后新消息才能搜索到
+    pub search_commit_millis: u64,
+}
+
+impl ChatConfig {
+    pub fn init_from_env() -> Self {
+        dotenvy::dotenv().ok();
+
+        ChatConfig {
+            edit_window_seconds: parse_env("CHAT_EDIT_WINDOW_SECONDS"),
+            recall_window_seconds: parse_env("CHAT_RECALL_WINDOW_SECONDS"),
+            disappear_sweep_seconds: parse_env("CHAT_DISAPPEAR_SWEEP_SECONDS"),
+            search_index_dir: parse_env("CHAT_SEARCH_INDEX_DIR"),
+            search_commit_millis: parse_env("CHAT_SEARCH_COMMIT_MILLIS"),
+        }
+    }
+}
+
+// 文件上传的配置
+#[derive(Debug, Clone)]
+pub struct FileConfig {
+    // 上传中的文件保存的目录
+    pub upload_dir: String,
+    // 上传完成并校验成功的文件保存的目录
+    pub store_dir: String,
+    // 上传文件的大小上限，字节
+    pub max_file_size: u64,
+    // 每个账户同时进行的上传数量上限
+    pub max_uploads_per_account: usize,
+    // 超过这个时间没有继续的上传会被删除，秒
+    pub upload_expire_seconds: i64,
+    // 签名下载链接的密钥，server和userinfo-web需要相同
+    pub url_secret: String,
+    // 下载链接的有效期，秒
+    pub url_expire_seconds: i64,
+    // userinfo-web对外的地址，例如 http://127.0.0.1:8080
+    pub download_base_url: String,
+}
+
+impl FileConfig {
+    pub fn init_from_env() -> Self {
+        dotenvy::dotenv().ok();
+
+        FileConfig {
+            upload_dir: parse_env("FILE_UPLOAD_DIR"),
+            store_dir: parse_env("FILE_STORE_DIR"),
+            max_file_size: parse_env("FILE_MAX_SIZE"),
+            max_uploads_per_account: parse_env("FILE_MAX_UPLOADS_PER_ACCOUNT"),
+            upload_expire_seconds: parse_env("FILE_UPLOAD_EXPIRE_SECONDS"),
+            url_secret: parse_env("FILE_URL_SECRET"),
+            url_expire_seconds: parse_env("FILE_URL_EXPIRE_SECONDS"),
+            download_base_url: parse_env("FILE_DOWNLOAD_BASE_URL"),
+        }
+    }
+}
+
+fn parse_env<T: std::str::FromStr>(key: &str) -> T {
+    env::var(key)
+        .unwrap_or_else(|_| panic!("{key} is not set in .env file"))
+        .parse()
+        .unwrap_or_else(|_| panic!("{key} in .env file is invalid"))
+}
@@ -0,0 +1,612 @@
+use crate::base::ConnectionRegistry;
+use crate::chat_module::ChatData;
+use crate::chat_protocol::{ChatCommand, Protocol};
+use crate::login_module::BizResult;
+use crate::protocol_factory::HandlerProtocolData;
+use hmac::{Hmac, Mac};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::Error;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+// client每次发送的分片大小
+pub const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+// 上传失败后从server确认的位置重新开始的次数上限
+const UPLOAD_MAX_RETRY: u32 = 3;
+
+// 上传完成的文件的url前缀，后面为文件的sha256
+const FILE_URL_PREFIX: &str = "sha256:";
+
+pub struct DefaultFileHandler {
+    server: Option<Box<dyn ServerFileModule + Send>>,
+
+    client: Option<Box<dyn ClientFileModule + Send>>,
+}
+
+impl DefaultFileHandler {
+    pub fn new(
+        server: Option<Box<dyn ServerFileModule + Send>>,
+        client: Option<Box<dyn ClientFileModule + Send>>,
+    ) -> Self {
+        DefaultFileHandler { server, client }
+    }
+
+    fn server_module(&mut self) -> &mut Box<dyn ServerFileModule + Send> {
+        if self.server.is_none() {
+            panic!("ServerFileModule is None!");
+        }
+        self.server.as_mut().unwrap()
+    }
+
+    fn client_module(&mut self) -> &mut Box<dyn ClientFileModule + Send> {
+        if self.client.is_none() {
+            panic!("ClientFileModule is None!");
+        }
+        self.client.as_mut().unwrap()
+    }
+
+    // 无法解析的数据: server端回复失败的确认，不知道是哪个文件所以sha256为空. client端忽略
+    fn reject(&self, address: SocketAddr, msg: String) -> Option<Vec<u8>> {
+        if self.server.is_none() {
+            warn!("ignore file data from {}: {}", address, msg);
+            return None;
+        }
+
+        let resp_data = BizFileData {
+            data: FileDataEnum::UploadResp(UploadRespData {
+                sha256: String::new(),
+                result: to_biz_result(Err(msg), address),
+            }),
+        };
+        Some(bincode::serialize(&resp_data).unwrap())
+    }
+}
+
+impl HandlerProtocolData for DefaultFileHandler {
+    fn handle(&mut self, address: SocketAddr, data: &Vec<u8>) -> Option<Vec<u8>> {
+        let biz: BizFileData = match bincode::deserialize(data) {
+            Ok(t) => t,
+            Err(e) => return self.reject(address, format!("invalid file data: {e}")),
+        };
+
+        // server端处理请求
+        let (sha256, resp) = match biz.data {
+            FileDataEnum::StartUploadReq(req) => (
+                req.sha256.clone(),
+                self.server_module().handle_start_upload(req, address),
+            ),
+
+            FileDataEnum::ChunkReq(req) => (
+                req.sha256.clone(),
+                self.server_module().handle_chunk(req, address),
+            ),
+
+            FileDataEnum::DownloadUrlReq(req) => {
+                let resp = self.server_module().handle_download_url(req, address);
+                let resp_data = BizFileData {
+                    data: FileDataEnum::DownloadUrlResp(to_biz_result(resp, address)),
+                };
+                return Some(bincode::serialize(&resp_data).unwrap());
+            }
+
+            // client端处理server的确认，继续发送下一个分片或者重新开始
+            FileDataEnum::UploadResp(resp) => {
+                return self.client_module().handle_upload_resp(resp).map(|t| {
+                    let req = BizFileData { data: t };
+                    bincode::serialize(&req).unwrap()
+                });
+            }
+
+            FileDataEnum::DownloadUrlResp(resp) => {
+                self.client_module().handle_download_url_resp(resp);
+                return None;
+            }
+        };
+
+        let resp_data = BizFileData {
+            data: FileDataEnum::UploadResp(UploadRespData {
+                sha256,
+                result: to_biz_result(resp, address),
+            }),
+        };
+        Some(bincode::serialize(&resp_data).unwrap())
+    }
+}
+
+fn to_biz_result<T>(resp: Result<T, String>, address: SocketAddr) -> BizResult<T> {
+    match resp {
+        Ok(t) => BizResult {
+            is_success: true,
+            msg: None,
+            data: Some(t),
+        },
+        Err(e) => {
+            warn!("file req from {} fail: {}", address, e);
+            BizResult {
+                is_success: false,
+                msg: Some(e),
+                data: None,
+            }
+        }
+    }
+}
+
+/**
+*  server端接收分片上传的文件. 已确认的数据保存在磁盘上，
+*  连接断开、server重启之后client重新开始上传时从已确认的位置继续
+**/
+pub trait ServerFileModule {
+    // 开始或继续上传，返回已经确认的位置
+    fn handle_start_upload(
+        &mut self,
+        req: StartUploadReqData,
+        address: SocketAddr,
+    ) -> Result<UploadStateData, String>;
+
+    // 分片的位置必须是已经确认的位置，收到全部数据后校验sha256
+    fn handle_chunk(
+        &mut self,
+        req: FileChunkData,
+        address: SocketAddr,
+    ) -> Result<UploadStateData, String>;
+
+    // 上传者或者附件所在会话的参与者可以获取有时效的下载链接
+    fn handle_download_url(
+        &mut self,
+        req: DownloadUrlReqData,
+        address: SocketAddr,
+    ) -> Result<DownloadUrlData, String>;
+}
+
+/**
+ *  client端处理上传确认的模块trait
+ **/
+pub trait ClientFileModule {
+    // 返回需要继续发送给server的请求
+    fn handle_upload_resp(&mut self, resp: UploadRespData) -> Option<FileDataEnum>;
+
+    fn handle_download_url_resp(&mut self, resp: BizResult<DownloadUrlData>);
+}
+
+// 进行中的上传，完成之后把url填写到message的文件内容中再发送
+#[derive(Debug, Clone)]
+pub struct UploadTask {
+    pub file_path: String,
+    pub file_name: String,
+    pub size: u64,
+    pub sha256: String,
+    pub message: Option<ChatData>,
+    // 已经重新开始的次数
+    pub retry: u32,
+}
+
+impl UploadTask {
+    // 读取从offset开始的一个分片
+    pub fn read_chunk(&self, offset: u64) -> Result<Vec<u8>, String> {
+        let mut file = File::open(&self.file_path).map_err(|e| e.to_string())?;
+        file.seek(SeekFrom::Start(offset))
+            .map_err(|e| e.to_string())?;
+
+        let len = (self.size - offset).min(FILE_CHUNK_SIZE as u64) as usize;
+        let mut buf = vec![0; len];
+        file.read_exact(&mut buf).map_err(|e| e.to_string())?;
+        Ok(buf)
+    }
+}
+
+// 进行中的上传，key为文件的sha256. 发送上传请求和处理确认的module共用
+pub type UploadTasks = Arc<Mutex<HashMap<String, UploadTask>>>;
+
+/**
+ *  client端上传文件. 需要在已登录的连接上发送
+ **/
+#[derive(Clone)]
+pub struct DefaultClientFileModule {
+    registry: ConnectionRegistry,
+    server_addr: SocketAddr,
+    uploads: UploadTasks,
+}
+
+impl DefaultClientFileModule {
+    pub fn new(
+        registry: ConnectionRegistry,
+        server_addr: SocketAddr,
+        uploads: UploadTasks,
+    ) -> Self {
+        DefaultClientFileModule {
+            registry,
+            server_addr,
+            uploads,
+        }
+    }
+
+    // 上传文件，完成之后发送message. 同一个文件正在上传时覆盖之前的任务
+    pub fn upload(
+        &self,
+        file_path: String,
+        file_name: String,
+        message: Option<ChatData>,
+    ) -> Result<(), Error> {
+        let size = std::fs::metadata(&file_path)
+            .map_err(|e| {
+                error!("read file {} fail: {}", file_path, e);
+                Error
+            })?
+            .len();
+        let sha256 = file_sha256(Path::new(&file_path)).map_err(|e| {
+            error!("hash file {} fail: {}", file_path, e);
+            Error
+        })?;
+
+        let task = UploadTask {
+            file_path,
+            file_name,
+            size,
+            sha256: sha256.clone(),
+            message,
+            retry: 0,
+        };
+        let req = create_start_upload_req(&task);
+        self.uploads.lock().unwrap().insert(sha256, task);
+        self.send_req(req)
+    }
+
+    // 重新连接登录之后继续所有未完成的上传
+    pub fn resume_all(&self) -> Result<(), Error> {
+        let reqs: Vec<FileDataEnum> = self
+            .uploads
+            .lock()
+            .unwrap()
+            .values()
+            .map(create_start_upload_req)
+            .collect();
+
+        for req in reqs {
+            self.send_req(req)?;
+        }
+        Ok(())
+    }
+
+    // 根据server的确认处理上传任务: 继续发送下一个分片，失败时从server确认的位置重新开始.
+    // 上传完成时返回任务和文件的url
+    pub fn next_upload_req(
+        &self,
+        resp: UploadRespData,
+    ) -> (Option<FileDataEnum>, Option<(UploadTask, String)>) {
+        let mut uploads = self.uploads.lock().unwrap();
+        let task = match uploads.get_mut(&resp.sha256) {
+            Some(t) => t,
+            None => return (None, None),
+        };
+
+        if !resp.result.is_success {
+            task.retry += 1;
+            if task.retry > UPLOAD_MAX_RETRY {
+                warn!(
+                    "upload {} fail: {}",
+                    task.file_path,
+                    resp.result.msg.unwrap_or_default()
+                );
+                uploads.remove(&resp.sha256);
+                return (None, None);
+            }
+            return (Some(create_start_upload_req(task)), None);
+        }
+
+        task.retry = 0;
+        let state = resp.result.data.unwrap();
+        if let Some(url) = state.url {
+            let task = uploads.remove(&resp.sha256).unwrap();
+            return (None, Some((task, url)));
+        }
+
+        match task.read_chunk(state.offset) {
+            Ok(data) => (
+                Some(FileDataEnum::ChunkReq(FileChunkData {
+                    sha256: task.sha256.clone(),
+                    offset: state.offset,
+                    data,
+                })),
+                None,
+            ),
+            Err(e) => {
+                warn!("read file {} fail: {}", task.file_path, e);
+                uploads.remove(&resp.sha256);
+                (None, None)
+            }
+        }
+    }
+
+    // 获取附件的下载链接，url为消息中文件的url
+    pub fn request_download_url(&self, url: String) -> Result<(), Error> {
+        self.send_req(FileDataEnum::DownloadUrlReq(DownloadUrlReqData { url }))
+    }
+
+    fn send_req(&self, data: FileDataEnum) -> Result<(), Error> {
+        let biz = BizFileData { data };
+
+        self.registry
+            .send_to(&self.server_addr, &biz.to_protocol_bytes())
+            .map_err(|e| {
+                error!("send file request fail: {}", e);
+                Error
+            })
+    }
+}
+
+fn create_start_upload_req(task: &UploadTask) -> FileDataEnum {
+    FileDataEnum::StartUploadReq(StartUploadReqData {
+        file_name: task.file_name.clone(),
+        size: task.size,
+        sha256: task.sha256.clone(),
+    })
+}
+
+// 文件内容的sha256，小写的16进制字符串
+pub fn file_sha256(path: &Path) -> Result<String, String> {
+    let mut file = File::open(path).map_err(|e| e.to_string())?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; FILE_CHUNK_SIZE];
+    loop {
+        let len = file.read(&mut buf).map_err(|e| e.to_string())?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+// 上传完成的文件的url
+pub fn get_file_url(sha256: &str) -> String {
+    format!("{}{}", FILE_URL_PREFIX, sha256)
+}
+
+// url对应文件的sha256，不是上传的文件时返回None
+pub fn get_file_sha256(url: &str) -> Option<&str> {
+    url.strip_prefix(FILE_URL_PREFIX)
+}
+
+// 下载链接的签名: HMAC-SHA256(secret, "sha256:account:expire_time")的16进制字符串
+pub fn sign_download(secret: &str, sha256: &str, account: &str, expire_time: i64) -> String {
+    let mac = create_download_mac(secret, sha256, account, expire_time);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// 使用固定时间的比较校验签名
+pub fn verify_download_sign(
+    secret: &str,
+    sha256: &str,
+    account: &str,
+    expire_time: i64,
+    sign: &str,
+) -> bool {
+    let sign = match hex::decode(sign) {
+        Ok(t) => t,
+        Err(_) => return false,
+    };
+    create_download_mac(secret, sha256, account, expire_time)
+        .verify_slice(&sign)
+        .is_ok()
+}
+
+fn create_download_mac(
+    secret: &str,
+    sha256: &str,
+    account: &str,
+    expire_time: i64,
+) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(format!("{}:{}:{}", sha256, account, expire_time).as_bytes());
+    mac
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BizFileData {
+    pub data: FileDataEnum,
+}
+
+impl BizFileData {
+    // 打包成可以直接写入stream的字节
+    pub fn to_protocol_bytes(&self) -> Vec<u8> {
+        let data = bincode::serialize(self).unwrap();
+        Protocol::create_by_data(ChatCommand::File, data).to_vec()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum FileDataEnum {
+    StartUploadReq(StartUploadReqData),
+    ChunkReq(FileChunkData),
+    // 开始上传和每个分片的确认
+    UploadResp(UploadRespData),
+    // 获取附件的下载链接
+    DownloadUrlReq(DownloadUrlReqData),
+    DownloadUrlResp(BizResult<DownloadUrlData>),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DownloadUrlReqData {
+    // 消息中文件的url
+    pub url: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DownloadUrlData {
+    pub url: String,
+    // userinfo-web提供的下载地址，过期之后需要重新获取
+    pub download_url: String,
+    // utc毫秒时间戳
+    pub expire_time: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StartUploadReqData {
+    pub file_name: String,
+    pub size: u64,
+    // 文件内容的sha256，同一个账户上传同一个文件时从已确认的位置继续
+    pub sha256: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FileChunkData {
+    pub sha256: String,
+    // 分片在文件中的位置
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UploadStateData {
+    // server已经确认的位置，client从这里继续发送
+    pub offset: u64,
+    // 全部上传并且校验成功之后填写
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadRespData {
+    // 失败时client也需要知道是哪个文件
+    pub sha256: String,
+    pub result: BizResult<UploadStateData>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn create_upload_resp(sha256: &str, offset: Option<u64>, url: Option<&str>) -> UploadRespData {
+        UploadRespData {
+            sha256: sha256.to_string(),
+            result: BizResult {
+                is_success: offset.is_some(),
+                msg: offset.is_none().then(|| "offset mismatch".to_string()),
+                data: offset.map(|offset| UploadStateData {
+                    offset,
+                    url: url.map(|t| t.to_string()),
+                }),
+            },
+        }
+    }
+
+    #[test]
+    fn upload_by_chunks_and_resume() {
+        let file_path = env::temp_dir().join(format!("file_module_{}", std::process::id()));
+        let content: Vec<u8> = (0..FILE_CHUNK_SIZE + 10).map(|t| t as u8).collect();
+        fs::write(&file_path, &content).unwrap();
+        let sha256 = file_sha256(&file_path).unwrap();
+        assert_eq!(sha256, hex::encode(Sha256::digest(&content)));
+
+        let uploads: UploadTasks = Default::default();
+        let module = DefaultClientFileModule::new(
+            ConnectionRegistry::default(),
+            "127.0.0.1:9000".parse().unwrap(),
+            uploads.clone(),
+        );
+        let task = UploadTask {
+            file_path: file_path.to_string_lossy().to_string(),
+            file_name: "a.bin".to_string(),
+            size: content.len() as u64,
+            sha256: sha256.clone(),
+            message: None,
+            retry: 0,
+        };
+        uploads.lock().unwrap().insert(sha256.clone(), task);
+
+        // 按server确认的位置发送分片
+        let (req, done) = module.next_upload_req(create_upload_resp(&sha256, Some(0), None));
+        assert!(done.is_none());
+        match req {
+            Some(FileDataEnum::ChunkReq(t)) => assert_eq!(t.data, content[..FILE_CHUNK_SIZE]),
+            _ => panic!("expect chunk"),
+        }
+        let offset = FILE_CHUNK_SIZE as u64;
+        match module
+            .next_upload_req(create_upload_resp(&sha256, Some(offset), None))
+            .0
+        {
+            Some(FileDataEnum::ChunkReq(t)) => {
+                assert_eq!(t.offset, offset);
+                assert_eq!(t.data, content[FILE_CHUNK_SIZE..]);
+            }
+            _ => panic!("expect chunk"),
+        }
+
+        // 失败时重新开始，从server确认的位置继续
+        match module
+            .next_upload_req(create_upload_resp(&sha256, None, None))
+            .0
+        {
+            Some(FileDataEnum::StartUploadReq(t)) => assert_eq!(t.sha256, sha256),
+            _ => panic!("expect start upload"),
+        }
+
+        let url = get_file_url(&sha256);
+        let (req, done) = module.next_upload_req(create_upload_resp(&sha256, Some(0), Some(&url)));
+        assert!(req.is_none());
+        assert_eq!(done.unwrap().1, url);
+        assert!(uploads.lock().unwrap().is_empty());
+        fs::remove_file(&file_path).ok();
+    }
+
+    #[test]
+    fn give_up_after_max_retry() {
+        let uploads: UploadTasks = Default::default();
+        let module = DefaultClientFileModule::new(
+            ConnectionRegistry::default(),
+            "127.0.0.1:9000".parse().unwrap(),
+            uploads.clone(),
+        );
+        let task = UploadTask {
+            file_path: String::new(),
+            file_name: "a.bin".to_string(),
+            size: 1,
+            sha256: "abc".to_string(),
+            message: None,
+            retry: 0,
+        };
+        uploads.lock().unwrap().insert("abc".to_string(), task);
+
+        for _ in 0..UPLOAD_MAX_RETRY {
+            assert!(module
+                .next_upload_req(create_upload_resp("abc", None, None))
+                .0
+                .is_some());
+        }
+        assert!(module
+            .next_upload_req(create_upload_resp("abc", None, None))
+            .0
+            .is_none());
+        assert!(uploads.lock().unwrap().is_empty());
+        // 未知的上传直接忽略
+        let (req, done) = module.next_upload_req(create_upload_resp("abc", Some(0), None));
+        assert!(req.is_none() && done.is_none());
+    }
+
+    #[test]
+    fn file_url_and_download_sign() {
+        let url = get_file_url("abc");
+        assert_eq!(get_file_sha256(&url), Some("abc"));
+        assert_eq!(get_file_sha256("https://example.com/a.png"), None);
+
+        let sign = sign_download("secret", "abc", "alice", 100);
+        assert!(verify_download_sign("secret", "abc", "alice", 100, &sign));
+        // 签名绑定了文件、账户和过期时间
+        assert!(!verify_download_sign("other", "abc", "alice", 100, &sign));
+        assert!(!verify_download_sign("secret", "abd", "alice", 100, &sign));
+        assert!(!verify_download_sign("secret", "abc", "bob", 100, &sign));
+        assert!(!verify_download_sign("secret", "abc", "alice", 101, &sign));
+        assert!(!verify_download_sign(
+            "secret", "abc", "alice", 100, "not hex"
+        ));
+    }
+}
@@ -0,0 +1,422 @@
+use crate::base::ConnectionRegistry;
+use crate::chat_protocol::{ChatCommand, Protocol};
+use crate::login_module::BizResult;
+use crate::protocol_factory::HandlerProtocolData;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Error, Formatter};
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+pub struct DefaultGroupHandler {
+    server: Option<Box<dyn ServerGroupModule + Send>>,
+
+    client: Option<Box<dyn ClientGroupModule + Send>>,
+}
+
+impl DefaultGroupHandler {
+    pub fn new(
+        server: Option<Box<dyn ServerGroupModule + Send>>,
+        client: Option<Box<dyn ClientGroupModule + Send>>,
+    ) -> Self {
+        DefaultGroupHandler { server, client }
+    }
+
+    fn server_module(&mut self) -> &mut Box<dyn ServerGroupModule + Send> {
+        if self.server.is_none() {
+            panic!("ServerGroupModule is None!");
+        }
+        self.server.as_mut().unwrap()
+    }
+
+    fn client_module(&mut self) -> &mut Box<dyn ClientGroupModule + Send> {
+        if self.client.is_none() {
+            panic!("ClientGroupModule is None!");
+        }
+        self.client.as_mut().unwrap()
+    }
+
+    // 无法解析的数据: server端回复失败的结果.
+    // 回复中需要带上请求的类型，client才知道是哪个请求失败，类型也无法解析时不回复
+    fn reject(&self, address: SocketAddr, data: &[u8], msg: String) -> Option<Vec<u8>> {
+        warn!("reject group data from {}: {}", address, msg);
+        // client端忽略
+        self.server.as_ref()?;
+
+        let group_type: GroupTypeEnum = bincode::deserialize(data).ok()?;
+        let resp_data = BizGroupData {
+            group_type,
+            data: GroupDataEnum::RespData(BizResult {
+                is_success: false,
+                msg: Some(msg),
+                data: None,
+            }),
+        };
+        Some(bincode::serialize(&resp_data).unwrap())
+    }
+}
+
+impl HandlerProtocolData for DefaultGroupHandler {
+    fn handle(&mut self, address: SocketAddr, data: &Vec<u8>) -> Option<Vec<u8>> {
+        let biz: BizGroupData = match bincode::deserialize(data) {
+            Ok(t) => t,
+            Err(e) => return self.reject(address, data, format!("invalid group data: {e}")),
+        };
+
+        let group_type = biz.group_type;
+
+        // server端处理请求
+        let resp = match biz.data {
+            GroupDataEnum::CreateReq(req) => self.server_module().handle_create(req, address),
+
+            GroupDataEnum::RenameReq(req) => self.server_module().handle_rename(req, address),
+
+            GroupDataEnum::InviteReq(req) => self.server_module().handle_invite(req, address),
+
+            GroupDataEnum::KickReq(req) => self.server_module().handle_kick(req, address),
+
+            GroupDataEnum::LeaveReq(req) => self.server_module().handle_leave(req, address),
+
+            GroupDataEnum::SetRoleReq(req) => self.server_module().handle_set_role(req, address),
+
+            GroupDataEnum::InfoReq(req) => self.server_module().handle_info(req, address),
+
+            GroupDataEnum::ListReq => self.server_module().handle_list(address),
+
+            // client端处理响应和server推送的事件
+            GroupDataEnum::RespData(resp) => {
+                self.client_module().handle_group_resp(group_type, resp);
+                return None;
+            }
+
+            GroupDataEnum::Event(event) => {
+                self.client_module().handle_group_event(group_type, event);
+                return None;
+            }
+        };
+
+        let biz_result = match resp {
+            Ok(t) => BizResult {
+                is_success: true,
+                msg: None,
+                data: Some(t),
+            },
+            Err(e) => {
+                warn!("group request {:?} fail: {}", group_type, e);
+                BizResult {
+                    is_success: false,
+                    msg: Some(e),
+                    data: None,
+                }
+            }
+        };
+
+        let resp_data = BizGroupData {
+            group_type,
+            data: GroupDataEnum::RespData(biz_result),
+        };
+
+        Some(bincode::serialize(&resp_data).unwrap())
+    }
+}
+
+/**
+*  server端处理群组的创建、改名、邀请、踢出、退出和角色修改的模块trait.
+*  群组的变化以事件的形式推送给所有成员
+**/
+pub trait ServerGroupModule {
+    // 创建者成为群主
+    fn handle_create(
+        &mut self,
+        req: CreateGroupReqData,
+        address: SocketAddr,
+    ) -> Result<GroupRespData, String>;
+
+    // 群主、管理员可以改名
+    fn handle_rename(
+        &mut self,
+        req: RenameGroupReqData,
+        address: SocketAddr,
+    ) -> Result<GroupRespData, String>;
+
+    // 群主、管理员可以邀请
+    fn handle_invite(
+        &mut self,
+        req: GroupMembersReqData,
+        address: SocketAddr,
+    ) -> Result<GroupRespData, String>;
+
+    // 群主可以踢出任何成员，管理员只能踢出普通成员
+    fn handle_kick(
+        &mut self,
+        req: GroupMembersReqData,
+        address: SocketAddr,
+    ) -> Result<GroupRespData, String>;
+
+    // 群主需要先转让群主才能退出，最后一个成员退出时解散群组
+    fn handle_leave(
+        &mut self,
+        req: GroupIdReqData,
+        address: SocketAddr,
+    ) -> Result<GroupRespData, String>;
+
+    // 只有群主可以修改角色，设置为群主表示转让
+    fn handle_set_role(
+        &mut self,
+        req: SetGroupRoleReqData,
+        address: SocketAddr,
+    ) -> Result<GroupRespData, String>;
+
+    // 只有成员可以查看群组信息
+    fn handle_info(
+        &mut self,
+        req: GroupIdReqData,
+        address: SocketAddr,
+    ) -> Result<GroupRespData, String>;
+
+    // 当前账户加入的所有群组
+    fn handle_list(&mut self, address: SocketAddr) -> Result<GroupRespData, String>;
+}
+
+/**
+ *  client端处理群组响应和事件的模块trait
+ **/
+pub trait ClientGroupModule {
+    fn handle_group_resp(&mut self, group_type: GroupTypeEnum, resp: BizResult<GroupRespData>);
+
+    // 群组发生了变化，group_type为引起变化的操作
+    fn handle_group_event(&mut self, group_type: GroupTypeEnum, event: GroupEventData);
+}
+
+/**
+ *  client端发送群组请求. 需要在已登录的连接上发送
+ **/
+#[derive(Clone)]
+pub struct DefaultClientGroupModule {
+    registry: ConnectionRegistry,
+    server_addr: SocketAddr,
+}
+
+impl DefaultClientGroupModule {
+    pub fn new(registry: ConnectionRegistry, server_addr: SocketAddr) -> Self {
+        DefaultClientGroupModule {
+            registry,
+            server_addr,
+        }
+    }
+
+    pub fn create_group(&self, name: String, members: Vec<String>) -> Result<(), Error> {
+        self.send_req(
+            GroupTypeEnum::Create,
+            GroupDataEnum::CreateReq(CreateGroupReqData { name, members }),
+        )
+    }
+
+    pub fn rename_group(&self, group_id: i32, name: String) -> Result<(), Error> {
+        self.send_req(
+            GroupTypeEnum::Rename,
+            GroupDataEnum::RenameReq(RenameGroupReqData { group_id, name }),
+        )
+    }
+
+    pub fn invite(&self, group_id: i32, accounts: Vec<String>) -> Result<(), Error> {
+        self.send_req(
+            GroupTypeEnum::Invite,
+            GroupDataEnum::InviteReq(GroupMembersReqData { group_id, accounts }),
+        )
+    }
+
+    pub fn kick(&self, group_id: i32, accounts: Vec<String>) -> Result<(), Error> {
+        self.send_req(
+            GroupTypeEnum::Kick,
+            GroupDataEnum::KickReq(GroupMembersReqData { group_id, accounts }),
+        )
+    }
+
+    pub fn leave(&self, group_id: i32) -> Result<(), Error> {
+        self.send_req(
+            GroupTypeEnum::Leave,
+            GroupDataEnum::LeaveReq(GroupIdReqData { group_id }),
+        )
+    }
+
+    pub fn set_role(&self, group_id: i32, account: String, role: GroupRole) -> Result<(), Error> {
+        self.send_req(
+            GroupTypeEnum::SetRole,
+            GroupDataEnum::SetRoleReq(SetGroupRoleReqData {
+                group_id,
+                account,
+                role,
+            }),
+        )
+    }
+
+    pub fn find_group(&self, group_id: i32) -> Result<(), Error> {
+        self.send_req(
+            GroupTypeEnum::Info,
+            GroupDataEnum::InfoReq(GroupIdReqData { group_id }),
+        )
+    }
+
+    pub fn list_groups(&self) -> Result<(), Error> {
+        self.send_req(GroupTypeEnum::List, GroupDataEnum::ListReq)
+    }
+
+    fn send_req(&self, group_type: GroupTypeEnum, data: GroupDataEnum) -> Result<(), Error> {
+        let biz = BizGroupData { group_type, data };
+
+        self.registry
+            .send_to(&self.server_addr, &biz.to_protocol_bytes())
+            .map_err(|e| {
+                error!("send group request fail: {}", e);
+                Error
+            })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BizGroupData {
+    pub group_type: GroupTypeEnum,
+    pub data: GroupDataEnum,
+}
+
+impl BizGroupData {
+    // 打包成可以直接写入stream的字节
+    pub fn to_protocol_bytes(&self) -> Vec<u8> {
+        let data = bincode::serialize(self).unwrap();
+        Protocol::create_by_data(ChatCommand::Group, data).to_vec()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub enum GroupTypeEnum {
+    Create,
+    Rename,
+    Invite,
+    Kick,
+    Leave,
+    SetRole,
+    Info,
+    List,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum GroupDataEnum {
+    CreateReq(CreateGroupReqData),
+    RenameReq(RenameGroupReqData),
+    InviteReq(GroupMembersReqData),
+    KickReq(GroupMembersReqData),
+    LeaveReq(GroupIdReqData),
+    SetRoleReq(SetGroupRoleReqData),
+    InfoReq(GroupIdReqData),
+    ListReq,
+    RespData(BizResult<GroupRespData>),
+    // server推送的群组变化
+    Event(GroupEventData),
+}
+
+// 群组中的角色. 存储在group_member.role中，值为 as_str() 的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum GroupRole {
+    Owner,
+    Admin,
+    Member,
+}
+
+impl GroupRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GroupRole::Owner => "owner",
+            GroupRole::Admin => "admin",
+            GroupRole::Member => "member",
+        }
+    }
+
+    // 群主、管理员可以管理群组
+    pub fn is_manager(&self) -> bool {
+        matches!(self, GroupRole::Owner | GroupRole::Admin)
+    }
+}
+
+impl FromStr for GroupRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "owner" => Ok(GroupRole::Owner),
+            "admin" => Ok(GroupRole::Admin),
+            "member" => Ok(GroupRole::Member),
+            _ => Err(format!("unknown group role: {}", s)),
+        }
+    }
+}
+
+impl Display for GroupRole {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateGroupReqData {
+    pub name: String,
+    // 创建时加入的成员，不需要包含创建者
+    pub members: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RenameGroupReqData {
+    pub group_id: i32,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroupMembersReqData {
+    pub group_id: i32,
+    pub accounts: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroupIdReqData {
+    pub group_id: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetGroupRoleReqData {
+    pub group_id: i32,
+    pub account: String,
+    pub role: GroupRole,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GroupMemberData {
+    pub account: String,
+    pub role: GroupRole,
+    // 毫秒时间戳
+    pub join_time: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GroupInfoData {
+    pub group_id: i32,
+    pub name: String,
+    pub members: Vec<GroupMemberData>,
+}
+
+// 创建、修改、查看返回对应的群组，列表返回所有群组，退出时为空
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GroupRespData {
+    pub groups: Vec<GroupInfoData>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GroupEventData {
+    // 变化之后的群组，解散时成员为空
+    pub group: GroupInfoData,
+    // 进行操作的账户
+    pub operator: String,
+    // 受影响的账户，例如被邀请、踢出的账户
+    pub accounts: Vec<String>,
+    // 毫秒时间戳
+    pub time: i64,
+}
@@ -4,12 +4,19 @@ use crate::storage_module::StorageModule;
 use crate::ui_module::UiModule;
 use std::any::Any;
 
+pub mod account_module;
+pub mod chat_cache;
+pub mod chat_content;
 pub mod chat_module;
 pub mod chat_protocol;
 pub mod config;
+pub mod file_module;
+pub mod group_module;
 pub mod login_module;
 pub mod p2p_module;
+pub mod permission;
 pub mod protocol_factory;
+pub mod scram;
 pub mod storage_module;
 pub mod ui_module;
 
@@ -1,4 +1,5 @@
 use crate::protocol_factory::HandlerProtocolData;
+use log::warn;
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 
@@ -11,65 +12,229 @@ pub struct DefaultLoginHandler {
     client: Option<Box<dyn ClientLoginModule + Send>>,
 }
 
-impl DefaultLoginHandler{
-    
-    pub fn new(server_flg:bool ,server: Option<Box<dyn ServerLoginModule + Send>>, client: Option<Box<dyn ClientLoginModule + Send>>,)->Self{
-        DefaultLoginHandler{server_flg,server,client}
+impl DefaultLoginHandler {
+    pub fn new(
+        server_flg: bool,
+        server: Option<Box<dyn ServerLoginModule + Send>>,
+        client: Option<Box<dyn ClientLoginModule + Send>>,
+    ) -> Self {
+        DefaultLoginHandler {
+            server_flg,
+            server,
+            client,
+        }
     }
-    
 }
 
+impl DefaultLoginHandler {
+    // 无法处理的数据: server端回复失败的登录结果，client端忽略
+    fn reject(&self, address: SocketAddr, msg: String) -> Option<Vec<u8>> {
+        warn!("reject login data from {}: {}", address, msg);
+        if !self.server_flg {
+            return None;
+        }
+
+        let data = LoginDataEnum::RespData(to_biz_result(Err(msg)));
+        Some(create_login_data(LoginTypeEnum::Resp, data))
+    }
+}
 
 impl HandlerProtocolData for DefaultLoginHandler {
     fn handle(&mut self, address: SocketAddr, data: &Vec<u8>) -> Option<Vec<u8>> {
         // 反序列化为 BizLoginData
-        let login: BizLoginData = bincode::deserialize(data).unwrap();
+        let login: BizLoginData = match bincode::deserialize(data) {
+            Ok(t) => t,
+            Err(e) => return self.reject(address, format!("invalid login data: {e}")),
+        };
 
         // server端处理请求
         match (login.login_type, login.data) {
             (LoginTypeEnum::Req, LoginDataEnum::ReqData(req)) => {
                 if self.server.is_none() {
-                    panic!("ServerLoginModule is None!");
+                    return self.reject(address, "unexpected login data !".to_string());
                 }
                 let resp = self.server.as_mut().unwrap().handle_login_req(req, address);
 
-                let mut bizResult: Option<BizResult<LoginRespData>> = None;
-
-                if resp.is_err() {
-                    let t = BizResult {
-                        is_success: false,
-                        msg: Some(resp.err().unwrap().to_string()),
-                        data: None,
-                    };
-                    bizResult = Some(t);
-                } else {
-                    let t = BizResult {
-                        is_success: true,
-                        msg: None,
-                        data: Some(resp.unwrap()),
-                    };
-                    bizResult = Some(t);
+                let data = match resp {
+                    // 开启了两步验证，需要client再提交验证码
+                    Ok(LoginStepResult::TotpRequired(t)) => LoginDataEnum::TotpChallenge(t),
+                    Ok(LoginStepResult::Success(t)) => {
+                        LoginDataEnum::RespData(to_biz_result(Ok(t)))
+                    }
+                    Err(e) => LoginDataEnum::RespData(to_biz_result(Err(e))),
+                };
+
+                return Some(create_login_data(LoginTypeEnum::Resp, data));
+            }
+
+            (LoginTypeEnum::Req, LoginDataEnum::ScramStartReq(req)) => {
+                if self.server.is_none() {
+                    return self.reject(address, "unexpected login data !".to_string());
+                }
+                let resp = self
+                    .server
+                    .as_mut()
+                    .unwrap()
+                    .handle_scram_start_req(req, address);
+
+                let data = match resp {
+                    Ok(t) => LoginDataEnum::ScramChallenge(t),
+                    Err(e) => LoginDataEnum::RespData(to_biz_result(Err(e))),
+                };
+                return Some(create_login_data(LoginTypeEnum::Resp, data));
+            }
+
+            (LoginTypeEnum::Req, LoginDataEnum::ScramProofReq(req)) => {
+                if self.server.is_none() {
+                    return self.reject(address, "unexpected login data !".to_string());
                 }
+                let resp = self
+                    .server
+                    .as_mut()
+                    .unwrap()
+                    .handle_scram_proof_req(req, address);
 
-                return Some(bincode::serialize(&bizResult.unwrap()).unwrap());
+                let data = match resp {
+                    Ok((server_signature, step)) => {
+                        let (totp_challenge, resp) = match step {
+                            LoginStepResult::Success(t) => (None, Some(t)),
+                            LoginStepResult::TotpRequired(t) => (Some(t), None),
+                        };
+                        LoginDataEnum::ScramFinal(ScramFinalData {
+                            server_signature,
+                            totp_challenge,
+                            resp,
+                        })
+                    }
+                    Err(e) => LoginDataEnum::RespData(to_biz_result(Err(e))),
+                };
+                return Some(create_login_data(LoginTypeEnum::Resp, data));
+            }
+
+            (LoginTypeEnum::Req, LoginDataEnum::TotpReqData(req)) => {
+                if self.server.is_none() {
+                    return self.reject(address, "unexpected login data !".to_string());
+                }
+                let resp = self.server.as_mut().unwrap().handle_totp_req(req, address);
+
+                let data = LoginDataEnum::RespData(to_biz_result(resp));
+                return Some(create_login_data(LoginTypeEnum::Resp, data));
+            }
+
+            (LoginTypeEnum::Req, LoginDataEnum::ApiKeyReq(req)) => {
+                if self.server.is_none() {
+                    return self.reject(address, "unexpected login data !".to_string());
+                }
+                let resp = self
+                    .server
+                    .as_mut()
+                    .unwrap()
+                    .handle_api_key_req(req, address);
+
+                let data = LoginDataEnum::RespData(to_biz_result(resp));
+                return Some(create_login_data(LoginTypeEnum::Resp, data));
             }
 
             // client端处理响应
             (LoginTypeEnum::Resp, LoginDataEnum::RespData(resp)) => {
                 if self.client.is_none() {
-                    panic!("ClientLoginModule is None!");
+                    return self.reject(address, "unexpected login data !".to_string());
                 }
 
                 self.client.as_mut().unwrap().handle_login_biz_resp(resp);
             }
 
-            _ => {
-                panic!("不支持的login数据类型!")
+            (LoginTypeEnum::Resp, LoginDataEnum::ScramChallenge(challenge)) => {
+                if self.client.is_none() {
+                    return self.reject(address, "unexpected login data !".to_string());
+                }
+
+                // client计算proof，作为响应直接发回server
+                let req = self
+                    .client
+                    .as_mut()
+                    .unwrap()
+                    .handle_scram_challenge(challenge);
+
+                return req.map(|t| {
+                    create_login_data(LoginTypeEnum::Req, LoginDataEnum::ScramProofReq(t))
+                });
+            }
+
+            (LoginTypeEnum::Resp, LoginDataEnum::ScramFinal(data)) => {
+                if self.client.is_none() {
+                    return self.reject(address, "unexpected login data !".to_string());
+                }
+
+                let client = self.client.as_mut().unwrap();
+
+                // 先校验server签名，不通过说明对端不是持有正确校验数据的server
+                if !client.verify_server_signature(&data.server_signature) {
+                    client.handle_login_biz_resp(to_biz_result(Err(
+                        "server signature is invalid !".to_string(),
+                    )));
+                    return None;
+                }
+
+                match (data.totp_challenge, data.resp) {
+                    (Some(challenge), _) => {
+                        return client.handle_totp_challenge(challenge).map(|t| {
+                            create_login_data(LoginTypeEnum::Req, LoginDataEnum::TotpReqData(t))
+                        });
+                    }
+                    (None, resp) => client.handle_login_biz_resp(to_biz_result(
+                        resp.ok_or("login resp is empty !".to_string()),
+                    )),
+                }
             }
+
+            (LoginTypeEnum::Resp, LoginDataEnum::TotpChallenge(challenge)) => {
+                if self.client.is_none() {
+                    return self.reject(address, "unexpected login data !".to_string());
+                }
+
+                // client提交验证码，作为响应直接发回server
+                let req = self
+                    .client
+                    .as_mut()
+                    .unwrap()
+                    .handle_totp_challenge(challenge);
+
+                return req
+                    .map(|t| create_login_data(LoginTypeEnum::Req, LoginDataEnum::TotpReqData(t)));
+            }
+
+            _ => return self.reject(address, "不支持的login数据类型!".to_string()),
         }
 
         None
     }
+
+    fn on_disconnect(&mut self, address: SocketAddr) {
+        if let Some(t) = self.server.as_mut() {
+            t.handle_disconnect(address);
+        }
+    }
+}
+
+fn to_biz_result(resp: Result<LoginRespData, String>) -> BizResult<LoginRespData> {
+    match resp {
+        Ok(t) => BizResult {
+            is_success: true,
+            msg: None,
+            data: Some(t),
+        },
+        Err(e) => BizResult {
+            is_success: false,
+            msg: Some(e),
+            data: None,
+        },
+    }
+}
+
+fn create_login_data(login_type: LoginTypeEnum, data: LoginDataEnum) -> Vec<u8> {
+    let login_data = BizLoginData { login_type, data };
+    bincode::serialize(&login_data).unwrap()
 }
 
 /**
@@ -80,9 +245,48 @@ pub trait ServerLoginModule {
         &mut self,
         req: LoginReqData,
         address: SocketAddr,
-    ) -> Result<LoginRespData, String> {
+    ) -> Result<LoginStepResult, String> {
         panic!("暂未实现该函数 [handle_login_req]!");
     }
+
+    // scram登录第一步，返回salt、迭代次数和nonce
+    fn handle_scram_start_req(
+        &mut self,
+        req: ScramStartReqData,
+        address: SocketAddr,
+    ) -> Result<ScramChallengeData, String> {
+        panic!("暂未实现该函数 [handle_scram_start_req]!");
+    }
+
+    // scram登录第二步，校验client proof. 成功时同时返回server签名
+    fn handle_scram_proof_req(
+        &mut self,
+        req: ScramProofReqData,
+        address: SocketAddr,
+    ) -> Result<(String, LoginStepResult), String> {
+        panic!("暂未实现该函数 [handle_scram_proof_req]!");
+    }
+
+    // 两步验证的第二步，校验验证码
+    fn handle_totp_req(
+        &mut self,
+        req: TotpReqData,
+        address: SocketAddr,
+    ) -> Result<LoginRespData, String> {
+        panic!("暂未实现该函数 [handle_totp_req]!");
+    }
+
+    // bot、服务账户使用api key登录
+    fn handle_api_key_req(
+        &mut self,
+        req: ApiKeyReqData,
+        address: SocketAddr,
+    ) -> Result<LoginRespData, String> {
+        panic!("暂未实现该函数 [handle_api_key_req]!");
+    }
+
+    // 连接断开，清理该连接的登录状态
+    fn handle_disconnect(&mut self, address: SocketAddr) {}
 }
 
 /**
@@ -92,6 +296,32 @@ pub trait ClientLoginModule {
     fn handle_login_biz_resp(&mut self, resp: BizResult<LoginRespData>) {
         panic!("暂未实现该函数 [handle_login_biz_resp]!");
     }
+
+    // 根据server返回的参数计算proof，返回None表示放弃本次登录
+    fn handle_scram_challenge(
+        &mut self,
+        challenge: ScramChallengeData,
+    ) -> Option<ScramProofReqData> {
+        panic!("暂未实现该函数 [handle_scram_challenge]!");
+    }
+
+    // 校验server签名
+    fn verify_server_signature(&mut self, server_signature: &str) -> bool {
+        panic!("暂未实现该函数 [verify_server_signature]!");
+    }
+
+    // 返回None表示放弃本次登录
+    fn handle_totp_challenge(&mut self, challenge: TotpChallengeData) -> Option<TotpReqData> {
+        panic!("暂未实现该函数 [handle_totp_challenge]!");
+    }
+}
+
+// 密码校验的结果
+pub enum LoginStepResult {
+    // 登录成功
+    Success(LoginRespData),
+    // 账户开启了两步验证，还需要校验验证码
+    TotpRequired(TotpChallengeData),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -104,6 +334,20 @@ pub struct BizLoginData {
 pub enum LoginDataEnum {
     ReqData(LoginReqData),
     RespData(BizResult<LoginRespData>),
+    // server要求client提交两步验证的验证码
+    TotpChallenge(TotpChallengeData),
+    // client提交两步验证的验证码
+    TotpReqData(TotpReqData),
+    // scram登录: client发起
+    ScramStartReq(ScramStartReqData),
+    // scram登录: server返回salt等参数
+    ScramChallenge(ScramChallengeData),
+    // scram登录: client提交proof
+    ScramProofReq(ScramProofReqData),
+    // scram登录: server返回签名和登录结果
+    ScramFinal(ScramFinalData),
+    // bot、服务账户使用api key登录，不需要交互输入密码
+    ApiKeyReq(ApiKeyReqData),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -118,6 +362,60 @@ pub struct LoginReqData {
     pub pwd: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScramStartReqData {
+    pub account: String,
+    pub client_nonce: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScramChallengeData {
+    pub salt: String,
+    pub iterations: u32,
+    // client_nonce + server生成的nonce
+    pub nonce: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScramProofReqData {
+    pub account: String,
+    pub nonce: String,
+    // ClientKey XOR ClientSignature，hex编码
+    pub proof: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScramFinalData {
+    // server签名，client校验通过才能信任本次响应
+    pub server_signature: String,
+    // 开启了两步验证时有值，client还需要提交验证码
+    pub totp_challenge: Option<TotpChallengeData>,
+    // 登录结果，totp_challenge有值时为None
+    pub resp: Option<LoginRespData>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpChallengeData {
+    pub account: String,
+    // 密码校验通过后server生成的临时凭证，提交验证码时需要带上
+    pub ticket: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpReqData {
+    pub account: String,
+    pub ticket: String,
+    // 认证器app上的验证码，或者一个恢复码
+    pub code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiKeyReqData {
+    pub account: String,
+    // 在userinfo-web上创建的api key
+    pub api_key: String,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct LoginRespData {
     pub user_id: i32,
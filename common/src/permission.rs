@@ -0,0 +1,121 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+// 账户角色. 存储在user_info.role中，值为 as_str() 的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum Role {
+    Admin,
+    Moderator,
+    #[default]
+    User,
+    Bot,
+}
+
+// 需要进行权限校验的操作
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Permission {
+    // 通过socket登录
+    Login,
+    // 修改自己的资料
+    UpdateProfile,
+    // 发送聊天消息
+    SendMessage,
+    // 管理他人的消息，例如撤回
+    ModerateMessage,
+    // 查看账户列表
+    ViewUsers,
+    // 新增账户、解锁账户、管理两步验证
+    ManageUsers,
+    // 修改账户的角色
+    ManageRoles,
+    // 查看审计日志
+    ViewAuditLog,
+}
+
+impl Role {
+    pub const ALL: [Role; 4] = [Role::Admin, Role::Moderator, Role::User, Role::Bot];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Moderator => "moderator",
+            Role::User => "user",
+            Role::Bot => "bot",
+        }
+    }
+
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        match self {
+            Role::Admin => true,
+
+            Role::Moderator => matches!(
+                permission,
+                Permission::Login
+                    | Permission::UpdateProfile
+                    | Permission::SendMessage
+                    | Permission::ModerateMessage
+                    | Permission::ViewUsers
+                    | Permission::ViewAuditLog
+            ),
+
+            Role::User => matches!(
+                permission,
+                Permission::Login | Permission::UpdateProfile | Permission::SendMessage
+            ),
+
+            // bot账户只能登录和收发消息
+            Role::Bot => matches!(permission, Permission::Login | Permission::SendMessage),
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Role::ALL
+            .into_iter()
+            .find(|t| t.as_str() == s)
+            .ok_or(format!("unknown role: {s}"))
+    }
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_role() {
+        for role in Role::ALL {
+            assert_eq!(role.as_str().parse::<Role>().unwrap(), role);
+            assert_eq!(role.to_string(), role.as_str());
+        }
+        assert!("root".parse::<Role>().is_err());
+        assert_eq!(Role::default(), Role::User);
+    }
+
+    #[test]
+    fn role_permissions() {
+        assert!(Role::Admin.has_permission(Permission::ManageRoles));
+
+        assert!(Role::Moderator.has_permission(Permission::ModerateMessage));
+        assert!(Role::Moderator.has_permission(Permission::ViewAuditLog));
+        assert!(!Role::Moderator.has_permission(Permission::ManageUsers));
+        assert!(!Role::Moderator.has_permission(Permission::ManageRoles));
+
+        assert!(Role::User.has_permission(Permission::UpdateProfile));
+        assert!(!Role::User.has_permission(Permission::ModerateMessage));
+        assert!(!Role::User.has_permission(Permission::ViewUsers));
+
+        // bot不能修改资料
+        assert!(Role::Bot.has_permission(Permission::SendMessage));
+        assert!(!Role::Bot.has_permission(Permission::UpdateProfile));
+    }
+}
@@ -4,10 +4,13 @@ use std::net::SocketAddr;
 
 pub trait HandlerProtocolData {
     fn handle(&mut self, address: SocketAddr, data: &Vec<u8>) -> Option<Vec<u8>>;
+
+    // 连接断开时调用，用于清理该连接相关的状态
+    fn on_disconnect(&mut self, address: SocketAddr) {}
 }
 
 pub struct HandleProtocolFactory {
-    pub all_handler: HashMap<ChatCommand, Box<dyn HandlerProtocolData>>,
+    pub all_handler: HashMap<ChatCommand, Box<dyn HandlerProtocolData + Send>>,
 }
 
 impl HandleProtocolFactory {
@@ -17,20 +20,26 @@ impl HandleProtocolFactory {
         }
     }
 
-    pub fn get_handler(&mut self, a: &ChatCommand) -> &mut Box<dyn HandlerProtocolData> {
-        match self.all_handler.get_mut(a) {
-            None => {
-                panic!("Not exist command:{:?}", a);
-            }
-            Some(t) => t,
-        }
+    // command来自对端，没有注册的command返回None，由调用方决定如何处理
+    pub fn get_handler(
+        &mut self,
+        a: &ChatCommand,
+    ) -> Option<&mut Box<dyn HandlerProtocolData + Send>> {
+        self.all_handler.get_mut(a)
     }
 
-    pub fn registry_handler(&mut self, a: ChatCommand, b: Box<dyn HandlerProtocolData>) {
+    pub fn registry_handler(&mut self, a: ChatCommand, b: Box<dyn HandlerProtocolData + Send>) {
         if self.all_handler.get(&a).is_some() {
             panic!("ChatCommand:{:?} already exist! ", a);
         }
 
         self.all_handler.insert(a, b);
     }
+
+    // 通知所有handler连接已断开
+    pub fn on_disconnect(&mut self, address: SocketAddr) {
+        for handler in self.all_handler.values_mut() {
+            handler.on_disconnect(address);
+        }
+    }
 }
@@ -0,0 +1,206 @@
+use hmac::{Hmac, Mac};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// 参考 SCRAM-SHA-256 (RFC 5802 / RFC 7677) 的登录校验，密码不在网络上传输.
+// server只保存 salt、iterations、StoredKey、ServerKey，不保存密码
+
+pub const SCRAM_DEFAULT_ITERATIONS: u32 = 4096;
+// client提交的校验数据的迭代次数上限. web端登录时server需要用明文密码计算，避免过大的值占用server
+const SCRAM_MAX_ITERATIONS: u32 = 100_000;
+const SCRAM_SALT_LEN: usize = 16;
+pub const SCRAM_NONCE_LEN: usize = 24;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// server端保存的校验数据. 注册、修改密码时由client根据密码计算之后提交
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScramVerifier {
+    pub salt: String,
+    pub iterations: u32,
+    pub stored_key: String,
+    pub server_key: String,
+}
+
+impl ScramVerifier {
+    // 根据密码生成新的校验数据，使用随机的salt
+    pub fn create(pwd: &str) -> Self {
+        let salt_bytes: [u8; SCRAM_SALT_LEN] = rand::thread_rng().gen();
+        Self::create_with_salt(pwd, &hex::encode(salt_bytes), SCRAM_DEFAULT_ITERATIONS)
+    }
+
+    pub fn create_with_salt(pwd: &str, salt: &str, iterations: u32) -> Self {
+        let salted_pwd = salted_password(pwd, salt, iterations);
+        let client_key = hmac(&salted_pwd, b"Client Key");
+        let server_key = hmac(&salted_pwd, b"Server Key");
+
+        ScramVerifier {
+            salt: salt.to_string(),
+            iterations,
+            stored_key: hex::encode(Sha256::digest(&client_key)),
+            server_key: hex::encode(server_key),
+        }
+    }
+
+    // 校验client提交的数据格式，server无法校验密码本身
+    pub fn validate(&self) -> Result<(), String> {
+        let salt_len = hex::decode(&self.salt).map_or(0, |t| t.len());
+        if !(SCRAM_SALT_LEN..=SCRAM_SALT_LEN * 4).contains(&salt_len) {
+            return Err("invalid scram salt !".to_string());
+        }
+        if !(SCRAM_DEFAULT_ITERATIONS..=SCRAM_MAX_ITERATIONS).contains(&self.iterations) {
+            return Err(format!(
+                "scram iterations must be in {SCRAM_DEFAULT_ITERATIONS}..={SCRAM_MAX_ITERATIONS} !"
+            ));
+        }
+        for key in [&self.stored_key, &self.server_key] {
+            if hex::decode(key).map_or(0, |t| t.len()) != 32 {
+                return Err("invalid scram key !".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    // 直接用明文密码校验，兼容旧的登录方式
+    pub fn verify_pwd(&self, pwd: &str) -> bool {
+        let t = Self::create_with_salt(pwd, &self.salt, self.iterations);
+        constant_time_eq(t.stored_key.as_bytes(), self.stored_key.as_bytes())
+    }
+
+    // 校验client proof. 返回server签名，校验失败返回None
+    pub fn verify_proof(&self, auth_message: &str, proof: &str) -> Option<String> {
+        let stored_key = hex::decode(&self.stored_key).ok()?;
+        let proof = hex::decode(proof).ok()?;
+
+        let client_signature = hmac(&stored_key, auth_message.as_bytes());
+        if proof.len() != client_signature.len() {
+            return None;
+        }
+
+        // ClientKey = ClientProof XOR ClientSignature
+        let client_key: Vec<u8> = proof
+            .iter()
+            .zip(client_signature.iter())
+            .map(|(a, b)| a ^ b)
+            .collect();
+
+        if !constant_time_eq(&Sha256::digest(&client_key), &stored_key) {
+            return None;
+        }
+
+        let server_key = hex::decode(&self.server_key).ok()?;
+        Some(hex::encode(hmac(&server_key, auth_message.as_bytes())))
+    }
+}
+
+// client端计算的结果
+pub struct ScramClientProof {
+    pub proof: String,
+    // 期望的server签名，用于校验server
+    pub server_signature: String,
+}
+
+// client端根据密码和server返回的参数计算proof
+pub fn create_client_proof(
+    pwd: &str,
+    salt: &str,
+    iterations: u32,
+    auth_message: &str,
+) -> ScramClientProof {
+    let salted_pwd = salted_password(pwd, salt, iterations);
+    let client_key = hmac(&salted_pwd, b"Client Key");
+    let stored_key = Sha256::digest(&client_key);
+    let client_signature = hmac(&stored_key, auth_message.as_bytes());
+
+    let proof: Vec<u8> = client_key
+        .iter()
+        .zip(client_signature.iter())
+        .map(|(a, b)| a ^ b)
+        .collect();
+
+    let server_key = hmac(&salted_pwd, b"Server Key");
+
+    ScramClientProof {
+        proof: hex::encode(proof),
+        server_signature: hex::encode(hmac(&server_key, auth_message.as_bytes())),
+    }
+}
+
+// client和server双方用相同的规则拼接出被签名的消息
+pub fn auth_message(account: &str, nonce: &str, salt: &str, iterations: u32) -> String {
+    format!("n={account},r={nonce},s={salt},i={iterations}")
+}
+
+pub fn create_nonce() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SCRAM_NONCE_LEN)
+        .map(char::from)
+        .collect()
+}
+
+// 比较时间不依赖于第一个不相同字节的位置
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn salted_password(pwd: &str, salt: &str, iterations: u32) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(pwd.as_bytes(), salt.as_bytes(), iterations, &mut out);
+    out
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("hmac can take key of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_proof_matches_verifier() {
+        let verifier = ScramVerifier::create("secret-pwd");
+        let nonce = format!("{}{}", create_nonce(), create_nonce());
+        let message = auth_message("test", &nonce, &verifier.salt, verifier.iterations);
+
+        let client =
+            create_client_proof("secret-pwd", &verifier.salt, verifier.iterations, &message);
+        let server_signature = verifier.verify_proof(&message, &client.proof);
+        assert_eq!(server_signature, Some(client.server_signature));
+        assert!(verifier.verify_pwd("secret-pwd"));
+
+        let wrong = create_client_proof("wrong-pwd", &verifier.salt, verifier.iterations, &message);
+        assert_eq!(verifier.verify_proof(&message, &wrong.proof), None);
+        assert!(!verifier.verify_pwd("wrong-pwd"));
+    }
+
+    #[test]
+    fn validate_verifier() {
+        let verifier = ScramVerifier::create("secret-pwd");
+        assert!(verifier.validate().is_ok());
+
+        let mut t = verifier.clone();
+        t.iterations = 1;
+        assert!(t.validate().is_err());
+
+        let mut t = verifier.clone();
+        t.iterations = u32::MAX;
+        assert!(t.validate().is_err());
+
+        let mut t = verifier.clone();
+        t.salt = "salt".to_string();
+        assert!(t.validate().is_err());
+
+        let mut t = verifier;
+        t.stored_key.truncate(10);
+        assert!(t.validate().is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use crate::login_guard::SharedIpLoginGuard;
+use crate::{
+    block_on, find_account_by_address, record_ip_login_fail, LoginCache, PendingScram,
+    SCRAM_EXPIRE_MILLIS,
+};
+use common::account_module::{
+    AccountRespData, ChangePwdReqData, RegisterReqData, ServerAccountModule, UpdateProfileReqData,
+};
+use common::base::now_millis;
+use common::config::LoginGuardConfig;
+use common::login_module::{ScramChallengeData, ScramStartReqData};
+use common::permission::Permission;
+use common::scram;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use userinfo_web::entity::userinfo;
+use userinfo_web::userinfo_service::Service;
+
+// server端处理注册、修改密码、修改资料请求. 与web端共用同一个Service
+pub struct DefaultServerAccountModule {
+    user_service: Arc<Service>,
+    login_cache: LoginCache,
+    // 与登录共用，校验旧密码失败同样计入ip的失败次数
+    ip_guard: SharedIpLoginGuard,
+    guard_config: LoginGuardConfig,
+    // 已返回challenge、等待提交新密码的修改，key为nonce
+    scram_pending: HashMap<String, PendingScram>,
+}
+
+impl DefaultServerAccountModule {
+    pub fn init(
+        user_service: Arc<Service>,
+        login_cache: LoginCache,
+        ip_guard: SharedIpLoginGuard,
+    ) -> Self {
+        let guard_config = ip_guard.lock().unwrap().get_config().clone();
+        DefaultServerAccountModule {
+            user_service,
+            login_cache,
+            ip_guard,
+            guard_config,
+            scram_pending: Default::default(),
+        }
+    }
+
+    // 只能在已登录的连接上修改自己的密码
+    fn check_owner(&self, account: &String, address: &SocketAddr) -> Result<(), String> {
+        let login_account = find_account_by_address(&self.login_cache, address)
+            .ok_or("please login first !".to_string())?;
+        if login_account != *account {
+            return Err("can not change password of other account !".to_string());
+        }
+
+        self.ip_guard
+            .lock()
+            .unwrap()
+            .check(&address.ip(), now_millis())
+    }
+}
+
+impl ServerAccountModule for DefaultServerAccountModule {
+    fn handle_register(&mut self, req: RegisterReqData) -> Result<AccountRespData, String> {
+        let model = block_on(
+            self.user_service
+                .register_by_verifier(req.account, req.verifier),
+        )?;
+        Ok(to_resp(model))
+    }
+
+    fn handle_change_pwd_start(
+        &mut self,
+        req: ScramStartReqData,
+        address: SocketAddr,
+    ) -> Result<ScramChallengeData, String> {
+        self.check_owner(&req.account, &address)?;
+
+        if req.client_nonce.len() < scram::SCRAM_NONCE_LEN {
+            return Err("client nonce is too short !".to_string());
+        }
+
+        let (salt, iterations) = block_on(self.user_service.find_scram_params(&req.account))?;
+
+        let nonce = format!("{}{}", req.client_nonce, scram::create_nonce());
+
+        let now = now_millis();
+        self.scram_pending.retain(|_, v| v.expire_time > now);
+        self.scram_pending.insert(
+            nonce.clone(),
+            PendingScram {
+                account: req.account,
+                salt: salt.clone(),
+                iterations,
+                address,
+                expire_time: now + SCRAM_EXPIRE_MILLIS,
+            },
+        );
+
+        Ok(ScramChallengeData {
+            salt,
+            iterations,
+            nonce,
+        })
+    }
+
+    fn handle_change_pwd(
+        &mut self,
+        req: ChangePwdReqData,
+        address: SocketAddr,
+    ) -> Result<AccountRespData, String> {
+        self.check_owner(&req.account, &address)?;
+
+        // nonce只能使用一次，且必须是同一个连接、同一个账户
+        let pending = self
+            .scram_pending
+            .remove(&req.nonce)
+            .filter(|t| t.expire_time > now_millis())
+            .filter(|t| t.address == address && t.account == req.account)
+            .ok_or("nonce is invalid or expired, please retry !".to_string())?;
+
+        let auth_message = scram::auth_message(
+            &pending.account,
+            &req.nonce,
+            &pending.salt,
+            pending.iterations,
+        );
+
+        let account = pending.account;
+        let result = block_on(self.user_service.change_pwd(
+            req,
+            auth_message,
+            address.to_string(),
+            &self.guard_config,
+        ));
+
+        match result {
+            Ok(model) => Ok(to_resp(model)),
+            Err(e) => {
+                record_ip_login_fail(&self.ip_guard, &self.user_service, &account, address);
+                Err(e)
+            }
+        }
+    }
+
+    fn handle_update_profile(
+        &mut self,
+        req: UpdateProfileReqData,
+        address: SocketAddr,
+    ) -> Result<AccountRespData, String> {
+        let account = find_account_by_address(&self.login_cache, &address)
+            .ok_or("please login first !".to_string())?;
+
+        block_on(
+            self.user_service
+                .check_permission(&account, Permission::UpdateProfile),
+        )?;
+
+        let model = block_on(self.user_service.update_profile(
+            account,
+            req.nickname,
+            req.signature,
+        ))?;
+        Ok(to_resp(model))
+    }
+}
+
+fn to_resp(model: userinfo::Model) -> AccountRespData {
+    AccountRespData {
+        user_id: model.id,
+        account: model.name,
+        nickname: model.nickname,
+        signature: model.signature,
+    }
+}
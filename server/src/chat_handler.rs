@@ -0,0 +1,1171 @@
+use crate::{block_on, find_account_by_address, find_addresses_by_account, LoginCache};
+use common::base::{now_millis, ConnectionRegistry};
+use common::chat_content::{check_contents, find_file_urls};
+use common::chat_module::{
+    create_msg_id, get_conversation_id, get_conversation_peer, get_group_conversation_id,
+    get_group_id, BizChatData, ChatAckData, ChatContent, ChatData, ChatDataEnum, ChatTypeEnum,
+    ConversationMetadata, DisappearTimerReqData, EditHistoryReqData, EditHistoryRespData,
+    EditMessageData, EventData, ForwardData, ForwardReqData, HistoryReqData, HistoryRespData,
+    MessageState, MetadataReqData, OfflineAckData, OfflineChatData, ReactionReqData,
+    RecallMessageData, ReceiptData, ScheduleCancelData, ScheduleEditData, ScheduleReqData,
+    ScheduledData, SearchReqData, SearchRespData, ServerChatModule, SyncRespData, SyncSinceData,
+    ThreadReqData, ThreadRespData, UpdateMetadataData,
+};
+use common::config::ChatConfig;
+use common::file_module::get_file_sha256;
+use common::permission::Permission;
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use userinfo_web::chat_service::ChatService;
+use userinfo_web::userinfo_service::Service;
+
+// 查询历史消息时默认、最多返回的消息数量
+const DEFAULT_HISTORY_LIMIT: u32 = 20;
+const MAX_HISTORY_LIMIT: u32 = 100;
+
+// 同一个账户在同一个会话中发送同一种临时事件的最小间隔
+const EVENT_MIN_INTERVAL_MILLIS: i64 = 1000;
+
+// 临时事件的最长有效时间
+const EVENT_MAX_TTL_MILLIS: i64 = 30_000;
+
+// 每次发送的到期定时消息数量
+const SCHEDULE_BATCH_SIZE: u64 = 100;
+
+// 检查定时消息是否到期的间隔
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// 搜索时默认、最多返回的消息数量
+const DEFAULT_SEARCH_LIMIT: u32 = 20;
+const MAX_SEARCH_LIMIT: u32 = 100;
+
+// 搜索关键词的最大长度
+const SEARCH_TEXT_MAX_LEN: usize = 256;
+
+// 一次回执最多包含的消息数量
+const RECEIPT_MAX_MSGS: usize = 100;
+
+// 一次最多转发的消息数量和目标会话数量
+const FORWARD_MAX_MSGS: usize = 100;
+const FORWARD_MAX_CONVERSATIONS: usize = 20;
+
+// 临时事件类型和内容的最大长度
+const EVENT_KIND_MAX_LEN: usize = 32;
+const EVENT_PAYLOAD_MAX_LEN: usize = 1024;
+
+// 最近一次转发临时事件的时间，key为 (账户, 会话, 事件类型).
+// 同一个账户可以在多个连接上发送，所以由所有连接共享
+pub type EventTimes = Arc<Mutex<HashMap<(String, String, String), i64>>>;
+
+// server端转发聊天消息和回执: 根据接收方账户找到其所有在线的连接，把数据推送过去.
+// 接收方没有任何设备在线时保存为离线数据
+pub struct DefaultServerChatModule {
+    user_service: Arc<Service>,
+    chat_service: Arc<ChatService>,
+    login_cache: LoginCache,
+    registry: ConnectionRegistry,
+    chat_config: ChatConfig,
+    event_times: EventTimes,
+}
+
+impl DefaultServerChatModule {
+    pub fn init(
+        user_service: Arc<Service>,
+        chat_service: Arc<ChatService>,
+        login_cache: LoginCache,
+        registry: ConnectionRegistry,
+        event_times: EventTimes,
+    ) -> Self {
+        DefaultServerChatModule {
+            user_service,
+            chat_service,
+            login_cache,
+            registry,
+            chat_config: ChatConfig::init_from_env(),
+            event_times,
+        }
+    }
+
+    // 需要登录并且有发送消息的权限
+    fn find_sender(&self, address: &SocketAddr) -> Result<String, String> {
+        let account = find_account_by_address(&self.login_cache, address)
+            .ok_or("please login first !".to_string())?;
+
+        block_on(
+            self.user_service
+                .check_permission(&account, Permission::SendMessage),
+        )?;
+        Ok(account)
+    }
+
+    // 消息中只能引用自己上传的，或者自己能访问的会话中的附件
+    fn check_attachments(
+        &self,
+        account: &String,
+        contents: &Vec<ChatContent>,
+    ) -> Result<(), String> {
+        for url in find_file_urls(contents) {
+            if let Some(sha256) = get_file_sha256(&url) {
+                block_on(
+                    self.chat_service
+                        .check_attachment_access(account, &sha256.to_string()),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // 两人会话的另一方必须是存在的账户
+    fn check_conversation_peer(&self, account: &str, conversation: &str) -> Result<(), String> {
+        if let Some(peer) = get_conversation_peer(conversation, account) {
+            block_on(self.user_service.dao.find_by_name(peer.clone()))
+                .map_err(|e| e.to_string())?
+                .ok_or(format!("account not exist: {}", peer))?;
+        }
+        Ok(())
+    }
+
+    // 检查消息的内容和接收方，直接发送和定时发送的消息都需要检查
+    fn check_chat_msg(&self, account: &String, data: &mut ChatData) -> Result<(), String> {
+        check_contents(&mut data.contents)?;
+        self.check_attachments(account, &data.contents)?;
+
+        match data.group_id {
+            // 只有群组成员可以发送到群组
+            Some(group_id) => {
+                block_on(self.chat_service.find_group_role(group_id, account))?;
+                data.to_account = String::new();
+            }
+            // 接收方必须是存在的账户，否则离线消息永远不会被投递
+            None => {
+                block_on(self.user_service.dao.find_by_name(data.to_account.clone()))
+                    .map_err(|e| e.to_string())?
+                    .ok_or(format!("account not exist: {}", data.to_account))?;
+            }
+        }
+        Ok(())
+    }
+
+    // 保存并转发已经检查过的消息，msg_id为server分配的消息id.
+    // address为None时是server发送的定时消息，此时也推送到发送方的所有设备
+    fn send_chat_msg(
+        &self,
+        account: String,
+        mut data: ChatData,
+        msg_id: String,
+        address: Option<SocketAddr>,
+    ) -> Result<ChatAckData, String> {
+        // 发送方以当前连接登录的账户为准，不能冒充其它账户.
+        // 消息id由server重新分配，client填写的只作为临时id回复给发送方
+        let client_msg_id = data.msg_id.clone();
+        data.msg_id = msg_id;
+        data.from_account = account;
+        // 回应只能通过Reaction请求修改
+        data.reactions = vec![];
+
+        // 回复的摘要和话题以server保存的消息为准
+        if let Some(reply) = data.reply_to.take() {
+            data.reply_to = Some(block_on(
+                self.chat_service
+                    .find_reply(&data.conversation_id(), reply.msg_id),
+            )?);
+        }
+
+        // 先保存聊天记录并分配会话序号、时间，保存失败时不转发
+        block_on(self.chat_service.save_message(&mut data))?;
+
+        let mut ack = ChatAckData {
+            msg_id: data.msg_id.clone(),
+            client_msg_id,
+            to_account: data.to_account.clone(),
+            seq: data.seq,
+            time: data.time,
+            offline: false,
+        };
+
+        info!(
+            "relay chat msg {} from {} to {}",
+            data.msg_id,
+            data.from_account,
+            data.conversation_id()
+        );
+
+        let from_account = data.from_account.clone();
+        ack.offline = match (data.group_id, address) {
+            (Some(group_id), _) => {
+                !self.relay_to_group(group_id, from_account, address, ChatDataEnum::Msg(data))?
+            }
+            (None, Some(_)) => !self.relay(
+                from_account,
+                data.to_account.clone(),
+                ChatDataEnum::Msg(data),
+            )?,
+            (None, None) => {
+                let accounts = vec![from_account.clone(), data.to_account.clone()];
+                !self.relay_to_accounts(accounts, from_account, None, ChatDataEnum::Msg(data))?
+            }
+        };
+        Ok(ack)
+    }
+
+    // 发送已经到达发送时间的定时消息. 使用schedule_id作为消息id，
+    // 发送之后删除定时消息之前server重启时不会重复发送
+    pub fn send_due_scheduled(&self) {
+        let list = match block_on(
+            self.chat_service
+                .find_due_scheduled(now_millis(), SCHEDULE_BATCH_SIZE),
+        ) {
+            Ok(t) => t,
+            Err(e) => {
+                error!("find due scheduled msgs fail: {}", e);
+                return;
+            }
+        };
+
+        for scheduled in list {
+            let schedule_id = scheduled.schedule_id.clone();
+            match self.send_scheduled(scheduled) {
+                Ok(ack) => info!("send scheduled msg {} to {}", ack.msg_id, ack.to_account),
+                // 发送时不满足条件的消息不再重试，例如已经退出群组
+                Err(e) => warn!("send scheduled msg {} fail: {}", schedule_id, e),
+            }
+
+            if let Err(e) = block_on(self.chat_service.delete_scheduled(schedule_id.clone())) {
+                error!("delete scheduled msg {} fail: {}", schedule_id, e);
+            }
+        }
+    }
+
+    // 发送时重新检查发送方的权限和消息，定时期间可能已经发生变化
+    fn send_scheduled(&self, scheduled: ScheduledData) -> Result<ChatAckData, String> {
+        let account = scheduled.data.from_account.clone();
+        block_on(
+            self.user_service
+                .check_permission(&account, Permission::SendMessage),
+        )?;
+
+        if block_on(
+            self.chat_service
+                .find_message(scheduled.schedule_id.clone()),
+        )
+        .is_ok()
+        {
+            return Err("scheduled msg has been sent !".to_string());
+        }
+
+        let mut data = scheduled.data;
+        self.check_chat_msg(&account, &mut data)?;
+        self.send_chat_msg(account, data, scheduled.schedule_id, None)
+    }
+
+    // 把修改之后的消息推送给会话的所有参与者
+    fn push_updated(&self, data: &ChatData, from_address: SocketAddr) -> Result<(), String> {
+        let from_account = find_account_by_address(&self.login_cache, &from_address)
+            .ok_or("please login first !".to_string())?;
+
+        let accounts = block_on(self.chat_service.find_participants(data))?;
+        self.relay_to_accounts(
+            accounts,
+            from_account,
+            Some(from_address),
+            ChatDataEnum::Updated(data.clone()),
+        )?;
+        Ok(())
+    }
+}
+
+impl ServerChatModule for DefaultServerChatModule {
+    fn handle_chat_msg(
+        &mut self,
+        mut data: ChatData,
+        address: SocketAddr,
+    ) -> Result<ChatAckData, String> {
+        let account = self.find_sender(&address)?;
+
+        // 转发的来源只能由server填写
+        data.forward_from = None;
+        self.check_chat_msg(&account, &mut data)?;
+        self.send_chat_msg(account, data, create_msg_id(), Some(address))
+    }
+
+    fn handle_offline_ack(
+        &mut self,
+        req: OfflineAckData,
+        address: SocketAddr,
+    ) -> Result<(), String> {
+        let account = find_account_by_address(&self.login_cache, &address)
+            .ok_or("please login first !".to_string())?;
+
+        // 删除之前取出被确认的离线消息，代替接收方给发送方回复送达回执
+        let acked: Vec<ChatData> =
+            block_on(self.chat_service.find_offline_messages(account.clone()))?
+                .into_iter()
+                .filter(|(id, _)| req.ids.contains(id))
+                .filter_map(|(_, data)| match data {
+                    ChatDataEnum::Msg(t) => Some(t),
+                    _ => None,
+                })
+                .collect();
+
+        block_on(
+            self.chat_service
+                .delete_offline_messages(account.clone(), req.ids),
+        )?;
+
+        let mut msg_ids_by_sender: HashMap<String, Vec<String>> = HashMap::new();
+        for data in acked {
+            msg_ids_by_sender
+                .entry(data.from_account)
+                .or_default()
+                .push(data.msg_id);
+        }
+
+        for (sender, msg_ids) in msg_ids_by_sender {
+            let receipt = ReceiptData {
+                from_account: account.clone(),
+                to_account: sender.clone(),
+                msg_ids,
+                state: MessageState::Delivered,
+                time: now_millis(),
+            };
+            self.relay(account.clone(), sender, ChatDataEnum::Receipt(receipt))?;
+        }
+        Ok(())
+    }
+
+    fn handle_receipt(&mut self, mut req: ReceiptData, address: SocketAddr) -> Result<(), String> {
+        let account = find_account_by_address(&self.login_cache, &address)
+            .ok_or("please login first !".to_string())?;
+
+        // 只有消息的接收方才能回复回执
+        if req.from_account != account {
+            return Err("can not send receipt for other account !".to_string());
+        }
+
+        if req.state == MessageState::Sent {
+            return Err("receipt state must be Delivered or Read !".to_string());
+        }
+
+        req.msg_ids.sort();
+        req.msg_ids.dedup();
+        if req.msg_ids.is_empty() {
+            return Ok(());
+        }
+        if req.msg_ids.len() > RECEIPT_MAX_MSGS {
+            return Err(format!(
+                "receipt can not contain more than {RECEIPT_MAX_MSGS} messages !"
+            ));
+        }
+
+        // 只能回复to_account发给自己的消息，避免伪造其它消息的回执
+        block_on(
+            self.chat_service
+                .check_receipt_msgs(&account, &req.to_account, &req.msg_ids),
+        )?;
+
+        req.time = now_millis();
+        self.relay(account, req.to_account.clone(), ChatDataEnum::Receipt(req))?;
+        Ok(())
+    }
+
+    fn handle_history_req(
+        &mut self,
+        req: HistoryReqData,
+        address: SocketAddr,
+    ) -> Result<HistoryRespData, String> {
+        let account = find_account_by_address(&self.login_cache, &address)
+            .ok_or("please login first !".to_string())?;
+
+        // 只能查询当前账户参与的会话
+        let conversation = match req.group_id {
+            Some(group_id) => {
+                block_on(self.chat_service.find_group_role(group_id, &account))?;
+                get_group_conversation_id(group_id)
+            }
+            None => get_conversation_id(&account, &req.peer_account),
+        };
+        let limit = match req.limit {
+            0 => DEFAULT_HISTORY_LIMIT,
+            t => t.min(MAX_HISTORY_LIMIT),
+        };
+
+        let (messages, has_more) = block_on(self.chat_service.find_history(
+            conversation,
+            &req.cursor,
+            limit as u64,
+        ))?;
+
+        Ok(HistoryRespData {
+            peer_account: req.peer_account,
+            group_id: req.group_id,
+            cursor: req.cursor,
+            messages,
+            has_more,
+        })
+    }
+
+    fn handle_sync_since(
+        &mut self,
+        req: SyncSinceData,
+        address: SocketAddr,
+    ) -> Result<SyncRespData, String> {
+        let account = find_account_by_address(&self.login_cache, &address)
+            .ok_or("please login first !".to_string())?;
+
+        // 只能同步当前账户参与的会话
+        match get_group_id(&req.conversation) {
+            Some(group_id) => {
+                block_on(self.chat_service.find_group_role(group_id, &account))?;
+            }
+            None => {
+                get_conversation_peer(&req.conversation, &account)
+                    .ok_or(format!("conversation not exist: {}", req.conversation))?;
+            }
+        }
+
+        let (messages, has_more) = block_on(self.chat_service.find_since(
+            req.conversation.clone(),
+            req.seq,
+            MAX_HISTORY_LIMIT as u64,
+        ))?;
+
+        Ok(SyncRespData {
+            conversation: req.conversation,
+            messages,
+            has_more,
+        })
+    }
+
+    fn handle_edit_message(
+        &mut self,
+        mut req: EditMessageData,
+        address: SocketAddr,
+    ) -> Result<ChatData, String> {
+        let account = self.find_sender(&address)?;
+
+        check_contents(&mut req.contents)?;
+        self.check_attachments(&account, &req.contents)?;
+
+        let data = block_on(self.chat_service.edit_message(
+            account,
+            req.msg_id,
+            req.contents,
+            self.chat_config.edit_window_seconds * 1000,
+        ))?;
+
+        info!("chat msg {} edited by {}", data.msg_id, data.from_account);
+        self.push_updated(&data, address)?;
+        Ok(data)
+    }
+
+    fn handle_recall_message(
+        &mut self,
+        req: RecallMessageData,
+        address: SocketAddr,
+    ) -> Result<ChatData, String> {
+        let account = self.find_sender(&address)?;
+
+        // 管理员撤回不受时限和发送方的限制
+        let window_millis = match block_on(
+            self.user_service
+                .check_permission(&account, Permission::ModerateMessage),
+        ) {
+            Ok(_) => None,
+            Err(_) => Some(self.chat_config.recall_window_seconds * 1000),
+        };
+
+        let data = block_on(self.chat_service.recall_message(
+            account.clone(),
+            req.msg_id,
+            window_millis,
+        ))?;
+
+        info!("chat msg {} recalled by {}", data.msg_id, account);
+        self.push_updated(&data, address)?;
+        Ok(data)
+    }
+
+    fn handle_edit_history_req(
+        &mut self,
+        req: EditHistoryReqData,
+        address: SocketAddr,
+    ) -> Result<EditHistoryRespData, String> {
+        let account = find_account_by_address(&self.login_cache, &address)
+            .ok_or("please login first !".to_string())?;
+
+        block_on(
+            self.user_service
+                .check_permission(&account, Permission::ModerateMessage),
+        )?;
+
+        let versions = block_on(self.chat_service.find_edit_history(req.msg_id.clone()))?;
+        Ok(EditHistoryRespData {
+            msg_id: req.msg_id,
+            versions,
+        })
+    }
+
+    fn handle_reaction(
+        &mut self,
+        req: ReactionReqData,
+        address: SocketAddr,
+    ) -> Result<ChatData, String> {
+        let account = self.find_sender(&address)?;
+
+        let data = block_on(self.chat_service.react_message(
+            account.clone(),
+            req.msg_id,
+            req.emoji,
+            req.add,
+        ))?;
+
+        info!("chat msg {} reacted by {}", data.msg_id, account);
+        self.push_updated(&data, address)?;
+        Ok(data)
+    }
+
+    fn handle_thread_req(
+        &mut self,
+        req: ThreadReqData,
+        address: SocketAddr,
+    ) -> Result<ThreadRespData, String> {
+        let account = find_account_by_address(&self.login_cache, &address)
+            .ok_or("please login first !".to_string())?;
+
+        let limit = match req.limit {
+            0 => DEFAULT_HISTORY_LIMIT,
+            t => t.min(MAX_HISTORY_LIMIT),
+        };
+
+        let (root, replies, has_more) = block_on(self.chat_service.find_thread(
+            account,
+            req.msg_id,
+            req.after_seq,
+            limit as u64,
+        ))?;
+
+        Ok(ThreadRespData {
+            root,
+            replies,
+            has_more,
+        })
+    }
+
+    fn handle_event(&mut self, mut req: EventData, address: SocketAddr) -> Result<(), String> {
+        let account = self.find_sender(&address)?;
+        check_event(&req)?;
+
+        // 只能发送到当前账户参与的会话
+        let accounts = match get_group_id(&req.conversation) {
+            Some(group_id) => {
+                block_on(self.chat_service.find_group_role(group_id, &account))?;
+                block_on(self.chat_service.find_group_accounts(group_id))?
+            }
+            None => {
+                let peer = get_conversation_peer(&req.conversation, &account)
+                    .ok_or(format!("conversation not exist: {}", req.conversation))?;
+                vec![account.clone(), peer]
+            }
+        };
+
+        let time = now_millis();
+        let key = (account.clone(), req.conversation.clone(), req.kind.clone());
+        let allowed = check_event_rate(
+            &mut self.event_times.lock().unwrap_or_else(|e| e.into_inner()),
+            key,
+            time,
+        );
+        if !allowed {
+            return Ok(());
+        }
+
+        req.from_account = account;
+        req.time = time;
+        req.ttl_millis = req.ttl_millis.clamp(0, EVENT_MAX_TTL_MILLIS);
+
+        // 临时事件不保存为离线数据，没有在线设备的账户直接忽略
+        let push = BizChatData {
+            chat_type: ChatTypeEnum::Push,
+            data: ChatDataEnum::Event(req),
+        };
+        let bytes = push.to_protocol_bytes();
+        for t in accounts {
+            push_to_account(&self.login_cache, &self.registry, &t, &bytes, Some(address));
+        }
+        Ok(())
+    }
+
+    fn handle_disappear_timer(
+        &mut self,
+        req: DisappearTimerReqData,
+        address: SocketAddr,
+    ) -> Result<ChatData, String> {
+        let account = self.find_sender(&address)?;
+        self.check_conversation_peer(&account, &req.conversation)?;
+
+        let data = block_on(self.chat_service.set_disappear_timer(
+            account.clone(),
+            req.conversation,
+            req.expire_seconds,
+        ))?;
+
+        info!(
+            "disappear timer of {} set to {:?} by {}",
+            data.conversation_id(),
+            req.expire_seconds,
+            account
+        );
+        // 计时器修改的系统消息和普通消息一样推送，包括操作者的其它设备
+        let accounts = block_on(self.chat_service.find_participants(&data))?;
+        self.relay_to_accounts(
+            accounts,
+            account,
+            Some(address),
+            ChatDataEnum::Msg(data.clone()),
+        )?;
+        Ok(data)
+    }
+
+    fn handle_schedule(
+        &mut self,
+        req: ScheduleReqData,
+        address: SocketAddr,
+    ) -> Result<ScheduledData, String> {
+        let account = self.find_sender(&address)?;
+
+        let mut data = req.data;
+        self.check_chat_msg(&account, &mut data)?;
+        data.from_account = account;
+        data.reactions = vec![];
+        data.forward_from = None;
+
+        let scheduled = block_on(self.chat_service.schedule_message(data, req.send_time))?;
+        info!(
+            "chat msg {} scheduled by {} at {}",
+            scheduled.schedule_id, scheduled.data.from_account, scheduled.send_time
+        );
+        Ok(scheduled)
+    }
+
+    fn handle_schedule_edit(
+        &mut self,
+        mut req: ScheduleEditData,
+        address: SocketAddr,
+    ) -> Result<ScheduledData, String> {
+        let account = self.find_sender(&address)?;
+
+        if let Some(contents) = req.contents.as_mut() {
+            check_contents(contents)?;
+            self.check_attachments(&account, contents)?;
+        }
+
+        block_on(self.chat_service.edit_scheduled(
+            account,
+            req.schedule_id,
+            req.contents,
+            req.send_time,
+        ))
+    }
+
+    fn handle_schedule_cancel(
+        &mut self,
+        req: ScheduleCancelData,
+        address: SocketAddr,
+    ) -> Result<ScheduledData, String> {
+        let account = find_account_by_address(&self.login_cache, &address)
+            .ok_or("please login first !".to_string())?;
+
+        let scheduled = block_on(
+            self.chat_service
+                .cancel_scheduled(account.clone(), req.schedule_id),
+        )?;
+        info!(
+            "scheduled msg {} canceled by {}",
+            scheduled.schedule_id, account
+        );
+        Ok(scheduled)
+    }
+
+    fn handle_schedule_list(&mut self, address: SocketAddr) -> Result<Vec<ScheduledData>, String> {
+        let account = find_account_by_address(&self.login_cache, &address)
+            .ok_or("please login first !".to_string())?;
+
+        block_on(self.chat_service.find_scheduled(account))
+    }
+
+    fn handle_metadata_req(
+        &mut self,
+        req: MetadataReqData,
+        address: SocketAddr,
+    ) -> Result<ConversationMetadata, String> {
+        let account = self.find_sender(&address)?;
+
+        block_on(self.chat_service.find_metadata(account, req.conversation))
+    }
+
+    fn handle_update_metadata(
+        &mut self,
+        req: UpdateMetadataData,
+        address: SocketAddr,
+    ) -> Result<ChatData, String> {
+        let account = self.find_sender(&address)?;
+        self.check_conversation_peer(&account, &req.conversation)?;
+
+        let data = block_on(self.chat_service.update_metadata(
+            account.clone(),
+            req.conversation,
+            req.change.clone(),
+        ))?;
+
+        info!(
+            "metadata of {} changed by {}: {:?}",
+            data.conversation_id(),
+            account,
+            req.change
+        );
+        // 与计时器修改相同，系统消息推送给所有参与者，包括操作者的其它设备
+        let accounts = block_on(self.chat_service.find_participants(&data))?;
+        self.relay_to_accounts(
+            accounts,
+            account,
+            Some(address),
+            ChatDataEnum::Msg(data.clone()),
+        )?;
+        Ok(data)
+    }
+
+    fn handle_forward(
+        &mut self,
+        req: ForwardReqData,
+        address: SocketAddr,
+    ) -> Result<Vec<ChatData>, String> {
+        let account = self.find_sender(&address)?;
+
+        if req.msg_ids.is_empty() || req.msg_ids.len() > FORWARD_MAX_MSGS {
+            return Err(format!(
+                "forward msg count must be in 1..={FORWARD_MAX_MSGS} !"
+            ));
+        }
+        if req.conversations.is_empty() || req.conversations.len() > FORWARD_MAX_CONVERSATIONS {
+            return Err(format!(
+                "forward conversation count must be in 1..={FORWARD_MAX_CONVERSATIONS} !"
+            ));
+        }
+
+        let mut sources = vec![];
+        for msg_id in req.msg_ids {
+            sources.push(block_on(
+                self.chat_service.find_forward_source(&account, msg_id),
+            )?);
+        }
+
+        // 先检查所有的消息和目标会话，都满足条件之后才转发.
+        // 附件的访问权限来自源会话，转发之后目标会话的参与者也可以下载
+        let mut list = vec![];
+        for conversation in req.conversations.iter() {
+            for source in sources.iter() {
+                let mut data = create_forward_msg(&account, conversation, source)?;
+                self.check_chat_msg(&account, &mut data)?;
+                list.push(data);
+            }
+        }
+
+        let mut forwarded = vec![];
+        for data in list {
+            let ack = self.send_chat_msg(account.clone(), data, create_msg_id(), Some(address))?;
+            // 返回server保存的消息，包括目标会话的计时器填写的过期时间
+            forwarded.push(block_on(self.chat_service.find_message(ack.msg_id))?);
+        }
+        info!(
+            "{} msgs forwarded by {} to {:?}",
+            sources.len(),
+            account,
+            req.conversations
+        );
+        Ok(forwarded)
+    }
+
+    fn handle_search(
+        &mut self,
+        req: SearchReqData,
+        address: SocketAddr,
+    ) -> Result<SearchRespData, String> {
+        let account = find_account_by_address(&self.login_cache, &address)
+            .ok_or("please login first !".to_string())?;
+
+        if req.text.chars().count() > SEARCH_TEXT_MAX_LEN {
+            return Err(format!(
+                "search text can not exceed {SEARCH_TEXT_MAX_LEN} !"
+            ));
+        }
+        let limit = match req.limit {
+            0 => DEFAULT_SEARCH_LIMIT,
+            t => t.min(MAX_SEARCH_LIMIT),
+        };
+
+        let (messages, has_more) = block_on(self.chat_service.search_messages(
+            account,
+            &req,
+            limit as u64,
+        ))?;
+        Ok(SearchRespData {
+            offset: req.offset,
+            messages,
+            has_more,
+        })
+    }
+}
+
+impl DefaultServerChatModule {
+    // 推送到接收方所有在线的设备，一个都没有推送成功时保存为离线数据.
+    // 返回是否推送成功
+    fn relay(
+        &self,
+        from_account: String,
+        to_account: String,
+        data: ChatDataEnum,
+    ) -> Result<bool, String> {
+        let push = BizChatData {
+            chat_type: ChatTypeEnum::Push,
+            data,
+        };
+
+        let delivered = push_to_account(
+            &self.login_cache,
+            &self.registry,
+            &to_account,
+            &push.to_protocol_bytes(),
+            None,
+        );
+
+        if !delivered {
+            block_on(
+                self.chat_service
+                    .save_offline_message(from_account, to_account, &push.data),
+            )?;
+        }
+        Ok(delivered)
+    }
+
+    // 推送到群组所有成员的设备，返回是否所有成员都推送成功
+    fn relay_to_group(
+        &self,
+        group_id: i32,
+        from_account: String,
+        from_address: Option<SocketAddr>,
+        data: ChatDataEnum,
+    ) -> Result<bool, String> {
+        let accounts = block_on(self.chat_service.find_group_accounts(group_id))?;
+        self.relay_to_accounts(accounts, from_account, from_address, data)
+    }
+
+    // 推送到多个账户的设备，发送方只推送到发送设备之外的其它设备.
+    // 没有设备在线的账户保存为离线数据，返回是否所有账户都推送成功
+    fn relay_to_accounts(
+        &self,
+        accounts: Vec<String>,
+        from_account: String,
+        from_address: Option<SocketAddr>,
+        data: ChatDataEnum,
+    ) -> Result<bool, String> {
+        let push = BizChatData {
+            chat_type: ChatTypeEnum::Push,
+            data,
+        };
+        let bytes = push.to_protocol_bytes();
+
+        let mut all_delivered = true;
+        for account in accounts {
+            let delivered = push_to_account(
+                &self.login_cache,
+                &self.registry,
+                &account,
+                &bytes,
+                from_address,
+            );
+
+            if !delivered && account != from_account {
+                all_delivered = false;
+                block_on(self.chat_service.save_offline_message(
+                    from_account.clone(),
+                    account,
+                    &push.data,
+                ))?;
+            }
+        }
+        Ok(all_delivered)
+    }
+}
+
+// 推送到账户所有在线的设备，exclude为不需要推送的设备. 返回是否至少有一个设备推送成功
+pub fn push_to_account(
+    login_cache: &LoginCache,
+    registry: &ConnectionRegistry,
+    account: &String,
+    bytes: &Vec<u8>,
+    exclude: Option<SocketAddr>,
+) -> bool {
+    let mut delivered = false;
+    for address in find_addresses_by_account(login_cache, account) {
+        if Some(address) == exclude {
+            continue;
+        }
+
+        match registry.send_to(&address, bytes) {
+            Ok(_) => delivered = true,
+            // 连接已经断开但还没有清理，跳过这个设备
+            Err(e) => warn!("push data to {} fail: {}", address, e),
+        }
+    }
+    delivered
+}
+
+// 定时删除过期的阅后即焚消息，client在本地按消息的过期时间删除缓存
+pub fn start_disappear_sweeper(chat_service: Arc<ChatService>) {
+    let interval = Duration::from_secs(ChatConfig::init_from_env().disappear_sweep_seconds);
+
+    loop {
+        thread::sleep(interval);
+
+        match block_on(chat_service.purge_expired_messages(now_millis())) {
+            Ok((0, 0)) => {}
+            Ok((count, offline_count)) => info!(
+                "purge {} expired msgs and {} offline msgs",
+                count, offline_count
+            ),
+            Err(e) => error!("purge expired msgs fail: {}", e),
+        }
+    }
+}
+
+// 读取所有已经保存的消息建立索引，重建期间保存的消息重复添加时会覆盖
+pub fn rebuild_search_index(chat_service: Arc<ChatService>) {
+    match block_on(chat_service.rebuild_search_index()) {
+        Ok(0) => {}
+        Ok(count) => info!("rebuild search index of {} msgs", count),
+        Err(e) => error!("rebuild search index fail: {}", e),
+    }
+}
+
+// 定时提交消息全文索引的修改，保存消息时只写入索引的缓冲
+pub fn start_search_index_committer(chat_service: Arc<ChatService>) {
+    let interval = Duration::from_millis(ChatConfig::init_from_env().search_commit_millis);
+
+    loop {
+        thread::sleep(interval);
+
+        if let Err(e) = chat_service.message_index.flush() {
+            error!("commit search index fail: {}", e);
+        }
+    }
+}
+
+// 定时消息保存在数据库中，server重启之后继续发送. module只用于发送，与连接无关
+pub fn start_message_scheduler(module: DefaultServerChatModule) {
+    loop {
+        thread::sleep(SCHEDULE_CHECK_INTERVAL);
+        module.send_due_scheduled();
+    }
+}
+
+// 登录成功后按保存的顺序投递离线消息. 消息在client确认之后才删除，
+// 投递过程中断开时，下次登录会重新投递
+pub fn push_offline_messages(
+    chat_service: &ChatService,
+    registry: &ConnectionRegistry,
+    account: String,
+    address: SocketAddr,
+) {
+    let list = match block_on(chat_service.find_offline_messages(account.clone())) {
+        Ok(t) => t,
+        Err(e) => {
+            error!("find offline messages of {} fail: {}", account, e);
+            return;
+        }
+    };
+
+    if !list.is_empty() {
+        info!("push {} offline messages to {}", list.len(), account);
+    }
+
+    for (offline_id, data) in list {
+        let push = BizChatData {
+            chat_type: ChatTypeEnum::Push,
+            data: ChatDataEnum::OfflineMsg(OfflineChatData {
+                offline_id,
+                data: Box::new(data),
+            }),
+        };
+
+        if let Err(e) = registry.send_to(&address, &push.to_protocol_bytes()) {
+            warn!("push offline messages to {} fail: {}", account, e);
+            return;
+        }
+    }
+}
+
+fn check_event(req: &EventData) -> Result<(), String> {
+    if req.kind.is_empty() || req.kind.len() > EVENT_KIND_MAX_LEN {
+        return Err(format!(
+            "event kind length must be in 1..={EVENT_KIND_MAX_LEN} !"
+        ));
+    }
+    if req.payload.len() > EVENT_PAYLOAD_MAX_LEN {
+        return Err(format!(
+            "event payload can not exceed {EVENT_PAYLOAD_MAX_LEN} bytes !"
+        ));
+    }
+    Ok(())
+}
+
+// 超过频率限制时返回false. 顺便清理已经超过间隔的记录，避免一直增长
+fn check_event_rate(
+    event_times: &mut HashMap<(String, String, String), i64>,
+    key: (String, String, String),
+    time: i64,
+) -> bool {
+    if let Some(last) = event_times.get(&key) {
+        if time - last < EVENT_MIN_INTERVAL_MILLIS {
+            return false;
+        }
+    }
+
+    event_times.retain(|_, last| time - *last < EVENT_MIN_INTERVAL_MILLIS);
+    event_times.insert(key, time);
+    true
+}
+
+// 复制源消息的内容，来源为源消息最初的发送方
+fn create_forward_msg(
+    account: &str,
+    conversation: &str,
+    source: &ChatData,
+) -> Result<ChatData, String> {
+    let (to_account, group_id) = match get_group_id(conversation) {
+        Some(group_id) => (String::new(), Some(group_id)),
+        None => {
+            let peer = get_conversation_peer(conversation, account)
+                .ok_or(format!("conversation not exist: {}", conversation))?;
+            (peer, None)
+        }
+    };
+
+    let forward_from = source.forward_from.clone().unwrap_or(ForwardData {
+        msg_id: source.msg_id.clone(),
+        from_account: source.from_account.clone(),
+        time: source.time,
+    });
+    Ok(ChatData {
+        msg_id: String::new(),
+        from_account: account.to_string(),
+        to_account,
+        group_id,
+        contents: source.contents.clone(),
+        seq: 0,
+        time: now_millis(),
+        edit_time: None,
+        recall_by: None,
+        reply_to: None,
+        reactions: vec![],
+        expire_time: None,
+        forward_from: Some(forward_from),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_event(kind: &str, payload_len: usize) -> EventData {
+        EventData {
+            conversation: "a_b".to_string(),
+            from_account: String::new(),
+            kind: kind.to_string(),
+            payload: vec![0; payload_len],
+            ttl_millis: 0,
+            time: 0,
+        }
+    }
+
+    #[test]
+    fn check_event_kind_and_payload() {
+        assert!(check_event(&create_event("typing", EVENT_PAYLOAD_MAX_LEN)).is_ok());
+        assert!(check_event(&create_event("", 0)).is_err());
+        assert!(check_event(&create_event(&"k".repeat(EVENT_KIND_MAX_LEN + 1), 0)).is_err());
+        assert!(check_event(&create_event("typing", EVENT_PAYLOAD_MAX_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn limit_event_rate() {
+        let mut event_times = HashMap::new();
+        let key = |kind: &str| ("a".to_string(), "a_b".to_string(), kind.to_string());
+
+        assert!(check_event_rate(&mut event_times, key("typing"), 0));
+        assert!(!check_event_rate(
+            &mut event_times,
+            key("typing"),
+            EVENT_MIN_INTERVAL_MILLIS - 1
+        ));
+        // 不同类型的事件分别限制
+        assert!(check_event_rate(&mut event_times, key("recording"), 10));
+        assert_eq!(event_times.len(), 2);
+
+        // 超过间隔后可以再次发送，过期的记录被清理
+        let time = EVENT_MIN_INTERVAL_MILLIS + 10;
+        assert!(check_event_rate(&mut event_times, key("typing"), time));
+        assert_eq!(event_times.len(), 1);
+        assert_eq!(event_times.get(&key("typing")), Some(&time));
+    }
+
+    fn create_source(forward_from: Option<ForwardData>) -> ChatData {
+        ChatData {
+            msg_id: "m1".to_string(),
+            from_account: "bob".to_string(),
+            to_account: "alice".to_string(),
+            group_id: None,
+            contents: vec![],
+            seq: 3,
+            time: 100,
+            edit_time: Some(200),
+            recall_by: None,
+            reply_to: None,
+            reactions: vec![],
+            expire_time: Some(300),
+            forward_from,
+        }
+    }
+
+    #[test]
+    fn forward_keeps_original_sender() {
+        let source = create_source(None);
+        let data =
+            create_forward_msg("alice", &get_conversation_id("alice", "carol"), &source).unwrap();
+        assert_eq!(data.from_account, "alice");
+        assert_eq!(data.to_account, "carol");
+        assert!(data.group_id.is_none());
+        // 转发的是新消息，不继承源消息的状态
+        assert!(data.edit_time.is_none() && data.expire_time.is_none());
+        let forward_from = data.forward_from.unwrap();
+        assert_eq!(forward_from.msg_id, "m1");
+        assert_eq!(forward_from.from_account, "bob");
+        assert_eq!(forward_from.time, 100);
+
+        // 再次转发时来源仍然是最初的消息
+        let source = create_source(Some(ForwardData {
+            msg_id: "m0".to_string(),
+            from_account: "dave".to_string(),
+            time: 50,
+        }));
+        let data = create_forward_msg("alice", &get_group_conversation_id(7), &source).unwrap();
+        assert_eq!(data.group_id, Some(7));
+        assert!(data.to_account.is_empty());
+        assert_eq!(data.forward_from.unwrap().from_account, "dave");
+
+        // 不是自己参与的两人会话
+        assert!(create_forward_msg("alice", "bob:carol", &source).is_err());
+    }
+}
@@ -0,0 +1,447 @@
+use crate::{block_on, find_account_by_address, LoginCache};
+use common::base::now_millis;
+use common::config::FileConfig;
+use common::file_module::{
+    file_sha256, get_file_sha256, get_file_url, sign_download, DownloadUrlData, DownloadUrlReqData,
+    FileChunkData, ServerFileModule, StartUploadReqData, UploadStateData, FILE_CHUNK_SIZE,
+};
+use common::permission::Permission;
+use log::{error, info};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use userinfo_web::chat_service::ChatService;
+use userinfo_web::userinfo_service::Service;
+
+// 每个分片的大小上限
+const MAX_CHUNK_SIZE: usize = FILE_CHUNK_SIZE * 4;
+
+// sha256的16进制字符串长度
+const SHA256_HEX_LEN: usize = 64;
+
+// 检查长时间没有继续的上传的间隔
+const UPLOAD_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// 进行中的上传，已确认的数据保存在上传目录的 <账户>_<sha256>.part 文件中
+pub struct UploadState {
+    account: String,
+    file_name: String,
+    size: u64,
+    sha256: String,
+    offset: u64,
+    // 最后一次收到请求的时间，超过有效期之后删除
+    last_active: i64,
+}
+
+// 进行中的上传，key为 <账户>_<sha256>. 接收上传的module和定时清理共用
+pub type UploadStates = Arc<Mutex<HashMap<String, UploadState>>>;
+
+// server端接收分片上传的文件. 上传的位置以磁盘上的数据为准，
+// server重启之后client重新开始上传时从已经保存的位置继续
+pub struct DefaultServerFileModule {
+    user_service: Arc<Service>,
+    chat_service: Arc<ChatService>,
+    login_cache: LoginCache,
+    file_config: FileConfig,
+    uploads: UploadStates,
+}
+
+impl DefaultServerFileModule {
+    pub fn init(
+        user_service: Arc<Service>,
+        chat_service: Arc<ChatService>,
+        login_cache: LoginCache,
+        uploads: UploadStates,
+    ) -> Self {
+        DefaultServerFileModule {
+            user_service,
+            chat_service,
+            login_cache,
+            file_config: FileConfig::init_from_env(),
+            uploads,
+        }
+    }
+
+    // 上传文件需要登录并且有发送消息的权限
+    fn find_uploader(&self, address: &SocketAddr) -> Result<String, String> {
+        let account = find_account_by_address(&self.login_cache, address)
+            .ok_or("please login first !".to_string())?;
+
+        block_on(
+            self.user_service
+                .check_permission(&account, Permission::SendMessage),
+        )?;
+        Ok(account)
+    }
+
+    fn part_path(&self, key: &str) -> PathBuf {
+        get_part_path(&self.file_config.upload_dir, key)
+    }
+
+    // 收到全部数据之后校验sha256，校验成功才保存到blob store. 失败时删除已上传的数据.
+    // 调用之前已经从进行中的上传中移除
+    fn finish_upload(&self, key: &str, state: UploadState) -> Result<UploadStateData, String> {
+        let part_path = self.part_path(key);
+
+        let sha256 = file_sha256(&part_path)?;
+        if sha256 != state.sha256 {
+            fs::remove_file(&part_path).map_err(|e| e.to_string())?;
+            return Err("file hash mismatch, please upload again !".to_string());
+        }
+
+        // 内容相同的文件只保存一份
+        block_on(self.chat_service.save_blob(
+            state.account.clone(),
+            sha256.clone(),
+            state.size,
+            &part_path,
+        ))?;
+
+        info!(
+            "file {} upload completed, size: {}, sha256: {}",
+            state.file_name, state.size, sha256
+        );
+        Ok(UploadStateData {
+            offset: state.size,
+            url: Some(get_file_url(&sha256)),
+        })
+    }
+}
+
+impl ServerFileModule for DefaultServerFileModule {
+    fn handle_start_upload(
+        &mut self,
+        req: StartUploadReqData,
+        address: SocketAddr,
+    ) -> Result<UploadStateData, String> {
+        let account = self.find_uploader(&address)?;
+        check_start_upload(&req, self.file_config.max_file_size)?;
+
+        // 之前上传过相同的文件时不需要再上传
+        if block_on(self.chat_service.has_uploaded_blob(&account, &req.sha256))? {
+            return Ok(UploadStateData {
+                offset: req.size,
+                url: Some(get_file_url(&req.sha256)),
+            });
+        }
+
+        fs::create_dir_all(&self.file_config.upload_dir).map_err(|e| e.to_string())?;
+        let key = format!("{}_{}", account, req.sha256);
+        let part_path = self.part_path(&key);
+
+        let mut uploads = self.uploads.lock().unwrap();
+        // 限制每个账户同时进行的上传，避免占满磁盘
+        let count = uploads
+            .iter()
+            .filter(|(k, t)| t.account == account && **k != key)
+            .count();
+        if count >= self.file_config.max_uploads_per_account {
+            return Err(format!(
+                "concurrent uploads can not exceed {} !",
+                self.file_config.max_uploads_per_account
+            ));
+        }
+
+        // 已经保存的数据比声明的大小还多时重新上传
+        let mut offset = fs::metadata(&part_path).map(|t| t.len()).unwrap_or(0);
+        if offset > req.size {
+            fs::remove_file(&part_path).map_err(|e| e.to_string())?;
+            offset = 0;
+        }
+
+        let state = UploadState {
+            account,
+            file_name: req.file_name,
+            size: req.size,
+            sha256: req.sha256,
+            offset,
+            last_active: now_millis(),
+        };
+        if offset == req.size {
+            uploads.remove(&key);
+            drop(uploads);
+            return self.finish_upload(&key, state);
+        }
+        uploads.insert(key, state);
+        Ok(UploadStateData { offset, url: None })
+    }
+
+    fn handle_chunk(
+        &mut self,
+        req: FileChunkData,
+        address: SocketAddr,
+    ) -> Result<UploadStateData, String> {
+        let account = self.find_uploader(&address)?;
+
+        let key = format!("{}_{}", account, req.sha256);
+        let part_path = self.part_path(&key);
+        let mut uploads = self.uploads.lock().unwrap();
+        let state = uploads
+            .get_mut(&key)
+            .ok_or("upload not started !".to_string())?;
+        let offset = check_chunk(state, &req)?;
+
+        // 写入失败时磁盘上可能已经有部分数据，需要重新开始上传从磁盘上的位置继续
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&part_path)
+            .and_then(|mut t| t.write_all(&req.data));
+        if let Err(e) = written {
+            uploads.remove(&key);
+            return Err(e.to_string());
+        }
+
+        state.offset = offset;
+        state.last_active = now_millis();
+        if offset == state.size {
+            let state = uploads.remove(&key).unwrap();
+            drop(uploads);
+            return self.finish_upload(&key, state);
+        }
+        Ok(UploadStateData { offset, url: None })
+    }
+
+    // 下载地址由userinfo-web提供，签名包含账户和过期时间，下载时会再次检查权限
+    fn handle_download_url(
+        &mut self,
+        req: DownloadUrlReqData,
+        address: SocketAddr,
+    ) -> Result<DownloadUrlData, String> {
+        let account = find_account_by_address(&self.login_cache, &address)
+            .ok_or("please login first !".to_string())?;
+
+        let sha256 = get_file_sha256(&req.url)
+            .ok_or(format!("invalid file url: {}", req.url))?
+            .to_string();
+        block_on(self.chat_service.check_attachment_access(&account, &sha256))?;
+
+        let expire_time = now_millis() + self.file_config.url_expire_seconds * 1000;
+        let sign = sign_download(&self.file_config.url_secret, &sha256, &account, expire_time);
+        Ok(DownloadUrlData {
+            url: req.url,
+            download_url: format!(
+                "{}/files/{}?account={}&expires={}&sign={}",
+                self.file_config.download_base_url, sha256, account, expire_time, sign
+            ),
+            expire_time,
+        })
+    }
+}
+
+// 定时删除长时间没有继续的上传和已经保存的数据
+pub fn start_upload_sweeper(uploads: UploadStates) {
+    let file_config = FileConfig::init_from_env();
+    let upload_dir = PathBuf::from(&file_config.upload_dir);
+    let expire_millis = file_config.upload_expire_seconds * 1000;
+
+    loop {
+        thread::sleep(UPLOAD_SWEEP_INTERVAL);
+
+        match sweep_uploads(&uploads, &upload_dir, now_millis(), expire_millis) {
+            Ok(0) => {}
+            Ok(count) => info!("remove {} abandoned uploads", count),
+            Err(e) => error!("remove abandoned uploads fail: {}", e),
+        }
+    }
+}
+
+// 返回删除的数据文件数量. 不在进行中的数据(例如server重启之前留下的)按文件的修改时间判断
+fn sweep_uploads(
+    uploads: &UploadStates,
+    upload_dir: &Path,
+    now: i64,
+    expire_millis: i64,
+) -> Result<usize, String> {
+    let mut uploads = uploads.lock().unwrap();
+    let mut count = 0;
+
+    let expired: Vec<String> = uploads
+        .iter()
+        .filter(|(_, t)| now - t.last_active >= expire_millis)
+        .map(|(k, _)| k.clone())
+        .collect();
+    for key in expired {
+        uploads.remove(&key);
+        let part_path = get_part_path(upload_dir, &key);
+        if part_path.is_file() {
+            fs::remove_file(&part_path).map_err(|e| e.to_string())?;
+            count += 1;
+        }
+    }
+
+    if !upload_dir.is_dir() {
+        return Ok(count);
+    }
+    for entry in fs::read_dir(upload_dir).map_err(|e| e.to_string())? {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        let key = match path.file_name().and_then(|t| t.to_str()) {
+            Some(t) => match t.strip_suffix(".part") {
+                Some(t) => t.to_string(),
+                None => continue,
+            },
+            None => continue,
+        };
+        if uploads.contains_key(&key) {
+            continue;
+        }
+
+        let modified = fs::metadata(&path)
+            .and_then(|t| t.modified())
+            .map_err(|e| e.to_string())?;
+        let age = SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        if age >= expire_millis {
+            fs::remove_file(&path).map_err(|e| e.to_string())?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+fn get_part_path<P: AsRef<Path>>(upload_dir: P, key: &str) -> PathBuf {
+    upload_dir.as_ref().join(format!("{}.part", key))
+}
+
+fn check_start_upload(req: &StartUploadReqData, max_file_size: u64) -> Result<(), String> {
+    if req.file_name.trim().is_empty() {
+        return Err("file name can not be empty !".to_string());
+    }
+    if req.size == 0 || req.size > max_file_size {
+        return Err(format!("file size must be in 1..={} !", max_file_size));
+    }
+    // sha256作为文件名的一部分，只能是小写的16进制字符串
+    if req.sha256.len() != SHA256_HEX_LEN
+        || !req
+            .sha256
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+    {
+        return Err("invalid file sha256 !".to_string());
+    }
+    Ok(())
+}
+
+// 返回写入分片之后的位置. 只接收紧接着已确认位置的分片，client需要重新开始上传获取正确的位置
+fn check_chunk(state: &UploadState, req: &FileChunkData) -> Result<u64, String> {
+    if req.offset != state.offset {
+        return Err(format!(
+            "chunk offset {} does not match {} !",
+            req.offset, state.offset
+        ));
+    }
+    if req.data.is_empty() || req.data.len() > MAX_CHUNK_SIZE {
+        return Err(format!("chunk size must be in 1..={MAX_CHUNK_SIZE} !"));
+    }
+    let offset = state.offset + req.data.len() as u64;
+    if offset > state.size {
+        return Err("chunk exceeds the declared file size !".to_string());
+    }
+    Ok(offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_start_upload_req() {
+        let mut req = StartUploadReqData {
+            file_name: "a.txt".to_string(),
+            size: 10,
+            sha256: "a".repeat(SHA256_HEX_LEN),
+        };
+        assert!(check_start_upload(&req, 10).is_ok());
+        assert!(check_start_upload(&req, 9).is_err());
+
+        req.sha256 = "A".repeat(SHA256_HEX_LEN);
+        assert!(check_start_upload(&req, 10).is_err());
+        // 防止路径穿越
+        req.sha256 = format!("../{}", "a".repeat(SHA256_HEX_LEN - 3));
+        assert!(check_start_upload(&req, 10).is_err());
+
+        req.sha256 = "a".repeat(SHA256_HEX_LEN);
+        req.file_name = " ".to_string();
+        assert!(check_start_upload(&req, 10).is_err());
+    }
+
+    #[test]
+    fn accept_chunks_at_confirmed_offset() {
+        let state = UploadState {
+            account: "alice".to_string(),
+            file_name: "a.txt".to_string(),
+            size: 10,
+            sha256: String::new(),
+            offset: 4,
+            last_active: 0,
+        };
+        let chunk = |offset: u64, len: usize| FileChunkData {
+            sha256: String::new(),
+            offset,
+            data: vec![0; len],
+        };
+
+        assert_eq!(check_chunk(&state, &chunk(4, 3)), Ok(7));
+        assert_eq!(check_chunk(&state, &chunk(4, 6)), Ok(10));
+        assert!(check_chunk(&state, &chunk(0, 3)).is_err());
+        assert!(check_chunk(&state, &chunk(4, 0)).is_err());
+        assert!(check_chunk(&state, &chunk(4, 7)).is_err());
+    }
+
+    #[test]
+    fn sweep_abandoned_uploads() {
+        let dir = std::env::temp_dir().join(format!("upload_sweeper_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+
+        let state = |last_active: i64| UploadState {
+            account: "alice".to_string(),
+            file_name: "a.txt".to_string(),
+            size: 10,
+            sha256: String::new(),
+            offset: 1,
+            last_active,
+        };
+        let uploads: UploadStates = Default::default();
+        uploads
+            .lock()
+            .unwrap()
+            .insert("alice_1".to_string(), state(9_000));
+        uploads
+            .lock()
+            .unwrap()
+            .insert("alice_2".to_string(), state(1_000));
+        for name in ["alice_1.part", "alice_2.part", "bob_3.part", "other.txt"] {
+            fs::write(dir.join(name), b"a").unwrap();
+        }
+
+        // 过期的上传连同数据一起删除，刚写入的数据保留
+        assert_eq!(sweep_uploads(&uploads, &dir, 10_000, 5_000), Ok(1));
+        assert!(uploads.lock().unwrap().contains_key("alice_1"));
+        assert!(!uploads.lock().unwrap().contains_key("alice_2"));
+        assert!(!dir.join("alice_2.part").exists());
+        assert!(dir.join("bob_3.part").exists());
+
+        // 不在进行中的数据按修改时间判断
+        let old = SystemTime::now() - Duration::from_secs(10);
+        fs::File::options()
+            .write(true)
+            .open(dir.join("bob_3.part"))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        assert_eq!(sweep_uploads(&uploads, &dir, 10_000, 5_000), Ok(1));
+        assert!(!dir.join("bob_3.part").exists());
+        assert!(dir.join("alice_1.part").exists());
+        assert!(dir.join("other.txt").exists());
+        fs::remove_dir_all(&dir).ok();
+    }
+}
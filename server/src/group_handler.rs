@@ -0,0 +1,265 @@
+use crate::chat_handler::push_to_account;
+use crate::{block_on, find_account_by_address, LoginCache};
+use common::base::{now_millis, ConnectionRegistry};
+use common::group_module::{
+    BizGroupData, CreateGroupReqData, GroupDataEnum, GroupEventData, GroupIdReqData, GroupInfoData,
+    GroupMembersReqData, GroupRespData, GroupTypeEnum, RenameGroupReqData, ServerGroupModule,
+    SetGroupRoleReqData,
+};
+use common::permission::Permission;
+use log::info;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use userinfo_web::chat_service::ChatService;
+use userinfo_web::userinfo_service::Service;
+
+// server端管理群组，群组发生变化时把事件推送给所有成员在线的设备.
+// 事件不保存为离线数据，成员登录后可以查询群组列表获取最新的群组
+pub struct DefaultServerGroupModule {
+    user_service: Arc<Service>,
+    chat_service: Arc<ChatService>,
+    login_cache: LoginCache,
+    registry: ConnectionRegistry,
+}
+
+impl DefaultServerGroupModule {
+    pub fn init(
+        user_service: Arc<Service>,
+        chat_service: Arc<ChatService>,
+        login_cache: LoginCache,
+        registry: ConnectionRegistry,
+    ) -> Self {
+        DefaultServerGroupModule {
+            user_service,
+            chat_service,
+            login_cache,
+            registry,
+        }
+    }
+
+    // 群组操作需要登录并且有发送消息的权限
+    fn find_operator(&self, address: &SocketAddr) -> Result<String, String> {
+        let account = find_account_by_address(&self.login_cache, address)
+            .ok_or("please login first !".to_string())?;
+
+        block_on(
+            self.user_service
+                .check_permission(&account, Permission::SendMessage),
+        )?;
+        Ok(account)
+    }
+
+    // 推送给群组当前的成员，以及extra中已经不是成员的账户(被踢出、退出)
+    fn push_event(
+        &self,
+        group_type: GroupTypeEnum,
+        group: GroupInfoData,
+        operator: String,
+        accounts: Vec<String>,
+        extra: Vec<String>,
+    ) {
+        let mut targets: Vec<String> = group.members.iter().map(|t| t.account.clone()).collect();
+        targets.extend(extra);
+
+        info!(
+            "group {} {:?} by {}, accounts: {:?}",
+            group.group_id, group_type, operator, accounts
+        );
+
+        let push = BizGroupData {
+            group_type,
+            data: GroupDataEnum::Event(GroupEventData {
+                group,
+                operator,
+                accounts,
+                time: now_millis(),
+            }),
+        };
+        let bytes = push.to_protocol_bytes();
+
+        for account in targets {
+            push_to_account(&self.login_cache, &self.registry, &account, &bytes, None);
+        }
+    }
+}
+
+impl ServerGroupModule for DefaultServerGroupModule {
+    fn handle_create(
+        &mut self,
+        req: CreateGroupReqData,
+        address: SocketAddr,
+    ) -> Result<GroupRespData, String> {
+        let operator = self.find_operator(&address)?;
+
+        let group = block_on(self.chat_service.create_group(
+            operator.clone(),
+            req.name,
+            req.members,
+        ))?;
+
+        let accounts = group.members.iter().map(|t| t.account.clone()).collect();
+        self.push_event(
+            GroupTypeEnum::Create,
+            group.clone(),
+            operator,
+            accounts,
+            vec![],
+        );
+        Ok(GroupRespData {
+            groups: vec![group],
+        })
+    }
+
+    fn handle_rename(
+        &mut self,
+        req: RenameGroupReqData,
+        address: SocketAddr,
+    ) -> Result<GroupRespData, String> {
+        let operator = self.find_operator(&address)?;
+
+        let group = block_on(self.chat_service.rename_group(
+            operator.clone(),
+            req.group_id,
+            req.name,
+        ))?;
+
+        self.push_event(
+            GroupTypeEnum::Rename,
+            group.clone(),
+            operator,
+            vec![],
+            vec![],
+        );
+        Ok(GroupRespData {
+            groups: vec![group],
+        })
+    }
+
+    fn handle_invite(
+        &mut self,
+        req: GroupMembersReqData,
+        address: SocketAddr,
+    ) -> Result<GroupRespData, String> {
+        let operator = self.find_operator(&address)?;
+
+        let (group, added) = block_on(self.chat_service.invite_group_members(
+            operator.clone(),
+            req.group_id,
+            req.accounts,
+        ))?;
+
+        if !added.is_empty() {
+            self.push_event(
+                GroupTypeEnum::Invite,
+                group.clone(),
+                operator,
+                added,
+                vec![],
+            );
+        }
+        Ok(GroupRespData {
+            groups: vec![group],
+        })
+    }
+
+    fn handle_kick(
+        &mut self,
+        req: GroupMembersReqData,
+        address: SocketAddr,
+    ) -> Result<GroupRespData, String> {
+        let operator = self.find_operator(&address)?;
+
+        let (group, removed) = block_on(self.chat_service.kick_group_members(
+            operator.clone(),
+            req.group_id,
+            req.accounts,
+        ))?;
+
+        if !removed.is_empty() {
+            self.push_event(
+                GroupTypeEnum::Kick,
+                group.clone(),
+                operator,
+                removed.clone(),
+                removed,
+            );
+        }
+        Ok(GroupRespData {
+            groups: vec![group],
+        })
+    }
+
+    fn handle_leave(
+        &mut self,
+        req: GroupIdReqData,
+        address: SocketAddr,
+    ) -> Result<GroupRespData, String> {
+        let operator = self.find_operator(&address)?;
+
+        let group = block_on(
+            self.chat_service
+                .leave_group(operator.clone(), req.group_id),
+        )?
+        // 最后一个成员退出，群组已经解散
+        .unwrap_or(GroupInfoData {
+            group_id: req.group_id,
+            name: String::new(),
+            members: vec![],
+        });
+
+        self.push_event(
+            GroupTypeEnum::Leave,
+            group,
+            operator.clone(),
+            vec![operator.clone()],
+            vec![operator],
+        );
+        Ok(GroupRespData { groups: vec![] })
+    }
+
+    fn handle_set_role(
+        &mut self,
+        req: SetGroupRoleReqData,
+        address: SocketAddr,
+    ) -> Result<GroupRespData, String> {
+        let operator = self.find_operator(&address)?;
+
+        let group = block_on(self.chat_service.set_group_role(
+            operator.clone(),
+            req.group_id,
+            req.account.clone(),
+            req.role,
+        ))?;
+
+        self.push_event(
+            GroupTypeEnum::SetRole,
+            group.clone(),
+            operator,
+            vec![req.account],
+            vec![],
+        );
+        Ok(GroupRespData {
+            groups: vec![group],
+        })
+    }
+
+    fn handle_info(
+        &mut self,
+        req: GroupIdReqData,
+        address: SocketAddr,
+    ) -> Result<GroupRespData, String> {
+        let operator = self.find_operator(&address)?;
+
+        let group = block_on(self.chat_service.find_group(operator, req.group_id))?;
+        Ok(GroupRespData {
+            groups: vec![group],
+        })
+    }
+
+    fn handle_list(&mut self, address: SocketAddr) -> Result<GroupRespData, String> {
+        let operator = self.find_operator(&address)?;
+
+        let groups = block_on(self.chat_service.find_groups_by_account(operator))?;
+        Ok(GroupRespData { groups })
+    }
+}
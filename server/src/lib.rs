@@ -1,80 +1,419 @@
-use common::base::TcpServerSide;
-use common::chat_module::ChatData;
+use crate::account_handler::DefaultServerAccountModule;
+use crate::chat_handler::{
+    push_offline_messages, rebuild_search_index, start_disappear_sweeper, start_message_scheduler,
+    start_search_index_committer, DefaultServerChatModule, EventTimes,
+};
+use crate::file_handler::{start_upload_sweeper, DefaultServerFileModule, UploadStates};
+use crate::group_handler::DefaultServerGroupModule;
+use crate::login_guard::{IpLoginGuard, SharedIpLoginGuard};
+use crate::scope_guard::{ApiKeyScopeCache, ScopedHandler};
+use common::account_module::DefaultAccountHandler;
+use common::base::{now_millis, ConnectionRegistry, TcpServerSide};
+use common::chat_module::DefaultChatHandler;
 use common::chat_protocol::ChatCommand;
-use common::config::TcpSocketConfig;
-use common::login_module::{DefaultLoginHandler, LoginReqData, LoginRespData, ServerLoginModule};
+use common::config::{
+    ChatConfig, FileConfig, LoginGuardConfig, ServerLoginConfig, TcpSocketConfig,
+};
+use common::file_module::DefaultFileHandler;
+use common::group_module::DefaultGroupHandler;
+use common::login_module::{
+    ApiKeyReqData, DefaultLoginHandler, LoginReqData, LoginRespData, LoginStepResult,
+    ScramChallengeData, ScramProofReqData, ScramStartReqData, ServerLoginModule, TotpChallengeData,
+    TotpReqData,
+};
 use common::p2p_module::{GetIpV4Req, P2pData};
 use common::protocol_factory::{HandleProtocolFactory, HandlerProtocolData};
+use common::scram;
 use env_logger::Env;
 use log::{error, info, warn};
 use std::collections::HashMap;
+use std::future::Future;
 use std::net::SocketAddr;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::{env, thread};
-use userinfo_web::sea_orm::Database;
+use userinfo_web::api_key_dao::ApiKeyDao;
+use userinfo_web::audit_log_dao::{AuditLogDao, AUDIT_EVENT_IP_LOCK};
+use userinfo_web::blob_store::LocalBlobStore;
+use userinfo_web::chat_group_dao::ChatGroupDao;
+use userinfo_web::chat_message_dao::ChatMessageDao;
+use userinfo_web::chat_service::ChatService;
+use userinfo_web::conversation_dao::ConversationDao;
+use userinfo_web::conversation_pin_dao::ConversationPinDao;
+use userinfo_web::entity::userinfo;
+use userinfo_web::file_blob_dao::FileBlobDao;
+use userinfo_web::group_member_dao::GroupMemberDao;
+use userinfo_web::message_attachment_dao::MessageAttachmentDao;
+use userinfo_web::message_edit_dao::MessageEditDao;
+use userinfo_web::message_index::MessageIndex;
+use userinfo_web::message_reaction_dao::MessageReactionDao;
+use userinfo_web::offline_message_dao::OfflineMessageDao;
+use userinfo_web::scheduled_message_dao::ScheduledMessageDao;
+use userinfo_web::sea_orm::{Database, DatabaseConnection};
+use userinfo_web::totp;
 use userinfo_web::userinfo_dao::Dao;
 use userinfo_web::userinfo_service::Service;
 
+mod account_handler;
+mod chat_handler;
+mod file_handler;
+mod group_handler;
+mod login_guard;
+mod scope_guard;
+
+// 两步验证临时凭证的长度和有效期
+const TOTP_TICKET_LEN: usize = 32;
+const TOTP_TICKET_EXPIRE_MILLIS: i64 = 5 * 60 * 1000;
+
+// scram登录从第一步到提交proof的有效期
+pub(crate) const SCRAM_EXPIRE_MILLIS: i64 = 60 * 1000;
+
+// 已登录的socket地址与账户的映射，各个handler共享.
+// 同一个账户可以在多个设备(连接)上同时登录
+pub type LoginCache = Arc<RwLock<HashMap<SocketAddr, String>>>;
+
 pub struct DefaultServerLoginModule {
     user_service: Arc<Service>,
-    login_cache: HashMap<String, SocketAddr>,
+    chat_service: Arc<ChatService>,
+    registry: ConnectionRegistry,
+    login_cache: LoginCache,
+    scope_cache: ApiKeyScopeCache,
+    ip_guard: SharedIpLoginGuard,
+    guard_config: LoginGuardConfig,
+    login_config: ServerLoginConfig,
+    // 密码校验通过、等待提交两步验证码的登录，key为临时凭证
+    totp_pending: HashMap<String, PendingTotp>,
+    // 已返回challenge、等待提交proof的scram登录，key为nonce
+    scram_pending: HashMap<String, PendingScram>,
+}
+
+// 登录和修改密码时已返回challenge、等待提交proof的scram交互
+pub(crate) struct PendingScram {
+    account: String,
+    salt: String,
+    iterations: u32,
+    address: SocketAddr,
+    expire_time: i64,
+}
+
+struct PendingTotp {
+    account: String,
+    address: SocketAddr,
+    expire_time: i64,
 }
 
 impl DefaultServerLoginModule {
-    fn init(user_service: Arc<Service>) -> Self {
+    fn init(
+        user_service: Arc<Service>,
+        chat_service: Arc<ChatService>,
+        registry: ConnectionRegistry,
+        login_cache: LoginCache,
+        scope_cache: ApiKeyScopeCache,
+        ip_guard: SharedIpLoginGuard,
+    ) -> Self {
+        let guard_config = ip_guard.lock().unwrap().get_config().clone();
         DefaultServerLoginModule {
             user_service,
-            login_cache: Default::default(),
+            chat_service,
+            registry,
+            login_cache,
+            scope_cache,
+            ip_guard,
+            guard_config,
+            login_config: ServerLoginConfig::init_from_env(),
+            totp_pending: Default::default(),
+            scram_pending: Default::default(),
         }
     }
 
+    // 密码校验通过. 开启了两步验证时生成临时凭证，等待client提交验证码
+    fn on_pwd_verified(&mut self, model: userinfo::Model, address: SocketAddr) -> LoginStepResult {
+        if !model.totp_enabled {
+            return LoginStepResult::Success(self.on_login_success(model, address));
+        }
+
+        let ticket = totp::random_token(TOTP_TICKET_LEN);
+        self.remove_expired_totp_ticket();
+        self.totp_pending.insert(
+            ticket.clone(),
+            PendingTotp {
+                account: model.name.clone(),
+                address,
+                expire_time: now_millis() + TOTP_TICKET_EXPIRE_MILLIS,
+            },
+        );
+
+        LoginStepResult::TotpRequired(TotpChallengeData {
+            account: model.name,
+            ticket,
+        })
+    }
+
+    fn on_login_success(&mut self, model: userinfo::Model, address: SocketAddr) -> LoginRespData {
+        // todo: 生成token
+        let token = "token".to_string();
+        self.ip_guard.lock().unwrap().record_success(&address.ip());
+        // insert cache
+        self.update_cache(address, model.name.clone());
+        // 同一个地址之前可能使用api key登录过，重新登录后以本次登录为准
+        self.scope_cache.write().unwrap().remove(&address);
+        // 投递不在线期间收到的消息
+        push_offline_messages(
+            &self.chat_service,
+            &self.registry,
+            model.name.clone(),
+            address,
+        );
+        LoginRespData {
+            user_id: model.id,
+            account: model.name,
+            token,
+        }
+    }
+
+    // 检查来源ip当前是否允许尝试登录
+    fn check_ip(&self, address: &SocketAddr) -> Result<(), String> {
+        self.ip_guard
+            .lock()
+            .unwrap()
+            .check(&address.ip(), now_millis())
+    }
+
+    fn on_login_fail(&mut self, account: &str, address: SocketAddr) {
+        record_ip_login_fail(&self.ip_guard, &self.user_service, account, address);
+    }
+
+    fn remove_expired_totp_ticket(&mut self) {
+        let now = now_millis();
+        self.totp_pending.retain(|_, v| v.expire_time > now);
+    }
+
     fn update_cache(&mut self, address: SocketAddr, account: String) {
-        self.login_cache.insert(account, address).unwrap();
+        self.login_cache.write().unwrap().insert(address, account);
     }
+}
 
-    fn find_address_by_account_from_cache(&self, account: &String) -> SocketAddr {
-        self.login_cache.get(account).unwrap().clone()
+// 记录ip维度的失败次数，ip被锁定时记录审计日志. 登录和修改密码校验旧密码失败时调用
+pub(crate) fn record_ip_login_fail(
+    ip_guard: &SharedIpLoginGuard,
+    user_service: &Service,
+    account: &str,
+    address: SocketAddr,
+) {
+    let ip = address.ip();
+    let now = now_millis();
+    if ip_guard.lock().unwrap().record_fail(ip, now) {
+        warn!("ip:{} is locked because of too many failed login", ip);
+        let audit = user_service.audit_log_dao.insert(
+            account.to_string(),
+            AUDIT_EVENT_IP_LOCK,
+            address.to_string(),
+            None,
+            now,
+        );
+        if let Err(t) = block_on(audit) {
+            error!("insert audit log fail: {}", t);
+        }
     }
 }
 
+// 根据socket地址查找已登录的账户
+pub fn find_account_by_address(login_cache: &LoginCache, address: &SocketAddr) -> Option<String> {
+    login_cache.read().unwrap().get(address).cloned()
+}
+
+// 查找账户所有在线设备的socket地址
+pub fn find_addresses_by_account(login_cache: &LoginCache, account: &String) -> Vec<SocketAddr> {
+    login_cache
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(_, v)| *v == account)
+        .map(|(k, _)| *k)
+        .collect()
+}
+
+// 在当前线程中执行async函数
+pub(crate) fn block_on<F: Future>(f: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(f)
+}
+
 impl ServerLoginModule for DefaultServerLoginModule {
     fn handle_login_req(
         &mut self,
         req: LoginReqData,
         address: SocketAddr,
+    ) -> Result<LoginStepResult, String> {
+        if !self.login_config.allow_plain_pwd {
+            return Err("plain password login is disabled, please upgrade client !".to_string());
+        }
+
+        let user_service_ref = Arc::clone(&self.user_service);
+
+        // 先检查来源ip是否被限制
+        self.check_ip(&address)?;
+
+        // get account info. 账户维度的锁定在service中处理
+        let account_info =
+            block_on(user_service_ref.login(&req, address.to_string(), &self.guard_config));
+
+        match account_info {
+            Ok(model) => Ok(self.on_pwd_verified(model, address)),
+
+            Err(e) => {
+                self.on_login_fail(&req.account, address);
+                Err(e)
+            }
+        }
+    }
+
+    fn handle_scram_start_req(
+        &mut self,
+        req: ScramStartReqData,
+        address: SocketAddr,
+    ) -> Result<ScramChallengeData, String> {
+        self.check_ip(&address)?;
+
+        if req.client_nonce.len() < scram::SCRAM_NONCE_LEN {
+            return Err("client nonce is too short !".to_string());
+        }
+
+        let (salt, iterations) = block_on(self.user_service.find_scram_params(&req.account))?;
+
+        let nonce = format!("{}{}", req.client_nonce, scram::create_nonce());
+
+        let now = now_millis();
+        self.scram_pending.retain(|_, v| v.expire_time > now);
+        self.scram_pending.insert(
+            nonce.clone(),
+            PendingScram {
+                account: req.account,
+                salt: salt.clone(),
+                iterations,
+                address,
+                expire_time: now + SCRAM_EXPIRE_MILLIS,
+            },
+        );
+
+        Ok(ScramChallengeData {
+            salt,
+            iterations,
+            nonce,
+        })
+    }
+
+    fn handle_scram_proof_req(
+        &mut self,
+        req: ScramProofReqData,
+        address: SocketAddr,
+    ) -> Result<(String, LoginStepResult), String> {
+        self.check_ip(&address)?;
+
+        // nonce只能使用一次，且必须是同一个连接、同一个账户
+        let pending = self
+            .scram_pending
+            .remove(&req.nonce)
+            .filter(|t| t.expire_time > now_millis())
+            .filter(|t| t.address == address && t.account == req.account)
+            .ok_or("login nonce is invalid or expired, please login again !".to_string())?;
+
+        let auth_message = scram::auth_message(
+            &pending.account,
+            &req.nonce,
+            &pending.salt,
+            pending.iterations,
+        );
+
+        let user_service_ref = Arc::clone(&self.user_service);
+
+        let account_info = block_on(user_service_ref.login_by_scram(
+            pending.account,
+            auth_message,
+            req.proof,
+            address.to_string(),
+            &self.guard_config,
+        ));
+
+        match account_info {
+            Ok((model, server_signature)) => {
+                Ok((server_signature, self.on_pwd_verified(model, address)))
+            }
+            Err(e) => {
+                self.on_login_fail(&req.account, address);
+                Err(e)
+            }
+        }
+    }
+
+    fn handle_totp_req(
+        &mut self,
+        req: TotpReqData,
+        address: SocketAddr,
     ) -> Result<LoginRespData, String> {
+        self.check_ip(&address)?;
+
+        // 临时凭证只能使用一次，且必须是同一个连接、同一个账户
+        let pending = self
+            .totp_pending
+            .remove(&req.ticket)
+            .filter(|t| t.expire_time > now_millis())
+            .filter(|t| t.address == address && t.account == req.account)
+            .ok_or("login ticket is invalid or expired, please login again !".to_string())?;
+
         let user_service_ref = Arc::clone(&self.user_service);
 
-        // get account info
-        let account_info = tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .unwrap()
-            .block_on(user_service_ref.find_by_account_and_pwd(&req));
-
-        let result = match account_info {
-            Ok(t) => {
-                // todo: 生成token
-                let token = "token".to_string();
-                if t.is_some() {
-                    let model = t.unwrap();
-                    let resp = LoginRespData {
-                        user_id: model.id.clone(),
-                        account: model.name.clone(),
-                        token,
-                    };
-                    // insert cache
-                    self.login_cache.insert(model.name.clone(), address);
-                    Ok(resp)
-                } else {
-                    Err("login fail : account of password err !".to_string())
-                }
+        let account_info = block_on(user_service_ref.login_by_totp(
+            pending.account,
+            req.code,
+            address.to_string(),
+            &self.guard_config,
+        ));
+
+        match account_info {
+            Ok(model) => Ok(self.on_login_success(model, address)),
+            Err(e) => {
+                self.on_login_fail(&req.account, address);
+                Err(e)
             }
+        }
+    }
 
-            Err(e) => Err(e),
-        };
+    fn handle_api_key_req(
+        &mut self,
+        req: ApiKeyReqData,
+        address: SocketAddr,
+    ) -> Result<LoginRespData, String> {
+        self.check_ip(&address)?;
 
-        return result;
+        let user_service_ref = Arc::clone(&self.user_service);
+
+        let account_info = block_on(user_service_ref.login_by_api_key(
+            req.account.clone(),
+            req.api_key,
+            &self.guard_config,
+        ));
+
+        match account_info {
+            Ok((model, scopes)) => {
+                let resp = self.on_login_success(model, address);
+                // 之后的请求只能使用key授权过的command
+                self.scope_cache.write().unwrap().insert(address, scopes);
+                Ok(resp)
+            }
+            Err(e) => {
+                self.on_login_fail(&req.account, address);
+                Err(e)
+            }
+        }
+    }
+
+    fn handle_disconnect(&mut self, address: SocketAddr) {
+        // 只删除该连接的记录，同一个账户在其它设备上的登录不受影响
+        self.login_cache.write().unwrap().remove(&address);
+        self.scope_cache.write().unwrap().remove(&address);
     }
 }
 
@@ -88,19 +427,44 @@ pub fn start_server() {
     // start trace info collect.  开启堆栈信息收集
     // tracing_subscriber::fmt::init();
 
-    let service = Arc::new(init_user_info_service());
+    let conn = connect_database();
+    let service = Arc::new(init_user_info_service(conn.clone()));
+    let chat_service = Arc::new(init_chat_service(conn));
+
+    // 旧账户的明文密码转换为scram校验数据，需要在开始接收登录之前完成
+    match block_on(service.migrate_plain_pwd()) {
+        Ok(0) => {}
+        Ok(count) => info!("migrate plain password of {} accounts", count),
+        Err(e) => error!("migrate plain password fail: {}", e),
+    }
 
     let service_cp = Arc::clone(&service);
+    let chat_service_cp = Arc::clone(&chat_service);
 
     // 开启用户信息的web服务
     let userinfo_web_task = thread::spawn(|| {
-        userinfo_web::start_webserver_userinfo(service_cp).expect("webserver start fail!")
+        userinfo_web::start_webserver_userinfo(service_cp, chat_service_cp)
+            .expect("webserver start fail!")
     });
 
     let service_cp2 = Arc::clone(&service);
 
+    // 定时删除过期的阅后即焚消息
+    let chat_service_cp2 = Arc::clone(&chat_service);
+    thread::spawn(move || start_disappear_sweeper(chat_service_cp2));
+
+    // 索引为空时为已经保存的消息建立搜索索引，需要在开始接收消息之前判断
+    if chat_service.message_index.is_empty() {
+        let chat_service_cp3 = Arc::clone(&chat_service);
+        thread::spawn(move || rebuild_search_index(chat_service_cp3));
+    }
+
+    // 定时提交搜索索引
+    let chat_service_cp4 = Arc::clone(&chat_service);
+    thread::spawn(move || start_search_index_committer(chat_service_cp4));
+
     // 开启socket服务
-    let socket_task = thread::spawn(|| start_socket(service_cp2));
+    let socket_task = thread::spawn(|| start_socket(service_cp2, chat_service));
 
     userinfo_web_task
         .join()
@@ -109,65 +473,251 @@ pub fn start_server() {
     socket_task.join().expect("socket_task fail!");
 }
 
-fn init_user_info_service() -> Service {
+fn connect_database() -> DatabaseConnection {
     // get env vars   读取.env文件中的变量，相当于读取配置文件
     dotenvy::dotenv().ok();
 
     let db_url = env::var("DATABASE_URL").expect("DATABASE_URL is not set in .env file");
 
     // establish connection to database.   建立与数据的链接
-    let conn = tokio::runtime::Builder::new_current_thread()
+    tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()
         .unwrap()
-        .block_on(async { Database::connect(&db_url).await.unwrap() });
+        .block_on(async { Database::connect(&db_url).await.unwrap() })
+}
 
-    let dao = Dao { db: conn };
-    Service { dao }
+fn init_user_info_service(conn: DatabaseConnection) -> Service {
+    let dao = Dao { db: conn.clone() };
+    let audit_log_dao = AuditLogDao { db: conn.clone() };
+    let api_key_dao = ApiKeyDao { db: conn };
+    Service {
+        dao,
+        audit_log_dao,
+        api_key_dao,
+        fake_salt_key: totp::random_token(32),
+    }
+}
+
+fn init_chat_service(conn: DatabaseConnection) -> ChatService {
+    ChatService {
+        offline_message_dao: OfflineMessageDao { db: conn.clone() },
+        chat_message_dao: ChatMessageDao { db: conn.clone() },
+        conversation_dao: ConversationDao { db: conn.clone() },
+        conversation_pin_dao: ConversationPinDao { db: conn.clone() },
+        chat_group_dao: ChatGroupDao { db: conn.clone() },
+        group_member_dao: GroupMemberDao { db: conn.clone() },
+        message_edit_dao: MessageEditDao { db: conn.clone() },
+        message_reaction_dao: MessageReactionDao { db: conn.clone() },
+        file_blob_dao: FileBlobDao { db: conn.clone() },
+        message_attachment_dao: MessageAttachmentDao { db: conn.clone() },
+        scheduled_message_dao: ScheduledMessageDao { db: conn.clone() },
+        userinfo_dao: Dao { db: conn },
+        blob_store: Arc::new(LocalBlobStore::new(FileConfig::init_from_env().store_dir)),
+        message_index: MessageIndex::open(ChatConfig::init_from_env().search_index_dir)
+            .expect("open search index fail!"),
+    }
 }
 
 // 开启socket服务
-fn start_socket(user_service: Arc<Service>) {
-    let factory = create_factory(user_service);
+fn start_socket(user_service: Arc<Service>, chat_service: Arc<ChatService>) {
+    let registry = ConnectionRegistry::default();
+    let shared = SharedState {
+        user_service,
+        chat_service,
+        login_cache: Default::default(),
+        registry: registry.clone(),
+        uploads: Default::default(),
+        scope_cache: Default::default(),
+        // 登录和修改密码共用ip维度的失败记录
+        ip_guard: Arc::new(Mutex::new(IpLoginGuard::new(
+            LoginGuardConfig::init_from_env(),
+        ))),
+        event_times: Default::default(),
+    };
+
+    // 定时消息与在线的消息使用相同的方式转发
+    let scheduler = DefaultServerChatModule::init(
+        Arc::clone(&shared.user_service),
+        Arc::clone(&shared.chat_service),
+        Arc::clone(&shared.login_cache),
+        registry.clone(),
+        Arc::clone(&shared.event_times),
+    );
+    thread::spawn(move || start_message_scheduler(scheduler));
+
+    // 定时删除长时间没有继续的上传
+    let uploads = Arc::clone(&shared.uploads);
+    thread::spawn(move || start_upload_sweeper(uploads));
 
     let config = TcpSocketConfig::get_default_server_socket_config();
 
-    let mut server = TcpServerSide::new(config.get_url(), factory);
+    let mut server = TcpServerSide::new_with_registry(
+        config.get_url(),
+        Arc::new(move || create_factory(&shared)),
+        registry,
+    );
 
     server.start();
 }
 
-// 创建HandleProtocolFactory, 实际里面填充解析socket协议的handler
-fn create_factory(user_service: Arc<Service>) -> HandleProtocolFactory {
+// 所有连接共享的状态. 每个连接创建自己的handler，handler中只持有这些状态的引用
+struct SharedState {
+    user_service: Arc<Service>,
+    chat_service: Arc<ChatService>,
+    login_cache: LoginCache,
+    registry: ConnectionRegistry,
+    uploads: UploadStates,
+    scope_cache: ApiKeyScopeCache,
+    ip_guard: SharedIpLoginGuard,
+    event_times: EventTimes,
+}
+
+// 为一个连接创建HandleProtocolFactory, 实际里面填充解析socket协议的handler
+fn create_factory(shared: &SharedState) -> HandleProtocolFactory {
+    let scope_cache = &shared.scope_cache;
+
     // login handler
-    let login_handler = create_default_server_login_handler(user_service);
-    // todo: chat handler
-    let chat_handler = Box::new(ServerChatHandler {});
+    let login_handler = create_default_server_login_handler(
+        Arc::clone(&shared.user_service),
+        Arc::clone(&shared.chat_service),
+        shared.registry.clone(),
+        Arc::clone(&shared.login_cache),
+        Arc::clone(scope_cache),
+        Arc::clone(&shared.ip_guard),
+    );
+    // chat handler
+    let chat_handler = create_default_server_chat_handler(
+        Arc::clone(&shared.user_service),
+        Arc::clone(&shared.chat_service),
+        Arc::clone(&shared.login_cache),
+        shared.registry.clone(),
+        Arc::clone(&shared.event_times),
+    );
+    // group handler
+    let group_handler = create_default_server_group_handler(
+        Arc::clone(&shared.user_service),
+        Arc::clone(&shared.chat_service),
+        Arc::clone(&shared.login_cache),
+        shared.registry.clone(),
+    );
+    // file handler
+    let file_handler = create_default_server_file_handler(
+        Arc::clone(&shared.user_service),
+        Arc::clone(&shared.chat_service),
+        Arc::clone(&shared.login_cache),
+        Arc::clone(&shared.uploads),
+    );
+    // account handler
+    let account_handler = create_default_server_account_handler(
+        Arc::clone(&shared.user_service),
+        Arc::clone(&shared.login_cache),
+        Arc::clone(&shared.ip_guard),
+    );
     // todo: p2p handler
     let p2p_handler = Box::new(ServiceP2pHandler {});
 
     let mut factory = HandleProtocolFactory::new();
     factory.registry_handler(ChatCommand::Login, login_handler);
-    factory.registry_handler(ChatCommand::Chat, chat_handler);
-    factory.registry_handler(ChatCommand::P2p, p2p_handler);
+    // 除了登录，其它command都需要检查api key的scope
+    factory.registry_handler(
+        ChatCommand::Chat,
+        create_scoped_handler(ChatCommand::Chat, scope_cache, chat_handler),
+    );
+    factory.registry_handler(
+        ChatCommand::P2p,
+        create_scoped_handler(ChatCommand::P2p, scope_cache, p2p_handler),
+    );
+    factory.registry_handler(
+        ChatCommand::Account,
+        create_scoped_handler(ChatCommand::Account, scope_cache, account_handler),
+    );
+    factory.registry_handler(
+        ChatCommand::Group,
+        create_scoped_handler(ChatCommand::Group, scope_cache, group_handler),
+    );
+    factory.registry_handler(
+        ChatCommand::File,
+        create_scoped_handler(ChatCommand::File, scope_cache, file_handler),
+    );
     factory
 }
 
-fn create_default_server_login_handler(user_service: Arc<Service>) -> Box<DefaultLoginHandler> {
-    let server = DefaultServerLoginModule::init(user_service);
+fn create_scoped_handler(
+    command: ChatCommand,
+    scope_cache: &ApiKeyScopeCache,
+    handler: Box<dyn HandlerProtocolData + Send>,
+) -> Box<ScopedHandler> {
+    Box::new(ScopedHandler::new(
+        command,
+        Arc::clone(scope_cache),
+        handler,
+    ))
+}
+
+fn create_default_server_login_handler(
+    user_service: Arc<Service>,
+    chat_service: Arc<ChatService>,
+    registry: ConnectionRegistry,
+    login_cache: LoginCache,
+    scope_cache: ApiKeyScopeCache,
+    ip_guard: SharedIpLoginGuard,
+) -> Box<DefaultLoginHandler> {
+    let server = DefaultServerLoginModule::init(
+        user_service,
+        chat_service,
+        registry,
+        login_cache,
+        scope_cache,
+        ip_guard,
+    );
     Box::new(DefaultLoginHandler::new(true, Some(Box::new(server)), None))
 }
 
-pub struct ServerChatHandler {}
+fn create_default_server_account_handler(
+    user_service: Arc<Service>,
+    login_cache: LoginCache,
+    ip_guard: SharedIpLoginGuard,
+) -> Box<DefaultAccountHandler> {
+    let server = DefaultServerAccountModule::init(user_service, login_cache, ip_guard);
+    Box::new(DefaultAccountHandler::new(Some(Box::new(server)), None))
+}
 
-impl HandlerProtocolData for ServerChatHandler {
-    // note: this function could do  what you want  it
-    // for example ,you could record this ChatData in db. but this time ,just print it by info!.
-    fn handle(&mut self, address: SocketAddr, a: &Vec<u8>) -> Option<Vec<u8>> {
-        let req: ChatData = bincode::deserialize(a).unwrap();
-        info!("OverrideChatHandler received data :{:?}  ", req);
-        None
-    }
+fn create_default_server_chat_handler(
+    user_service: Arc<Service>,
+    chat_service: Arc<ChatService>,
+    login_cache: LoginCache,
+    registry: ConnectionRegistry,
+    event_times: EventTimes,
+) -> Box<DefaultChatHandler> {
+    let server = DefaultServerChatModule::init(
+        user_service,
+        chat_service,
+        login_cache,
+        registry,
+        event_times,
+    );
+    Box::new(DefaultChatHandler::new(Some(Box::new(server)), None))
+}
+
+fn create_default_server_group_handler(
+    user_service: Arc<Service>,
+    chat_service: Arc<ChatService>,
+    login_cache: LoginCache,
+    registry: ConnectionRegistry,
+) -> Box<DefaultGroupHandler> {
+    let server = DefaultServerGroupModule::init(user_service, chat_service, login_cache, registry);
+    Box::new(DefaultGroupHandler::new(Some(Box::new(server)), None))
+}
+
+fn create_default_server_file_handler(
+    user_service: Arc<Service>,
+    chat_service: Arc<ChatService>,
+    login_cache: LoginCache,
+    uploads: UploadStates,
+) -> Box<DefaultFileHandler> {
+    let server = DefaultServerFileModule::init(user_service, chat_service, login_cache, uploads);
+    Box::new(DefaultFileHandler::new(Some(Box::new(server)), None))
 }
 
 // handle msg "p2p" on server side
@@ -175,21 +725,35 @@ pub struct ServiceP2pHandler {}
 
 impl ServiceP2pHandler {
     fn handleGetIpV4Req(&self, a: &Vec<u8>) -> Option<Vec<u8>> {
-        let req: GetIpV4Req = bincode::deserialize(a).unwrap();
+        let req: GetIpV4Req = match bincode::deserialize(a) {
+            Ok(t) => t,
+            Err(e) => {
+                warn!("invalid GetIpV4Req: {}", e);
+                return None;
+            }
+        };
 
         // todo: 读取db或缓存，获取指定账户的ip地址，然后封装成GetIpV4Resp，再通过socket返回
         None
     }
 
     fn handleTryConnectReq(&self, a: &Vec<u8>) -> Option<Vec<u8>> {
-        todo!()
+        // todo: 尚未实现，请求来自client，不能panic
+        warn!("TryConnectReq is not supported yet");
+        None
     }
 }
 
 impl HandlerProtocolData for ServiceP2pHandler {
     fn handle(&mut self, address: SocketAddr, a: &Vec<u8>) -> Option<Vec<u8>> {
         // todo: 获取biz类型
-        let param: P2pData = bincode::deserialize(a).unwrap();
+        let param: P2pData = match bincode::deserialize(a) {
+            Ok(t) => t,
+            Err(e) => {
+                warn!("invalid p2p data from {}: {}", address, e);
+                return None;
+            }
+        };
         match param.biz {
             common::p2p_module::P2pDataType::GetIpV4Req => {
                 return self.handleGetIpV4Req(a);
@@ -204,3 +768,36 @@ impl HandlerProtocolData for ServiceP2pHandler {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_account_and_all_devices() {
+        let login_cache: LoginCache = Arc::new(RwLock::new(HashMap::new()));
+        let phone: SocketAddr = "127.0.0.1:5001".parse().unwrap();
+        let pc: SocketAddr = "127.0.0.1:5002".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:5003".parse().unwrap();
+        {
+            let mut cache = login_cache.write().unwrap();
+            cache.insert(phone, "alice".to_string());
+            cache.insert(pc, "alice".to_string());
+            cache.insert(other, "bob".to_string());
+        }
+
+        assert_eq!(
+            find_account_by_address(&login_cache, &pc),
+            Some("alice".to_string())
+        );
+        assert_eq!(
+            find_account_by_address(&login_cache, &"127.0.0.1:5004".parse().unwrap()),
+            None
+        );
+
+        let mut addresses = find_addresses_by_account(&login_cache, &"alice".to_string());
+        addresses.sort();
+        assert_eq!(addresses, vec![phone, pc]);
+        assert!(find_addresses_by_account(&login_cache, &"carol".to_string()).is_empty());
+    }
+}
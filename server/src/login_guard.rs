@@ -0,0 +1,134 @@
+use common::config::LoginGuardConfig;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+// 登录和修改密码共用同一个guard，同一个ip在两处的失败次数合并计算
+pub type SharedIpLoginGuard = Arc<Mutex<IpLoginGuard>>;
+
+// 按来源ip统计登录失败次数，防止同一个ip轮流尝试多个账户.
+// 账户维度的失败次数保存在user_info表中，见 Service::login
+pub struct IpLoginGuard {
+    config: LoginGuardConfig,
+    fail_cache: HashMap<IpAddr, IpFailRecord>,
+}
+
+struct IpFailRecord {
+    fail_count: i32,
+    last_fail_time: i64,
+}
+
+impl IpLoginGuard {
+    pub fn new(config: LoginGuardConfig) -> Self {
+        IpLoginGuard {
+            config,
+            fail_cache: Default::default(),
+        }
+    }
+
+    pub fn get_config(&self) -> &LoginGuardConfig {
+        &self.config
+    }
+
+    // 检查该ip当前是否允许尝试登录
+    pub fn check(&self, ip: &IpAddr, now: i64) -> Result<(), String> {
+        let record = match self.fail_cache.get(ip) {
+            None => return Ok(()),
+            Some(t) => t,
+        };
+
+        let wait_until = if record.fail_count >= self.config.ip_max_fail_count {
+            record.last_fail_time + self.config.lock_seconds * 1000
+        } else {
+            record.last_fail_time + self.config.delay_millis(record.fail_count)
+        };
+
+        if wait_until > now {
+            return Err(format!(
+                "too many failed attempts from {ip}, retry after {} seconds",
+                (wait_until - now + 999) / 1000
+            ));
+        }
+
+        Ok(())
+    }
+
+    // 记录一次失败. 返回true表示本次失败导致该ip被锁定
+    pub fn record_fail(&mut self, ip: IpAddr, now: i64) -> bool {
+        self.remove_expired(now);
+
+        let record = self.fail_cache.entry(ip).or_insert(IpFailRecord {
+            fail_count: 0,
+            last_fail_time: now,
+        });
+
+        record.fail_count += 1;
+        record.last_fail_time = now;
+
+        record.fail_count == self.config.ip_max_fail_count
+    }
+
+    pub fn record_success(&mut self, ip: &IpAddr) {
+        self.fail_cache.remove(ip);
+    }
+
+    // 锁定时长之后仍没有再失败的记录可以丢弃，避免缓存无限增长
+    fn remove_expired(&mut self, now: i64) {
+        let expire_millis = self.config.lock_seconds * 1000;
+        self.fail_cache
+            .retain(|_, v| v.last_fail_time + expire_millis > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_guard() -> IpLoginGuard {
+        IpLoginGuard::new(LoginGuardConfig {
+            max_fail_count: 5,
+            ip_max_fail_count: 3,
+            lock_seconds: 60,
+            base_delay_millis: 1000,
+            max_delay_millis: 4000,
+        })
+    }
+
+    #[test]
+    fn delay_then_lock_ip() {
+        let mut guard = create_guard();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+
+        assert!(!guard.record_fail(ip, 0));
+        // 第一次失败之后等待1秒
+        assert!(guard.check(&ip, 999).is_err());
+        assert!(guard.check(&ip, 1000).is_ok());
+        assert!(guard.check(&other, 0).is_ok());
+
+        assert!(!guard.record_fail(ip, 1000));
+        assert!(guard.check(&ip, 2999).is_err());
+        assert!(guard.check(&ip, 3000).is_ok());
+
+        // 达到上限之后锁定lock_seconds
+        assert!(guard.record_fail(ip, 3000));
+        assert!(guard.check(&ip, 62_999).is_err());
+        assert!(guard.check(&ip, 63_000).is_ok());
+    }
+
+    #[test]
+    fn reset_on_success_or_expire() {
+        let mut guard = create_guard();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        guard.record_fail(ip, 0);
+        guard.record_success(&ip);
+        assert!(guard.check(&ip, 0).is_ok());
+
+        guard.record_fail(ip, 0);
+        guard.record_fail(ip, 0);
+        // 锁定时长之后没有再失败的记录被丢弃，重新计数
+        assert!(!guard.record_fail(ip, 60_000));
+        assert!(guard.check(&ip, 61_000).is_ok());
+    }
+}
@@ -0,0 +1,95 @@
+use common::chat_protocol::ChatCommand;
+use common::protocol_factory::HandlerProtocolData;
+use log::warn;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+// 使用api key登录的连接允许使用的command名称，key为socket地址.
+// 使用密码登录的连接没有记录，不受限制
+pub type ApiKeyScopeCache = Arc<RwLock<HashMap<SocketAddr, Vec<String>>>>;
+
+/**
+ *  包装一个handler，使用api key登录的连接只能调用key授权过的command
+ **/
+pub struct ScopedHandler {
+    command: ChatCommand,
+    scope_cache: ApiKeyScopeCache,
+    inner: Box<dyn HandlerProtocolData + Send>,
+}
+
+impl ScopedHandler {
+    pub fn new(
+        command: ChatCommand,
+        scope_cache: ApiKeyScopeCache,
+        inner: Box<dyn HandlerProtocolData + Send>,
+    ) -> Self {
+        ScopedHandler {
+            command,
+            scope_cache,
+            inner,
+        }
+    }
+}
+
+impl HandlerProtocolData for ScopedHandler {
+    fn handle(&mut self, address: SocketAddr, data: &Vec<u8>) -> Option<Vec<u8>> {
+        let allowed = match self.scope_cache.read().unwrap().get(&address) {
+            None => true,
+            Some(scopes) => scopes.contains(&self.command.name()),
+        };
+
+        if !allowed {
+            warn!(
+                "api key of {} has no scope for command:{:?}",
+                address, self.command
+            );
+            return None;
+        }
+
+        self.inner.handle(address, data)
+    }
+
+    fn on_disconnect(&mut self, address: SocketAddr) {
+        self.inner.on_disconnect(address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct EchoHandler;
+
+    impl HandlerProtocolData for EchoHandler {
+        fn handle(&mut self, _address: SocketAddr, data: &Vec<u8>) -> Option<Vec<u8>> {
+            Some(data.clone())
+        }
+    }
+
+    #[test]
+    fn only_scoped_commands_for_api_key() {
+        let scope_cache: ApiKeyScopeCache = Default::default();
+        let mut chat = ScopedHandler::new(
+            ChatCommand::Chat,
+            scope_cache.clone(),
+            Box::new(EchoHandler),
+        );
+        let mut group = ScopedHandler::new(
+            ChatCommand::Group,
+            scope_cache.clone(),
+            Box::new(EchoHandler),
+        );
+        let bot: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let user: SocketAddr = "127.0.0.1:5001".parse().unwrap();
+        scope_cache
+            .write()
+            .unwrap()
+            .insert(bot, vec![ChatCommand::Chat.name()]);
+
+        assert_eq!(chat.handle(bot, &vec![1]), Some(vec![1]));
+        assert_eq!(group.handle(bot, &vec![1]), None);
+        // 使用密码登录的连接不受限制
+        assert_eq!(group.handle(user, &vec![1]), Some(vec![1]));
+    }
+}
@@ -0,0 +1,185 @@
+use crate::{AppState, MyError};
+use actix_session::Session;
+use actix_web::cookie::Key;
+use actix_web::{error, get, post, web, Error, HttpRequest, HttpResponse};
+use common::config::LoginGuardConfig;
+use common::login_module::LoginReqData;
+use common::permission::{Permission, Role};
+use entity::userinfo::Model;
+use log::{debug, warn};
+use serde::Deserialize;
+use service::userinfo_service::{get_role, Service};
+use std::env;
+
+// session中保存当前登录账户的key
+const SESSION_ACCOUNT_KEY: &str = "account";
+
+// 派生cookie密钥时要求的最小长度
+const SESSION_KEY_MIN_LEN: usize = 32;
+
+#[derive(Debug, Deserialize)]
+pub struct WebLoginForm {
+    account: String,
+    pwd: String,
+    // 开启了两步验证的账户需要填写
+    code: Option<String>,
+}
+
+// session cookie的签名密钥. 未配置 WEB_SESSION_KEY 时随机生成，重启后需要重新登录
+pub fn load_session_key() -> Key {
+    dotenvy::dotenv().ok();
+
+    match env::var("WEB_SESSION_KEY") {
+        Ok(t) if t.len() >= SESSION_KEY_MIN_LEN => Key::derive_from(t.as_bytes()),
+        _ => {
+            warn!(
+                "WEB_SESSION_KEY is not set or shorter than {SESSION_KEY_MIN_LEN}, use random key"
+            );
+            Key::generate()
+        }
+    }
+}
+
+// 启动时把 INIT_ADMIN_ACCOUNT 指定的账户设置为admin，用于初始化第一个管理员
+pub async fn init_admin_account(user_service: &Service) {
+    dotenvy::dotenv().ok();
+
+    let account = match env::var("INIT_ADMIN_ACCOUNT") {
+        Ok(t) if !t.is_empty() => t,
+        _ => return,
+    };
+
+    let model = match user_service.dao.find_by_name(account.clone()).await {
+        Ok(Some(t)) => t,
+        _ => {
+            warn!("INIT_ADMIN_ACCOUNT {account} not exist");
+            return;
+        }
+    };
+
+    if let Err(e) = user_service
+        .update_role(model.id, Role::Admin, "init".to_string())
+        .await
+    {
+        warn!("init admin account {account} fail: {e}");
+    }
+}
+
+// 校验当前登录的账户是否有指定的权限，返回当前账户.
+// 未登录时返回Unauthorized(跳转到登录页)，没有权限时返回Forbidden
+pub(crate) async fn require_permission(
+    session: &Session,
+    data: &AppState,
+    permission: Permission,
+) -> Result<Model, MyError> {
+    let account = session
+        .get::<String>(SESSION_ACCOUNT_KEY)
+        .map_err(|_| MyError::Unauthorized)?
+        .ok_or(MyError::Unauthorized)?;
+
+    // 每次都从数据库读取角色，角色修改后立即生效
+    data.user_service
+        .check_permission(&account, permission)
+        .await
+        .map_err(|_| MyError::Forbidden)
+}
+
+// web端的操作人，记录到审计日志中
+pub fn get_operator(model: &Model) -> String {
+    format!("web:{}", model.name)
+}
+
+#[get("/login")]
+async fn login_index(data: web::Data<AppState>) -> Result<HttpResponse, Error> {
+    render_login_page(&data, None)
+}
+
+#[post("/login")]
+async fn login(
+    req: HttpRequest,
+    session: Session,
+    param: web::Form<WebLoginForm>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let param = param.0;
+    let source = req
+        .peer_addr()
+        .map(|t| format!("web:{}", t.ip()))
+        .unwrap_or("web".to_string());
+
+    debug!("invoke web login account:{} ", param.account);
+
+    let result = web_login(&data, &param, source).await;
+
+    match result {
+        Ok(model) => {
+            // 登录成功后更换session id，防止session固定攻击
+            session.renew();
+            session.insert(SESSION_ACCOUNT_KEY, model.name)?;
+            Ok(HttpResponse::Found()
+                .append_header(("location", "/"))
+                .finish())
+        }
+        Err(e) => render_login_page(&data, Some(e)),
+    }
+}
+
+#[post("/logout")]
+async fn logout(session: Session) -> HttpResponse {
+    session.purge();
+    HttpResponse::Found()
+        .append_header(("location", "/login"))
+        .finish()
+}
+
+// 与socket端使用相同的登录校验，包括失败计数、锁定和两步验证
+async fn web_login(data: &AppState, param: &WebLoginForm, source: String) -> Result<Model, String> {
+    let config: &LoginGuardConfig = &data.login_guard_config;
+
+    let req = LoginReqData {
+        account: param.account.clone(),
+        pwd: param.pwd.clone(),
+    };
+    let model = data
+        .user_service
+        .login(&req, source.clone(), config)
+        .await?;
+
+    let model = if model.totp_enabled {
+        let code = param
+            .code
+            .clone()
+            .filter(|t| !t.is_empty())
+            .ok_or("verification code is required !".to_string())?;
+        data.user_service
+            .login_by_totp(model.name, code, source, config)
+            .await?
+    } else {
+        model
+    };
+
+    // 只能查看自己资料的账户不需要登录管理页面
+    if !get_role(&model).has_permission(Permission::ViewUsers) {
+        return Err("permission denied !".to_string());
+    }
+
+    Ok(model)
+}
+
+fn render_login_page(data: &AppState, error_msg: Option<String>) -> Result<HttpResponse, Error> {
+    let mut ctx = tera::Context::new();
+    ctx.insert("error_msg", &error_msg);
+
+    let body = data
+        .templates
+        .render("login.html.tera", &ctx)
+        .map_err(|m| error::ErrorInternalServerError(m))?;
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+}
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(login_index);
+    cfg.service(login);
+    cfg.service(logout);
+}
@@ -0,0 +1,140 @@
+use crate::AppState;
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::web::Bytes;
+use actix_web::{error, get, web, Error, HttpResponse};
+use common::base::now_millis;
+use common::file_module::{verify_download_sign, FILE_CHUNK_SIZE};
+use serde::Deserialize;
+use std::io;
+use std::io::Read;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+use tokio::task;
+
+// 下载时已经读取但还没有发送的分片数量上限
+const BLOB_BODY_BUFFER_CHUNKS: usize = 4;
+
+// 签名下载链接的参数，由socket服务端的file handler生成
+#[derive(Debug, Deserialize)]
+pub struct DownloadParams {
+    account: String,
+    expires: i64,
+    sign: String,
+}
+
+// 下载消息中的附件. 除了校验签名和过期时间，还会再次检查账户是否仍然可以访问该附件，
+// 例如账户已经退出了附件所在的群组
+#[get("/files/{sha256}")]
+async fn download_file(
+    path: web::Path<String>,
+    params: web::Query<DownloadParams>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let sha256 = path.into_inner();
+
+    if !verify_download_sign(
+        &data.file_config.url_secret,
+        &sha256,
+        &params.account,
+        params.expires,
+        &params.sign,
+    ) {
+        return Err(error::ErrorForbidden("invalid download sign !"));
+    }
+    if params.expires < now_millis() {
+        return Err(error::ErrorForbidden("download url expired !"));
+    }
+
+    data.chat_service
+        .check_attachment_access(&params.account, &sha256)
+        .await
+        .map_err(error::ErrorNotFound)?;
+
+    // 读取文件是阻塞操作，放到线程池中执行
+    let blob_store = Arc::clone(&data.chat_service.blob_store);
+    let file_name = sha256.clone();
+    let (size, reader) = web::block(move || -> Result<_, String> {
+        Ok((blob_store.size(&sha256)?, blob_store.open(&sha256)?))
+    })
+    .await
+    .map_err(error::ErrorInternalServerError)?
+    .map_err(error::ErrorNotFound)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(file_name)],
+        })
+        .body(BlobBody::new(size, reader)))
+}
+
+/**
+ *  分片读取文件的响应体，不需要把整个文件读到内存中. 大小已知，响应会带上Content-Length
+ **/
+struct BlobBody {
+    size: u64,
+    receiver: mpsc::Receiver<Result<Bytes, io::Error>>,
+}
+
+impl BlobBody {
+    fn new(size: u64, mut reader: Box<dyn Read + Send>) -> Self {
+        let (sender, receiver) = mpsc::channel(BLOB_BODY_BUFFER_CHUNKS);
+
+        // 在阻塞线程中读取，client断开之后发送失败时停止
+        task::spawn_blocking(move || loop {
+            let mut buf = vec![0; FILE_CHUNK_SIZE];
+            let data = match reader.read(&mut buf) {
+                Ok(0) => return,
+                Ok(len) => {
+                    buf.truncate(len);
+                    Ok(Bytes::from(buf))
+                }
+                Err(e) => Err(e),
+            };
+            let failed = data.is_err();
+            if sender.blocking_send(data).is_err() || failed {
+                return;
+            }
+        });
+
+        BlobBody { size, receiver }
+    }
+}
+
+impl MessageBody for BlobBody {
+    type Error = io::Error;
+
+    fn size(&self) -> BodySize {
+        BodySize::Sized(self.size)
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        self.get_mut().receiver.poll_recv(cx)
+    }
+}
+
+pub fn init(cfg: &mut web::ServiceConfig) {
+    cfg.service(download_file);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+    use std::io::Cursor;
+
+    #[actix_rt::test]
+    async fn read_blob_by_chunks() {
+        let content: Vec<u8> = (0..FILE_CHUNK_SIZE * 2 + 10).map(|t| t as u8).collect();
+        let body = BlobBody::new(content.len() as u64, Box::new(Cursor::new(content.clone())));
+        assert_eq!(body.size(), BodySize::Sized(content.len() as u64));
+        assert_eq!(to_bytes(body).await.unwrap(), content);
+    }
+}
@@ -1,27 +1,36 @@
+use crate::auth::{get_operator, require_permission};
 use actix_files::Files as Fs;
 use actix_files::NamedFile;
 use actix_http::body::BoxBody;
+use actix_session::storage::CookieSessionStore;
+use actix_session::{Session, SessionMiddleware};
 use actix_web::http::header::ContentType;
 use actix_web::http::StatusCode;
 use actix_web::{
     error, get, middleware, post, web, App, Error, HttpRequest, HttpResponse, HttpServer,
     Responder, Result,
 };
-use common::config::{TcpSocketConfig, WebSocketConfig};
+use common::base::now_millis;
+use common::chat_protocol::ChatCommand;
+use common::config::{FileConfig, LoginGuardConfig, TcpSocketConfig, WebSocketConfig};
+use common::permission::{Permission, Role};
 use derive_more::Display;
-use entity::userinfo;
 use entity::userinfo::Model;
 use env_logger::Env;
 use listenfd::ListenFd;
 use log::debug;
 use serde::{Deserialize, Serialize};
+use service::chat_service::ChatService;
 use service::sea_orm::{Database, DbErr};
-use service::userinfo_service::Service;
+use service::userinfo_service::{get_role, Service};
 use std::env;
 use std::fmt::{Debug, Display, Formatter};
 use std::sync::Arc;
 use tera::Tera;
 
+mod auth;
+mod files;
+
 const PAGE_SIZE: u64 = 5;
 
 #[derive(Debug, Clone)]
@@ -29,6 +38,9 @@ struct AppState {
     templates: Tera,
     // conn: Arc<DatabaseConnection>,
     user_service: Arc<Service>,
+    chat_service: Arc<ChatService>,
+    login_guard_config: LoginGuardConfig,
+    file_config: FileConfig,
 }
 
 #[derive(Debug, Deserialize)]
@@ -66,7 +78,13 @@ impl ServerEnvConfig {
 }
 
 #[get("/")]
-async fn user_index(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse, Error> {
+async fn user_index(
+    req: HttpRequest,
+    session: Session,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let operator = require_permission(&session, &data, Permission::ViewUsers).await?;
+
     let template = &data.templates;
 
     // get page params from httpRequest. 从HttpRequest中获取分页参数
@@ -86,6 +104,9 @@ async fn user_index(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpR
         .await
         .expect("Cannot find user_index in page");
 
+    // 只把需要展示的字段传入页面，不暴露密码等敏感数据
+    let page_data: Vec<UserInfoListVo> = page_data.into_iter().map(UserInfoListVo::from).collect();
+
     // send page_data to html. 将分页数据传入html页面中
     let mut ctx = tera::Context::new();
     // 分页的数据
@@ -96,6 +117,15 @@ async fn user_index(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpR
     ctx.insert("page_size", &page_size);
     // 查询出的分页页数
     ctx.insert("num_page", &num_page);
+    // 当前时间，用于判断账户是否处于锁定状态
+    ctx.insert("now", &now_millis());
+    // 当前登录的账户，以及是否可以修改角色
+    ctx.insert("current_account", &operator.name);
+    ctx.insert(
+        "can_manage_roles",
+        &get_role(&operator).has_permission(Permission::ManageRoles),
+    );
+    ctx.insert("roles", &Role::ALL.map(|t| t.as_str()));
 
     let body = template
         .render("index.html.tera", &ctx)
@@ -105,7 +135,9 @@ async fn user_index(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpR
 }
 
 #[get("/new")]
-async fn new(data: web::Data<AppState>) -> Result<HttpResponse, Error> {
+async fn new(session: Session, data: web::Data<AppState>) -> Result<HttpResponse, Error> {
+    require_permission(&session, &data, Permission::ManageUsers).await?;
+
     let template = &data.templates;
 
     // send page_data to html. 将分页数据传入html页面中
@@ -143,6 +175,12 @@ pub enum MyError {
 
     #[display(fmt = "Validation error on field: {}", field)]
     ValidationError { field: String },
+
+    #[display(fmt = "please login first")]
+    Unauthorized,
+
+    #[display(fmt = "permission denied")]
+    Forbidden,
 }
 
 impl error::ResponseError for MyError {
@@ -152,10 +190,19 @@ impl error::ResponseError for MyError {
             MyError::BadClientData => StatusCode::BAD_REQUEST,
             MyError::Timeout => StatusCode::GATEWAY_TIMEOUT,
             MyError::ValidationError { .. } => StatusCode::BAD_REQUEST,
+            MyError::Unauthorized => StatusCode::FOUND,
+            MyError::Forbidden => StatusCode::FORBIDDEN,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
+        // 未登录时跳转到登录页
+        if let MyError::Unauthorized = self {
+            return HttpResponse::Found()
+                .append_header(("location", "/login"))
+                .finish();
+        }
+
         HttpResponse::build(self.status_code())
             .insert_header(ContentType::html())
             .body(self.to_string())
@@ -166,6 +213,8 @@ impl error::ResponseError for MyError {
 pub struct UserInfoVo {
     id: Option<i32>,
     name: String,
+    // 只用于接收表单，不返回给前端
+    #[serde(skip_serializing)]
     pwd: String,
 }
 
@@ -189,6 +238,44 @@ impl From<UserInfoVo> for Model {
             },
             name: value.name,
             pwd: value.pwd,
+            nickname: None,
+            signature: None,
+            failed_login_count: 0,
+            last_fail_time: None,
+            locked_until: None,
+            totp_secret: None,
+            totp_enabled: false,
+            totp_recovery_codes: None,
+            totp_last_step: None,
+            scram_salt: None,
+            scram_iterations: 0,
+            scram_stored_key: None,
+            scram_server_key: None,
+            role: Role::User.as_str().to_string(),
+        }
+    }
+}
+
+// 账户列表页展示的数据
+#[derive(Serialize, Debug, Clone)]
+pub struct UserInfoListVo {
+    id: i32,
+    name: String,
+    nickname: Option<String>,
+    role: String,
+    locked_until: Option<i64>,
+    totp_enabled: bool,
+}
+
+impl From<Model> for UserInfoListVo {
+    fn from(m: Model) -> Self {
+        UserInfoListVo {
+            id: m.id,
+            name: m.name,
+            nickname: m.nickname,
+            role: m.role,
+            locked_until: m.locked_until,
+            totp_enabled: m.totp_enabled,
         }
     }
 }
@@ -208,23 +295,361 @@ impl Responder for UserInfoVo {
 // registry account
 #[post("/insert")]
 async fn registry_account(
+    session: Session,
     param: web::Form<UserInfoVo>,
     data: web::Data<AppState>,
 ) -> Result<UserInfoVo, MyError> {
-    debug!("invoke registry_account data:{:?} ", param);
+    require_permission(&session, &data, Permission::ManageUsers).await?;
 
-    let model = userinfo::Model::from(param.0);
+    debug!("invoke registry_account name:{} ", param.name);
 
-    // invoke service to query data . 调用service来查询分页数据
-    let result = data.user_service.dao.insert(model).await;
+    let param = param.0;
+
+    // invoke service to registry account. 与socket端的注册使用相同的校验规则
+    let result = data.user_service.register(param.name, param.pwd).await;
     match result {
         Ok(t) => Ok(UserInfoVo::from(t)),
-        Err(e) => Err(MyError::ValidationError {
-            field: e.to_string(),
-        }),
+        Err(e) => Err(MyError::ValidationError { field: e }),
+    }
+}
+
+// unlock account which locked by too many failed login
+#[post("/unlock/{id}")]
+async fn unlock_account(
+    id: web::Path<i32>,
+    session: Session,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    let operator = require_permission(&session, &data, Permission::ManageUsers).await?;
+
+    let id = id.into_inner();
+
+    debug!("invoke unlock_account id:{} ", id);
+
+    let result = data
+        .user_service
+        .unlock_account(id, get_operator(&operator))
+        .await;
+
+    match result {
+        Ok(_) => Ok(HttpResponse::Found()
+            .append_header(("location", "/"))
+            .finish()),
+        Err(e) => Err(MyError::ValidationError { field: e }),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoleForm {
+    role: String,
+}
+
+// change role of account. 修改账户角色
+#[post("/role/{id}")]
+async fn update_role(
+    id: web::Path<i32>,
+    session: Session,
+    param: web::Form<RoleForm>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    let operator = require_permission(&session, &data, Permission::ManageRoles).await?;
+
+    let id = id.into_inner();
+
+    debug!("invoke update_role id:{} role:{}", id, param.role);
+
+    let role: Role = param
+        .0
+        .role
+        .parse()
+        .map_err(|e| MyError::ValidationError { field: e })?;
+
+    // 不允许修改自己的角色，避免最后一个管理员把自己降级
+    if operator.id == id {
+        return Err(MyError::ValidationError {
+            field: "can not change role of yourself !".to_string(),
+        });
+    }
+
+    let result = data
+        .user_service
+        .update_role(id, role, get_operator(&operator))
+        .await;
+
+    match result {
+        Ok(_) => Ok(HttpResponse::Found()
+            .append_header(("location", "/"))
+            .finish()),
+        Err(e) => Err(MyError::ValidationError { field: e }),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TotpCodeForm {
+    code: String,
+}
+
+// two-factor authentication enroll page. 未开启时展示provisioning uri，已开启时可以关闭
+#[get("/totp/{id}")]
+async fn totp_index(
+    id: web::Path<i32>,
+    session: Session,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    require_permission(&session, &data, Permission::ManageUsers).await?;
+
+    let id = id.into_inner();
+
+    let model = data
+        .user_service
+        .find_user_by_id(id)
+        .await
+        .map_err(|e| error::ErrorNotFound(e))?;
+
+    let mut ctx = tera::Context::new();
+    ctx.insert("id", &id);
+    ctx.insert("account", &model.name);
+    ctx.insert("totp_enabled", &model.totp_enabled);
+
+    if !model.totp_enabled {
+        let (_, uri) = data
+            .user_service
+            .start_totp_enroll(id)
+            .await
+            .map_err(|e| error::ErrorInternalServerError(e))?;
+        ctx.insert("provisioning_uri", &uri);
+    }
+
+    render_totp_page(&data.templates, &ctx)
+}
+
+#[post("/totp/{id}/confirm")]
+async fn totp_confirm(
+    id: web::Path<i32>,
+    session: Session,
+    param: web::Form<TotpCodeForm>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let operator = require_permission(&session, &data, Permission::ManageUsers).await?;
+
+    let id = id.into_inner();
+
+    let codes = data
+        .user_service
+        .confirm_totp_enroll(id, param.0.code, get_operator(&operator))
+        .await
+        .map_err(|e| MyError::ValidationError { field: e })?;
+
+    let model = data
+        .user_service
+        .find_user_by_id(id)
+        .await
+        .map_err(|e| error::ErrorNotFound(e))?;
+
+    // 恢复码明文只在这里展示一次
+    let mut ctx = tera::Context::new();
+    ctx.insert("id", &id);
+    ctx.insert("account", &model.name);
+    ctx.insert("totp_enabled", &model.totp_enabled);
+    ctx.insert("recovery_codes", &codes);
+
+    render_totp_page(&data.templates, &ctx)
+}
+
+#[post("/totp/{id}/disable")]
+async fn totp_disable(
+    id: web::Path<i32>,
+    session: Session,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    let operator = require_permission(&session, &data, Permission::ManageUsers).await?;
+
+    let id = id.into_inner();
+
+    let result = data
+        .user_service
+        .disable_totp(id, get_operator(&operator))
+        .await;
+
+    match result {
+        Ok(_) => Ok(HttpResponse::Found()
+            .append_header(("location", format!("/totp/{id}")))
+            .finish()),
+        Err(e) => Err(MyError::ValidationError { field: e }),
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ApiKeyForm {
+    name: String,
+    // 允许使用的command，逗号分隔
+    scopes: String,
+}
+
+// api keys of account. 查看账户的api key，以及创建新的key
+#[get("/api_key/{id}")]
+async fn api_key_index(
+    id: web::Path<i32>,
+    session: Session,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    require_permission(&session, &data, Permission::ManageUsers).await?;
+
+    render_api_key_page(&data, id.into_inner(), None).await
+}
+
+#[post("/api_key/{id}/create")]
+async fn api_key_create(
+    id: web::Path<i32>,
+    session: Session,
+    param: web::Form<ApiKeyForm>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let operator = require_permission(&session, &data, Permission::ManageUsers).await?;
+
+    let id = id.into_inner();
+    let param = param.0;
+
+    let scopes = param
+        .scopes
+        .split(',')
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+        .collect();
+
+    let (_, key) = data
+        .user_service
+        .create_api_key(id, param.name, scopes, get_operator(&operator))
+        .await
+        .map_err(|e| MyError::ValidationError { field: e })?;
+
+    // key的明文只在这里展示一次
+    render_api_key_page(&data, id, Some(key)).await
+}
+
+#[post("/api_key/{id}/revoke/{key_id}")]
+async fn api_key_revoke(
+    path: web::Path<(i32, i32)>,
+    session: Session,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, MyError> {
+    let operator = require_permission(&session, &data, Permission::ManageUsers).await?;
+
+    let (id, key_id) = path.into_inner();
+
+    debug!("invoke api_key_revoke id:{} key_id:{}", id, key_id);
+
+    // 只能吊销该账户自己的key
+    let model = data
+        .user_service
+        .find_user_by_id(id)
+        .await
+        .map_err(|e| MyError::ValidationError { field: e })?;
+    let keys = data
+        .user_service
+        .find_api_keys(model.name)
+        .await
+        .map_err(|e| MyError::ValidationError { field: e })?;
+    if !keys.iter().any(|t| t.id == key_id) {
+        return Err(MyError::BadClientData);
+    }
+
+    let result = data
+        .user_service
+        .revoke_api_key(key_id, get_operator(&operator))
+        .await;
+
+    match result {
+        Ok(_) => Ok(HttpResponse::Found()
+            .append_header(("location", format!("/api_key/{id}")))
+            .finish()),
+        Err(e) => Err(MyError::ValidationError { field: e }),
+    }
+}
+
+async fn render_api_key_page(
+    data: &AppState,
+    id: i32,
+    new_key: Option<String>,
+) -> Result<HttpResponse, Error> {
+    let model = data
+        .user_service
+        .find_user_by_id(id)
+        .await
+        .map_err(|e| error::ErrorNotFound(e))?;
+
+    let keys = data
+        .user_service
+        .find_api_keys(model.name.clone())
+        .await
+        .map_err(|e| error::ErrorInternalServerError(e))?;
+
+    // 登录不需要授权，其它command都可以作为scope
+    let scopes: Vec<String> = ChatCommand::all()
+        .into_iter()
+        .filter(|t| *t != ChatCommand::Login)
+        .map(|t| t.name())
+        .collect();
+
+    let mut ctx = tera::Context::new();
+    ctx.insert("id", &id);
+    ctx.insert("account", &model.name);
+    ctx.insert("keys", &keys);
+    ctx.insert("scopes", &scopes.join(","));
+    ctx.insert("new_key", &new_key);
+
+    let body = data
+        .templates
+        .render("api_key.html.tera", &ctx)
+        .map_err(|m| error::ErrorInternalServerError(m))?;
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+}
+
+fn render_totp_page(template: &Tera, ctx: &tera::Context) -> Result<HttpResponse, Error> {
+    let body = template
+        .render("totp.html.tera", ctx)
+        .map_err(|m| error::ErrorInternalServerError(m))?;
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+}
+
+#[get("/audit_log")]
+async fn audit_log_index(
+    req: HttpRequest,
+    session: Session,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    require_permission(&session, &data, Permission::ViewAuditLog).await?;
+
+    let template = &data.templates;
+
+    let params = web::Query::<PageParams>::from_query(req.query_string()).unwrap();
+
+    let page = params.page_index.unwrap_or(1);
+
+    let page_size = params.page_size.unwrap_or(PAGE_SIZE);
+
+    let (page_data, num_page) = data
+        .user_service
+        .audit_log_dao
+        .find_in_page(page, page_size)
+        .await
+        .expect("Cannot find audit_log in page");
+
+    let mut ctx = tera::Context::new();
+    ctx.insert("page_data", &page_data);
+    ctx.insert("page_index", &page);
+    ctx.insert("page_size", &page_size);
+    ctx.insert("num_page", &num_page);
+
+    let body = template
+        .render("audit_log.html.tera", &ctx)
+        .map_err(|m| error::ErrorInternalServerError(m))?;
+
+    Ok(HttpResponse::Ok().content_type("text/html").body(body))
+}
+
 #[get("/account_index")]
 async fn account_index() -> impl Responder {
     NamedFile::open_async("static/index.html").await
@@ -235,21 +660,43 @@ fn init(cfg: &mut web::ServiceConfig) {
     cfg.service(registry_account);
     cfg.service(account_index);
     cfg.service(new);
+    cfg.service(unlock_account);
+    cfg.service(audit_log_index);
+    cfg.service(totp_index);
+    cfg.service(totp_confirm);
+    cfg.service(totp_disable);
+    cfg.service(update_role);
+    cfg.service(api_key_index);
+    cfg.service(api_key_create);
+    cfg.service(api_key_revoke);
+    auth::init(cfg);
+    files::init(cfg);
 }
 
 #[actix_web::main]
-pub async fn api_start_web_server_new(user_service: Arc<Service>) -> std::io::Result<()> {
+pub async fn api_start_web_server_new(
+    user_service: Arc<Service>,
+    chat_service: Arc<ChatService>,
+) -> std::io::Result<()> {
     let web_socket_config = WebSocketConfig::init_from_env();
 
     // load tera templates
     let templates = Tera::new(concat!(env!("CARGO_MANIFEST_DIR"), "/templates/**/*")).unwrap();
 
+    auth::init_admin_account(&user_service).await;
+
     // build app state. 构建app的state，以便各个线程共享AppState
     let state = AppState {
         templates,
         user_service,
+        chat_service,
+        login_guard_config: LoginGuardConfig::init_from_env(),
+        file_config: FileConfig::init_from_env(),
     };
 
+    // 管理页面的登录状态保存在签名加密的cookie中
+    let session_key = auth::load_session_key();
+
     // create server
     let mut server = HttpServer::new(move || {
         App::new()
@@ -257,6 +704,10 @@ pub async fn api_start_web_server_new(user_service: Arc<Service>) -> std::io::Re
             .service(Fs::new("static", "./userinfo-web/api/static"))
             // app_data could share state for each thread
             .app_data(web::Data::new(state.clone()))
+            .wrap(SessionMiddleware::new(
+                CookieSessionStore::default(),
+                session_key.clone(),
+            ))
             .wrap(middleware::Logger::default())
             .default_service(web::route().to(not_found))
             .configure(init)
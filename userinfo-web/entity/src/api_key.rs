@@ -0,0 +1,34 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// bot、服务账户使用的api key. 只保存key的sha256，明文只在创建时返回一次
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
+#[sea_orm(table_name = "api_key")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = true)]
+    #[serde(skip_deserializing)]
+    pub id: i32,
+    // key所属的账户
+    pub account: String,
+    // 备注名称，例如用途
+    pub name: String,
+    // key的前缀，用于查找和展示
+    #[sea_orm(unique)]
+    pub key_prefix: String,
+    // 完整key的sha256，hex编码
+    #[serde(skip_serializing)]
+    pub key_hash: String,
+    // 允许使用的command，逗号分隔，例如 Chat,Account
+    pub scopes: String,
+    // 毫秒时间戳
+    pub create_time: i64,
+    // 吊销时间，为None表示未吊销
+    pub revoked_time: Option<i64>,
+    // 最近一次使用的时间
+    pub last_used_time: Option<i64>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,25 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// 审计日志，记录账户锁定、解锁等安全相关事件
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
+#[sea_orm(table_name = "audit_log")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = true)]
+    #[serde(skip_deserializing)]
+    pub id: i32,
+    // 事件涉及的账户
+    pub account: String,
+    // 事件类型，例如 LOCK、UNLOCK
+    pub event: String,
+    // 触发事件的来源，socket地址或者web端的操作人
+    pub source: String,
+    pub detail: Option<String>,
+    // 毫秒时间戳
+    pub create_time: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
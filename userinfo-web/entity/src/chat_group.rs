@@ -0,0 +1,21 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// 群组，成员保存在group_member中
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
+#[sea_orm(table_name = "chat_group")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = true)]
+    #[serde(skip_deserializing)]
+    pub id: i32,
+    pub name: String,
+    // 群主账户，转让群主时修改
+    pub owner: String,
+    // 毫秒时间戳
+    pub create_time: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
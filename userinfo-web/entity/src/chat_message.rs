@@ -0,0 +1,38 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// server保存的聊天记录，用于查询历史消息
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
+#[sea_orm(table_name = "chat_message")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = true)]
+    #[serde(skip_deserializing)]
+    pub id: i32,
+    // server分配的消息id
+    #[sea_orm(unique)]
+    pub msg_id: String,
+    // 会话id，两个账户之间的会话由账户名排序后拼接
+    #[sea_orm(indexed)]
+    pub conversation: String,
+    // 会话内递增的序号
+    pub seq: i64,
+    pub from_account: String,
+    pub to_account: String,
+    // 回复的消息id
+    pub reply_to: Option<String>,
+    // 回复所属话题的第一条消息id，用于查询整个话题
+    #[sea_orm(indexed)]
+    pub thread_id: Option<String>,
+    // bincode序列化后的ChatData
+    pub data: Vec<u8>,
+    // 阅后即焚的消息过期的时间，毫秒时间戳
+    #[sea_orm(indexed)]
+    pub expire_time: Option<i64>,
+    // 毫秒时间戳
+    pub create_time: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
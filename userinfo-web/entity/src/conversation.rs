@@ -0,0 +1,26 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// 会话，记录会话中最后分配的消息序号和会话的元数据
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
+#[sea_orm(table_name = "conversation")]
+pub struct Model {
+    // 会话id
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: String,
+    // 会话中最后一条消息的序号，从1开始递增
+    pub last_seq: i64,
+    // 阅后即焚的计时器，秒. 之后发送的消息在这段时间之后删除，None为关闭
+    pub disappear_seconds: Option<i64>,
+    // 标题、话题、头像文件的url，None为未设置. 置顶消息在conversation_pin中
+    pub title: Option<String>,
+    pub topic: Option<String>,
+    pub avatar: Option<String>,
+    // 毫秒时间戳
+    pub create_time: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -1 +1,14 @@
-pub mod userinfo;
\ No newline at end of file
+pub mod api_key;
+pub mod audit_log;
+pub mod chat_group;
+pub mod chat_message;
+pub mod conversation;
+pub mod conversation_pin;
+pub mod file_blob;
+pub mod group_member;
+pub mod message_attachment;
+pub mod message_edit;
+pub mod message_reaction;
+pub mod offline_message;
+pub mod scheduled_message;
+pub mod userinfo;
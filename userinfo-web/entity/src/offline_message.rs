@@ -0,0 +1,26 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// 接收方不在线时保存的聊天消息，接收方登录后按id顺序投递，client确认收到后删除
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
+#[sea_orm(table_name = "offline_message")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = true)]
+    #[serde(skip_deserializing)]
+    pub id: i32,
+    pub from_account: String,
+    #[sea_orm(indexed)]
+    pub to_account: String,
+    // bincode序列化后的ChatData
+    pub data: Vec<u8>,
+    // 阅后即焚的消息过期的时间，过期之后不再投递，毫秒时间戳
+    #[sea_orm(indexed)]
+    pub expire_time: Option<i64>,
+    // 毫秒时间戳
+    pub create_time: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,28 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// 定时发送的消息，到达发送时间后由server发送，发送之后删除
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Deserialize, Serialize)]
+#[sea_orm(table_name = "scheduled_message")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = true)]
+    #[serde(skip_deserializing)]
+    pub id: i32,
+    // 发送之后作为消息id
+    #[sea_orm(unique)]
+    pub schedule_id: String,
+    #[sea_orm(indexed)]
+    pub account: String,
+    // 毫秒时间戳
+    #[sea_orm(indexed)]
+    pub send_time: i64,
+    // bincode序列化后的ChatData
+    pub data: Vec<u8>,
+    // 毫秒时间戳
+    pub create_time: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -7,8 +7,35 @@ pub struct Model {
     #[sea_orm(primary_key, auto_increment = true)]
     #[serde(skip_deserializing)]
     pub id: i32,
+    #[sea_orm(unique)]
     pub name: String,
+    // 明文密码，只有旧数据才有值. 设置过密码的账户只保存下面的scram校验数据，该字段为空
     pub pwd: String,
+    // 昵称
+    pub nickname: Option<String>,
+    // 个性签名
+    pub signature: Option<String>,
+    // 连续登录失败次数，登录成功后清零
+    pub failed_login_count: i32,
+    // 最近一次登录失败的时间，毫秒时间戳
+    pub last_fail_time: Option<i64>,
+    // 锁定截止时间，毫秒时间戳. 为None或小于当前时间表示未锁定
+    pub locked_until: Option<i64>,
+    // 两步验证的totp密钥，base32编码. 开始绑定时生成
+    pub totp_secret: Option<String>,
+    // 是否已开启两步验证，绑定时验证码校验通过后才开启
+    pub totp_enabled: bool,
+    // 恢复码的sha256，逗号分隔
+    pub totp_recovery_codes: Option<String>,
+    // 最近一次校验通过的totp时间步长，同一个及更早的步长的验证码不能再次使用
+    pub totp_last_step: Option<i64>,
+    // scram登录的校验数据，由密码计算得到
+    pub scram_salt: Option<String>,
+    pub scram_iterations: i32,
+    pub scram_stored_key: Option<String>,
+    pub scram_server_key: Option<String>,
+    // 账户角色: admin/moderator/user/bot，见 common::permission::Role
+    pub role: String,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
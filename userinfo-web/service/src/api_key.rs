@@ -0,0 +1,77 @@
+use crate::totp::random_token;
+use common::scram::constant_time_eq;
+use sha2::{Digest, Sha256};
+
+// api key格式: rk_<prefix>_<secret>. prefix用于查找，secret只有持有人知道
+const API_KEY_TAG: &str = "rk";
+const API_KEY_PREFIX_LEN: usize = 8;
+const API_KEY_SECRET_LEN: usize = 40;
+
+// 生成新的api key，返回 (prefix, 完整key, 完整key的sha256)
+pub fn generate_api_key() -> (String, String, String) {
+    let prefix = random_token(API_KEY_PREFIX_LEN);
+    let key = format!(
+        "{API_KEY_TAG}_{prefix}_{}",
+        random_token(API_KEY_SECRET_LEN)
+    );
+    let hash = hash_api_key(&key);
+    (prefix, key, hash)
+}
+
+// 从完整key中解析出prefix，格式不正确时返回None
+pub fn parse_prefix(key: &str) -> Option<String> {
+    let mut parts = key.splitn(3, '_');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(API_KEY_TAG), Some(prefix), Some(secret))
+            if prefix.len() == API_KEY_PREFIX_LEN && secret.len() == API_KEY_SECRET_LEN =>
+        {
+            Some(prefix.to_string())
+        }
+        _ => None,
+    }
+}
+
+pub fn verify_api_key(key: &str, key_hash: &str) -> bool {
+    constant_time_eq(hash_api_key(key).as_bytes(), key_hash.as_bytes())
+}
+
+fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+// scopes在数据库中以逗号分隔保存
+pub fn split_scopes(scopes: &str) -> Vec<String> {
+    scopes
+        .split(',')
+        .map(|t| t.trim())
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generate_and_verify() {
+        let (prefix, key, hash) = generate_api_key();
+        assert_eq!(parse_prefix(&key), Some(prefix.clone()));
+        assert!(verify_api_key(&key, &hash));
+
+        // 修改secret的最后一个字符
+        let mut forged = key[..key.len() - 1].to_string();
+        forged.push(if key.ends_with('a') { 'b' } else { 'a' });
+        assert!(!verify_api_key(&forged, &hash));
+
+        assert_eq!(parse_prefix("rk_short_secret"), None);
+        assert_eq!(parse_prefix(&key.replacen("rk_", "xx_", 1)), None);
+        assert_eq!(parse_prefix(&format!("{key}0")), None);
+    }
+
+    #[test]
+    fn split_scopes_skip_empty() {
+        assert_eq!(split_scopes("Chat, Group,,"), vec!["Chat", "Group"]);
+        assert!(split_scopes("").is_empty());
+    }
+}
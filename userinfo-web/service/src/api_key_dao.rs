@@ -0,0 +1,72 @@
+use ::entity::api_key;
+use ::entity::api_key::{Entity, Model};
+use sea_orm::ActiveValue::{NotSet, Set};
+use sea_orm::DbErr;
+use sea_orm::*;
+
+#[derive(Debug)]
+pub struct ApiKeyDao {
+    pub db: DbConn,
+}
+
+impl ApiKeyDao {
+    pub async fn insert(&self, param: Model) -> Result<Model, DbErr> {
+        api_key::ActiveModel {
+            id: NotSet,
+            account: Set(param.account),
+            name: Set(param.name),
+            key_prefix: Set(param.key_prefix),
+            key_hash: Set(param.key_hash),
+            scopes: Set(param.scopes),
+            create_time: Set(param.create_time),
+            revoked_time: Set(param.revoked_time),
+            last_used_time: Set(param.last_used_time),
+        }
+        .insert(&self.db)
+        .await
+    }
+
+    pub async fn find_by_id(&self, id: i32) -> Result<Option<Model>, DbErr> {
+        Entity::find_by_id(id).one(&self.db).await
+    }
+
+    // 查询账户的所有key，包括已吊销的
+    pub async fn find_by_account(&self, account: String) -> Result<Vec<Model>, DbErr> {
+        Entity::find()
+            .filter(api_key::Column::Account.eq(account))
+            .order_by_desc(api_key::Column::Id)
+            .all(&self.db)
+            .await
+    }
+
+    pub async fn find_by_prefix(&self, key_prefix: String) -> Result<Option<Model>, DbErr> {
+        Entity::find()
+            .filter(api_key::Column::KeyPrefix.eq(key_prefix))
+            .one(&self.db)
+            .await
+    }
+
+    pub async fn update_revoked_time(&self, id: i32, revoked_time: i64) -> Result<Model, DbErr> {
+        api_key::ActiveModel {
+            id: Set(id),
+            revoked_time: Set(Some(revoked_time)),
+            ..Default::default()
+        }
+        .update(&self.db)
+        .await
+    }
+
+    pub async fn update_last_used_time(
+        &self,
+        id: i32,
+        last_used_time: i64,
+    ) -> Result<Model, DbErr> {
+        api_key::ActiveModel {
+            id: Set(id),
+            last_used_time: Set(Some(last_used_time)),
+            ..Default::default()
+        }
+        .update(&self.db)
+        .await
+    }
+}
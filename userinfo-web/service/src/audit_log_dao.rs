@@ -0,0 +1,57 @@
+use ::entity::audit_log;
+use ::entity::audit_log::{Entity, Model};
+use sea_orm::ActiveValue::{NotSet, Set};
+use sea_orm::DbErr;
+use sea_orm::*;
+
+pub const AUDIT_EVENT_LOCK: &str = "LOCK";
+pub const AUDIT_EVENT_UNLOCK: &str = "UNLOCK";
+pub const AUDIT_EVENT_IP_LOCK: &str = "IP_LOCK";
+pub const AUDIT_EVENT_TOTP_ENABLE: &str = "TOTP_ENABLE";
+pub const AUDIT_EVENT_TOTP_DISABLE: &str = "TOTP_DISABLE";
+pub const AUDIT_EVENT_ROLE_CHANGE: &str = "ROLE_CHANGE";
+pub const AUDIT_EVENT_API_KEY_CREATE: &str = "API_KEY_CREATE";
+pub const AUDIT_EVENT_API_KEY_REVOKE: &str = "API_KEY_REVOKE";
+
+#[derive(Debug)]
+pub struct AuditLogDao {
+    pub db: DbConn,
+}
+
+impl AuditLogDao {
+    // 记录一条审计日志
+    pub async fn insert(
+        &self,
+        account: String,
+        event: &str,
+        source: String,
+        detail: Option<String>,
+        create_time: i64,
+    ) -> Result<Model, DbErr> {
+        audit_log::ActiveModel {
+            id: NotSet,
+            account: Set(account),
+            event: Set(event.to_string()),
+            source: Set(source),
+            detail: Set(detail),
+            create_time: Set(create_time),
+        }
+        .insert(&self.db)
+        .await
+    }
+
+    // 按时间倒序分页查询
+    pub async fn find_in_page(
+        &self,
+        page: u64,
+        page_size: u64,
+    ) -> Result<(Vec<Model>, u64), DbErr> {
+        let paginator = Entity::find()
+            .order_by_desc(audit_log::Column::Id)
+            .paginate(&self.db, page_size);
+
+        let num_pages = paginator.num_pages().await?;
+
+        paginator.fetch_page(page - 1).await.map(|p| (p, num_pages))
+    }
+}
@@ -0,0 +1,115 @@
+use std::fmt::Debug;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/**
+ *  按内容的sha256保存文件的存储. 同样内容的文件只保存一份，
+ *  调用方需要保证sha256与文件内容一致
+ **/
+pub trait BlobStore: Debug + Send + Sync {
+    fn exists(&self, sha256: &str) -> Result<bool, String>;
+
+    // 保存已经校验过sha256的本地文件，保存之后删除本地文件. 已经存在时直接删除本地文件
+    fn put_file(&self, sha256: &str, path: &Path) -> Result<(), String>;
+
+    fn open(&self, sha256: &str) -> Result<Box<dyn Read + Send>, String>;
+
+    fn size(&self, sha256: &str) -> Result<u64, String>;
+}
+
+// 保存在本地目录中，按sha256的前两位分子目录，避免一个目录下的文件过多
+#[derive(Debug)]
+pub struct LocalBlobStore {
+    dir: PathBuf,
+}
+
+impl LocalBlobStore {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        LocalBlobStore {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    fn blob_path(&self, sha256: &str) -> Result<PathBuf, String> {
+        // sha256会作为文件名，不能包含路径分隔符等字符
+        if sha256.len() < 2 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("invalid blob sha256: {}", sha256));
+        }
+        Ok(self.dir.join(&sha256[..2]).join(sha256))
+    }
+}
+
+impl BlobStore for LocalBlobStore {
+    fn exists(&self, sha256: &str) -> Result<bool, String> {
+        Ok(self.blob_path(sha256)?.is_file())
+    }
+
+    fn put_file(&self, sha256: &str, path: &Path) -> Result<(), String> {
+        let blob_path = self.blob_path(sha256)?;
+        if blob_path.is_file() {
+            return fs::remove_file(path).map_err(|e| e.to_string());
+        }
+
+        fs::create_dir_all(blob_path.parent().unwrap()).map_err(|e| e.to_string())?;
+        // 上传目录与存储目录不在同一个文件系统时rename会失败，改为复制
+        if fs::rename(path, &blob_path).is_err() {
+            let tmp_path = blob_path.with_extension("tmp");
+            fs::copy(path, &tmp_path).map_err(|e| e.to_string())?;
+            fs::rename(&tmp_path, &blob_path).map_err(|e| e.to_string())?;
+            fs::remove_file(path).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn open(&self, sha256: &str) -> Result<Box<dyn Read + Send>, String> {
+        let file = File::open(self.blob_path(sha256)?).map_err(|e| e.to_string())?;
+        Ok(Box::new(file))
+    }
+
+    fn size(&self, sha256: &str) -> Result<u64, String> {
+        fs::metadata(self.blob_path(sha256)?)
+            .map(|t| t.len())
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn put_and_open_blob() {
+        let dir = env::temp_dir().join(format!("blob_store_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let store = LocalBlobStore::new(dir.join("blobs"));
+
+        let sha256 = "ab".repeat(32);
+        assert!(!store.exists(&sha256).unwrap());
+        let part_path = dir.join("a.part");
+        fs::write(&part_path, b"hello").unwrap();
+        store.put_file(&sha256, &part_path).unwrap();
+        assert!(!part_path.exists());
+        assert!(dir.join("blobs").join("ab").join(&sha256).is_file());
+
+        // 已经存在时只删除本地文件
+        fs::write(&part_path, b"other").unwrap();
+        store.put_file(&sha256, &part_path).unwrap();
+        assert!(!part_path.exists());
+        assert_eq!(store.size(&sha256).unwrap(), 5);
+        let mut content = String::new();
+        store
+            .open(&sha256)
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "hello");
+
+        assert!(store.exists("../etc").is_err());
+        assert!(store.open("a").is_err());
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,81 @@
+use ::entity::chat_group;
+use ::entity::chat_group::{Entity, Model};
+use sea_orm::ActiveValue::{NotSet, Set};
+use sea_orm::DbErr;
+use sea_orm::*;
+
+#[derive(Debug)]
+pub struct ChatGroupDao {
+    pub db: DbConn,
+}
+
+impl ChatGroupDao {
+    pub async fn insert(
+        &self,
+        name: String,
+        owner: String,
+        create_time: i64,
+    ) -> Result<Model, DbErr> {
+        chat_group::ActiveModel {
+            id: NotSet,
+            name: Set(name),
+            owner: Set(owner),
+            create_time: Set(create_time),
+        }
+        .insert(&self.db)
+        .await
+    }
+
+    pub async fn find_by_id(&self, id: i32) -> Result<Option<Model>, DbErr> {
+        Entity::find_by_id(id).one(&self.db).await
+    }
+
+    // 在事务中锁定群组，修改群主之前调用
+    pub async fn lock_by_id(
+        &self,
+        txn: &DatabaseTransaction,
+        id: i32,
+    ) -> Result<Option<Model>, DbErr> {
+        Entity::find_by_id(id).lock_exclusive().one(txn).await
+    }
+
+    pub async fn find_by_ids(&self, ids: Vec<i32>) -> Result<Vec<Model>, DbErr> {
+        Entity::find()
+            .filter(chat_group::Column::Id.is_in(ids))
+            .order_by_asc(chat_group::Column::Id)
+            .all(&self.db)
+            .await
+    }
+
+    pub async fn update_name(&self, id: i32, name: String) -> Result<Model, DbErr> {
+        chat_group::ActiveModel {
+            id: Set(id),
+            name: Set(name),
+            ..Default::default()
+        }
+        .update(&self.db)
+        .await
+    }
+
+    pub async fn update_owner(
+        &self,
+        txn: &DatabaseTransaction,
+        id: i32,
+        owner: String,
+    ) -> Result<Model, DbErr> {
+        chat_group::ActiveModel {
+            id: Set(id),
+            owner: Set(owner),
+            ..Default::default()
+        }
+        .update(txn)
+        .await
+    }
+
+    pub async fn delete_by_id(&self, id: i32) -> Result<u64, DbErr> {
+        Entity::delete_by_id(id)
+            .exec(&self.db)
+            .await
+            .map(|t| t.rows_affected)
+    }
+}
@@ -0,0 +1,124 @@
+use ::entity::chat_message;
+use ::entity::chat_message::{Entity, Model};
+use sea_orm::ActiveValue::{NotSet, Set};
+use sea_orm::DbErr;
+use sea_orm::*;
+
+#[derive(Debug)]
+pub struct ChatMessageDao {
+    pub db: DbConn,
+}
+
+impl ChatMessageDao {
+    // 与分配序号在同一个事务中保存，id由数据库生成
+    pub async fn insert(&self, txn: &DatabaseTransaction, param: Model) -> Result<Model, DbErr> {
+        let mut model = chat_message::ActiveModel::from(param);
+        model.id = NotSet;
+        model.insert(txn).await
+    }
+
+    // 修改、撤回消息时更新消息内容
+    pub async fn update_data(&self, id: i32, data: Vec<u8>) -> Result<Model, DbErr> {
+        chat_message::ActiveModel {
+            id: Set(id),
+            data: Set(data),
+            ..Default::default()
+        }
+        .update(&self.db)
+        .await
+    }
+
+    pub async fn find_by_msg_id(&self, msg_id: String) -> Result<Option<Model>, DbErr> {
+        Entity::find()
+            .filter(chat_message::Column::MsgId.eq(msg_id))
+            .one(&self.db)
+            .await
+    }
+
+    // 按消息id批量查询，不存在的id直接忽略，返回的顺序不确定
+    pub async fn find_by_msg_ids(&self, msg_ids: Vec<String>) -> Result<Vec<Model>, DbErr> {
+        Entity::find()
+            .filter(chat_message::Column::MsgId.is_in(msg_ids))
+            .all(&self.db)
+            .await
+    }
+
+    // 查询序号小于before_seq的消息，按序号倒序. before_seq为None时从最新的消息开始
+    pub async fn find_before(
+        &self,
+        conversation: String,
+        before_seq: Option<i64>,
+        limit: u64,
+    ) -> Result<Vec<Model>, DbErr> {
+        let mut select = Entity::find().filter(chat_message::Column::Conversation.eq(conversation));
+        if let Some(seq) = before_seq {
+            select = select.filter(chat_message::Column::Seq.lt(seq));
+        }
+
+        select
+            .order_by_desc(chat_message::Column::Seq)
+            .limit(limit)
+            .all(&self.db)
+            .await
+    }
+
+    // 查询序号大于after_seq的消息，按序号正序
+    pub async fn find_after(
+        &self,
+        conversation: String,
+        after_seq: i64,
+        limit: u64,
+    ) -> Result<Vec<Model>, DbErr> {
+        Entity::find()
+            .filter(chat_message::Column::Conversation.eq(conversation))
+            .filter(chat_message::Column::Seq.gt(after_seq))
+            .order_by_asc(chat_message::Column::Seq)
+            .limit(limit)
+            .all(&self.db)
+            .await
+    }
+
+    // 按id正序分页查询所有的消息，用于重建搜索索引
+    pub async fn find_after_id(&self, after_id: i32, limit: u64) -> Result<Vec<Model>, DbErr> {
+        Entity::find()
+            .filter(chat_message::Column::Id.gt(after_id))
+            .order_by_asc(chat_message::Column::Id)
+            .limit(limit)
+            .all(&self.db)
+            .await
+    }
+
+    // 查询已经过期的阅后即焚消息
+    pub async fn find_expired(&self, now: i64, limit: u64) -> Result<Vec<Model>, DbErr> {
+        Entity::find()
+            .filter(chat_message::Column::ExpireTime.lte(now))
+            .order_by_asc(chat_message::Column::ExpireTime)
+            .limit(limit)
+            .all(&self.db)
+            .await
+    }
+
+    pub async fn delete_by_ids(&self, ids: Vec<i32>) -> Result<u64, DbErr> {
+        Entity::delete_many()
+            .filter(chat_message::Column::Id.is_in(ids))
+            .exec(&self.db)
+            .await
+            .map(|t| t.rows_affected)
+    }
+
+    // 查询话题中序号大于after_seq的回复，按序号正序
+    pub async fn find_thread(
+        &self,
+        thread_id: String,
+        after_seq: i64,
+        limit: u64,
+    ) -> Result<Vec<Model>, DbErr> {
+        Entity::find()
+            .filter(chat_message::Column::ThreadId.eq(thread_id))
+            .filter(chat_message::Column::Seq.gt(after_seq))
+            .order_by_asc(chat_message::Column::Seq)
+            .limit(limit)
+            .all(&self.db)
+            .await
+    }
+}
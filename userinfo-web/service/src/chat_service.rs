@@ -0,0 +1,2053 @@
+use crate::blob_store::BlobStore;
+use crate::chat_group_dao::ChatGroupDao;
+use crate::chat_message_dao::ChatMessageDao;
+use crate::conversation_dao::ConversationDao;
+use crate::conversation_pin_dao::ConversationPinDao;
+use crate::file_blob_dao::FileBlobDao;
+use crate::group_member_dao::GroupMemberDao;
+use crate::message_attachment_dao::MessageAttachmentDao;
+use crate::message_edit_dao::{
+    MessageEditDao, MESSAGE_EDIT_ACTION_EDIT, MESSAGE_EDIT_ACTION_RECALL,
+};
+use crate::message_index::MessageIndex;
+use crate::message_reaction_dao::MessageReactionDao;
+use crate::offline_message_dao::OfflineMessageDao;
+use crate::scheduled_message_dao::ScheduledMessageDao;
+use crate::userinfo_dao::Dao;
+use ::entity::{
+    chat_group, chat_message, conversation, conversation_pin, group_member, message_reaction,
+    offline_message, scheduled_message,
+};
+use common::base::now_millis;
+use common::chat_content::{
+    find_file_urls, ChatDisappearTimerContent, ChatMetadataContent, ChatRichContent,
+    RichContentEnum,
+};
+use common::chat_module::{
+    create_msg_id, create_snippet, get_conversation_peer, get_group_conversation_id, get_group_id,
+    ChatContent, ChatData, ChatDataEnum, ConversationMetadata, HistoryCursor, MessageVersionData,
+    MetadataChangeEnum, ReactionData, ReplyData, ScheduledData, SearchReqData,
+    DISAPPEAR_TIMER_MAX_SECONDS, DISAPPEAR_TIMER_MIN_SECONDS,
+};
+use common::file_module::get_file_sha256;
+use common::group_module::{GroupInfoData, GroupMemberData, GroupRole};
+use log::warn;
+use sea_orm::{DatabaseTransaction, TransactionTrait};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// 聊天消息相关的存储
+#[derive(Debug)]
+pub struct ChatService {
+    pub offline_message_dao: OfflineMessageDao,
+    pub chat_message_dao: ChatMessageDao,
+    pub conversation_dao: ConversationDao,
+    pub conversation_pin_dao: ConversationPinDao,
+    pub chat_group_dao: ChatGroupDao,
+    pub group_member_dao: GroupMemberDao,
+    pub message_edit_dao: MessageEditDao,
+    pub message_reaction_dao: MessageReactionDao,
+    pub file_blob_dao: FileBlobDao,
+    pub message_attachment_dao: MessageAttachmentDao,
+    pub scheduled_message_dao: ScheduledMessageDao,
+    // 用于确认邀请的账户存在
+    pub userinfo_dao: Dao,
+    // 上传的附件
+    pub blob_store: Arc<dyn BlobStore>,
+    // 消息的全文索引
+    pub message_index: MessageIndex,
+}
+
+// 群组名称的最大长度
+const GROUP_NAME_MAX_LEN: usize = 64;
+
+// 群组成员数量上限
+const GROUP_MAX_MEMBERS: usize = 500;
+
+// 表情的最大长度，组合表情由多个字符组成
+const REACTION_EMOJI_MAX_LEN: usize = 16;
+
+// 一条消息最多的不同表情数量
+const REACTION_MAX_EMOJIS: usize = 20;
+
+// 会话标题、话题的最大长度
+const CONVERSATION_TITLE_MAX_LEN: usize = 64;
+const CONVERSATION_TOPIC_MAX_LEN: usize = 512;
+
+// 每个会话最多置顶的消息数量
+const CONVERSATION_MAX_PINNED: usize = 50;
+
+// 重建搜索索引时每次读取的消息数量
+const INDEX_BATCH_SIZE: u64 = 500;
+
+// 每次删除的过期消息数量
+const PURGE_BATCH_SIZE: u64 = 500;
+
+// 每个账户未发送的定时消息的数量上限
+const SCHEDULE_MAX_PENDING: u64 = 100;
+
+// 定时消息最晚可以在多长时间之后发送
+const SCHEDULE_MAX_DELAY_MILLIS: i64 = 365 * 24 * 3600 * 1000;
+
+impl ChatService {
+    // 保存聊天记录，并填写server分配的会话序号和时间.
+    // 分配序号和保存消息在同一个事务中，同一个会话的消息按序号顺序可见
+    pub async fn save_message(&self, data: &mut ChatData) -> Result<(), String> {
+        let conversation = data.conversation_id();
+
+        let txn = self
+            .chat_message_dao
+            .db
+            .begin()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        data.time = now_millis();
+        let model = self
+            .conversation_dao
+            .increase_seq(&txn, conversation.clone(), data.time)
+            .await
+            .map_err(|e| e.to_string())?;
+        data.seq = model.last_seq;
+        // 使用发送时会话的计时器
+        data.expire_time = model.disappear_seconds.map(|t| data.time + t * 1000);
+
+        self.insert_message(&txn, data, conversation).await?;
+        txn.commit().await.map_err(|e| e.to_string())?;
+
+        self.update_index(std::slice::from_ref(data));
+        Ok(())
+    }
+
+    // 修改会话的阅后即焚计时器，并在会话中保存一条系统消息. 修改之前发送的消息不受影响.
+    // 群组中只有群主、管理员可以修改，两人会话的双方都可以修改
+    pub async fn set_disappear_timer(
+        &self,
+        operator: String,
+        conversation: String,
+        expire_seconds: Option<i64>,
+    ) -> Result<ChatData, String> {
+        check_disappear_seconds(expire_seconds)?;
+        let (to_account, group_id) = self
+            .check_conversation_manager(&operator, &conversation)
+            .await?;
+
+        let content = RichContentEnum::DisappearTimer(ChatDisappearTimerContent {
+            operator: operator.clone(),
+            expire_seconds,
+        });
+        let mut data = create_system_message(operator, to_account, group_id, &content);
+
+        let txn = self
+            .chat_message_dao
+            .db
+            .begin()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        data.seq = self
+            .conversation_dao
+            .increase_seq(&txn, conversation.clone(), data.time)
+            .await
+            .map_err(|e| e.to_string())?
+            .last_seq;
+        self.conversation_dao
+            .update_disappear_seconds(&txn, conversation.clone(), expire_seconds)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        self.insert_message(&txn, &data, conversation).await?;
+        txn.commit().await.map_err(|e| e.to_string())?;
+        Ok(data)
+    }
+
+    // 查询会话的标题、话题、头像和置顶消息，没有消息的会话返回空的元数据
+    pub async fn find_metadata(
+        &self,
+        account: String,
+        conversation: String,
+    ) -> Result<ConversationMetadata, String> {
+        self.check_conversation_access(&account, &conversation)
+            .await?;
+
+        let model = self
+            .conversation_dao
+            .find_by_id(conversation.clone())
+            .await
+            .map_err(|e| e.to_string())?;
+        let pins = self
+            .conversation_pin_dao
+            .find_by_conversation(&self.conversation_pin_dao.db, conversation.clone())
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(to_metadata(conversation, model, pins))
+    }
+
+    // 修改会话的元数据，并在会话中保存一条带有修改之后的元数据的系统消息.
+    // 权限与修改阅后即焚计时器相同
+    pub async fn update_metadata(
+        &self,
+        operator: String,
+        conversation: String,
+        change: MetadataChangeEnum,
+    ) -> Result<ChatData, String> {
+        let (to_account, group_id) = self
+            .check_conversation_manager(&operator, &conversation)
+            .await?;
+        let change = self
+            .check_metadata_change(&operator, &conversation, change)
+            .await?;
+
+        let txn = self
+            .chat_message_dao
+            .db
+            .begin()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let time = now_millis();
+        let mut model = self
+            .conversation_dao
+            .increase_seq(&txn, conversation.clone(), time)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut pins = self
+            .conversation_pin_dao
+            .find_by_conversation(&txn, conversation.clone())
+            .await
+            .map_err(|e| e.to_string())?;
+
+        match &change {
+            MetadataChangeEnum::Title(t) => model.title = t.clone(),
+            MetadataChangeEnum::Topic(t) => model.topic = t.clone(),
+            MetadataChangeEnum::Avatar(t) => model.avatar = t.clone(),
+            MetadataChangeEnum::Pin(msg_id) => {
+                if pins.iter().any(|t| &t.msg_id == msg_id) {
+                    return Err("message has been pinned !".to_string());
+                }
+                if pins.len() >= CONVERSATION_MAX_PINNED {
+                    return Err(format!(
+                        "can not pin more than {CONVERSATION_MAX_PINNED} messages !"
+                    ));
+                }
+                let pin = self
+                    .conversation_pin_dao
+                    .insert(
+                        &txn,
+                        conversation.clone(),
+                        msg_id.clone(),
+                        operator.clone(),
+                        time,
+                    )
+                    .await
+                    .map_err(|e| e.to_string())?;
+                pins.push(pin);
+            }
+            MetadataChangeEnum::Unpin(msg_id) => {
+                let count = self
+                    .conversation_pin_dao
+                    .delete_by_msg_id(&txn, conversation.clone(), msg_id.clone())
+                    .await
+                    .map_err(|e| e.to_string())?;
+                if count == 0 {
+                    return Err("message is not pinned !".to_string());
+                }
+                pins.retain(|t| &t.msg_id != msg_id);
+            }
+        }
+        self.conversation_dao
+            .update_metadata(
+                &txn,
+                conversation.clone(),
+                model.title.clone(),
+                model.topic.clone(),
+                model.avatar.clone(),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let seq = model.last_seq;
+        let content = RichContentEnum::MetadataChanged(ChatMetadataContent {
+            operator: operator.clone(),
+            change,
+            metadata: to_metadata(conversation.clone(), Some(model), pins),
+        });
+        let mut data = create_system_message(operator, to_account, group_id, &content);
+        data.seq = seq;
+        data.time = time;
+
+        self.insert_message(&txn, &data, conversation).await?;
+        txn.commit().await.map_err(|e| e.to_string())?;
+        Ok(data)
+    }
+
+    // 校验修改的内容. 头像必须是操作者可以访问的已上传文件，置顶的消息必须属于该会话
+    async fn check_metadata_change(
+        &self,
+        operator: &String,
+        conversation: &String,
+        change: MetadataChangeEnum,
+    ) -> Result<MetadataChangeEnum, String> {
+        match change {
+            MetadataChangeEnum::Title(Some(t)) => Ok(MetadataChangeEnum::Title(Some(
+                check_metadata_text(t, "title", CONVERSATION_TITLE_MAX_LEN)?,
+            ))),
+            MetadataChangeEnum::Topic(Some(t)) => Ok(MetadataChangeEnum::Topic(Some(
+                check_metadata_text(t, "topic", CONVERSATION_TOPIC_MAX_LEN)?,
+            ))),
+            MetadataChangeEnum::Avatar(Some(url)) => {
+                let sha256 =
+                    get_file_sha256(&url).ok_or("avatar must be an uploaded file !".to_string())?;
+                self.check_attachment_access(operator, &sha256.to_string())
+                    .await?;
+                Ok(MetadataChangeEnum::Avatar(Some(url)))
+            }
+            MetadataChangeEnum::Pin(msg_id) => {
+                let (model, data) = self.find_message_model(msg_id.clone()).await?;
+                if &model.conversation != conversation || data.recall_by.is_some() {
+                    return Err(format!("message not exist: {}", msg_id));
+                }
+                Ok(MetadataChangeEnum::Pin(msg_id))
+            }
+            t => Ok(t),
+        }
+    }
+
+    // 群组中只有群主、管理员可以修改会话的设置，两人会话的双方都可以修改.
+    // 返回系统消息的to_account和group_id
+    async fn check_conversation_manager(
+        &self,
+        operator: &String,
+        conversation: &String,
+    ) -> Result<(String, Option<i32>), String> {
+        match get_group_id(conversation) {
+            Some(group_id) => {
+                self.check_group_manager(group_id, operator).await?;
+                Ok((String::new(), Some(group_id)))
+            }
+            None => {
+                let peer = get_conversation_peer(conversation, operator)
+                    .ok_or(format!("conversation not exist: {}", conversation))?;
+                Ok((peer, None))
+            }
+        }
+    }
+
+    // 删除已经过期的阅后即焚消息，以及消息的回应、修改历史、附件记录、置顶和离线数据.
+    // 返回删除的消息和离线数据的数量
+    pub async fn purge_expired_messages(&self, now: i64) -> Result<(u64, u64), String> {
+        let mut count = 0;
+        loop {
+            let list = self
+                .chat_message_dao
+                .find_expired(now, PURGE_BATCH_SIZE)
+                .await
+                .map_err(|e| e.to_string())?;
+            if list.is_empty() {
+                break;
+            }
+
+            let ids: Vec<i32> = list.iter().map(|t| t.id).collect();
+            let msg_ids: Vec<String> = list.into_iter().map(|t| t.msg_id).collect();
+
+            // 先删除关联的数据，中途失败时下次还能找到这些消息
+            self.message_reaction_dao
+                .delete_by_msg_ids(msg_ids.clone())
+                .await
+                .map_err(|e| e.to_string())?;
+            self.message_edit_dao
+                .delete_by_msg_ids(msg_ids.clone())
+                .await
+                .map_err(|e| e.to_string())?;
+            self.message_attachment_dao
+                .delete_by_msg_ids(msg_ids.clone())
+                .await
+                .map_err(|e| e.to_string())?;
+            self.conversation_pin_dao
+                .delete_by_msg_ids(msg_ids)
+                .await
+                .map_err(|e| e.to_string())?;
+
+            let len = ids.len() as u64;
+            count += self
+                .chat_message_dao
+                .delete_by_ids(ids)
+                .await
+                .map_err(|e| e.to_string())?;
+            if len < PURGE_BATCH_SIZE {
+                break;
+            }
+        }
+
+        let offline_count = self
+            .offline_message_dao
+            .delete_expired(now)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok((count, offline_count))
+    }
+
+    // 保存定时发送的消息，schedule_id在发送之后作为消息id. 消息内容和接收方需要调用方检查
+    pub async fn schedule_message(
+        &self,
+        mut data: ChatData,
+        send_time: i64,
+    ) -> Result<ScheduledData, String> {
+        let time = now_millis();
+        check_send_time(send_time, time)?;
+
+        let count = self
+            .scheduled_message_dao
+            .count_by_account(data.from_account.clone())
+            .await
+            .map_err(|e| e.to_string())?;
+        if count >= SCHEDULE_MAX_PENDING {
+            return Err(format!(
+                "scheduled msgs can not exceed {SCHEDULE_MAX_PENDING} !"
+            ));
+        }
+
+        data.msg_id = create_msg_id();
+        let bytes = bincode::serialize(&data).map_err(|e| e.to_string())?;
+        self.scheduled_message_dao
+            .insert(
+                data.msg_id.clone(),
+                data.from_account.clone(),
+                send_time,
+                bytes,
+                time,
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(ScheduledData {
+            schedule_id: data.msg_id.clone(),
+            data,
+            send_time,
+            create_time: time,
+        })
+    }
+
+    // 按发送时间返回账户未发送的定时消息
+    pub async fn find_scheduled(&self, account: String) -> Result<Vec<ScheduledData>, String> {
+        let list = self
+            .scheduled_message_dao
+            .find_by_account(account)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        list.into_iter().map(to_scheduled).collect()
+    }
+
+    // 修改未发送的定时消息，contents、send_time为None时不修改
+    pub async fn edit_scheduled(
+        &self,
+        account: String,
+        schedule_id: String,
+        contents: Option<Vec<ChatContent>>,
+        send_time: Option<i64>,
+    ) -> Result<ScheduledData, String> {
+        let (model, mut scheduled) = self.find_pending_scheduled(&account, schedule_id).await?;
+
+        if let Some(t) = send_time {
+            check_send_time(t, now_millis())?;
+            scheduled.send_time = t;
+        }
+        if let Some(t) = contents {
+            scheduled.data.contents = t;
+        }
+
+        let bytes = bincode::serialize(&scheduled.data).map_err(|e| e.to_string())?;
+        self.scheduled_message_dao
+            .update(model.id, scheduled.send_time, bytes)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(scheduled)
+    }
+
+    // 取消未发送的定时消息，返回被取消的消息
+    pub async fn cancel_scheduled(
+        &self,
+        account: String,
+        schedule_id: String,
+    ) -> Result<ScheduledData, String> {
+        let (_, scheduled) = self.find_pending_scheduled(&account, schedule_id).await?;
+        self.delete_scheduled(scheduled.schedule_id.clone()).await?;
+        Ok(scheduled)
+    }
+
+    // 按发送时间返回已经到达发送时间的定时消息
+    pub async fn find_due_scheduled(
+        &self,
+        now: i64,
+        limit: u64,
+    ) -> Result<Vec<ScheduledData>, String> {
+        let list = self
+            .scheduled_message_dao
+            .find_due(now, limit)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        list.into_iter().map(to_scheduled).collect()
+    }
+
+    pub async fn delete_scheduled(&self, schedule_id: String) -> Result<(), String> {
+        self.scheduled_message_dao
+            .delete_by_schedule_id(schedule_id)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    // 只能修改、取消自己的定时消息. 已经到达发送时间的消息可能正在发送，不能再修改
+    async fn find_pending_scheduled(
+        &self,
+        account: &String,
+        schedule_id: String,
+    ) -> Result<(scheduled_message::Model, ScheduledData), String> {
+        let model = self
+            .scheduled_message_dao
+            .find_by_schedule_id(schedule_id.clone())
+            .await
+            .map_err(|e| e.to_string())?
+            .filter(|t| &t.account == account)
+            .ok_or(format!("scheduled msg not exist: {}", schedule_id))?;
+
+        if model.send_time <= now_millis() {
+            return Err("scheduled msg is being sent !".to_string());
+        }
+
+        let scheduled = to_scheduled(model.clone())?;
+        Ok((model, scheduled))
+    }
+
+    // 保存消息以及消息中的附件，调用方负责分配序号和提交事务
+    async fn insert_message(
+        &self,
+        txn: &DatabaseTransaction,
+        data: &ChatData,
+        conversation: String,
+    ) -> Result<(), String> {
+        let bytes = bincode::serialize(data).map_err(|e| e.to_string())?;
+        let model = chat_message::Model {
+            id: 0,
+            msg_id: data.msg_id.clone(),
+            conversation: conversation.clone(),
+            seq: data.seq,
+            from_account: data.from_account.clone(),
+            to_account: data.to_account.clone(),
+            reply_to: data.reply_to.as_ref().map(|t| t.msg_id.clone()),
+            thread_id: data.reply_to.as_ref().map(|t| t.thread_id.clone()),
+            data: bytes,
+            expire_time: data.expire_time,
+            create_time: data.time,
+        };
+        self.chat_message_dao
+            .insert(txn, model)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        for sha256 in get_attachment_sha256s(&data.contents) {
+            self.message_attachment_dao
+                .insert(
+                    txn,
+                    data.msg_id.clone(),
+                    conversation.clone(),
+                    sha256,
+                    data.time,
+                )
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    // 保存上传完成并校验过sha256的文件，并记录上传的账户
+    pub async fn save_blob(
+        &self,
+        account: String,
+        sha256: String,
+        size: u64,
+        path: &Path,
+    ) -> Result<(), String> {
+        self.blob_store.put_file(&sha256, path)?;
+
+        if !self.has_uploaded_blob(&account, &sha256).await? {
+            self.file_blob_dao
+                .insert(sha256, account, size as i64, now_millis())
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    // 账户上传过该文件时不需要重新上传.
+    // 其它账户上传过的文件仍然需要上传完整的内容，不能只凭sha256获取文件
+    pub async fn has_uploaded_blob(&self, account: &str, sha256: &str) -> Result<bool, String> {
+        let uploaded = self
+            .file_blob_dao
+            .find_by_sha256_and_account(sha256.to_string(), account.to_string())
+            .await
+            .map_err(|e| e.to_string())?
+            .is_some();
+
+        Ok(uploaded && self.blob_store.exists(sha256)?)
+    }
+
+    // 上传者，或者附件所在的任何一个会话的参与者可以访问附件
+    pub async fn check_attachment_access(
+        &self,
+        account: &String,
+        sha256: &String,
+    ) -> Result<(), String> {
+        if self.has_uploaded_blob(account, sha256).await? {
+            return Ok(());
+        }
+
+        let conversations = self
+            .message_attachment_dao
+            .find_conversations(sha256.clone())
+            .await
+            .map_err(|e| e.to_string())?;
+        for conversation in conversations {
+            if self
+                .check_conversation_access(account, &conversation)
+                .await
+                .is_ok()
+            {
+                return Ok(());
+            }
+        }
+        Err(format!("file not exist: {}", sha256))
+    }
+
+    // 只有会话的参与者可以访问会话
+    pub async fn check_conversation_access(
+        &self,
+        account: &String,
+        conversation: &String,
+    ) -> Result<(), String> {
+        match get_group_id(conversation) {
+            Some(group_id) => self.find_group_role(group_id, account).await.map(|_| ()),
+            None => get_conversation_peer(conversation, account)
+                .map(|_| ())
+                .ok_or(format!("conversation not exist: {}", conversation)),
+        }
+    }
+
+    // 查询会话中序号大于seq的消息，用于重新连接的设备同步缺失的消息.
+    // 返回按序号正序的消息和是否还有更多消息
+    pub async fn find_since(
+        &self,
+        conversation: String,
+        seq: i64,
+        limit: u64,
+    ) -> Result<(Vec<ChatData>, bool), String> {
+        let list = self
+            .chat_message_dao
+            .find_after(conversation, seq, limit + 1)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        to_chat_data_page(list, limit)
+    }
+
+    // 分页查询会话的历史消息，返回按时间正序的消息和沿查询方向是否还有更多消息
+    pub async fn find_history(
+        &self,
+        conversation: String,
+        cursor: &HistoryCursor,
+        limit: u64,
+    ) -> Result<(Vec<ChatData>, bool), String> {
+        // 多查一条用于判断是否还有更多消息
+        let mut list = match cursor {
+            HistoryCursor::Latest => self
+                .chat_message_dao
+                .find_before(conversation, None, limit + 1)
+                .await
+                .map_err(|e| e.to_string())?,
+            HistoryCursor::Before(msg_id) => {
+                let seq = self.find_cursor_seq(&conversation, msg_id).await?;
+                self.chat_message_dao
+                    .find_before(conversation, Some(seq), limit + 1)
+                    .await
+                    .map_err(|e| e.to_string())?
+            }
+            HistoryCursor::After(msg_id) => {
+                let seq = self.find_cursor_seq(&conversation, msg_id).await?;
+                self.chat_message_dao
+                    .find_after(conversation, seq, limit + 1)
+                    .await
+                    .map_err(|e| e.to_string())?
+            }
+        };
+
+        // 向前查询时是倒序的，多查的一条在末尾
+        if !matches!(cursor, HistoryCursor::After(_)) {
+            let has_more = list.len() as u64 > limit;
+            list.truncate(limit as usize);
+            list.reverse();
+            return to_chat_data_page(list, limit).map(|(t, _)| (t, has_more));
+        }
+
+        to_chat_data_page(list, limit)
+    }
+
+    // 在账户参与的会话中搜索消息，返回按发送时间倒序的消息和是否还有更多消息.
+    // 群组以当前的成员为准，退出群组之后不能再搜索到群组中的消息
+    pub async fn search_messages(
+        &self,
+        account: String,
+        req: &SearchReqData,
+        limit: u64,
+    ) -> Result<(Vec<ChatData>, bool), String> {
+        if let Some(conversation) = &req.conversation {
+            self.check_conversation_access(&account, conversation)
+                .await?;
+        }
+
+        let group_conversations = self
+            .group_member_dao
+            .find_by_account(account.clone())
+            .await
+            .map_err(|e| e.to_string())?
+            .into_iter()
+            .map(|t| get_group_conversation_id(t.group_id))
+            .collect();
+        // 多查询一条，用于判断是否还有更多
+        let msg_ids =
+            self.message_index
+                .search(&account, group_conversations, req, limit as usize + 1)?;
+        let has_more = msg_ids.len() > limit as usize;
+
+        // 索引更新之前已经删除、过期或者撤回的消息不返回
+        let mut list = vec![];
+        for msg_id in msg_ids.into_iter().take(limit as usize) {
+            if let Ok(data) = self.find_message(msg_id).await {
+                if data.recall_by.is_none() {
+                    list.push(data);
+                }
+            }
+        }
+        Ok((list, has_more))
+    }
+
+    // 搜索索引为空时为已经保存的消息建立索引，返回读取的消息数量
+    pub async fn rebuild_search_index(&self) -> Result<u64, String> {
+        let mut count = 0;
+        let mut after_id = 0;
+        loop {
+            let models = self
+                .chat_message_dao
+                .find_after_id(after_id, INDEX_BATCH_SIZE)
+                .await
+                .map_err(|e| e.to_string())?;
+            if models.is_empty() {
+                break;
+            }
+            after_id = models.last().unwrap().id;
+            count += models.len() as u64;
+
+            let mut list = vec![];
+            for model in models {
+                list.push(bincode::deserialize(&model.data).map_err(|e| e.to_string())?);
+            }
+            self.message_index.add(&list)?;
+        }
+        self.message_index.flush()?;
+        Ok(count)
+    }
+
+    // 索引失败不影响消息的保存和修改，只记录日志
+    fn update_index(&self, list: &[ChatData]) {
+        if let Err(e) = self.message_index.add(list) {
+            warn!("update search index fail: {}", e);
+        }
+    }
+
+    // 查询被转发的消息. 只能转发自己能访问的会话中的消息，撤回、阅后即焚的消息不能转发
+    pub async fn find_forward_source(
+        &self,
+        account: &String,
+        msg_id: String,
+    ) -> Result<ChatData, String> {
+        let data = self.find_message(msg_id.clone()).await?;
+        self.check_participant(account, &data).await?;
+
+        if data.recall_by.is_some() {
+            return Err(format!("message not exist: {}", msg_id));
+        }
+        if data.expire_time.is_some() {
+            return Err("disappearing message can not be forwarded !".to_string());
+        }
+        Ok(data)
+    }
+
+    // 根据server分配的消息id查询消息
+    pub async fn find_message(&self, msg_id: String) -> Result<ChatData, String> {
+        self.find_message_model(msg_id).await.map(|(_, t)| t)
+    }
+
+    // 发送方在修改时限内可以修改自己的消息，修改之前的内容保存到修改历史
+    pub async fn edit_message(
+        &self,
+        editor: String,
+        msg_id: String,
+        contents: Vec<ChatContent>,
+        window_millis: i64,
+    ) -> Result<ChatData, String> {
+        let (model, mut data) = self.find_message_model(msg_id).await?;
+
+        let time = now_millis();
+        check_edit(&editor, &data, time, window_millis)?;
+
+        self.save_message_version(&data, editor, MESSAGE_EDIT_ACTION_EDIT, time)
+            .await?;
+
+        // 修改之后新增的附件也需要记录所在的会话
+        for sha256 in get_attachment_sha256s(&contents) {
+            self.message_attachment_dao
+                .insert(
+                    &self.message_attachment_dao.db,
+                    data.msg_id.clone(),
+                    data.conversation_id(),
+                    sha256,
+                    time,
+                )
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+
+        data.contents = contents;
+        data.edit_time = Some(time);
+        self.update_message(model.id, &data).await?;
+        self.update_index(&[data.clone()]);
+        Ok(data)
+    }
+
+    // 撤回消息，只保留消息的位置. window_millis为None时不限制时间，用于管理员撤回
+    pub async fn recall_message(
+        &self,
+        operator: String,
+        msg_id: String,
+        window_millis: Option<i64>,
+    ) -> Result<ChatData, String> {
+        let (model, mut data) = self.find_message_model(msg_id).await?;
+
+        let time = now_millis();
+        check_recall(&operator, &data, time, window_millis)?;
+
+        self.save_message_version(&data, operator.clone(), MESSAGE_EDIT_ACTION_RECALL, time)
+            .await?;
+
+        data.contents = vec![];
+        data.recall_by = Some(operator);
+        self.update_message(model.id, &data).await?;
+        // 撤回的消息不再置顶，也不能再被搜索到
+        self.conversation_pin_dao
+            .delete_by_msg_ids(vec![data.msg_id.clone()])
+            .await
+            .map_err(|e| e.to_string())?;
+        self.update_index(&[data.clone()]);
+        Ok(data)
+    }
+
+    // 按修改的顺序返回消息修改、撤回之前的内容
+    pub async fn find_edit_history(
+        &self,
+        msg_id: String,
+    ) -> Result<Vec<MessageVersionData>, String> {
+        let list = self
+            .message_edit_dao
+            .find_by_msg_id(msg_id)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        list.into_iter()
+            .map(|t| {
+                bincode::deserialize(&t.data)
+                    .map(|contents| MessageVersionData {
+                        editor: t.editor,
+                        recalled: t.action == MESSAGE_EDIT_ACTION_RECALL,
+                        contents,
+                        time: t.create_time,
+                    })
+                    .map_err(|e| e.to_string())
+            })
+            .collect()
+    }
+
+    // 查询被回复的消息，生成回复中引用的发送方、摘要和话题.
+    // 被回复的消息必须属于同一个会话
+    pub async fn find_reply(
+        &self,
+        conversation: &String,
+        msg_id: String,
+    ) -> Result<ReplyData, String> {
+        let data = self.find_message(msg_id.clone()).await?;
+
+        if &data.conversation_id() != conversation {
+            return Err(format!("message not exist: {}", msg_id));
+        }
+        if data.recall_by.is_some() {
+            return Err("message has been recalled !".to_string());
+        }
+
+        // 被回复的消息是回复时属于同一个话题，否则以它作为话题的开始
+        let thread_id = match &data.reply_to {
+            Some(t) => t.thread_id.clone(),
+            None => data.msg_id.clone(),
+        };
+        Ok(ReplyData {
+            msg_id: data.msg_id,
+            from_account: data.from_account,
+            snippet: create_snippet(&data.contents),
+            thread_id,
+        })
+    }
+
+    // 查询消息所在的话题，返回话题的第一条消息、按序号正序的回复和是否还有更多回复
+    pub async fn find_thread(
+        &self,
+        account: String,
+        msg_id: String,
+        after_seq: i64,
+        limit: u64,
+    ) -> Result<(ChatData, Vec<ChatData>, bool), String> {
+        let data = self.find_message(msg_id).await?;
+        self.check_participant(&account, &data).await?;
+
+        let root = match &data.reply_to {
+            Some(t) => self.find_message(t.thread_id.clone()).await?,
+            None => data,
+        };
+
+        let list = self
+            .chat_message_dao
+            .find_thread(root.msg_id.clone(), after_seq, limit + 1)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let (replies, has_more) = to_chat_data_page(list, limit)?;
+        Ok((root, replies, has_more))
+    }
+
+    // 会话的参与者添加、删除表情回应，重复添加、删除不存在的回应时不报错.
+    // 返回重新聚合回应之后的消息
+    pub async fn react_message(
+        &self,
+        account: String,
+        msg_id: String,
+        emoji: String,
+        add: bool,
+    ) -> Result<ChatData, String> {
+        let emoji = check_emoji(emoji)?;
+        let (model, mut data) = self.find_message_model(msg_id).await?;
+        self.check_participant(&account, &data).await?;
+
+        if data.recall_by.is_some() {
+            return Err("message has been recalled !".to_string());
+        }
+
+        let exist = self
+            .message_reaction_dao
+            .find_one(data.msg_id.clone(), account.clone(), emoji.clone())
+            .await
+            .map_err(|e| e.to_string())?;
+
+        match (add, exist) {
+            (true, None) => {
+                if data.reactions.len() >= REACTION_MAX_EMOJIS
+                    && !data.reactions.iter().any(|t| t.emoji == emoji)
+                {
+                    return Err(format!(
+                        "reactions of a message can not exceed {REACTION_MAX_EMOJIS} !"
+                    ));
+                }
+                self.message_reaction_dao
+                    .insert(data.msg_id.clone(), account, emoji, now_millis())
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+            (false, Some(t)) => {
+                self.message_reaction_dao
+                    .delete_by_id(t.id)
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+            _ => return Ok(data),
+        }
+
+        // 以回应表为准重新聚合，保存到消息中随消息同步
+        let list = self
+            .message_reaction_dao
+            .find_by_msg_id(data.msg_id.clone())
+            .await
+            .map_err(|e| e.to_string())?;
+        data.reactions = to_reactions(list);
+        self.update_message(model.id, &data).await?;
+        Ok(data)
+    }
+
+    // 只有会话的参与者可以回应、查看话题
+    pub async fn check_participant(&self, account: &String, data: &ChatData) -> Result<(), String> {
+        match data.group_id {
+            Some(group_id) => self.find_group_role(group_id, account).await.map(|_| ()),
+            None if &data.from_account == account || &data.to_account == account => Ok(()),
+            None => Err(format!("message not exist: {}", data.msg_id)),
+        }
+    }
+
+    // 回执只能针对sender发给account的消息，群组消息需要account还在群组中.
+    // 任何一条消息不满足时整个回执被拒绝
+    pub async fn check_receipt_msgs(
+        &self,
+        account: &String,
+        sender: &String,
+        msg_ids: &Vec<String>,
+    ) -> Result<(), String> {
+        let models = self
+            .chat_message_dao
+            .find_by_msg_ids(msg_ids.clone())
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let now = now_millis();
+        let mut group_ids = vec![];
+        for msg_id in msg_ids {
+            let data = models
+                .iter()
+                .find(|t| &t.msg_id == msg_id)
+                .and_then(|t| bincode::deserialize::<ChatData>(&t.data).ok())
+                .filter(|t| !t.is_expired(now))
+                .ok_or(format!("message not exist: {}", msg_id))?;
+            check_receipt_target(account, sender, &data)?;
+
+            if let Some(group_id) = data.group_id {
+                if !group_ids.contains(&group_id) {
+                    group_ids.push(group_id);
+                }
+            }
+        }
+
+        for group_id in group_ids {
+            self.find_group_role(group_id, account).await?;
+        }
+        Ok(())
+    }
+
+    // 消息所属会话的所有参与者
+    pub async fn find_participants(&self, data: &ChatData) -> Result<Vec<String>, String> {
+        match get_group_id(&data.conversation_id()) {
+            Some(group_id) => self.find_group_accounts(group_id).await,
+            None => Ok(vec![data.from_account.clone(), data.to_account.clone()]),
+        }
+    }
+
+    async fn find_message_model(
+        &self,
+        msg_id: String,
+    ) -> Result<(chat_message::Model, ChatData), String> {
+        let model = self
+            .chat_message_dao
+            .find_by_msg_id(msg_id.clone())
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or(format!("message not exist: {}", msg_id))?;
+
+        let data: ChatData = bincode::deserialize(&model.data).map_err(|e| e.to_string())?;
+        // 还没有被删除的过期消息
+        if data.is_expired(now_millis()) {
+            return Err(format!("message not exist: {}", msg_id));
+        }
+        Ok((model, data))
+    }
+
+    async fn update_message(&self, id: i32, data: &ChatData) -> Result<(), String> {
+        let bytes = bincode::serialize(data).map_err(|e| e.to_string())?;
+        self.chat_message_dao
+            .update_data(id, bytes)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    async fn save_message_version(
+        &self,
+        data: &ChatData,
+        editor: String,
+        action: &str,
+        time: i64,
+    ) -> Result<(), String> {
+        let bytes = bincode::serialize(&data.contents).map_err(|e| e.to_string())?;
+        self.message_edit_dao
+            .insert(data.msg_id.clone(), editor, action, bytes, time)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    // 分页位置的消息必须属于该会话，不能借此查询其它会话
+    async fn find_cursor_seq(&self, conversation: &String, msg_id: &String) -> Result<i64, String> {
+        self.chat_message_dao
+            .find_by_msg_id(msg_id.clone())
+            .await
+            .map_err(|e| e.to_string())?
+            .filter(|t: &chat_message::Model| &t.conversation == conversation)
+            .map(|t| t.seq)
+            .ok_or(format!("message not exist: {}", msg_id))
+    }
+
+    // 接收方不在线时保存消息或回执，返回离线数据的id
+    pub async fn save_offline_message(
+        &self,
+        from_account: String,
+        to_account: String,
+        data: &ChatDataEnum,
+    ) -> Result<i32, String> {
+        let bytes = bincode::serialize(data).map_err(|e| e.to_string())?;
+        let expire_time = match data {
+            ChatDataEnum::Msg(t) | ChatDataEnum::Updated(t) => t.expire_time,
+            _ => None,
+        };
+
+        self.offline_message_dao
+            .insert(from_account, to_account, bytes, expire_time, now_millis())
+            .await
+            .map(|t| t.id)
+            .map_err(|e| e.to_string())
+    }
+
+    // 按保存的顺序返回接收方的离线数据，元素为 (离线数据id, 消息或回执)
+    pub async fn find_offline_messages(
+        &self,
+        to_account: String,
+    ) -> Result<Vec<(i32, ChatDataEnum)>, String> {
+        let list = self
+            .offline_message_dao
+            .find_by_to_account(to_account)
+            .await
+            .map_err(|e| e.to_string())?;
+        to_offline_messages(list, now_millis())
+    }
+
+    // client确认收到后删除离线消息
+    pub async fn delete_offline_messages(
+        &self,
+        to_account: String,
+        ids: Vec<i32>,
+    ) -> Result<u64, String> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+
+        self.offline_message_dao
+            .delete_by_ids(to_account, ids)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    // 创建群组，创建者成为群主. 成员账户必须存在
+    pub async fn create_group(
+        &self,
+        owner: String,
+        name: String,
+        members: Vec<String>,
+    ) -> Result<GroupInfoData, String> {
+        let name = check_group_name(name)?;
+
+        let mut accounts: Vec<String> = vec![];
+        for t in members {
+            if t != owner && !accounts.contains(&t) {
+                accounts.push(t);
+            }
+        }
+        if accounts.len() + 1 > GROUP_MAX_MEMBERS {
+            return Err(format!(
+                "group members can not exceed {GROUP_MAX_MEMBERS} !"
+            ));
+        }
+        self.check_accounts_exist(&accounts).await?;
+
+        let time = now_millis();
+        let group = self
+            .chat_group_dao
+            .insert(name, owner.clone(), time)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        self.insert_member(group.id, owner, GroupRole::Owner, time)
+            .await?;
+        for account in accounts {
+            self.insert_member(group.id, account, GroupRole::Member, time)
+                .await?;
+        }
+
+        self.to_group_info(group).await
+    }
+
+    // 群主、管理员可以改名
+    pub async fn rename_group(
+        &self,
+        operator: String,
+        group_id: i32,
+        name: String,
+    ) -> Result<GroupInfoData, String> {
+        let name = check_group_name(name)?;
+        self.check_group_manager(group_id, &operator).await?;
+
+        let group = self
+            .chat_group_dao
+            .update_name(group_id, name)
+            .await
+            .map_err(|e| e.to_string())?;
+        self.to_group_info(group).await
+    }
+
+    // 群主、管理员可以邀请，已经是成员的账户会被忽略. 返回群组和新加入的账户
+    pub async fn invite_group_members(
+        &self,
+        operator: String,
+        group_id: i32,
+        accounts: Vec<String>,
+    ) -> Result<(GroupInfoData, Vec<String>), String> {
+        self.check_group_manager(group_id, &operator).await?;
+
+        let members = self.find_group_members(group_id).await?;
+        let mut added: Vec<String> = vec![];
+        for t in accounts {
+            if !members.iter().any(|m| m.account == t) && !added.contains(&t) {
+                added.push(t);
+            }
+        }
+        if members.len() + added.len() > GROUP_MAX_MEMBERS {
+            return Err(format!(
+                "group members can not exceed {GROUP_MAX_MEMBERS} !"
+            ));
+        }
+        self.check_accounts_exist(&added).await?;
+
+        let time = now_millis();
+        for account in added.iter() {
+            self.insert_member(group_id, account.clone(), GroupRole::Member, time)
+                .await?;
+        }
+
+        Ok((self.find_group_info(group_id).await?, added))
+    }
+
+    // 群主可以踢出任何成员，管理员只能踢出普通成员，不能踢出自己
+    pub async fn kick_group_members(
+        &self,
+        operator: String,
+        group_id: i32,
+        accounts: Vec<String>,
+    ) -> Result<(GroupInfoData, Vec<String>), String> {
+        let operator_role = self.check_group_manager(group_id, &operator).await?;
+
+        let members = self.find_group_members(group_id).await?;
+        let mut removed: Vec<String> = vec![];
+        for t in accounts {
+            let member = match members.iter().find(|m| m.account == t) {
+                Some(m) => m,
+                None => continue,
+            };
+            check_kick_member(&operator, operator_role, member)?;
+
+            if !removed.contains(&member.account) {
+                removed.push(member.account.clone());
+            }
+        }
+
+        if !removed.is_empty() {
+            self.group_member_dao
+                .delete_by_accounts(group_id, removed.clone())
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+
+        Ok((self.find_group_info(group_id).await?, removed))
+    }
+
+    // 群主需要先转让群主才能退出，最后一个成员退出时解散群组，返回None
+    pub async fn leave_group(
+        &self,
+        account: String,
+        group_id: i32,
+    ) -> Result<Option<GroupInfoData>, String> {
+        let role = self.find_group_role(group_id, &account).await?;
+        let members = self.find_group_members(group_id).await?;
+
+        if members.len() == 1 {
+            self.group_member_dao
+                .delete_by_accounts(group_id, vec![account])
+                .await
+                .map_err(|e| e.to_string())?;
+            self.chat_group_dao
+                .delete_by_id(group_id)
+                .await
+                .map_err(|e| e.to_string())?;
+            return Ok(None);
+        }
+
+        if role == GroupRole::Owner {
+            return Err("please transfer the owner before leaving !".to_string());
+        }
+
+        self.group_member_dao
+            .delete_by_accounts(group_id, vec![account])
+            .await
+            .map_err(|e| e.to_string())?;
+        self.find_group_info(group_id).await.map(Some)
+    }
+
+    // 只有群主可以修改角色. 设置为群主表示转让，原群主成为管理员
+    pub async fn set_group_role(
+        &self,
+        operator: String,
+        group_id: i32,
+        account: String,
+        role: GroupRole,
+    ) -> Result<GroupInfoData, String> {
+        if operator == account {
+            return Err("can not change your own role !".to_string());
+        }
+
+        // 锁定群组，转让群主的多个修改在同一个事务中，同时进行的转让按顺序执行
+        let txn = self
+            .chat_group_dao
+            .db
+            .begin()
+            .await
+            .map_err(|e| e.to_string())?;
+        let group = self
+            .chat_group_dao
+            .lock_by_id(&txn, group_id)
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or(format!("group not exist: {}", group_id))?;
+        if group.owner != operator {
+            return Err("only owner can change group roles !".to_string());
+        }
+
+        let operator_member = self.find_group_member(group_id, &operator).await?;
+        let member = self.find_group_member(group_id, &account).await?;
+        self.group_member_dao
+            .update_role(&txn, member.id, role.as_str().to_string())
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if role == GroupRole::Owner {
+            self.group_member_dao
+                .update_role(
+                    &txn,
+                    operator_member.id,
+                    GroupRole::Admin.as_str().to_string(),
+                )
+                .await
+                .map_err(|e| e.to_string())?;
+            self.chat_group_dao
+                .update_owner(&txn, group_id, account)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        txn.commit().await.map_err(|e| e.to_string())?;
+
+        self.find_group_info(group_id).await
+    }
+
+    // 只有成员可以查看群组信息
+    pub async fn find_group(
+        &self,
+        account: String,
+        group_id: i32,
+    ) -> Result<GroupInfoData, String> {
+        self.find_group_role(group_id, &account).await?;
+        self.find_group_info(group_id).await
+    }
+
+    // 账户加入的所有群组
+    pub async fn find_groups_by_account(
+        &self,
+        account: String,
+    ) -> Result<Vec<GroupInfoData>, String> {
+        let ids = self
+            .group_member_dao
+            .find_by_account(account)
+            .await
+            .map_err(|e| e.to_string())?
+            .into_iter()
+            .map(|t| t.group_id)
+            .collect();
+
+        let groups = self
+            .chat_group_dao
+            .find_by_ids(ids)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let mut list = vec![];
+        for group in groups {
+            list.push(self.to_group_info(group).await?);
+        }
+        Ok(list)
+    }
+
+    // 群组所有成员的账户，用于转发消息
+    pub async fn find_group_accounts(&self, group_id: i32) -> Result<Vec<String>, String> {
+        self.find_group_members(group_id)
+            .await
+            .map(|list| list.into_iter().map(|t| t.account).collect())
+    }
+
+    // 不是成员时返回错误
+    pub async fn find_group_role(
+        &self,
+        group_id: i32,
+        account: &String,
+    ) -> Result<GroupRole, String> {
+        self.find_group_member(group_id, account)
+            .await
+            .map(|t| get_group_role(&t))
+    }
+
+    async fn find_group_info(&self, group_id: i32) -> Result<GroupInfoData, String> {
+        let group = self
+            .chat_group_dao
+            .find_by_id(group_id)
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or(format!("group not exist: {}", group_id))?;
+        self.to_group_info(group).await
+    }
+
+    async fn to_group_info(&self, group: chat_group::Model) -> Result<GroupInfoData, String> {
+        let members = self
+            .find_group_members(group.id)
+            .await?
+            .iter()
+            .map(|t| GroupMemberData {
+                account: t.account.clone(),
+                role: get_group_role(t),
+                join_time: t.join_time,
+            })
+            .collect();
+
+        Ok(GroupInfoData {
+            group_id: group.id,
+            name: group.name,
+            members,
+        })
+    }
+
+    async fn find_group_members(&self, group_id: i32) -> Result<Vec<group_member::Model>, String> {
+        self.group_member_dao
+            .find_by_group_id(group_id)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    // 邀请的账户必须存在
+    async fn check_accounts_exist(&self, accounts: &[String]) -> Result<(), String> {
+        for account in accounts {
+            self.userinfo_dao
+                .find_by_name(account.clone())
+                .await
+                .map_err(|e| e.to_string())?
+                .ok_or(format!("account not exist: {}", account))?;
+        }
+        Ok(())
+    }
+
+    async fn find_group_member(
+        &self,
+        group_id: i32,
+        account: &String,
+    ) -> Result<group_member::Model, String> {
+        self.group_member_dao
+            .find_by_group_and_account(group_id, account.clone())
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or(format!(
+                "{} is not a member of group {} !",
+                account, group_id
+            ))
+    }
+
+    // 群主、管理员才能管理群组，返回操作者的角色
+    async fn check_group_manager(
+        &self,
+        group_id: i32,
+        account: &String,
+    ) -> Result<GroupRole, String> {
+        let role = self.find_group_role(group_id, account).await?;
+        match role.is_manager() {
+            true => Ok(role),
+            false => Err("only owner or admin can manage the group !".to_string()),
+        }
+    }
+
+    async fn insert_member(
+        &self,
+        group_id: i32,
+        account: String,
+        role: GroupRole,
+        join_time: i64,
+    ) -> Result<(), String> {
+        self.group_member_dao
+            .insert(group_id, account, role.as_str().to_string(), join_time)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+// 回执的消息必须是sender发送的，两人会话的消息接收方必须是account
+fn check_receipt_target(account: &String, sender: &String, data: &ChatData) -> Result<(), String> {
+    let to_account = data.group_id.is_some() || &data.to_account == account;
+    if &data.from_account != sender || sender == account || !to_account {
+        return Err(format!("message not exist: {}", data.msg_id));
+    }
+    Ok(())
+}
+
+// 只能在修改时限内修改自己未撤回的消息
+fn check_edit(editor: &str, data: &ChatData, time: i64, window_millis: i64) -> Result<(), String> {
+    if data.from_account != editor {
+        return Err("can only edit your own message !".to_string());
+    }
+    if data.recall_by.is_some() {
+        return Err("message has been recalled !".to_string());
+    }
+    if time - data.time > window_millis {
+        return Err("message can not be edited any more !".to_string());
+    }
+    Ok(())
+}
+
+// window_millis为None时是管理员撤回，可以撤回任何人的消息
+fn check_recall(
+    operator: &str,
+    data: &ChatData,
+    time: i64,
+    window_millis: Option<i64>,
+) -> Result<(), String> {
+    if data.recall_by.is_some() {
+        return Err("message has been recalled !".to_string());
+    }
+    if let Some(window_millis) = window_millis {
+        if data.from_account != operator {
+            return Err("can only recall your own message !".to_string());
+        }
+        if time - data.time > window_millis {
+            return Err("message can not be recalled any more !".to_string());
+        }
+    }
+    Ok(())
+}
+
+// 多查询了一条用于判断是否还有更多消息，返回前limit条消息和是否还有更多
+// 已经过期的消息不再投递，等待定时删除
+fn to_offline_messages(
+    list: Vec<offline_message::Model>,
+    now: i64,
+) -> Result<Vec<(i32, ChatDataEnum)>, String> {
+    list.into_iter()
+        .filter(|t| t.expire_time.map(|e| e > now).unwrap_or(true))
+        .map(|t| {
+            bincode::deserialize(&t.data)
+                .map(|data| (t.id, data))
+                .map_err(|e| e.to_string())
+        })
+        .collect()
+}
+
+// None为关闭阅后即焚
+fn check_disappear_seconds(expire_seconds: Option<i64>) -> Result<(), String> {
+    if let Some(t) = expire_seconds {
+        if !(DISAPPEAR_TIMER_MIN_SECONDS..=DISAPPEAR_TIMER_MAX_SECONDS).contains(&t) {
+            return Err(format!(
+                "disappear timer must be in {DISAPPEAR_TIMER_MIN_SECONDS}..={DISAPPEAR_TIMER_MAX_SECONDS} seconds !"
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn to_chat_data_page(
+    mut list: Vec<chat_message::Model>,
+    limit: u64,
+) -> Result<(Vec<ChatData>, bool), String> {
+    let has_more = list.len() as u64 > limit;
+    list.truncate(limit as usize);
+
+    let mut list = list
+        .into_iter()
+        .map(|t| bincode::deserialize(&t.data).map_err(|e| e.to_string()))
+        .collect::<Result<Vec<ChatData>, String>>()?;
+
+    // 定时删除之前已经过期的消息
+    let now = now_millis();
+    list.retain(|t| !t.is_expired(now));
+    Ok((list, has_more))
+}
+
+fn to_scheduled(model: scheduled_message::Model) -> Result<ScheduledData, String> {
+    let data = bincode::deserialize(&model.data).map_err(|e| e.to_string())?;
+    Ok(ScheduledData {
+        schedule_id: model.schedule_id,
+        data,
+        send_time: model.send_time,
+        create_time: model.create_time,
+    })
+}
+
+// 定时消息的发送时间需要在将来，并且不能太远
+fn check_send_time(send_time: i64, now: i64) -> Result<(), String> {
+    if send_time <= now || send_time - now > SCHEDULE_MAX_DELAY_MILLIS {
+        return Err("send time must be in the future and within one year !".to_string());
+    }
+    Ok(())
+}
+
+// server生成的系统消息，系统消息本身不会过期. 调用方负责分配序号
+fn create_system_message(
+    operator: String,
+    to_account: String,
+    group_id: Option<i32>,
+    content: &RichContentEnum,
+) -> ChatData {
+    ChatData {
+        msg_id: create_msg_id(),
+        from_account: operator,
+        to_account,
+        group_id,
+        contents: vec![ChatContent::Rich(ChatRichContent::new(content))],
+        seq: 0,
+        time: now_millis(),
+        edit_time: None,
+        recall_by: None,
+        reply_to: None,
+        reactions: vec![],
+        expire_time: None,
+        forward_from: None,
+    }
+}
+
+fn to_metadata(
+    conversation: String,
+    model: Option<conversation::Model>,
+    pins: Vec<conversation_pin::Model>,
+) -> ConversationMetadata {
+    let pinned_msg_ids = pins.into_iter().map(|t| t.msg_id).collect();
+    match model {
+        Some(t) => ConversationMetadata {
+            conversation,
+            title: t.title,
+            topic: t.topic,
+            avatar: t.avatar,
+            pinned_msg_ids,
+            disappear_seconds: t.disappear_seconds,
+        },
+        None => ConversationMetadata {
+            conversation,
+            title: None,
+            topic: None,
+            avatar: None,
+            pinned_msg_ids,
+            disappear_seconds: None,
+        },
+    }
+}
+
+fn check_metadata_text(text: String, name: &str, max_len: usize) -> Result<String, String> {
+    let text = text.trim().to_string();
+    if text.is_empty() || text.chars().count() > max_len {
+        return Err(format!("{} length must be in 1..={} !", name, max_len));
+    }
+    Ok(text)
+}
+
+// 消息中上传的附件的sha256，不包括外部链接
+fn get_attachment_sha256s(contents: &Vec<ChatContent>) -> Vec<String> {
+    let mut list: Vec<String> = vec![];
+    for url in find_file_urls(contents) {
+        if let Some(sha256) = get_file_sha256(&url) {
+            if !list.iter().any(|t| t == sha256) {
+                list.push(sha256.to_string());
+            }
+        }
+    }
+    list
+}
+
+// 按表情聚合回应，表情按第一次回应的顺序
+fn to_reactions(list: Vec<message_reaction::Model>) -> Vec<ReactionData> {
+    let mut reactions: Vec<ReactionData> = vec![];
+    for t in list {
+        match reactions.iter_mut().find(|r| r.emoji == t.emoji) {
+            Some(r) => r.accounts.push(t.account),
+            None => reactions.push(ReactionData {
+                emoji: t.emoji,
+                accounts: vec![t.account],
+            }),
+        }
+    }
+    reactions
+}
+
+fn check_emoji(emoji: String) -> Result<String, String> {
+    let emoji = emoji.trim().to_string();
+    if emoji.is_empty()
+        || emoji.chars().count() > REACTION_EMOJI_MAX_LEN
+        || emoji.chars().any(|c| c.is_whitespace() || c.is_control())
+    {
+        return Err("invalid reaction emoji !".to_string());
+    }
+    Ok(emoji)
+}
+
+// 无法解析的角色按普通成员处理
+fn get_group_role(member: &group_member::Model) -> GroupRole {
+    GroupRole::from_str(&member.role).unwrap_or(GroupRole::Member)
+}
+
+// 检查操作者能否踢出该成员
+fn check_kick_member(
+    operator: &str,
+    operator_role: GroupRole,
+    member: &group_member::Model,
+) -> Result<(), String> {
+    if member.account == operator {
+        return Err("can not kick yourself, please leave the group !".to_string());
+    }
+    let role = get_group_role(member);
+    if role == GroupRole::Owner || (role == GroupRole::Admin && operator_role != GroupRole::Owner) {
+        return Err(format!("permission denied to kick {} !", member.account));
+    }
+    Ok(())
+}
+
+fn check_group_name(name: String) -> Result<String, String> {
+    let name = name.trim().to_string();
+    if name.is_empty() || name.chars().count() > GROUP_NAME_MAX_LEN {
+        return Err(format!(
+            "group name length must be in 1..={GROUP_NAME_MAX_LEN} !"
+        ));
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::chat_module::{ChatFileContent, ChatTextContent};
+    use common::file_module::get_file_url;
+
+    fn create_chat_data(from: &str, to: &str, group_id: Option<i32>) -> ChatData {
+        ChatData {
+            msg_id: "msg".to_string(),
+            from_account: from.to_string(),
+            to_account: to.to_string(),
+            group_id,
+            seq: 1,
+            time: 0,
+            edit_time: None,
+            recall_by: None,
+            reply_to: None,
+            reactions: vec![],
+            expire_time: None,
+            forward_from: None,
+            contents: vec![ChatContent::Text(ChatTextContent {
+                text: "hello".to_string(),
+            })],
+        }
+    }
+
+    #[test]
+    fn receipt_only_for_msgs_from_sender_to_account() {
+        let alice = "alice".to_string();
+        let bob = "bob".to_string();
+        let carol = "carol".to_string();
+
+        let data = create_chat_data("bob", "alice", None);
+        assert!(check_receipt_target(&alice, &bob, &data).is_ok());
+        // 不是发给自己的消息
+        assert!(check_receipt_target(&carol, &bob, &data).is_err());
+        // 消息不是to_account发送的
+        assert!(check_receipt_target(&alice, &carol, &data).is_err());
+        // 不能为自己发送的消息回复回执
+        let data = create_chat_data("alice", "bob", None);
+        assert!(check_receipt_target(&alice, &alice, &data).is_err());
+
+        // 群组消息只校验发送方，成员身份由调用方查询
+        let data = create_chat_data("bob", "", Some(1));
+        assert!(check_receipt_target(&carol, &bob, &data).is_ok());
+        assert!(check_receipt_target(&carol, &alice, &data).is_err());
+    }
+
+    fn create_offline_message(
+        id: i32,
+        data: &ChatDataEnum,
+        expire_time: Option<i64>,
+    ) -> offline_message::Model {
+        offline_message::Model {
+            id,
+            from_account: "bob".to_string(),
+            to_account: "alice".to_string(),
+            data: bincode::serialize(data).unwrap(),
+            expire_time,
+            create_time: 0,
+        }
+    }
+
+    #[test]
+    fn offline_messages_in_order_and_skip_expired() {
+        let msg = ChatDataEnum::Msg(create_chat_data("bob", "alice", None));
+        let list = vec![
+            create_offline_message(3, &msg, None),
+            create_offline_message(5, &msg, Some(100)),
+            create_offline_message(7, &msg, Some(101)),
+        ];
+        let result = to_offline_messages(list, 100).unwrap();
+        let ids: Vec<i32> = result.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![3, 7]);
+        match &result[0].1 {
+            ChatDataEnum::Msg(t) => assert_eq!(t.from_account, "bob"),
+            _ => panic!("expect msg"),
+        }
+
+        let mut broken = create_offline_message(9, &msg, None);
+        broken.data.truncate(3);
+        assert!(to_offline_messages(vec![broken], 100).is_err());
+    }
+
+    #[test]
+    fn attachment_sha256s_without_duplicates() {
+        let file = |url: Option<String>| {
+            ChatContent::File(ChatFileContent {
+                file_name: "a.txt".to_string(),
+                url,
+                data: None,
+            })
+        };
+        let contents = vec![
+            file(Some(get_file_url("abc"))),
+            file(Some("https://example.com/a.txt".to_string())),
+            file(None),
+            file(Some(get_file_url("def"))),
+            file(Some(get_file_url("abc"))),
+        ];
+        assert_eq!(get_attachment_sha256s(&contents), vec!["abc", "def"]);
+    }
+
+    fn create_message_model(data: &ChatData) -> chat_message::Model {
+        chat_message::Model {
+            id: 0,
+            msg_id: data.msg_id.clone(),
+            conversation: "alice:bob".to_string(),
+            seq: data.seq,
+            from_account: data.from_account.clone(),
+            to_account: data.to_account.clone(),
+            reply_to: None,
+            thread_id: None,
+            data: bincode::serialize(data).unwrap(),
+            expire_time: data.expire_time,
+            create_time: data.time,
+        }
+    }
+
+    #[test]
+    fn disappear_timer_and_expired_msgs() {
+        assert!(check_disappear_seconds(None).is_ok());
+        assert!(check_disappear_seconds(Some(DISAPPEAR_TIMER_MIN_SECONDS)).is_ok());
+        assert!(check_disappear_seconds(Some(DISAPPEAR_TIMER_MAX_SECONDS)).is_ok());
+        assert!(check_disappear_seconds(Some(DISAPPEAR_TIMER_MIN_SECONDS - 1)).is_err());
+        assert!(check_disappear_seconds(Some(DISAPPEAR_TIMER_MAX_SECONDS + 1)).is_err());
+
+        let content = RichContentEnum::DisappearTimer(ChatDisappearTimerContent {
+            operator: "alice".to_string(),
+            expire_seconds: Some(60),
+        });
+        let data = create_system_message("alice".to_string(), "bob".to_string(), None, &content);
+        assert_eq!(data.from_account, "alice");
+        assert!(data.expire_time.is_none());
+        match &data.contents[..] {
+            [ChatContent::Rich(t)] => match t.decode() {
+                Some(RichContentEnum::DisappearTimer(t)) => assert_eq!(t.expire_seconds, Some(60)),
+                _ => panic!("expect disappear timer"),
+            },
+            _ => panic!("expect rich content"),
+        }
+
+        // 已经过期但还没有被定时删除的消息不返回，has_more按查询结果计算
+        let mut expired = create_chat_data("bob", "alice", None);
+        expired.expire_time = Some(1);
+        let mut alive = create_chat_data("bob", "alice", None);
+        alive.expire_time = Some(i64::MAX);
+        let list = vec![
+            create_message_model(&expired),
+            create_message_model(&alive),
+            create_message_model(&create_chat_data("alice", "bob", None)),
+        ];
+        let (page, has_more) = to_chat_data_page(list, 2).unwrap();
+        assert!(has_more);
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].expire_time, Some(i64::MAX));
+    }
+
+    #[test]
+    fn scheduled_send_time_and_data() {
+        let now = 1_000;
+        assert!(check_send_time(now + 1, now).is_ok());
+        assert!(check_send_time(now + SCHEDULE_MAX_DELAY_MILLIS, now).is_ok());
+        assert!(check_send_time(now, now).is_err());
+        assert!(check_send_time(now - 1, now).is_err());
+        assert!(check_send_time(now + SCHEDULE_MAX_DELAY_MILLIS + 1, now).is_err());
+
+        let data = create_chat_data("alice", "bob", None);
+        let model = scheduled_message::Model {
+            id: 1,
+            schedule_id: "s1".to_string(),
+            account: "alice".to_string(),
+            send_time: 2_000,
+            data: bincode::serialize(&data).unwrap(),
+            create_time: now,
+        };
+        let scheduled = to_scheduled(model.clone()).unwrap();
+        assert_eq!(scheduled.schedule_id, "s1");
+        assert_eq!(scheduled.send_time, 2_000);
+        assert_eq!(scheduled.create_time, now);
+        assert_eq!(scheduled.data.to_account, "bob");
+
+        let mut broken = model;
+        broken.data = vec![1];
+        assert!(to_scheduled(broken).is_err());
+    }
+
+    #[test]
+    fn metadata_text_and_pins() {
+        assert_eq!(
+            check_metadata_text(" 周末 ".to_string(), "title", 2).unwrap(),
+            "周末"
+        );
+        assert!(check_metadata_text("  ".to_string(), "title", 2).is_err());
+        assert!(check_metadata_text("周末聚会".to_string(), "title", 2).is_err());
+
+        let pin = |id: i32, msg_id: &str| conversation_pin::Model {
+            id,
+            conversation: "alice:bob".to_string(),
+            msg_id: msg_id.to_string(),
+            operator: "alice".to_string(),
+            create_time: 0,
+        };
+        let pins = vec![pin(1, "m1"), pin(2, "m2")];
+        let model = conversation::Model {
+            id: "alice:bob".to_string(),
+            last_seq: 3,
+            disappear_seconds: Some(60),
+            title: Some("title".to_string()),
+            topic: None,
+            avatar: None,
+            create_time: 0,
+        };
+        let metadata = to_metadata("alice:bob".to_string(), Some(model), pins.clone());
+        assert_eq!(metadata.title, Some("title".to_string()));
+        assert_eq!(metadata.disappear_seconds, Some(60));
+        assert_eq!(metadata.pinned_msg_ids, vec!["m1", "m2"]);
+
+        // 还没有会话记录时只有置顶的消息
+        let metadata = to_metadata("alice:bob".to_string(), None, pins);
+        assert_eq!(metadata.conversation, "alice:bob");
+        assert!(metadata.title.is_none() && metadata.disappear_seconds.is_none());
+        assert_eq!(metadata.pinned_msg_ids.len(), 2);
+    }
+
+    fn create_member(account: &str, role: GroupRole) -> group_member::Model {
+        group_member::Model {
+            id: 1,
+            group_id: 1,
+            account: account.to_string(),
+            role: role.as_str().to_string(),
+            join_time: 0,
+        }
+    }
+
+    #[test]
+    fn kick_by_role() {
+        let owner = create_member("alice", GroupRole::Owner);
+        let admin = create_member("bob", GroupRole::Admin);
+        let member = create_member("carol", GroupRole::Member);
+
+        assert!(check_kick_member("alice", GroupRole::Owner, &admin).is_ok());
+        assert!(check_kick_member("alice", GroupRole::Owner, &member).is_ok());
+        assert!(check_kick_member("alice", GroupRole::Owner, &owner).is_err());
+        // 管理员只能踢出普通成员
+        assert!(check_kick_member("bob", GroupRole::Admin, &member).is_ok());
+        assert!(check_kick_member("dave", GroupRole::Admin, &admin).is_err());
+        assert!(check_kick_member("bob", GroupRole::Admin, &owner).is_err());
+    }
+
+    #[test]
+    fn parse_group_role_and_name() {
+        assert_eq!(
+            get_group_role(&create_member("a", GroupRole::Admin)),
+            GroupRole::Admin
+        );
+        let mut member = create_member("a", GroupRole::Owner);
+        member.role = "unknown".to_string();
+        assert_eq!(get_group_role(&member), GroupRole::Member);
+        assert!(GroupRole::Admin.is_manager());
+        assert!(!GroupRole::Member.is_manager());
+
+        assert_eq!(check_group_name("  team ".to_string()).unwrap(), "team");
+        assert!(check_group_name(" ".to_string()).is_err());
+        assert!(check_group_name("名".repeat(GROUP_NAME_MAX_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn edit_and_recall_within_window() {
+        // create_chat_data的发送时间为0
+        let mut data = create_chat_data("bob", "alice", None);
+
+        assert!(check_edit("bob", &data, 1000, 1000).is_ok());
+        assert!(check_edit("bob", &data, 1001, 1000).is_err());
+        assert!(check_edit("alice", &data, 0, 1000).is_err());
+
+        assert!(check_recall("bob", &data, 1000, Some(1000)).is_ok());
+        assert!(check_recall("bob", &data, 1001, Some(1000)).is_err());
+        assert!(check_recall("alice", &data, 0, Some(1000)).is_err());
+        // 管理员撤回不受发送方和时间的限制
+        assert!(check_recall("admin", &data, i64::MAX, None).is_ok());
+
+        data.recall_by = Some("bob".to_string());
+        assert!(check_edit("bob", &data, 0, 1000).is_err());
+        assert!(check_recall("admin", &data, 0, None).is_err());
+    }
+
+    #[test]
+    fn check_and_group_reactions() {
+        assert_eq!(check_emoji(" 👍 ".to_string()).unwrap(), "👍");
+        assert!(check_emoji("".to_string()).is_err());
+        assert!(check_emoji("👍 👍".to_string()).is_err());
+        assert!(check_emoji("👍".repeat(REACTION_EMOJI_MAX_LEN + 1)).is_err());
+
+        let reaction = |account: &str, emoji: &str| message_reaction::Model {
+            id: 0,
+            msg_id: "msg".to_string(),
+            account: account.to_string(),
+            emoji: emoji.to_string(),
+            create_time: 0,
+        };
+        let reactions = to_reactions(vec![
+            reaction("alice", "👍"),
+            reaction("bob", "🎉"),
+            reaction("carol", "👍"),
+        ]);
+        assert_eq!(reactions.len(), 2);
+        assert_eq!(reactions[0].emoji, "👍");
+        assert_eq!(reactions[0].accounts, vec!["alice", "carol"]);
+        assert_eq!(reactions[1].accounts, vec!["bob"]);
+    }
+}
@@ -0,0 +1,92 @@
+use ::entity::conversation;
+use ::entity::conversation::{Entity, Model};
+use sea_orm::sea_query::OnConflict;
+use sea_orm::ActiveValue::Set;
+use sea_orm::DbErr;
+use sea_orm::*;
+
+#[derive(Debug)]
+pub struct ConversationDao {
+    pub db: DbConn,
+}
+
+impl ConversationDao {
+    pub async fn find_by_id(&self, id: String) -> Result<Option<Model>, DbErr> {
+        Entity::find_by_id(id).one(&self.db).await
+    }
+
+    // 分配会话的下一个消息序号，会话不存在时创建，返回分配之后的会话.
+    // 需要在事务中调用，会话记录被锁定到事务结束，同一个会话的消息按序号顺序提交
+    pub async fn increase_seq(
+        &self,
+        txn: &DatabaseTransaction,
+        id: String,
+        create_time: i64,
+    ) -> Result<Model, DbErr> {
+        // 不存在的记录不能用FOR UPDATE锁定，先插入，已经存在时不做修改.
+        // 同时插入时由主键保证只有一条，之后再锁定读取
+        Entity::insert(conversation::ActiveModel {
+            id: Set(id.clone()),
+            last_seq: Set(0),
+            disappear_seconds: Set(None),
+            title: Set(None),
+            topic: Set(None),
+            avatar: Set(None),
+            create_time: Set(create_time),
+        })
+        .on_conflict(
+            OnConflict::column(conversation::Column::Id)
+                .update_column(conversation::Column::Id)
+                .to_owned(),
+        )
+        .exec_without_returning(txn)
+        .await?;
+
+        let model = Entity::find_by_id(id.clone())
+            .lock_exclusive()
+            .one(txn)
+            .await?
+            .ok_or(DbErr::RecordNotFound(format!("conversation: {}", id)))?;
+
+        let seq = model.last_seq + 1;
+        let mut active: conversation::ActiveModel = model.into();
+        active.last_seq = Set(seq);
+        active.update(txn).await
+    }
+
+    // 在分配序号的事务中修改，计时器之后的消息使用新的计时器
+    pub async fn update_disappear_seconds(
+        &self,
+        txn: &DatabaseTransaction,
+        id: String,
+        disappear_seconds: Option<i64>,
+    ) -> Result<Model, DbErr> {
+        conversation::ActiveModel {
+            id: Set(id),
+            disappear_seconds: Set(disappear_seconds),
+            ..Default::default()
+        }
+        .update(txn)
+        .await
+    }
+
+    // 在分配序号的事务中修改标题、话题和头像
+    pub async fn update_metadata(
+        &self,
+        txn: &DatabaseTransaction,
+        id: String,
+        title: Option<String>,
+        topic: Option<String>,
+        avatar: Option<String>,
+    ) -> Result<Model, DbErr> {
+        conversation::ActiveModel {
+            id: Set(id),
+            title: Set(title),
+            topic: Set(topic),
+            avatar: Set(avatar),
+            ..Default::default()
+        }
+        .update(txn)
+        .await
+    }
+}
@@ -0,0 +1,68 @@
+use ::entity::conversation_pin;
+use ::entity::conversation_pin::{Entity, Model};
+use sea_orm::ActiveValue::{NotSet, Set};
+use sea_orm::DbErr;
+use sea_orm::*;
+
+#[derive(Debug)]
+pub struct ConversationPinDao {
+    pub db: DbConn,
+}
+
+impl ConversationPinDao {
+    // 在修改会话元数据的事务中置顶、取消置顶
+    pub async fn insert(
+        &self,
+        txn: &DatabaseTransaction,
+        conversation: String,
+        msg_id: String,
+        operator: String,
+        create_time: i64,
+    ) -> Result<Model, DbErr> {
+        conversation_pin::ActiveModel {
+            id: NotSet,
+            conversation: Set(conversation),
+            msg_id: Set(msg_id),
+            operator: Set(operator),
+            create_time: Set(create_time),
+        }
+        .insert(txn)
+        .await
+    }
+
+    pub async fn delete_by_msg_id(
+        &self,
+        txn: &DatabaseTransaction,
+        conversation: String,
+        msg_id: String,
+    ) -> Result<u64, DbErr> {
+        Entity::delete_many()
+            .filter(conversation_pin::Column::Conversation.eq(conversation))
+            .filter(conversation_pin::Column::MsgId.eq(msg_id))
+            .exec(txn)
+            .await
+            .map(|t| t.rows_affected)
+    }
+
+    // 按置顶的顺序返回会话中所有置顶的消息
+    pub async fn find_by_conversation<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        conversation: String,
+    ) -> Result<Vec<Model>, DbErr> {
+        Entity::find()
+            .filter(conversation_pin::Column::Conversation.eq(conversation))
+            .order_by_asc(conversation_pin::Column::Id)
+            .all(db)
+            .await
+    }
+
+    // 删除过期、撤回消息的置顶
+    pub async fn delete_by_msg_ids(&self, msg_ids: Vec<String>) -> Result<u64, DbErr> {
+        Entity::delete_many()
+            .filter(conversation_pin::Column::MsgId.is_in(msg_ids))
+            .exec(&self.db)
+            .await
+            .map(|t| t.rows_affected)
+    }
+}
@@ -0,0 +1,42 @@
+use ::entity::file_blob;
+use ::entity::file_blob::{Entity, Model};
+use sea_orm::ActiveValue::{NotSet, Set};
+use sea_orm::DbErr;
+use sea_orm::*;
+
+#[derive(Debug)]
+pub struct FileBlobDao {
+    pub db: DbConn,
+}
+
+impl FileBlobDao {
+    pub async fn insert(
+        &self,
+        sha256: String,
+        account: String,
+        size: i64,
+        create_time: i64,
+    ) -> Result<Model, DbErr> {
+        file_blob::ActiveModel {
+            id: NotSet,
+            sha256: Set(sha256),
+            account: Set(account),
+            size: Set(size),
+            create_time: Set(create_time),
+        }
+        .insert(&self.db)
+        .await
+    }
+
+    pub async fn find_by_sha256_and_account(
+        &self,
+        sha256: String,
+        account: String,
+    ) -> Result<Option<Model>, DbErr> {
+        Entity::find()
+            .filter(file_blob::Column::Sha256.eq(sha256))
+            .filter(file_blob::Column::Account.eq(account))
+            .one(&self.db)
+            .await
+    }
+}
@@ -0,0 +1,87 @@
+use ::entity::group_member;
+use ::entity::group_member::{Entity, Model};
+use sea_orm::ActiveValue::{NotSet, Set};
+use sea_orm::DbErr;
+use sea_orm::*;
+
+#[derive(Debug)]
+pub struct GroupMemberDao {
+    pub db: DbConn,
+}
+
+impl GroupMemberDao {
+    pub async fn insert(
+        &self,
+        group_id: i32,
+        account: String,
+        role: String,
+        join_time: i64,
+    ) -> Result<Model, DbErr> {
+        group_member::ActiveModel {
+            id: NotSet,
+            group_id: Set(group_id),
+            account: Set(account),
+            role: Set(role),
+            join_time: Set(join_time),
+        }
+        .insert(&self.db)
+        .await
+    }
+
+    // 按加入的顺序返回群组的所有成员
+    pub async fn find_by_group_id(&self, group_id: i32) -> Result<Vec<Model>, DbErr> {
+        Entity::find()
+            .filter(group_member::Column::GroupId.eq(group_id))
+            .order_by_asc(group_member::Column::Id)
+            .all(&self.db)
+            .await
+    }
+
+    pub async fn find_by_group_and_account(
+        &self,
+        group_id: i32,
+        account: String,
+    ) -> Result<Option<Model>, DbErr> {
+        Entity::find()
+            .filter(group_member::Column::GroupId.eq(group_id))
+            .filter(group_member::Column::Account.eq(account))
+            .one(&self.db)
+            .await
+    }
+
+    // 账户加入的所有群组
+    pub async fn find_by_account(&self, account: String) -> Result<Vec<Model>, DbErr> {
+        Entity::find()
+            .filter(group_member::Column::Account.eq(account))
+            .all(&self.db)
+            .await
+    }
+
+    pub async fn update_role(
+        &self,
+        txn: &DatabaseTransaction,
+        id: i32,
+        role: String,
+    ) -> Result<Model, DbErr> {
+        group_member::ActiveModel {
+            id: Set(id),
+            role: Set(role),
+            ..Default::default()
+        }
+        .update(txn)
+        .await
+    }
+
+    pub async fn delete_by_accounts(
+        &self,
+        group_id: i32,
+        accounts: Vec<String>,
+    ) -> Result<u64, DbErr> {
+        Entity::delete_many()
+            .filter(group_member::Column::GroupId.eq(group_id))
+            .filter(group_member::Column::Account.is_in(accounts))
+            .exec(&self.db)
+            .await
+            .map(|t| t.rows_affected)
+    }
+}
@@ -1,3 +1,21 @@
+pub mod api_key;
+pub mod api_key_dao;
+pub mod audit_log_dao;
+pub mod blob_store;
+pub mod chat_group_dao;
+pub mod chat_message_dao;
+pub mod chat_service;
+pub mod conversation_dao;
+pub mod conversation_pin_dao;
+pub mod file_blob_dao;
+pub mod group_member_dao;
+pub mod message_attachment_dao;
+pub mod message_edit_dao;
+pub mod message_index;
+pub mod message_reaction_dao;
+pub mod offline_message_dao;
+pub mod scheduled_message_dao;
+pub mod totp;
 pub mod userinfo_dao;
 pub mod userinfo_service;
 
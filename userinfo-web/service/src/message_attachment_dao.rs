@@ -0,0 +1,56 @@
+use ::entity::message_attachment;
+use ::entity::message_attachment::{Entity, Model};
+use sea_orm::ActiveValue::{NotSet, Set};
+use sea_orm::DbErr;
+use sea_orm::*;
+
+#[derive(Debug)]
+pub struct MessageAttachmentDao {
+    pub db: DbConn,
+}
+
+impl MessageAttachmentDao {
+    pub async fn insert<C: ConnectionTrait>(
+        &self,
+        db: &C,
+        msg_id: String,
+        conversation: String,
+        sha256: String,
+        create_time: i64,
+    ) -> Result<Model, DbErr> {
+        message_attachment::ActiveModel {
+            id: NotSet,
+            msg_id: Set(msg_id),
+            conversation: Set(conversation),
+            sha256: Set(sha256),
+            create_time: Set(create_time),
+        }
+        .insert(db)
+        .await
+    }
+
+    // 附件所在的所有会话
+    pub async fn find_conversations(&self, sha256: String) -> Result<Vec<String>, DbErr> {
+        let list = Entity::find()
+            .filter(message_attachment::Column::Sha256.eq(sha256))
+            .all(&self.db)
+            .await?;
+
+        let mut conversations: Vec<String> = vec![];
+        for t in list {
+            if !conversations.contains(&t.conversation) {
+                conversations.push(t.conversation);
+            }
+        }
+        Ok(conversations)
+    }
+
+    // 消息过期之后不能再通过该会话访问附件
+    pub async fn delete_by_msg_ids(&self, msg_ids: Vec<String>) -> Result<u64, DbErr> {
+        Entity::delete_many()
+            .filter(message_attachment::Column::MsgId.is_in(msg_ids))
+            .exec(&self.db)
+            .await
+            .map(|t| t.rows_affected)
+    }
+}
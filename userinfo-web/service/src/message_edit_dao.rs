@@ -0,0 +1,53 @@
+use ::entity::message_edit;
+use ::entity::message_edit::{Entity, Model};
+use sea_orm::ActiveValue::{NotSet, Set};
+use sea_orm::DbErr;
+use sea_orm::*;
+
+pub const MESSAGE_EDIT_ACTION_EDIT: &str = "EDIT";
+pub const MESSAGE_EDIT_ACTION_RECALL: &str = "RECALL";
+
+#[derive(Debug)]
+pub struct MessageEditDao {
+    pub db: DbConn,
+}
+
+impl MessageEditDao {
+    pub async fn insert(
+        &self,
+        msg_id: String,
+        editor: String,
+        action: &str,
+        data: Vec<u8>,
+        create_time: i64,
+    ) -> Result<Model, DbErr> {
+        message_edit::ActiveModel {
+            id: NotSet,
+            msg_id: Set(msg_id),
+            editor: Set(editor),
+            action: Set(action.to_string()),
+            data: Set(data),
+            create_time: Set(create_time),
+        }
+        .insert(&self.db)
+        .await
+    }
+
+    // 按修改的顺序返回消息的所有历史版本
+    pub async fn find_by_msg_id(&self, msg_id: String) -> Result<Vec<Model>, DbErr> {
+        Entity::find()
+            .filter(message_edit::Column::MsgId.eq(msg_id))
+            .order_by_asc(message_edit::Column::Id)
+            .all(&self.db)
+            .await
+    }
+
+    // 过期消息的修改历史一起删除
+    pub async fn delete_by_msg_ids(&self, msg_ids: Vec<String>) -> Result<u64, DbErr> {
+        Entity::delete_many()
+            .filter(message_edit::Column::MsgId.is_in(msg_ids))
+            .exec(&self.db)
+            .await
+            .map(|t| t.rows_affected)
+    }
+}
@@ -0,0 +1,446 @@
+use common::chat_content::{ChatContentTypeEnum, RichContentEnum};
+use common::chat_module::{get_group_id, ChatContent, ChatData, SearchReqData};
+use std::fmt::{Debug, Formatter};
+use std::fs;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tantivy::collector::TopDocs;
+use tantivy::directory::MmapDirectory;
+use tantivy::query::{BooleanQuery, Occur, PhraseQuery, Query, RangeQuery, TermQuery};
+use tantivy::schema::{
+    Field, IndexRecordOption, Schema, Value, FAST, INDEXED, STORED, STRING, TEXT,
+};
+use tantivy::tokenizer::TextAnalyzer;
+use tantivy::{Index, IndexReader, IndexWriter, Order, ReloadPolicy, TantivyDocument, Term};
+
+// 索引写入的内存上限，字节
+const INDEX_WRITER_MEMORY: usize = 50_000_000;
+
+// 未提交的修改达到该数量时马上提交，否则等待定时提交
+const INDEX_COMMIT_BATCH: usize = 1000;
+
+/**
+ *  聊天消息的全文索引，保存在本地目录. 索引中只保存消息id，
+ *  搜索到的消息需要再从数据库中查询，以数据库中的消息为准.
+ *  撤回、阅后即焚的消息和系统消息不建立索引.
+ *  修改先写入writer的缓冲，由flush定时提交，提交之后才能搜索到
+ **/
+pub struct MessageIndex {
+    dir: PathBuf,
+    reader: IndexReader,
+    writer: Mutex<IndexWriteBuffer>,
+    // 与text字段相同的分词器，用于拆分搜索的关键词
+    tokenizer: TextAnalyzer,
+    msg_id: Field,
+    conversation: Field,
+    // 两人会话的双方，群组消息为空，按会话搜索
+    account: Field,
+    from_account: Field,
+    content_type: Field,
+    text: Field,
+    time: Field,
+}
+
+struct IndexWriteBuffer {
+    writer: IndexWriter,
+    // 未提交的修改数量
+    pending: usize,
+}
+
+impl Debug for MessageIndex {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MessageIndex")
+            .field("dir", &self.dir)
+            .finish()
+    }
+}
+
+impl MessageIndex {
+    // 打开目录中的索引，不存在时创建
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self, String> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+
+        let mut builder = Schema::builder();
+        let msg_id = builder.add_text_field("msg_id", STRING | STORED);
+        let conversation = builder.add_text_field("conversation", STRING);
+        let account = builder.add_text_field("account", STRING);
+        let from_account = builder.add_text_field("from_account", STRING);
+        let content_type = builder.add_text_field("content_type", STRING);
+        let text = builder.add_text_field("text", TEXT);
+        let time = builder.add_i64_field("time", INDEXED | FAST);
+
+        let directory = MmapDirectory::open(&dir).map_err(|e| e.to_string())?;
+        let index = Index::open_or_create(directory, builder.build()).map_err(|e| e.to_string())?;
+        // 每次提交之后手动刷新
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()
+            .map_err(|e: tantivy::TantivyError| e.to_string())?;
+        let writer = index
+            .writer(INDEX_WRITER_MEMORY)
+            .map_err(|e| e.to_string())?;
+        let tokenizer = index.tokenizer_for_field(text).map_err(|e| e.to_string())?;
+
+        Ok(MessageIndex {
+            dir,
+            reader,
+            writer: Mutex::new(IndexWriteBuffer { writer, pending: 0 }),
+            tokenizer,
+            msg_id,
+            conversation,
+            account,
+            from_account,
+            content_type,
+            text,
+            time,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reader.searcher().num_docs() == 0
+    }
+
+    // 添加或者更新消息的索引. 不能搜索的消息只删除旧的索引，例如已经撤回的消息
+    pub fn add(&self, list: &[ChatData]) -> Result<(), String> {
+        let mut buffer = self.writer.lock().unwrap();
+        for data in list {
+            buffer
+                .writer
+                .delete_term(Term::from_field_text(self.msg_id, &data.msg_id));
+            if let Some(doc) = self.to_document(data) {
+                buffer.writer.add_document(doc).map_err(|e| e.to_string())?;
+            }
+        }
+        buffer.pending += list.len();
+        self.commit_if_full(&mut buffer)
+    }
+
+    pub fn remove(&self, msg_ids: &[String]) -> Result<(), String> {
+        let mut buffer = self.writer.lock().unwrap();
+        for msg_id in msg_ids {
+            buffer
+                .writer
+                .delete_term(Term::from_field_text(self.msg_id, msg_id));
+        }
+        buffer.pending += msg_ids.len();
+        self.commit_if_full(&mut buffer)
+    }
+
+    // 提交未提交的修改，没有修改时不提交
+    pub fn flush(&self) -> Result<(), String> {
+        let mut buffer = self.writer.lock().unwrap();
+        if buffer.pending == 0 {
+            return Ok(());
+        }
+        self.commit(&mut buffer)
+    }
+
+    // 在account参与的会话中搜索，group_conversations为account所在群组的会话.
+    // 按发送时间倒序返回消息id
+    pub fn search(
+        &self,
+        account: &str,
+        group_conversations: Vec<String>,
+        req: &SearchReqData,
+        limit: usize,
+    ) -> Result<Vec<String>, String> {
+        let mut scope: Vec<(Occur, Box<dyn Query>)> =
+            vec![(Occur::Should, term_query(self.account, account))];
+        for conversation in group_conversations.iter() {
+            scope.push((Occur::Should, term_query(self.conversation, conversation)));
+        }
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> =
+            vec![(Occur::Must, Box::new(BooleanQuery::new(scope)))];
+
+        if let Some(conversation) = &req.conversation {
+            clauses.push((Occur::Must, term_query(self.conversation, conversation)));
+        }
+        if let Some(from_account) = &req.from_account {
+            clauses.push((Occur::Must, term_query(self.from_account, from_account)));
+        }
+        if let Some(content_type) = &req.content_type {
+            clauses.push((
+                Occur::Must,
+                term_query(self.content_type, content_type.as_str()),
+            ));
+        }
+        if req.start_time.is_some() || req.end_time.is_some() {
+            clauses.push((
+                Occur::Must,
+                Box::new(RangeQuery::new_i64_bounds(
+                    "time".to_string(),
+                    req.start_time.map_or(Bound::Unbounded, Bound::Included),
+                    req.end_time.map_or(Bound::Unbounded, Bound::Excluded),
+                )),
+            ));
+        }
+        // 每个关键词都要匹配，关键词拆分出的多个词需要相邻
+        for word in req.text.split_whitespace() {
+            if let Some(query) = self.text_query(word) {
+                clauses.push((Occur::Must, query));
+            }
+        }
+
+        let searcher = self.reader.searcher();
+        let collector = TopDocs::with_limit(limit)
+            .and_offset(req.offset as usize)
+            .order_by_fast_field::<i64>("time", Order::Desc);
+        let top = searcher
+            .search(&BooleanQuery::new(clauses), &collector)
+            .map_err(|e| e.to_string())?;
+
+        let mut msg_ids = vec![];
+        for (_, address) in top {
+            let doc: TantivyDocument = searcher.doc(address).map_err(|e| e.to_string())?;
+            if let Some(msg_id) = doc.get_first(self.msg_id).and_then(|t| t.as_str()) {
+                msg_ids.push(msg_id.to_string());
+            }
+        }
+        Ok(msg_ids)
+    }
+
+    fn commit_if_full(&self, buffer: &mut IndexWriteBuffer) -> Result<(), String> {
+        if buffer.pending < INDEX_COMMIT_BATCH {
+            return Ok(());
+        }
+        self.commit(buffer)
+    }
+
+    fn commit(&self, buffer: &mut IndexWriteBuffer) -> Result<(), String> {
+        buffer.writer.commit().map_err(|e| e.to_string())?;
+        buffer.pending = 0;
+        self.reader.reload().map_err(|e| e.to_string())
+    }
+
+    fn to_document(&self, data: &ChatData) -> Option<TantivyDocument> {
+        if data.recall_by.is_some() || data.expire_time.is_some() {
+            return None;
+        }
+        let types: Vec<ChatContentTypeEnum> = data
+            .contents
+            .iter()
+            .filter_map(ChatContentTypeEnum::of)
+            .collect();
+        if types.is_empty() {
+            return None;
+        }
+
+        let mut doc = TantivyDocument::default();
+        doc.add_text(self.msg_id, &data.msg_id);
+        doc.add_text(self.conversation, data.conversation_id());
+        if get_group_id(&data.conversation_id()).is_none() {
+            doc.add_text(self.account, &data.from_account);
+            doc.add_text(self.account, &data.to_account);
+        }
+        doc.add_text(self.from_account, &data.from_account);
+        for t in types {
+            doc.add_text(self.content_type, t.as_str());
+        }
+        doc.add_text(self.text, split_cjk(&find_search_text(&data.contents)));
+        doc.add_i64(self.time, data.time);
+        Some(doc)
+    }
+
+    fn text_query(&self, word: &str) -> Option<Box<dyn Query>> {
+        let word = split_cjk(word);
+        let mut tokenizer = self.tokenizer.clone();
+        let mut stream = tokenizer.token_stream(&word);
+        let mut terms = vec![];
+        while let Some(token) = stream.next() {
+            terms.push((
+                token.position,
+                Term::from_field_text(self.text, &token.text),
+            ));
+        }
+
+        match terms.len() {
+            0 => None,
+            1 => Some(Box::new(TermQuery::new(
+                terms.pop().unwrap().1,
+                IndexRecordOption::Basic,
+            ))),
+            _ => Some(Box::new(PhraseQuery::new_with_offset(terms))),
+        }
+    }
+}
+
+fn term_query(field: Field, text: &str) -> Box<dyn Query> {
+    Box::new(TermQuery::new(
+        Term::from_field_text(field, text),
+        IndexRecordOption::Basic,
+    ))
+}
+
+// 可以搜索的文本: 文本、markdown、文件名和位置名称
+fn find_search_text(contents: &Vec<ChatContent>) -> String {
+    let mut list = vec![];
+    for content in contents {
+        match content {
+            ChatContent::Text(t) => list.push(t.text.clone()),
+            ChatContent::File(t) => list.push(t.file_name.clone()),
+            ChatContent::Rich(t) => match t.decode() {
+                Some(RichContentEnum::Image(t)) => list.push(t.file.file_name),
+                Some(RichContentEnum::Markdown(t)) => list.push(t.text),
+                Some(RichContentEnum::Location(t)) => list.extend(t.label),
+                _ => {}
+            },
+        }
+    }
+    list.join("\n")
+}
+
+// 默认分词器按空格和标点分词，中日韩文字之间没有空格，拆分为单个字符之后按相邻的字符搜索
+fn split_cjk(text: &str) -> String {
+    let mut result = String::with_capacity(text.len() * 2);
+    for c in text.chars() {
+        if is_cjk(c) {
+            result.push(' ');
+            result.push(c);
+            result.push(' ');
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30FF}'
+        | '\u{3400}'..='\u{4DBF}'
+        | '\u{4E00}'..='\u{9FFF}'
+        | '\u{AC00}'..='\u{D7AF}'
+        | '\u{F900}'..='\u{FAFF}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::chat_module::ChatTextContent;
+
+    // 每个测试使用单独的临时目录
+    struct TestIndex {
+        index: MessageIndex,
+    }
+
+    impl TestIndex {
+        fn open() -> Self {
+            let dir = std::env::temp_dir().join(format!("r-chat-index-{}", rand::random::<u64>()));
+            TestIndex {
+                index: MessageIndex::open(dir).unwrap(),
+            }
+        }
+    }
+
+    impl Drop for TestIndex {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.index.dir);
+        }
+    }
+
+    fn create_chat_data(msg_id: &str, text: &str, time: i64) -> ChatData {
+        ChatData {
+            msg_id: msg_id.to_string(),
+            from_account: "alice".to_string(),
+            to_account: "bob".to_string(),
+            group_id: None,
+            seq: 1,
+            time,
+            edit_time: None,
+            recall_by: None,
+            reply_to: None,
+            reactions: vec![],
+            expire_time: None,
+            forward_from: None,
+            contents: vec![ChatContent::Text(ChatTextContent {
+                text: text.to_string(),
+            })],
+        }
+    }
+
+    fn create_req(text: &str) -> SearchReqData {
+        SearchReqData {
+            text: text.to_string(),
+            conversation: None,
+            from_account: None,
+            start_time: None,
+            end_time: None,
+            content_type: None,
+            offset: 0,
+            limit: 0,
+        }
+    }
+
+    fn search(index: &MessageIndex, account: &str, text: &str) -> Vec<String> {
+        index
+            .search(account, vec![], &create_req(text), 10)
+            .unwrap()
+    }
+
+    #[test]
+    fn split_cjk_chars() {
+        assert_eq!(split_cjk("ab中文c"), "ab 中  文 c");
+        assert_eq!(split_cjk("hello"), "hello");
+    }
+
+    #[test]
+    fn search_after_flush() {
+        let test = TestIndex::open();
+        let index = &test.index;
+        index
+            .add(&[
+                create_chat_data("1", "明天一起吃饭", 1),
+                create_chat_data("2", "吃了吗 hello world", 2),
+            ])
+            .unwrap();
+        // 提交之前搜索不到
+        assert!(search(index, "alice", "吃饭").is_empty());
+        index.flush().unwrap();
+
+        assert_eq!(search(index, "alice", "吃饭"), vec!["1"]);
+        // 多个字需要相邻
+        assert!(search(index, "bob", "饭吃").is_empty());
+        // 按时间倒序
+        assert_eq!(search(index, "bob", "吃"), vec!["2", "1"]);
+        assert_eq!(search(index, "bob", "吃 world"), vec!["2"]);
+        // 不是会话的参与者
+        assert!(search(index, "carol", "吃").is_empty());
+    }
+
+    #[test]
+    fn skip_recalled_and_disappearing_msgs() {
+        let test = TestIndex::open();
+        let index = &test.index;
+        let mut expiring = create_chat_data("2", "阅后即焚", 2);
+        expiring.expire_time = Some(100);
+        index
+            .add(&[create_chat_data("1", "撤回的消息", 1), expiring])
+            .unwrap();
+        index.flush().unwrap();
+        assert_eq!(search(index, "alice", "消息"), vec!["1"]);
+        assert!(search(index, "alice", "阅后即焚").is_empty());
+
+        // 撤回之后删除旧的索引
+        let mut recalled = create_chat_data("1", "撤回的消息", 1);
+        recalled.recall_by = Some("alice".to_string());
+        index.add(&[recalled]).unwrap();
+        index.flush().unwrap();
+        assert!(search(index, "alice", "消息").is_empty());
+        assert!(index.is_empty());
+    }
+
+    #[test]
+    fn commit_when_batch_is_full() {
+        let test = TestIndex::open();
+        let index = &test.index;
+        let list: Vec<ChatData> = (0..INDEX_COMMIT_BATCH)
+            .map(|i| create_chat_data(&i.to_string(), "hello", i as i64))
+            .collect();
+        index.add(&list).unwrap();
+        // 不需要等待定时提交
+        assert!(!index.is_empty());
+    }
+}
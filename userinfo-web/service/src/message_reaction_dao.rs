@@ -0,0 +1,69 @@
+use ::entity::message_reaction;
+use ::entity::message_reaction::{Entity, Model};
+use sea_orm::ActiveValue::{NotSet, Set};
+use sea_orm::DbErr;
+use sea_orm::*;
+
+#[derive(Debug)]
+pub struct MessageReactionDao {
+    pub db: DbConn,
+}
+
+impl MessageReactionDao {
+    pub async fn insert(
+        &self,
+        msg_id: String,
+        account: String,
+        emoji: String,
+        create_time: i64,
+    ) -> Result<Model, DbErr> {
+        message_reaction::ActiveModel {
+            id: NotSet,
+            msg_id: Set(msg_id),
+            account: Set(account),
+            emoji: Set(emoji),
+            create_time: Set(create_time),
+        }
+        .insert(&self.db)
+        .await
+    }
+
+    pub async fn find_one(
+        &self,
+        msg_id: String,
+        account: String,
+        emoji: String,
+    ) -> Result<Option<Model>, DbErr> {
+        Entity::find()
+            .filter(message_reaction::Column::MsgId.eq(msg_id))
+            .filter(message_reaction::Column::Account.eq(account))
+            .filter(message_reaction::Column::Emoji.eq(emoji))
+            .one(&self.db)
+            .await
+    }
+
+    // 按回应的顺序返回消息的所有回应
+    pub async fn find_by_msg_id(&self, msg_id: String) -> Result<Vec<Model>, DbErr> {
+        Entity::find()
+            .filter(message_reaction::Column::MsgId.eq(msg_id))
+            .order_by_asc(message_reaction::Column::Id)
+            .all(&self.db)
+            .await
+    }
+
+    pub async fn delete_by_id(&self, id: i32) -> Result<u64, DbErr> {
+        Entity::delete_by_id(id)
+            .exec(&self.db)
+            .await
+            .map(|t| t.rows_affected)
+    }
+
+    // 删除过期消息的所有回应
+    pub async fn delete_by_msg_ids(&self, msg_ids: Vec<String>) -> Result<u64, DbErr> {
+        Entity::delete_many()
+            .filter(message_reaction::Column::MsgId.is_in(msg_ids))
+            .exec(&self.db)
+            .await
+            .map(|t| t.rows_affected)
+    }
+}
@@ -0,0 +1,60 @@
+use ::entity::offline_message;
+use ::entity::offline_message::{Entity, Model};
+use sea_orm::ActiveValue::{NotSet, Set};
+use sea_orm::DbErr;
+use sea_orm::*;
+
+#[derive(Debug)]
+pub struct OfflineMessageDao {
+    pub db: DbConn,
+}
+
+impl OfflineMessageDao {
+    pub async fn insert(
+        &self,
+        from_account: String,
+        to_account: String,
+        data: Vec<u8>,
+        expire_time: Option<i64>,
+        create_time: i64,
+    ) -> Result<Model, DbErr> {
+        offline_message::ActiveModel {
+            id: NotSet,
+            from_account: Set(from_account),
+            to_account: Set(to_account),
+            data: Set(data),
+            expire_time: Set(expire_time),
+            create_time: Set(create_time),
+        }
+        .insert(&self.db)
+        .await
+    }
+
+    // 按保存的顺序查询接收方的所有离线消息
+    pub async fn find_by_to_account(&self, to_account: String) -> Result<Vec<Model>, DbErr> {
+        Entity::find()
+            .filter(offline_message::Column::ToAccount.eq(to_account))
+            .order_by_asc(offline_message::Column::Id)
+            .all(&self.db)
+            .await
+    }
+
+    // 删除已经过期的阅后即焚消息，接收方登录后不再投递
+    pub async fn delete_expired(&self, now: i64) -> Result<u64, DbErr> {
+        Entity::delete_many()
+            .filter(offline_message::Column::ExpireTime.lte(now))
+            .exec(&self.db)
+            .await
+            .map(|t| t.rows_affected)
+    }
+
+    // 只删除属于该接收方的消息
+    pub async fn delete_by_ids(&self, to_account: String, ids: Vec<i32>) -> Result<u64, DbErr> {
+        Entity::delete_many()
+            .filter(offline_message::Column::ToAccount.eq(to_account))
+            .filter(offline_message::Column::Id.is_in(ids))
+            .exec(&self.db)
+            .await
+            .map(|t| t.rows_affected)
+    }
+}
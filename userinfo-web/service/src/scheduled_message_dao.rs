@@ -0,0 +1,84 @@
+use ::entity::scheduled_message;
+use ::entity::scheduled_message::{Entity, Model};
+use sea_orm::ActiveValue::{NotSet, Set};
+use sea_orm::DbErr;
+use sea_orm::*;
+
+#[derive(Debug)]
+pub struct ScheduledMessageDao {
+    pub db: DbConn,
+}
+
+impl ScheduledMessageDao {
+    pub async fn insert(
+        &self,
+        schedule_id: String,
+        account: String,
+        send_time: i64,
+        data: Vec<u8>,
+        create_time: i64,
+    ) -> Result<Model, DbErr> {
+        scheduled_message::ActiveModel {
+            id: NotSet,
+            schedule_id: Set(schedule_id),
+            account: Set(account),
+            send_time: Set(send_time),
+            data: Set(data),
+            create_time: Set(create_time),
+        }
+        .insert(&self.db)
+        .await
+    }
+
+    pub async fn update(&self, id: i32, send_time: i64, data: Vec<u8>) -> Result<Model, DbErr> {
+        scheduled_message::ActiveModel {
+            id: Set(id),
+            send_time: Set(send_time),
+            data: Set(data),
+            ..Default::default()
+        }
+        .update(&self.db)
+        .await
+    }
+
+    pub async fn find_by_schedule_id(&self, schedule_id: String) -> Result<Option<Model>, DbErr> {
+        Entity::find()
+            .filter(scheduled_message::Column::ScheduleId.eq(schedule_id))
+            .one(&self.db)
+            .await
+    }
+
+    // 按发送时间返回账户所有未发送的消息
+    pub async fn find_by_account(&self, account: String) -> Result<Vec<Model>, DbErr> {
+        Entity::find()
+            .filter(scheduled_message::Column::Account.eq(account))
+            .order_by_asc(scheduled_message::Column::SendTime)
+            .all(&self.db)
+            .await
+    }
+
+    pub async fn count_by_account(&self, account: String) -> Result<u64, DbErr> {
+        Entity::find()
+            .filter(scheduled_message::Column::Account.eq(account))
+            .count(&self.db)
+            .await
+    }
+
+    // 按发送时间返回已经到达发送时间的消息
+    pub async fn find_due(&self, now: i64, limit: u64) -> Result<Vec<Model>, DbErr> {
+        Entity::find()
+            .filter(scheduled_message::Column::SendTime.lte(now))
+            .order_by_asc(scheduled_message::Column::SendTime)
+            .limit(limit)
+            .all(&self.db)
+            .await
+    }
+
+    pub async fn delete_by_schedule_id(&self, schedule_id: String) -> Result<u64, DbErr> {
+        Entity::delete_many()
+            .filter(scheduled_message::Column::ScheduleId.eq(schedule_id))
+            .exec(&self.db)
+            .await
+            .map(|t| t.rows_affected)
+    }
+}
@@ -0,0 +1,167 @@
+use hmac::{Hmac, Mac};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+// RFC 6238 默认参数: 30秒一个时间步长，6位数字
+const TOTP_STEP_SECONDS: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+// 允许前后各偏差一个时间步长，兼容客户端时钟误差
+const TOTP_ALLOWED_SKEW: i64 = 1;
+
+const TOTP_SECRET_LEN: usize = 20;
+const TOTP_ISSUER: &str = "r-chat";
+
+const RECOVERY_CODE_COUNT: usize = 8;
+const RECOVERY_CODE_LEN: usize = 10;
+
+// 生成随机的totp密钥，base32编码
+pub fn generate_secret() -> String {
+    let bytes: [u8; TOTP_SECRET_LEN] = rand::thread_rng().gen();
+    base32::encode(base32::Alphabet::RFC4648 { padding: false }, &bytes)
+}
+
+// 生成认证器app使用的provisioning uri
+pub fn provisioning_uri(account: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{TOTP_ISSUER}:{account}?secret={secret}&issuer={TOTP_ISSUER}&algorithm=SHA1&digits={TOTP_DIGITS}&period={TOTP_STEP_SECONDS}"
+    )
+}
+
+// 校验验证码，unix_seconds为当前时间. 通过时返回验证码对应的时间步长，
+// 不大于last_step的步长已经使用过，防止验证码在有效期内被重放
+pub fn verify_code(
+    secret: &str,
+    code: &str,
+    unix_seconds: i64,
+    last_step: Option<i64>,
+) -> Option<i64> {
+    let key = base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret)?;
+
+    let code = code.trim();
+    if code.len() != TOTP_DIGITS as usize {
+        return None;
+    }
+
+    let step = unix_seconds / TOTP_STEP_SECONDS;
+    (-TOTP_ALLOWED_SKEW..=TOTP_ALLOWED_SKEW)
+        .map(|skew| step + skew)
+        .filter(|t| Some(*t) > last_step)
+        .find(|t| generate_code(&key, *t as u64) == code)
+}
+
+// 计算指定时间的验证码，用于测试
+#[cfg(test)]
+pub(crate) fn code_at(secret: &str, unix_seconds: i64) -> Option<String> {
+    let key = base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret)?;
+    Some(generate_code(
+        &key,
+        (unix_seconds / TOTP_STEP_SECONDS) as u64,
+    ))
+}
+
+// RFC 4226 HOTP
+fn generate_code(key: &[u8], counter: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("hmac can take key of any size");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    format!(
+        "{:0width$}",
+        binary % 10u32.pow(TOTP_DIGITS),
+        width = TOTP_DIGITS as usize
+    )
+}
+
+// 生成一组恢复码. 返回(明文, 存储用的hash)，明文只展示给用户一次
+pub fn generate_recovery_codes() -> (Vec<String>, String) {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(RECOVERY_CODE_LEN)
+                .map(|c| (c as char).to_ascii_lowercase())
+                .collect()
+        })
+        .collect();
+
+    let hashes = codes
+        .iter()
+        .map(|t| hash_recovery_code(t))
+        .collect::<Vec<String>>()
+        .join(",");
+
+    (codes, hashes)
+}
+
+// 使用恢复码. 匹配成功时返回移除了该恢复码之后的hash列表，每个恢复码只能使用一次
+pub fn use_recovery_code(stored_hashes: &str, code: &str) -> Option<String> {
+    let hash = hash_recovery_code(code.trim());
+    let hashes: Vec<&str> = stored_hashes.split(',').filter(|t| !t.is_empty()).collect();
+
+    if !hashes.contains(&hash.as_str()) {
+        return None;
+    }
+
+    Some(
+        hashes
+            .into_iter()
+            .filter(|t| *t != hash)
+            .collect::<Vec<&str>>()
+            .join(","),
+    )
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    hex::encode(Sha256::digest(code.to_ascii_lowercase().as_bytes()))
+}
+
+// 生成随机字符串，用于登录流程中的临时凭证等
+pub fn random_token(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc6238_sha1_test_vector() {
+        // RFC 6238 附录B中的测试密钥 "12345678901234567890"，取8位结果的后6位
+        let key = b"12345678901234567890";
+        assert_eq!(generate_code(key, 59 / 30), "287082");
+        assert_eq!(generate_code(key, 1111111109 / 30), "081804");
+
+        let secret = base32::encode(base32::Alphabet::RFC4648 { padding: false }, key);
+        assert_eq!(verify_code(&secret, "287082", 59, None), Some(1));
+        assert_eq!(verify_code(&secret, "287083", 59, None), None);
+    }
+
+    #[test]
+    fn reject_used_step() {
+        let secret = generate_secret();
+        let now = 1_700_000_000;
+        let code = code_at(&secret, now).unwrap();
+
+        let step = verify_code(&secret, &code, now, None).unwrap();
+        assert_eq!(verify_code(&secret, &code, now, Some(step)), None);
+        // 时钟偏差范围内的下一个步长仍然可以使用
+        let next = code_at(&secret, now + TOTP_STEP_SECONDS).unwrap();
+        assert_eq!(verify_code(&secret, &next, now, Some(step)), Some(step + 1));
+        // 已经使用了更晚的步长之后，更早的验证码也不能使用
+        assert_eq!(verify_code(&secret, &code, now, Some(step + 1)), None);
+    }
+}
@@ -30,6 +30,11 @@ impl Dao {
         paginator.fetch_page(page - 1).await.map(|p| (p, num_pages))
     }
 
+    // find by id
+    pub async fn find_by_id(&self, id: i32) -> Result<Option<Model>, DbErr> {
+        Entity::find_by_id(id).one(&self.db).await
+    }
+
     // find by name
     pub async fn find_by_name(&self, name: String) -> Result<Option<Model>, DbErr> {
         Entity::find()
@@ -54,6 +59,15 @@ impl Dao {
             .await
     }
 
+    // 只有明文密码、还没有scram校验数据的旧账户
+    pub async fn find_plain_pwd(&self) -> Result<Vec<Model>, DbErr> {
+        Entity::find()
+            .filter(userinfo::Column::Pwd.ne(""))
+            .filter(userinfo::Column::ScramStoredKey.is_null())
+            .all(&self.db)
+            .await
+    }
+
     // find like name
     pub async fn find_like_name(&self, name: &String) -> Result<Vec<Model>, DbErr> {
         Entity::find()
@@ -74,14 +88,28 @@ impl Dao {
             id: data.id,
             name: Set(param.name.to_owned()),
             pwd: Set(param.pwd.to_owned()),
+            nickname: Set(param.nickname.to_owned()),
+            signature: Set(param.signature.to_owned()),
+            failed_login_count: Set(param.failed_login_count),
+            last_fail_time: Set(param.last_fail_time),
+            locked_until: Set(param.locked_until),
+            totp_secret: Set(param.totp_secret.to_owned()),
+            totp_enabled: Set(param.totp_enabled),
+            totp_recovery_codes: Set(param.totp_recovery_codes.to_owned()),
+            totp_last_step: Set(param.totp_last_step),
+            scram_salt: Set(param.scram_salt.to_owned()),
+            scram_iterations: Set(param.scram_iterations),
+            scram_stored_key: Set(param.scram_stored_key.to_owned()),
+            scram_server_key: Set(param.scram_server_key.to_owned()),
+            role: Set(param.role.to_owned()),
         }
         .update(&self.db)
         .await
     }
 
     pub async fn insert(&self, param: Model) -> Result<Model, DbErr> {
-        let exist = Dao::find_like_name(self, &param.name).await;
-        if exist.is_ok() && exist.unwrap().len() > 0 {
+        // 按账户名精确匹配，name上有唯一索引，并发注册时由数据库拒绝重复的账户
+        if self.find_by_name(param.name.clone()).await?.is_some() {
             return Err(DbErr::Custom(format!(
                 "name already exist ,name:{}",
                 param.name
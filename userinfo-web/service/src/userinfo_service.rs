@@ -1,10 +1,44 @@
+use crate::api_key;
+use crate::api_key_dao::ApiKeyDao;
+use crate::audit_log_dao::{
+    AuditLogDao, AUDIT_EVENT_API_KEY_CREATE, AUDIT_EVENT_API_KEY_REVOKE, AUDIT_EVENT_LOCK,
+    AUDIT_EVENT_ROLE_CHANGE, AUDIT_EVENT_TOTP_DISABLE, AUDIT_EVENT_TOTP_ENABLE, AUDIT_EVENT_UNLOCK,
+};
+use crate::totp;
 use crate::userinfo_dao::Dao;
-use ::entity::userinfo;
+use ::entity::{api_key as api_key_entity, userinfo};
+use common::account_module::{check_pwd, ChangePwdReqData};
+use common::base::now_millis;
+use common::chat_protocol::ChatCommand;
+use common::config::LoginGuardConfig;
 use common::login_module::LoginReqData;
+use common::permission::{Permission, Role};
+use common::scram::{ScramVerifier, SCRAM_DEFAULT_ITERATIONS};
+use sha2::{Digest, Sha256};
+
+// 账户名长度限制
+const ACCOUNT_MIN_LEN: usize = 3;
+const ACCOUNT_MAX_LEN: usize = 32;
+
+// 昵称、签名长度限制
+const NICKNAME_MAX_LEN: usize = 32;
+const SIGNATURE_MAX_LEN: usize = 128;
+
+// api key备注名称长度限制
+const API_KEY_NAME_MAX_LEN: usize = 32;
+
+// 账户不存在和密码错误返回相同的信息，避免暴露账户是否存在
+const LOGIN_FAIL_MSG: &str = "login fail : account of password err !";
+
+const API_KEY_LOGIN_FAIL_MSG: &str = "login fail : api key is invalid or revoked !";
 
 #[derive(Debug)]
 pub struct Service {
     pub dao: Dao,
+    pub audit_log_dao: AuditLogDao,
+    pub api_key_dao: ApiKeyDao,
+    // 账户不存在时用来生成假的salt，启动时随机生成
+    pub fake_salt_key: String,
 }
 
 impl Service {
@@ -14,12 +48,1003 @@ impl Service {
     ) -> Result<Option<userinfo::Model>, String> {
         let result = self
             .dao
-            .find_by_name_and_pwd(param.account.clone(), param.account.clone())
-            .await;
+            .find_by_name(param.account.clone())
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(result.filter(|t| verify_pwd(t, &param.pwd)))
+    }
+
+    // 登录校验. 会检查账户是否被锁定、是否还在失败等待期内，并记录失败次数. source为请求来源
+    pub async fn login(
+        &self,
+        param: &LoginReqData,
+        source: String,
+        config: &LoginGuardConfig,
+    ) -> Result<userinfo::Model, String> {
+        let now = now_millis();
+
+        let model = self
+            .dao
+            .find_by_name(param.account.clone())
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or(LOGIN_FAIL_MSG.to_string())?;
+
+        check_login_allowed(&model, now, config)?;
+
+        if !verify_pwd(&model, &param.pwd) {
+            self.record_login_fail(model, source, now, config).await?;
+            return Err(LOGIN_FAIL_MSG.to_string());
+        }
+
+        // 启动之后才写入的明文密码，登录成功时转换为scram校验数据
+        let mut model = model;
+        if migrate_plain_pwd(&mut model) {
+            model = self
+                .dao
+                .update_by_id(model.id, model)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+
+        // 开启了两步验证的账户，需要验证码也校验通过之后才算登录成功，
+        // 否则知道密码的人可以通过反复登录清零失败次数来暴力尝试验证码
+        if !model.totp_enabled {
+            self.reset_login_fail(&model).await?;
+        }
+
+        Ok(model)
+    }
+
+    // 把旧数据中的明文密码转换为scram校验数据并清空明文，启动时执行. 返回转换的账户数量.
+    // 转换之后这些账户可以使用scram登录，数据库中也不再保存明文密码
+    pub async fn migrate_plain_pwd(&self) -> Result<usize, String> {
+        let list = self.dao.find_plain_pwd().await.map_err(|e| e.to_string())?;
+
+        let mut count = 0;
+        for mut model in list {
+            if migrate_plain_pwd(&mut model) {
+                self.dao
+                    .update_by_id(model.id, model)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    // scram登录的第一步，查询账户的salt和迭代次数.
+    // 账户不存在时返回根据账户名生成的假数据，避免暴露账户是否存在
+    pub async fn find_scram_params(&self, account: &String) -> Result<(String, u32), String> {
+        let model = self
+            .dao
+            .find_by_name(account.clone())
+            .await
+            .map_err(|e| e.to_string())?;
 
-        match result {
-            Ok(t) => Ok(t),
-            Err(e) => Err(e.to_string()),
+        match model.as_ref().and_then(get_verifier) {
+            Some(t) => Ok((t.salt, t.iterations)),
+            None => {
+                let fake = Sha256::digest(format!("{}:{}", self.fake_salt_key, account));
+                Ok((hex::encode(&fake[..16]), SCRAM_DEFAULT_ITERATIONS))
+            }
         }
     }
+
+    // scram登录的第二步，校验client proof. 成功时返回账户信息和server签名
+    pub async fn login_by_scram(
+        &self,
+        account: String,
+        auth_message: String,
+        proof: String,
+        source: String,
+        config: &LoginGuardConfig,
+    ) -> Result<(userinfo::Model, String), String> {
+        let now = now_millis();
+
+        let model = self
+            .dao
+            .find_by_name(account)
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or(LOGIN_FAIL_MSG.to_string())?;
+
+        check_login_allowed(&model, now, config)?;
+
+        let server_signature = match get_verifier(&model) {
+            // 只有明文密码的旧数据无法使用scram登录
+            None => return Err(LOGIN_FAIL_MSG.to_string()),
+            Some(t) => t.verify_proof(&auth_message, &proof),
+        };
+
+        match server_signature {
+            None => {
+                self.record_login_fail(model, source, now, config).await?;
+                Err(LOGIN_FAIL_MSG.to_string())
+            }
+            Some(t) => {
+                if !model.totp_enabled {
+                    self.reset_login_fail(&model).await?;
+                }
+                Ok((model, t))
+            }
+        }
+    }
+
+    // 使用api key登录，成功时返回账户信息和key允许使用的command.
+    // key是随机生成的，不会被暴力猜出，失败时不累计账户的失败次数，避免被人恶意锁定账户
+    pub async fn login_by_api_key(
+        &self,
+        account: String,
+        key: String,
+        config: &LoginGuardConfig,
+    ) -> Result<(userinfo::Model, Vec<String>), String> {
+        let now = now_millis();
+
+        let prefix = api_key::parse_prefix(&key).ok_or(API_KEY_LOGIN_FAIL_MSG.to_string())?;
+
+        let key_model = self
+            .api_key_dao
+            .find_by_prefix(prefix)
+            .await
+            .map_err(|e| e.to_string())?
+            .filter(|t| t.revoked_time.is_none() && t.account == account)
+            .filter(|t| api_key::verify_api_key(&key, &t.key_hash))
+            .ok_or(API_KEY_LOGIN_FAIL_MSG.to_string())?;
+
+        let model = self
+            .dao
+            .find_by_name(account)
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or(API_KEY_LOGIN_FAIL_MSG.to_string())?;
+
+        // 被锁定或者没有登录权限的账户同样不能使用api key
+        check_login_allowed(&model, now, config)?;
+
+        self.api_key_dao
+            .update_last_used_time(key_model.id, now)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok((model, api_key::split_scopes(&key_model.scopes)))
+    }
+
+    // 为账户创建api key，返回保存的数据和key的明文. 明文只在这里返回一次
+    pub async fn create_api_key(
+        &self,
+        id: i32,
+        name: String,
+        scopes: Vec<String>,
+        operator: String,
+    ) -> Result<(api_key_entity::Model, String), String> {
+        let model = self.find_user_by_id(id).await?;
+
+        if name.is_empty() || name.chars().count() > API_KEY_NAME_MAX_LEN {
+            return Err(format!(
+                "name length must be in 1..={API_KEY_NAME_MAX_LEN} !"
+            ));
+        }
+        check_scopes(&scopes)?;
+
+        let (key_prefix, key, key_hash) = api_key::generate_api_key();
+
+        let key_model = self
+            .api_key_dao
+            .insert(api_key_entity::Model {
+                id: 0,
+                account: model.name.clone(),
+                name,
+                key_prefix,
+                key_hash,
+                scopes: scopes.join(","),
+                create_time: now_millis(),
+                revoked_time: None,
+                last_used_time: None,
+            })
+            .await
+            .map_err(|e| e.to_string())?;
+
+        self.insert_audit_log(
+            model.name,
+            AUDIT_EVENT_API_KEY_CREATE,
+            operator,
+            Some(format!("{} [{}]", key_model.key_prefix, key_model.scopes)),
+        )
+        .await?;
+
+        Ok((key_model, key))
+    }
+
+    // 吊销api key. 已经使用该key登录的连接在断开前不受影响
+    pub async fn revoke_api_key(
+        &self,
+        key_id: i32,
+        operator: String,
+    ) -> Result<api_key_entity::Model, String> {
+        let key_model = self
+            .api_key_dao
+            .find_by_id(key_id)
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or(format!("cannot find api key ,id:{key_id}"))?;
+
+        if key_model.revoked_time.is_some() {
+            return Ok(key_model);
+        }
+
+        let key_model = self
+            .api_key_dao
+            .update_revoked_time(key_id, now_millis())
+            .await
+            .map_err(|e| e.to_string())?;
+
+        self.insert_audit_log(
+            key_model.account.clone(),
+            AUDIT_EVENT_API_KEY_REVOKE,
+            operator,
+            Some(key_model.key_prefix.clone()),
+        )
+        .await?;
+
+        Ok(key_model)
+    }
+
+    pub async fn find_api_keys(
+        &self,
+        account: String,
+    ) -> Result<Vec<api_key_entity::Model>, String> {
+        self.api_key_dao
+            .find_by_account(account)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    // 两步验证的第二步，校验totp验证码或者恢复码
+    pub async fn login_by_totp(
+        &self,
+        account: String,
+        code: String,
+        source: String,
+        config: &LoginGuardConfig,
+    ) -> Result<userinfo::Model, String> {
+        let now = now_millis();
+
+        let mut model = self
+            .dao
+            .find_by_name(account)
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or(LOGIN_FAIL_MSG.to_string())?;
+
+        check_login_allowed(&model, now, config)?;
+
+        if !model.totp_enabled || model.totp_secret.is_none() {
+            return Err("two-factor authentication is not enabled !".to_string());
+        }
+
+        if let Err(e) = check_second_factor(&mut model, &Some(code), now) {
+            self.record_login_fail(model, source, now, config).await?;
+            return Err(format!("login fail : {e}"));
+        }
+
+        clear_login_fail(&mut model);
+        self.dao
+            .update_by_id(model.id, model)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    // 登录成功，清除失败记录
+    async fn reset_login_fail(&self, model: &userinfo::Model) -> Result<(), String> {
+        if model.failed_login_count > 0 || model.locked_until.is_some() {
+            let mut t = model.clone();
+            clear_login_fail(&mut t);
+            self.dao
+                .update_by_id(t.id, t)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    // 开始绑定两步验证，返回账户信息和provisioning uri. 重复调用时返回同一个密钥
+    pub async fn start_totp_enroll(&self, id: i32) -> Result<(userinfo::Model, String), String> {
+        let mut model = self.find_user_by_id(id).await?;
+
+        if model.totp_enabled {
+            return Err("two-factor authentication is already enabled !".to_string());
+        }
+
+        if model.totp_secret.is_none() {
+            model.totp_secret = Some(totp::generate_secret());
+            model = self
+                .dao
+                .update_by_id(id, model)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+
+        let uri = totp::provisioning_uri(&model.name, model.totp_secret.as_ref().unwrap());
+        Ok((model, uri))
+    }
+
+    // 确认绑定: 验证码校验通过后开启两步验证，返回恢复码明文
+    pub async fn confirm_totp_enroll(
+        &self,
+        id: i32,
+        code: String,
+        operator: String,
+    ) -> Result<Vec<String>, String> {
+        let mut model = self.find_user_by_id(id).await?;
+
+        let secret = model
+            .totp_secret
+            .as_ref()
+            .ok_or("please start enroll first !".to_string())?;
+
+        let step = totp::verify_code(secret, &code, now_millis() / 1000, model.totp_last_step)
+            .ok_or("verification code err !".to_string())?;
+
+        let (codes, hashes) = totp::generate_recovery_codes();
+        model.totp_last_step = Some(step);
+        model.totp_enabled = true;
+        model.totp_recovery_codes = Some(hashes);
+
+        let model = self
+            .dao
+            .update_by_id(id, model)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        self.insert_audit_log(model.name, AUDIT_EVENT_TOTP_ENABLE, operator, None)
+            .await?;
+
+        Ok(codes)
+    }
+
+    // 关闭两步验证
+    pub async fn disable_totp(&self, id: i32, operator: String) -> Result<userinfo::Model, String> {
+        let mut model = self.find_user_by_id(id).await?;
+
+        model.totp_enabled = false;
+        model.totp_secret = None;
+        model.totp_recovery_codes = None;
+        model.totp_last_step = None;
+
+        let model = self
+            .dao
+            .update_by_id(id, model)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        self.insert_audit_log(model.name.clone(), AUDIT_EVENT_TOTP_DISABLE, operator, None)
+            .await?;
+
+        Ok(model)
+    }
+
+    pub async fn find_user_by_id(&self, id: i32) -> Result<userinfo::Model, String> {
+        self.dao
+            .find_by_id(id)
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or(format!("cannot find userInfo ,id:{id}"))
+    }
+
+    async fn insert_audit_log(
+        &self,
+        account: String,
+        event: &str,
+        source: String,
+        detail: Option<String>,
+    ) -> Result<(), String> {
+        self.audit_log_dao
+            .insert(account, event, source, detail, now_millis())
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    // 记录一次登录失败，失败次数达到上限时锁定账户并记录审计日志
+    async fn record_login_fail(
+        &self,
+        mut model: userinfo::Model,
+        source: String,
+        now: i64,
+        config: &LoginGuardConfig,
+    ) -> Result<(), String> {
+        let lock = apply_login_fail(&mut model, now, config);
+
+        let account = model.name.clone();
+        let fail_count = model.failed_login_count;
+
+        self.dao
+            .update_by_id(model.id, model)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if lock {
+            self.audit_log_dao
+                .insert(
+                    account,
+                    AUDIT_EVENT_LOCK,
+                    source,
+                    Some(format!("login failed {fail_count} times")),
+                    now,
+                )
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    // 管理员解锁账户，并记录审计日志. operator为操作人
+    pub async fn unlock_account(
+        &self,
+        id: i32,
+        operator: String,
+    ) -> Result<userinfo::Model, String> {
+        let mut model = self.find_user_by_id(id).await?;
+
+        clear_login_fail(&mut model);
+
+        let model = self
+            .dao
+            .update_by_id(id, model)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        self.insert_audit_log(model.name.clone(), AUDIT_EVENT_UNLOCK, operator, None)
+            .await?;
+
+        Ok(model)
+    }
+
+    // 修改账户角色，并记录审计日志. operator为操作人
+    pub async fn update_role(
+        &self,
+        id: i32,
+        role: Role,
+        operator: String,
+    ) -> Result<userinfo::Model, String> {
+        let mut model = self.find_user_by_id(id).await?;
+
+        let old_role = get_role(&model);
+        if old_role == role {
+            return Ok(model);
+        }
+
+        model.role = role.as_str().to_string();
+
+        let model = self
+            .dao
+            .update_by_id(id, model)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        self.insert_audit_log(
+            model.name.clone(),
+            AUDIT_EVENT_ROLE_CHANGE,
+            operator,
+            Some(format!("{old_role} -> {role}")),
+        )
+        .await?;
+
+        Ok(model)
+    }
+
+    // 校验账户是否有指定的权限，有权限时返回账户信息
+    pub async fn check_permission(
+        &self,
+        account: &String,
+        permission: Permission,
+    ) -> Result<userinfo::Model, String> {
+        let model = self
+            .dao
+            .find_by_name(account.clone())
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or(format!("account not exist: {account}"))?;
+
+        if !get_role(&model).has_permission(permission) {
+            return Err(format!("permission denied: {permission:?}"));
+        }
+
+        Ok(model)
+    }
+
+    // web端注册账户，使用表单提交的密码计算校验数据
+    pub async fn register(&self, account: String, pwd: String) -> Result<userinfo::Model, String> {
+        check_pwd(&pwd)?;
+        self.register_by_verifier(account, ScramVerifier::create(&pwd))
+            .await
+    }
+
+    // 注册账户. socket端由client计算校验数据，server不接触密码.
+    // web端和socket端都通过该函数注册，保证校验规则一致
+    pub async fn register_by_verifier(
+        &self,
+        account: String,
+        verifier: ScramVerifier,
+    ) -> Result<userinfo::Model, String> {
+        check_account(&account)?;
+        verifier.validate()?;
+
+        let mut model = userinfo::Model {
+            id: 0,
+            name: account,
+            pwd: String::new(),
+            nickname: None,
+            signature: None,
+            failed_login_count: 0,
+            last_fail_time: None,
+            locked_until: None,
+            totp_secret: None,
+            totp_enabled: false,
+            totp_recovery_codes: None,
+            totp_last_step: None,
+            scram_salt: None,
+            scram_iterations: 0,
+            scram_stored_key: None,
+            scram_server_key: None,
+            role: Role::User.as_str().to_string(),
+        };
+        set_verifier(&mut model, verifier);
+
+        self.dao.insert(model).await.map_err(|e| e.to_string())
+    }
+
+    // 修改密码，使用scram proof校验旧密码，保存client计算的新密码的校验数据.
+    // 旧密码与登录一样受账户锁定限制，校验失败同样累计失败次数，避免绕过登录的限制暴力尝试密码.
+    // 开启了两步验证的账户还需要验证码或者恢复码
+    pub async fn change_pwd(
+        &self,
+        req: ChangePwdReqData,
+        auth_message: String,
+        source: String,
+        config: &LoginGuardConfig,
+    ) -> Result<userinfo::Model, String> {
+        req.verifier.validate()?;
+
+        let now = now_millis();
+
+        let mut model = self
+            .dao
+            .find_by_name(req.account)
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or(LOGIN_FAIL_MSG.to_string())?;
+
+        check_login_allowed(&model, now, config)?;
+
+        let verified = get_verifier(&model)
+            .and_then(|t| t.verify_proof(&auth_message, &req.proof))
+            .is_some();
+        if !verified {
+            self.record_login_fail(model, source, now, config).await?;
+            return Err(LOGIN_FAIL_MSG.to_string());
+        }
+
+        if let Err(e) = check_second_factor(&mut model, &req.totp_code, now) {
+            self.record_login_fail(model, source, now, config).await?;
+            return Err(e);
+        }
+
+        set_verifier(&mut model, req.verifier);
+        clear_login_fail(&mut model);
+
+        self.dao
+            .update_by_id(model.id, model)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    // 修改资料，参数为None的字段保持不变
+    pub async fn update_profile(
+        &self,
+        account: String,
+        nickname: Option<String>,
+        signature: Option<String>,
+    ) -> Result<userinfo::Model, String> {
+        check_optional_len("nickname", &nickname, NICKNAME_MAX_LEN)?;
+        check_optional_len("signature", &signature, SIGNATURE_MAX_LEN)?;
+
+        let mut model = self
+            .dao
+            .find_by_name(account.clone())
+            .await
+            .map_err(|e| e.to_string())?
+            .ok_or(format!("account not exist: {account}"))?;
+
+        if nickname.is_some() {
+            model.nickname = nickname;
+        }
+
+        if signature.is_some() {
+            model.signature = signature;
+        }
+
+        self.dao
+            .update_by_id(model.id, model)
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+// 获取账户的scram校验数据，只有明文密码的旧数据返回None
+pub fn get_verifier(model: &userinfo::Model) -> Option<ScramVerifier> {
+    match (
+        &model.scram_salt,
+        &model.scram_stored_key,
+        &model.scram_server_key,
+    ) {
+        (Some(salt), Some(stored_key), Some(server_key)) => Some(ScramVerifier {
+            salt: salt.clone(),
+            iterations: model.scram_iterations as u32,
+            stored_key: stored_key.clone(),
+            server_key: server_key.clone(),
+        }),
+        _ => None,
+    }
+}
+
+// api key只能使用已有的command，登录不需要授权
+fn check_scopes(scopes: &[String]) -> Result<(), String> {
+    if scopes.is_empty() {
+        return Err("scopes can not be empty !".to_string());
+    }
+
+    let allowed: Vec<String> = ChatCommand::all()
+        .into_iter()
+        .filter(|t| *t != ChatCommand::Login)
+        .map(|t| t.name())
+        .collect();
+
+    match scopes.iter().find(|t| !allowed.contains(t)) {
+        Some(t) => Err(format!(
+            "unknown scope: {t}, allowed: {}",
+            allowed.join(",")
+        )),
+        None => Ok(()),
+    }
+}
+
+// 获取账户的角色. 无法识别的值按权限最少的bot处理
+pub fn get_role(model: &userinfo::Model) -> Role {
+    model.role.parse().unwrap_or(Role::Bot)
+}
+
+// 设置密码: 只保存scram校验数据，清空明文密码
+fn set_pwd(model: &mut userinfo::Model, pwd: &str) {
+    set_verifier(model, ScramVerifier::create(pwd));
+}
+
+// 只有明文密码的旧数据转换为scram校验数据，返回是否有修改
+fn migrate_plain_pwd(model: &mut userinfo::Model) -> bool {
+    if get_verifier(model).is_some() || model.pwd.is_empty() {
+        return false;
+    }
+    let pwd = model.pwd.clone();
+    set_pwd(model, &pwd);
+    true
+}
+
+fn set_verifier(model: &mut userinfo::Model, verifier: ScramVerifier) {
+    model.pwd = String::new();
+    model.scram_salt = Some(verifier.salt);
+    model.scram_iterations = verifier.iterations as i32;
+    model.scram_stored_key = Some(verifier.stored_key);
+    model.scram_server_key = Some(verifier.server_key);
+}
+
+fn verify_pwd(model: &userinfo::Model, pwd: &String) -> bool {
+    match get_verifier(model) {
+        Some(t) => t.verify_pwd(pwd),
+        // 旧数据保存的是明文密码
+        None => !model.pwd.is_empty() && model.pwd == *pwd,
+    }
+}
+
+// 开启了两步验证的账户校验验证码，验证码不匹配时尝试作为恢复码使用.
+// 通过时记录验证码的时间步长或者移除使用的恢复码，由调用方保存. 未开启两步验证时直接通过
+fn check_second_factor(
+    model: &mut userinfo::Model,
+    code: &Option<String>,
+    now: i64,
+) -> Result<(), String> {
+    if !model.totp_enabled {
+        return Ok(());
+    }
+
+    let code = code
+        .as_ref()
+        .ok_or("verification code is required !".to_string())?;
+
+    let step = model
+        .totp_secret
+        .as_ref()
+        .and_then(|t| totp::verify_code(t, code, now / 1000, model.totp_last_step));
+    if step.is_some() {
+        model.totp_last_step = step;
+        return Ok(());
+    }
+
+    let remain_codes = model
+        .totp_recovery_codes
+        .as_ref()
+        .and_then(|t| totp::use_recovery_code(t, code))
+        .ok_or("verification code err !".to_string())?;
+    model.totp_recovery_codes = Some(remain_codes);
+    Ok(())
+}
+
+// 检查账户当前是否允许尝试登录. 每次失败之后的一段时间内拒绝尝试，时长随失败次数翻倍，
+// 达到上限之后锁定账户. 被拒绝时与账户不存在、密码错误返回相同的信息，避免暴露账户是否存在
+fn check_login_allowed(
+    model: &userinfo::Model,
+    now: i64,
+    config: &LoginGuardConfig,
+) -> Result<(), String> {
+    // 没有登录权限的账户与密码错误返回相同的信息
+    if !get_role(model).has_permission(Permission::Login) {
+        return Err(LOGIN_FAIL_MSG.to_string());
+    }
+
+    let allowed_time = match (model.locked_until, model.last_fail_time) {
+        (Some(locked_until), _) => locked_until,
+        (None, Some(last_fail_time)) => {
+            last_fail_time + config.delay_millis(model.failed_login_count)
+        }
+        (None, None) => 0,
+    };
+
+    if allowed_time > now {
+        return Err(LOGIN_FAIL_MSG.to_string());
+    }
+
+    Ok(())
+}
+
+// 记录一次失败. 返回true表示本次失败导致账户被锁定
+fn apply_login_fail(model: &mut userinfo::Model, now: i64, config: &LoginGuardConfig) -> bool {
+    // 上一次锁定已经过期，重新开始计数
+    if model.locked_until.is_some() {
+        model.failed_login_count = 0;
+        model.locked_until = None;
+    }
+
+    model.failed_login_count += 1;
+    model.last_fail_time = Some(now);
+
+    let lock = model.failed_login_count >= config.max_fail_count;
+    if lock {
+        model.locked_until = Some(now + config.lock_seconds * 1000);
+    }
+    lock
+}
+
+// 登录成功或者管理员解锁时清除失败记录
+fn clear_login_fail(model: &mut userinfo::Model) {
+    model.failed_login_count = 0;
+    model.last_fail_time = None;
+    model.locked_until = None;
+}
+
+// 校验账户名: 长度限制，只能包含字母、数字和下划线
+pub fn check_account(account: &str) -> Result<(), String> {
+    let len = account.chars().count();
+    if !(ACCOUNT_MIN_LEN..=ACCOUNT_MAX_LEN).contains(&len) {
+        return Err(format!(
+            "account length must between {ACCOUNT_MIN_LEN} and {ACCOUNT_MAX_LEN}"
+        ));
+    }
+
+    if !account
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err("account can only contain letters, digits and '_'".to_string());
+    }
+
+    Ok(())
+}
+
+fn check_optional_len(field: &str, value: &Option<String>, max_len: usize) -> Result<(), String> {
+    match value {
+        Some(t) if t.chars().count() > max_len => {
+            Err(format!("{field} length can not over {max_len}"))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::scram;
+
+    fn create_model(pwd: &str) -> userinfo::Model {
+        let mut model = userinfo::Model {
+            id: 1,
+            name: "test".to_string(),
+            pwd: String::new(),
+            nickname: None,
+            signature: None,
+            failed_login_count: 0,
+            last_fail_time: None,
+            locked_until: None,
+            totp_secret: None,
+            totp_enabled: false,
+            totp_recovery_codes: None,
+            totp_last_step: None,
+            scram_salt: None,
+            scram_iterations: 0,
+            scram_stored_key: None,
+            scram_server_key: None,
+            role: Role::User.as_str().to_string(),
+        };
+        set_pwd(&mut model, pwd);
+        model
+    }
+
+    #[test]
+    fn totp_account_requires_code_besides_pwd() {
+        let now = 1_700_000_000_000;
+        let mut model = create_model("secret-pwd");
+        let (codes, hashes) = totp::generate_recovery_codes();
+        model.totp_secret = Some(totp::generate_secret());
+        model.totp_recovery_codes = Some(hashes);
+        model.totp_enabled = true;
+
+        // 只有密码不能通过
+        assert!(verify_pwd(&model, &"secret-pwd".to_string()));
+        assert!(check_second_factor(&mut model, &None, now).is_err());
+        assert!(check_second_factor(&mut model, &Some("000000x".to_string()), now).is_err());
+
+        let code = totp::code_at(model.totp_secret.as_ref().unwrap(), now / 1000).unwrap();
+        assert!(check_second_factor(&mut model, &Some(code.clone()), now).is_ok());
+        // 同一个验证码不能再次使用
+        assert!(check_second_factor(&mut model, &Some(code), now).is_err());
+
+        // 恢复码只能使用一次
+        let recovery = Some(codes[0].clone());
+        assert!(check_second_factor(&mut model, &recovery, now).is_ok());
+        assert!(check_second_factor(&mut model, &recovery, now).is_err());
+    }
+
+    fn create_config() -> LoginGuardConfig {
+        LoginGuardConfig {
+            max_fail_count: 3,
+            ip_max_fail_count: 10,
+            lock_seconds: 60,
+            base_delay_millis: 1000,
+            max_delay_millis: 10_000,
+        }
+    }
+
+    #[test]
+    fn lock_after_max_fail_count() {
+        let config = create_config();
+        let mut model = create_model("secret-pwd");
+        let mut now = 1_000_000;
+
+        assert!(!apply_login_fail(&mut model, now, &config));
+        // 等待期内被拒绝，且与密码错误的信息相同
+        assert_eq!(
+            check_login_allowed(&model, now + 999, &config),
+            Err(LOGIN_FAIL_MSG.to_string())
+        );
+        assert!(check_login_allowed(&model, now + 1000, &config).is_ok());
+
+        now += 1000;
+        assert!(!apply_login_fail(&mut model, now, &config));
+        assert!(check_login_allowed(&model, now + 1999, &config).is_err());
+
+        now += 2000;
+        assert!(apply_login_fail(&mut model, now, &config));
+        assert_eq!(
+            check_login_allowed(&model, now + 59_999, &config),
+            Err(LOGIN_FAIL_MSG.to_string())
+        );
+        assert!(check_login_allowed(&model, now + 60_000, &config).is_ok());
+
+        // 锁定过期之后重新开始计数
+        now += 60_000;
+        assert!(!apply_login_fail(&mut model, now, &config));
+        assert_eq!(model.failed_login_count, 1);
+        assert_eq!(model.locked_until, None);
+    }
+
+    #[test]
+    fn clear_fail_on_success_or_unlock() {
+        let config = create_config();
+        let mut model = create_model("secret-pwd");
+        let now = 1_000_000;
+
+        for _ in 0..config.max_fail_count {
+            apply_login_fail(&mut model, now, &config);
+        }
+        assert!(check_login_allowed(&model, now, &config).is_err());
+
+        clear_login_fail(&mut model);
+        assert!(check_login_allowed(&model, now, &config).is_ok());
+        assert_eq!(model.failed_login_count, 0);
+
+        // 清除之后重新从第一次失败开始计数
+        assert!(!apply_login_fail(&mut model, now, &config));
+        assert_eq!(model.failed_login_count, 1);
+    }
+
+    #[test]
+    fn migrate_plain_pwd_to_verifier() {
+        let mut model = create_model("secret-pwd");
+        model.pwd = "plain-pwd".to_string();
+        model.scram_salt = None;
+        model.scram_stored_key = None;
+        model.scram_server_key = None;
+        assert!(get_verifier(&model).is_none());
+
+        assert!(migrate_plain_pwd(&mut model));
+        assert!(model.pwd.is_empty());
+        let verifier = get_verifier(&model).unwrap();
+        assert!(verifier.verify_pwd("plain-pwd"));
+
+        // 转换之后可以使用scram登录
+        let message = scram::auth_message("test", "nonce", &verifier.salt, verifier.iterations);
+        let client =
+            scram::create_client_proof("plain-pwd", &verifier.salt, verifier.iterations, &message);
+        assert!(verifier.verify_proof(&message, &client.proof).is_some());
+
+        // 已经转换过的账户不再修改
+        let salt = model.scram_salt.clone();
+        assert!(!migrate_plain_pwd(&mut model));
+        assert_eq!(model.scram_salt, salt);
+    }
+
+    #[test]
+    fn account_without_totp_needs_no_code() {
+        let mut model = create_model("secret-pwd");
+        assert!(check_second_factor(&mut model, &None, 0).is_ok());
+    }
+
+    #[test]
+    fn unknown_role_as_bot() {
+        let mut model = create_model("secret-pwd");
+        assert_eq!(get_role(&model), Role::User);
+        model.role = "root".to_string();
+        assert_eq!(get_role(&model), Role::Bot);
+    }
+
+    #[test]
+    fn api_key_scopes_are_known_commands() {
+        let scopes = |list: &[&str]| list.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        assert!(check_scopes(&scopes(&["Chat", "Group"])).is_ok());
+        assert!(check_scopes(&scopes(&[])).is_err());
+        assert!(check_scopes(&scopes(&["Chat", "Admin"])).is_err());
+        // 登录不需要授权
+        assert!(check_scopes(&scopes(&["Login"])).is_err());
+    }
+
+    #[test]
+    fn check_register_and_profile_fields() {
+        assert!(check_account("bob_01").is_ok());
+        assert!(check_account("bo").is_err());
+        assert!(check_account(&"a".repeat(ACCOUNT_MAX_LEN + 1)).is_err());
+        assert!(check_account("bob-01").is_err());
+        assert!(check_account("账户名").is_err());
+
+        assert!(check_pwd("123456").is_ok());
+        assert!(check_pwd("12345").is_err());
+        assert!(check_pwd(&"a".repeat(65)).is_err());
+
+        let nickname = Some("昵".repeat(NICKNAME_MAX_LEN));
+        assert!(check_optional_len("nickname", &nickname, NICKNAME_MAX_LEN).is_ok());
+        let nickname = Some("昵".repeat(NICKNAME_MAX_LEN + 1));
+        assert!(check_optional_len("nickname", &nickname, NICKNAME_MAX_LEN).is_err());
+        assert!(check_optional_len("signature", &None, SIGNATURE_MAX_LEN).is_ok());
+    }
 }
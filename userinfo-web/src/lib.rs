@@ -1,11 +1,33 @@
 use common::config::TcpSocketConfig;
+use service::chat_service::ChatService;
 use service::userinfo_service::Service;
 use std::sync::Arc;
 
+pub use entity;
+pub use service::api_key_dao;
+pub use service::audit_log_dao;
+pub use service::blob_store;
+pub use service::chat_group_dao;
+pub use service::chat_message_dao;
+pub use service::chat_service;
+pub use service::conversation_dao;
+pub use service::conversation_pin_dao;
+pub use service::file_blob_dao;
+pub use service::group_member_dao;
+pub use service::message_attachment_dao;
+pub use service::message_index;
+pub use service::message_edit_dao;
+pub use service::message_reaction_dao;
+pub use service::offline_message_dao;
+pub use service::scheduled_message_dao;
 pub use service::sea_orm;
+pub use service::totp;
 pub use service::userinfo_dao;
 pub use service::userinfo_service;
 
-pub fn start_webserver_userinfo(user_service: Arc<Service>) -> std::io::Result<()> {
-    api::api_start_web_server_new(user_service)
+pub fn start_webserver_userinfo(
+    user_service: Arc<Service>,
+    chat_service: Arc<ChatService>,
+) -> std::io::Result<()> {
+    api::api_start_web_server_new(user_service, chat_service)
 }